@@ -0,0 +1,176 @@
+//! Pack, unpack and list benchmarks.
+
+#![allow(clippy::unwrap_used)]
+
+use std::cell::OnceCell;
+use std::fs::File;
+use std::hint::black_box;
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::path::Path;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BatchSize;
+use criterion::Criterion;
+use criterion::Throughput;
+use kpea::Archive;
+use kpea::Builder;
+use kpea::Metadata;
+use tempfile::TempDir;
+
+/// Synthetic file system tree.
+struct Tree {
+    dir: TempDir,
+    archive: Vec<u8>,
+}
+
+impl Tree {
+    fn new(create: fn(&Path)) -> Self {
+        let dir = TempDir::new().unwrap();
+        create(dir.path());
+        let archive = pack(dir.path());
+        Self { dir, archive }
+    }
+}
+
+/// The tree that is created on the first use,
+/// i.e. only for the benchmarks that are not filtered out.
+struct LazyTree {
+    name: &'static str,
+    /// The total size of the files' contents.
+    size: u64,
+    create: fn(&Path),
+    tree: OnceCell<Tree>,
+}
+
+impl LazyTree {
+    const fn new(name: &'static str, size: u64, create: fn(&Path)) -> Self {
+        Self {
+            name,
+            size,
+            create,
+            tree: OnceCell::new(),
+        }
+    }
+
+    fn get(&self) -> &Tree {
+        self.tree.get_or_init(|| Tree::new(self.create))
+    }
+}
+
+fn many_small_files(dir: &Path) {
+    for i in 0..100 {
+        let subdir = dir.join(format!("dir-{}", i));
+        std::fs::create_dir(&subdir).unwrap();
+        for j in 0..100 {
+            std::fs::write(subdir.join(format!("file-{}", j)), [j as u8; 100]).unwrap();
+        }
+    }
+}
+
+fn few_huge_files(dir: &Path) {
+    let chunk = vec![0xcc_u8; 1024 * 1024];
+    for i in 0..4 {
+        let mut file = File::create(dir.join(format!("file-{}", i))).unwrap();
+        for _ in 0..32 {
+            file.write_all(&chunk).unwrap();
+        }
+    }
+}
+
+fn deep_hierarchy(dir: &Path) {
+    let mut path = dir.to_path_buf();
+    for i in 0..100 {
+        path.push(format!("d{}", i));
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("file"), [i as u8; 100]).unwrap();
+    }
+}
+
+fn many_hard_links(dir: &Path) {
+    for i in 0..10 {
+        let original = dir.join(format!("file-{}", i));
+        std::fs::write(&original, [i as u8; 4096]).unwrap();
+        for j in 0..100 {
+            std::fs::hard_link(&original, dir.join(format!("link-{}-{}", i, j))).unwrap();
+        }
+    }
+}
+
+fn pack(dir: &Path) -> Vec<u8> {
+    let mut builder = Builder::new(Vec::new());
+    builder.append_dir_all(dir).unwrap();
+    builder.finish().unwrap()
+}
+
+fn trees() -> [LazyTree; 4] {
+    [
+        LazyTree::new("many-small-files", 100 * 100 * 100, many_small_files),
+        LazyTree::new("few-huge-files", 4 * 32 * 1024 * 1024, few_huge_files),
+        LazyTree::new("deep-hierarchy", 100 * 100, deep_hierarchy),
+        // the contents of hard links are stored once
+        LazyTree::new("many-hard-links", 10 * 4096, many_hard_links),
+    ]
+}
+
+fn benchmarks(c: &mut Criterion) {
+    for tree in trees().iter() {
+        let mut group = c.benchmark_group(tree.name);
+        group.sample_size(10);
+        group.throughput(Throughput::Bytes(tree.size));
+        group.bench_function("pack", |b| {
+            let tree = tree.get();
+            b.iter(|| black_box(pack(tree.dir.path())))
+        });
+        group.bench_function("unpack", |b| {
+            let tree = tree.get();
+            b.iter_batched(
+                || TempDir::new().unwrap(),
+                |target| {
+                    Archive::new(&tree.archive[..])
+                        .unpack(target.path())
+                        .unwrap();
+                    target
+                },
+                BatchSize::PerIteration,
+            )
+        });
+        group.bench_function("list", |b| {
+            let tree = tree.get();
+            b.iter(|| {
+                let mut num_entries = 0;
+                Archive::new(&tree.archive[..])
+                    .for_each_raw(|entry| {
+                        black_box(entry.name);
+                        num_entries += 1;
+                        ControlFlow::Continue(())
+                    })
+                    .unwrap();
+                num_entries
+            })
+        });
+        group.finish();
+    }
+}
+
+/// Header serialization and padding in isolation.
+fn headers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("headers");
+    group.throughput(Throughput::Elements(1000));
+    group.bench_function("append-empty-files", |b| {
+        b.iter(|| {
+            let mut builder = Builder::new(Vec::with_capacity(200 * 1000));
+            for i in 0..1000 {
+                builder
+                    .append_data(Metadata::regular(0o644, 0), format!("file-{}", i), b"x")
+                    .unwrap();
+            }
+            black_box(builder.finish().unwrap())
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, benchmarks, headers);
+criterion_main!(benches);
@@ -1,3 +1,33 @@
 #!/bin/sh
 
 . ./ci/preamble.sh
+
+# The maximum relative slowdown of the mean time compared to the baseline.
+threshold="${BENCH_THRESHOLD:-0.10}"
+
+# The baseline of the main branch is restored from the cache by the workflow.
+if test "$GITHUB_REF_NAME" = "main"; then
+    cargo bench --package kpea --bench kpea -- --save-baseline main
+elif test -n "$(find target/criterion -type d -name main 2>/dev/null)"; then
+    cargo bench --package kpea --bench kpea -- --baseline main
+    python3 - "$threshold" <<'PYTHON'
+import json
+import pathlib
+import sys
+
+threshold = float(sys.argv[1])
+regressions = []
+for path in sorted(pathlib.Path("target/criterion").glob("**/change/estimates.json")):
+    change = json.loads(path.read_text())["mean"]["point_estimate"]
+    name = str(path.parent.parent.relative_to("target/criterion"))
+    print(f"{name}: {change:+.1%}")
+    if change > threshold:
+        regressions.append(name)
+if regressions:
+    print(f"slower than the baseline by more than {threshold:.0%}:", *regressions, sep="\n")
+    sys.exit(1)
+PYTHON
+else
+    echo "no baseline found, skipping the comparison" >&2
+    cargo bench --package kpea --bench kpea
+fi
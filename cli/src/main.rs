@@ -1,16 +1,16 @@
 use std::ffi::OsString;
-use std::io::BufRead;
 use std::io::BufReader;
 use std::io::Error;
-use std::os::unix::ffi::OsStringExt;
 use std::path::Path;
-use std::path::PathBuf;
 use std::process::ExitCode;
 use std::str::FromStr;
 
 use clap::Parser;
-use cpio::Archive;
-use cpio::Builder;
+use cpio::driver;
+use cpio::driver::CopyInOptions;
+use cpio::driver::CopyOutOptions;
+use cpio::driver::DEFAULT_BLOCK_SIZE;
+use cpio::AbsolutePathPolicy;
 use cpio::ByteOrder;
 
 fn do_main() -> Result<ExitCode, Error> {
@@ -19,60 +19,81 @@ fn do_main() -> Result<ExitCode, Error> {
         println!("{}", VERSION);
         return Ok(ExitCode::SUCCESS);
     }
-    if args.copy_out {
-        copy_out(args)?;
+    let quiet = args.quiet;
+    let num_blocks = if args.copy_out {
+        copy_out(args)?
     } else if args.copy_in {
-        copy_in(args)?;
+        copy_in(args)?
     } else if args.list_contents {
-        list_contents()?;
+        list_contents(args)?
+    } else {
+        return Ok(ExitCode::SUCCESS);
+    };
+    if !quiet {
+        if num_blocks == 1 {
+            eprintln!("{} block", num_blocks);
+        } else {
+            eprintln!("{} blocks", num_blocks);
+        }
     }
     Ok(ExitCode::SUCCESS)
 }
 
-fn copy_out(args: Args) -> Result<(), Error> {
-    let mut reader = BufReader::new(std::io::stdin());
-    let mut builder = Builder::new(std::io::stdout());
-    builder.set_format(args.format.into());
-    let delimiter = if args.null_terminated { 0_u8 } else { b'\n' };
-    loop {
-        let mut line = Vec::new();
-        reader.read_until(delimiter, &mut line)?;
-        if let Some(ch) = line.last() {
-            if *ch == delimiter {
-                line.pop();
-            }
-        }
-        if line.is_empty() {
-            break;
-        }
-        let line = OsString::from_vec(line);
-        let path: PathBuf = line.into();
-        builder
-            .append_path(&path, &path)
-            .map_err(|e| Error::other(format!("failed to process {:?}: {}", path, e)))?;
-    }
-    builder.finish()?;
-    Ok(())
+fn copy_out(args: Args) -> Result<u64, Error> {
+    let options = CopyOutOptions {
+        format: args.format.into(),
+        dereference: args.dereference,
+        null_terminated: args.null_terminated,
+        max_size: args.max_size,
+        block_size: args.block_size(),
+        gnu_compat: args.gnu_compat,
+        metadata_overrides: args.metadata_overrides,
+    };
+    driver::copy_out(
+        BufReader::new(std::io::stdin()),
+        std::io::stdout(),
+        &options,
+    )
 }
 
-fn copy_in(args: Args) -> Result<(), Error> {
-    let mut archive = Archive::new(std::io::stdin());
-    if args.only_verify_crc {
-        archive.verify_crc(true);
-        while archive.read_entry()?.is_some() {}
+fn copy_in(args: Args) -> Result<u64, Error> {
+    let absolute_paths = if args.absolute_filenames {
+        AbsolutePathPolicy::Keep
+    } else {
+        AbsolutePathPolicy::Strip
+    };
+    let (include_patterns, exclude_patterns) = if args.nonmatching {
+        (Vec::new(), args.patterns.clone())
     } else {
-        archive.preserve_mtime(args.preserve_mtime);
-        archive.unpack(Path::new("."))?;
+        (args.patterns.clone(), Vec::new())
+    };
+    let options = CopyInOptions {
+        preserve_mtime: args.preserve_mtime,
+        preserve_owner: args.no_preserve_owner.then_some(false),
+        sandbox: args.sandbox,
+        absolute_paths,
+        include_patterns,
+        exclude_patterns,
+        only_verify_crc: args.only_verify_crc,
+        block_size: args.block_size(),
+    };
+    if args.dry_run {
+        return driver::plan_copy_in(
+            std::io::stdin(),
+            Path::new("."),
+            std::io::stdout().lock(),
+            &options,
+        );
     }
-    Ok(())
+    driver::copy_in(std::io::stdin(), Path::new("."), &options)
 }
 
-fn list_contents() -> Result<(), Error> {
-    let mut archive = Archive::new(std::io::stdin());
-    while let Some(entry) = archive.read_entry()? {
-        println!("{}", entry.path.display());
-    }
-    Ok(())
+fn list_contents(args: Args) -> Result<u64, Error> {
+    driver::list_contents(
+        std::io::stdin(),
+        std::io::stdout().lock(),
+        args.block_size(),
+    )
 }
 
 fn main() -> ExitCode {
@@ -112,6 +133,32 @@ impl FromStr for Format {
     }
 }
 
+/// Parse size in bytes with optional `K`, `M`, `G` or `T` binary suffix.
+fn parse_size(s: &str) -> Result<u64, Error> {
+    let (number, shift) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 30),
+        Some((i, 't' | 'T')) => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    let invalid_size = || Error::other(format!("invalid size `{}`", s));
+    let number: u64 = number.parse().map_err(|_| invalid_size())?;
+    number.checked_mul(1_u64 << shift).ok_or_else(invalid_size)
+}
+
+/// Parse the number of 512-byte blocks.
+fn parse_num_blocks(s: &str) -> Result<usize, Error> {
+    let invalid_block_size = || Error::other(format!("invalid block size `{}`", s));
+    let num_blocks: usize = s.parse().map_err(|_| invalid_block_size())?;
+    if num_blocks == 0 {
+        return Err(invalid_block_size());
+    }
+    num_blocks
+        .checked_mul(DEFAULT_BLOCK_SIZE)
+        .ok_or_else(invalid_block_size)
+}
+
 impl From<Format> for cpio::Format {
     fn from(other: Format) -> Self {
         match other {
@@ -140,15 +187,56 @@ struct Args {
     /// Path are delimited by NUL character instead of the newline.
     #[arg(short = '0', long = "null")]
     null_terminated: bool,
+    /// Archive the files that symbolic links point to instead of the links themselves.
+    #[arg(short = 'L', long = "dereference")]
+    dereference: bool,
     /// Preserve file modification time.
     #[arg(short = 'm', long = "preserve-modification-time")]
     preserve_mtime: bool,
+    /// Do not change the owner of the extracted files
+    /// (by default the owner is preserved when running as root).
+    #[arg(long = "no-preserve-owner")]
+    no_preserve_owner: bool,
     /// Do not print informational messages.
     #[arg(short = 'q', long = "quiet")]
     quiet: bool,
+    /// Restrict file system modifications to the current directory when extracting (Linux only).
+    #[arg(long = "sandbox")]
+    sandbox: bool,
+    /// Extract the files with absolute paths relative to the file system root
+    /// instead of the current directory.
+    #[arg(long = "absolute-filenames", overrides_with = "no_absolute_filenames")]
+    absolute_filenames: bool,
+    /// Extract all files relative to the current directory (the default).
+    #[arg(long = "no-absolute-filenames", overrides_with = "absolute_filenames")]
+    no_absolute_filenames: bool,
+    /// Print what extracting the archive would create, overwrite, link or skip
+    /// without modifying the file system.
+    #[arg(long = "dry-run", requires = "copy_in")]
+    dry_run: bool,
+    /// Extract only the files that don't match any of the patterns.
+    #[arg(short = 'f', long = "nonmatching", requires = "copy_in")]
+    nonmatching: bool,
     /// Verify files' checksum without unpacking them.
     #[arg(long = "only-verify-crc")]
     only_verify_crc: bool,
+    /// Fail if the created archive would be larger than the specified size in bytes
+    /// (`K`, `M`, `G` and `T` suffixes are supported).
+    #[arg(long = "max-size", value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<u64>,
+    /// Produce byte-for-byte the same archive as GNU cpio.
+    #[arg(long = "gnu-compat", requires = "copy_out")]
+    gnu_compat: bool,
+    /// Each path may be followed by TAB character and space-separated metadata overrides:
+    /// `mode=<octal>`, `uid=<id>`, `gid=<id>`, `mtime=<seconds>`.
+    #[arg(long = "metadata-overrides", requires = "copy_out")]
+    metadata_overrides: bool,
+    /// Use 5120-byte blocks instead of 512-byte blocks.
+    #[arg(short = 'B')]
+    large_blocks: bool,
+    /// Use blocks of the specified number of 512-byte sectors.
+    #[arg(long = "block-size", value_name = "BLOCKS", value_parser = parse_num_blocks)]
+    block_size: Option<usize>,
     /// CPIO format.
     #[arg(
         value_enum,
@@ -158,6 +246,73 @@ struct Args {
         default_value = "newc"
     )]
     format: Format,
+    /// Extract only the files which paths match at least one of the shell wildcard patterns.
+    #[arg(value_name = "PATTERN", requires = "copy_in")]
+    patterns: Vec<OsString>,
+}
+
+impl Args {
+    /// Block size in bytes.
+    fn block_size(&self) -> usize {
+        match self.block_size {
+            Some(block_size) => block_size,
+            None if self.large_blocks => 10 * DEFAULT_BLOCK_SIZE,
+            None => DEFAULT_BLOCK_SIZE,
+        }
+    }
 }
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size() {
+        assert_eq!(0, parse_size("0").unwrap());
+        assert_eq!(100, parse_size("100").unwrap());
+        assert_eq!(2 << 10, parse_size("2k").unwrap());
+        assert_eq!(2 << 10, parse_size("2K").unwrap());
+        assert_eq!(3 << 20, parse_size("3M").unwrap());
+        assert_eq!(4 << 30, parse_size("4g").unwrap());
+        assert_eq!(5 << 40, parse_size("5T").unwrap());
+        assert_eq!(u64::MAX, parse_size(&u64::MAX.to_string()).unwrap());
+        assert_eq!(((1 << 24) - 1) << 40, parse_size("16777215T").unwrap());
+        for s in [
+            "",
+            "K",
+            "-1",
+            "1.5M",
+            "1KB",
+            "1P",
+            " 1",
+            "18446744073709551616",
+            "16777216T",
+        ] {
+            assert!(parse_size(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn num_blocks() {
+        assert_eq!(DEFAULT_BLOCK_SIZE, parse_num_blocks("1").unwrap());
+        assert_eq!(10 * DEFAULT_BLOCK_SIZE, parse_num_blocks("10").unwrap());
+        let max = usize::MAX / DEFAULT_BLOCK_SIZE;
+        assert_eq!(
+            max * DEFAULT_BLOCK_SIZE,
+            parse_num_blocks(&max.to_string()).unwrap()
+        );
+        for s in [
+            "",
+            "0",
+            "-1",
+            "1K",
+            "1.5",
+            &(max + 1).to_string(),
+            &usize::MAX.to_string(),
+        ] {
+            assert!(parse_num_blocks(s).is_err(), "{:?}", s);
+        }
+    }
+}
@@ -100,6 +100,52 @@ fn their_copy_out_their_verify_crc() {
     only_verify_crc(|| Command::new("cpio"), || Command::new("cpio"), false);
 }
 
+#[test]
+#[cfg_attr(any(miri, not(target_os = "linux")), ignore)]
+fn their_copy_out_equals_our_gnu_compat_copy_out() {
+    do_not_truncate_assertions();
+    let workdir = TempDir::new().unwrap();
+    let files_txt = workdir.path().join("files.txt");
+    arbtest(|u| {
+        let format = u.choose(&["newc", "crc"]).unwrap();
+        let directory: Dir = u.arbitrary()?;
+        write_file_list(directory.path(), &files_txt).unwrap();
+        let mut outputs = Vec::new();
+        for mut cpio in [Command::new("cpio"), test_bin::get_test_bin!("kpea")] {
+            cpio.arg("--quiet");
+            cpio.arg("--null");
+            cpio.arg(format!("--format={}", format));
+            cpio.arg("-o");
+            if outputs.len() == 1 {
+                cpio.arg("--gnu-compat");
+            }
+            cpio.stdin(File::open(&files_txt).unwrap());
+            cpio.current_dir(directory.path());
+            let output = cpio.output().unwrap();
+            assert!(output.status.success());
+            outputs.push(output.stdout);
+        }
+        assert!(outputs[0] == outputs[1], "format = {}", format);
+        Ok(())
+    });
+}
+
+/// Write NUL-terminated paths of all the files in the directory relative to the directory.
+fn write_file_list(directory: &Path, files_txt: &Path) -> Result<(), Error> {
+    let mut file = BufWriter::new(File::create(files_txt)?);
+    for entry in WalkDir::new(directory).into_iter() {
+        let entry = entry?;
+        let entry_path = entry.path().strip_prefix(directory).map_err(Error::other)?;
+        if entry_path == Path::new("") {
+            continue;
+        }
+        file.write_all(entry_path.as_os_str().as_bytes())?;
+        file.write_all(&[0_u8])?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
 fn only_verify_crc<F1, F2>(mut cpio1: F1, mut cpio2: F2, allow_hard_link_to_symlink: bool)
 where
     F1: FnMut() -> Command,
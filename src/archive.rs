@@ -1,42 +1,49 @@
 use std::collections::HashMap;
-use std::ffi::OsString;
-use std::fs::create_dir;
+use std::collections::HashSet;
+use std::ffi::OsStr;
 use std::fs::create_dir_all;
-use std::fs::hard_link;
-use std::fs::set_permissions;
 use std::fs::File;
-use std::fs::Permissions;
 use std::io::Error;
 use std::io::ErrorKind;
 use std::io::IoSliceMut;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Take;
 use std::io::Write;
+use std::ops::ControlFlow;
+use std::os::fd::AsFd;
+use std::os::fd::AsRawFd;
+use std::os::fd::RawFd;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::ffi::OsStringExt;
-use std::os::unix::fs::symlink;
-use std::os::unix::fs::MetadataExt;
-use std::os::unix::fs::PermissionsExt;
-use std::os::unix::net::UnixDatagram;
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
 use std::path::PathBuf;
 
-use libc::dev_t;
-use libc::mode_t;
-use normalize_path::NormalizePath;
-
 use crate::constants::*;
+use crate::entry_error;
 use crate::io::*;
-use crate::lchown;
-use crate::mkfifo;
-use crate::mknod;
-use crate::path_to_c_string;
-use crate::set_file_modified_time;
+use crate::resolve_preserve_owner;
+#[cfg(all(target_os = "linux", feature = "landlock"))]
+use crate::restrict_writes_to;
+use crate::statvfs;
+use crate::strip_dot_slash;
+use crate::AbsoluteTargetPolicy;
 use crate::CrcWriter;
+use crate::DedupReport;
+use crate::DuplicateFinder;
+use crate::EntryMetadata;
 use crate::FileType;
 use crate::Format;
+use crate::HashWriter;
 use crate::Metadata;
 use crate::MetadataId;
+use crate::PlannedChange;
+use crate::Planner;
+use crate::UnpackError;
+use crate::UnpackOptions;
+use crate::UnpackReport;
+use crate::Unpacker;
 
 /// CPIO archive reader.
 pub struct Archive<R: Read> {
@@ -46,9 +53,16 @@ pub struct Archive<R: Read> {
     contents: HashMap<MetadataId, Vec<u8>>,
     // current entry's contents
     cur_contents: Vec<u8>,
-    preserve_mtime: bool,
-    preserve_owner: bool,
     verify_crc: bool,
+    unpack_options: UnpackOptions,
+    // the number of entries read so far
+    num_entries: usize,
+    // padding after the contents of the last entry
+    file_padding: Padding,
+    strip_dot_slash: bool,
+    nlink_policy: NlinkPolicy,
+    // The buffer that is used to copy and skip the contents of the entries.
+    io_buffer: Vec<u8>,
 }
 
 impl<R: Read> Archive<R> {
@@ -58,24 +72,36 @@ impl<R: Read> Archive<R> {
             reader,
             contents: Default::default(),
             cur_contents: Default::default(),
-            preserve_mtime: false,
-            preserve_owner: false,
             verify_crc: false,
+            unpack_options: Default::default(),
+            num_entries: 0,
+            file_padding: Default::default(),
+            strip_dot_slash: false,
+            nlink_policy: Default::default(),
+            io_buffer: Vec::new(),
         }
     }
 
     /// Preserve file modification time.
     ///
+    /// This sets [`UnpackOptions::preserve_mtime`] of the current [unpack options](Self::set_unpack_options)
+    /// and is overwritten by the subsequent calls to `set_unpack_options`.
+    ///
     /// `false` by default.
+    #[deprecated(note = "use `UnpackOptions::preserve_mtime` instead")]
     pub fn preserve_mtime(&mut self, value: bool) {
-        self.preserve_mtime = value;
+        self.unpack_options.preserve_mtime = value;
     }
 
     /// Preserve file's user and group IDs.
     ///
-    /// `false` by default.
+    /// This sets [`UnpackOptions::preserve_owner`] of the current [unpack options](Self::set_unpack_options)
+    /// and is overwritten by the subsequent calls to `set_unpack_options`.
+    ///
+    /// `true` for root and `false` for other users by default.
+    #[deprecated(note = "use `UnpackOptions::preserve_owner` instead")]
     pub fn preserve_owner(&mut self, value: bool) {
-        self.preserve_owner = value;
+        self.unpack_options.preserve_owner = Some(value);
     }
 
     /// Verify files' checksums.
@@ -85,6 +111,52 @@ impl<R: Read> Archive<R> {
         self.verify_crc = value;
     }
 
+    /// Remove `./` prefix from the paths of the entries, e.g. `./usr/bin/sh` becomes `usr/bin/sh`.
+    ///
+    /// Use this option to read RPM payloads.
+    ///
+    /// `false` by default.
+    pub fn strip_dot_slash(&mut self, value: bool) {
+        self.strip_dot_slash = value;
+    }
+
+    /// Choose which entries are considered hard links to the same file.
+    ///
+    /// Affects both [`next_entry`](Self::next_entry) and unpacking.
+    ///
+    /// [`NlinkPolicy::TrustDuplicateInodes`] by default.
+    pub fn set_nlink_policy(&mut self, policy: NlinkPolicy) {
+        self.nlink_policy = policy;
+    }
+
+    /// Set the size of the buffer in bytes that is used to copy and skip the contents of the entries.
+    ///
+    /// The buffer is allocated once and is reused for all the entries.
+    /// Affects [`EntryReader::copy_to`], unpacking and skipping the entries.
+    /// Zero means [`copy`](std::io::copy) with its default buffer.
+    ///
+    /// 0 by default.
+    pub fn set_io_buffer_size(&mut self, size: usize) {
+        self.io_buffer = vec![0; size];
+    }
+
+    /// Set options that are used by [`unpack`](Self::unpack) and [`unpack_in_dirfd`](Self::unpack_in_dirfd).
+    pub fn set_unpack_options(&mut self, options: UnpackOptions) {
+        self.unpack_options = options;
+    }
+
+    /// Get unpack options.
+    pub fn unpack_options(&self) -> &UnpackOptions {
+        &self.unpack_options
+    }
+
+    /// Get the padding that followed the contents of the last entry.
+    ///
+    /// Updated when the entry returned by [`next_entry`](Self::next_entry) is dropped.
+    pub fn file_padding(&self) -> Padding {
+        self.file_padding
+    }
+
     /// Get mutable reference to the underyling reader.
     pub fn get_mut(&mut self) -> &mut R {
         self.reader.by_ref()
@@ -101,168 +173,458 @@ impl<R: Read> Archive<R> {
     }
 
     /// Unpack the archive to the target `directory`.
-    pub fn unpack<P: AsRef<Path>>(mut self, directory: P) -> Result<(), Error> {
-        use std::collections::hash_map::Entry::*;
+    ///
+    /// All files are created relative to the opened target directory
+    /// without following symbolic links in the last path component.
+    /// The entries that would be written through any symbolic link inside the target directory
+    /// fail with [`ErrorKind::InvalidData`], i.e. such archives can't write outside of the target directory.
+    ///
+    /// Returns the paths that were written and the entries that were skipped.
+    /// On failure the error carries the report on the entries that were unpacked before it.
+    pub fn unpack<P: AsRef<Path>>(self, directory: P) -> Result<UnpackReport, UnpackError> {
+        let directory = directory.as_ref();
+        create_dir_all(directory)?;
+        let dir = File::options()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY)
+            .open(directory)?;
+        self.do_unpack(dir.as_raw_fd(), Some(directory))
+    }
+
+    /// Unpack the archive to the already opened directory `dirfd`.
+    ///
+    /// All files are created relative to this directory
+    /// which makes this method usable in sandboxed processes
+    /// that can't access the file system via absolute paths.
+    pub fn unpack_in_dirfd<D: AsFd>(self, dirfd: D) -> Result<UnpackReport, UnpackError> {
+        self.do_unpack(dirfd.as_fd().as_raw_fd(), None)
+    }
+
+    /// Unpack only the entry with the specified `path` to the target `directory`.
+    ///
+    /// The entry is found with [`find`](Self::find), i.e. the entries before it are skipped
+    /// and the hard links without contents are resolved.
+    /// The parent directories of the entry are created with the default permissions.
+    /// The unpack options are applied the same way as in [`unpack`](Self::unpack).
+    /// Returns `false` if there is no such entry.
+    pub fn extract_path<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        path: P,
+        directory: Q,
+    ) -> Result<bool, Error> {
         let directory = directory.as_ref();
         create_dir_all(directory)?;
-        let directory = directory.normalize();
-        let mut dirs = Vec::new();
-        // inode -> path
-        let mut hard_links = HashMap::new();
-        let preserve_mtime = self.preserve_mtime;
-        let preserve_owner = self.preserve_owner;
-        while let Some(mut entry) = self.read_entry()? {
-            let path = match entry.path.strip_prefix("/") {
-                Ok(path) => path,
-                Err(_) => entry.path.as_path(),
+        let dir = File::options()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY)
+            .open(directory)?;
+        let mut unpacker = self.unpacker(dir.as_raw_fd(), Some(directory))?;
+        let result = self.extract_entry(&mut unpacker, path.as_ref());
+        // the hooks are reused by the subsequent calls
+        unpacker.restore_hooks(&mut self.unpack_options);
+        result
+    }
+
+    fn extract_entry(&mut self, unpacker: &mut Unpacker<'_>, path: &Path) -> Result<bool, Error> {
+        let Some(mut entry) = self.find(path)? else {
+            return Ok(false);
+        };
+        let index = entry.index;
+        unpacker
+            .unpack_entry(&mut entry)
+            .map_err(|e| entry_error(e, index, &entry.path))?;
+        drop(entry);
+        unpacker.finish_files()?;
+        Ok(true)
+    }
+
+    /// Find the entry with the specified `path`.
+    ///
+    /// The entries are read until the matching one, their contents are skipped.
+    /// The leading `./` is ignored both in `path` and in the paths of the entries.
+    /// If the entry is a hard link which contents are stored in one of the subsequent links
+    /// (this is how New ASCII format stores hard links),
+    /// the archive is read further until that link,
+    /// and its contents and metadata are returned with the path of the requested entry.
+    /// Returns `None` if the end of the archive is reached.
+    pub fn find<P: AsRef<Path>>(&mut self, path: P) -> Result<Option<Entry<'_, R>>, Error> {
+        let path = strip_dot_slash(path.as_ref()).to_path_buf();
+        // the hard link without contents that was found
+        let mut link: Option<Header> = None;
+        loop {
+            let index = self.num_entries;
+            let header = self
+                .read_header()
+                .map_err(|e| Error::new(e.kind(), format!("entry #{}: {}", index, e)))?;
+            let Some(header) = header else {
+                return Ok(link.map(|link| self.new_entry(link)));
             };
-            let path = directory.join(path).normalize();
-            if !path.starts_with(&directory) {
-                continue;
-            }
-            if let Some(dirname) = path.parent() {
-                create_dir_all(dirname)?;
-            }
-            match hard_links.entry(entry.metadata.ino()) {
-                Vacant(v) => {
-                    v.insert((path.clone(), entry.metadata.file_size));
+            let found = match link.as_ref() {
+                Some(link) => {
+                    header.metadata.id() == link.metadata.id() && header.metadata.file_size != 0
                 }
-                Occupied(o) => {
-                    let (original, original_file_size) = o.get();
-                    hard_link(original, &path)?;
-                    if entry.metadata.is_file() && *original_file_size < entry.metadata.file_size {
-                        let old_mode = path.metadata()?.mode();
-                        if !is_writable(old_mode) {
-                            // make writable
-                            set_permissions(&path, Permissions::from_mode(0o644))?;
-                        }
-                        let mut file = File::options().write(true).truncate(true).open(&path)?;
-                        entry.reader.copy_to(&mut file)?;
-                        if preserve_mtime {
-                            if let Ok(modified) = entry.metadata.modified() {
-                                file.set_modified(modified)?;
-                            }
-                        }
-                        drop(file);
-                        if preserve_owner {
-                            std::os::unix::fs::lchown(
-                                &path,
-                                Some(entry.metadata.uid),
-                                Some(entry.metadata.gid),
-                            )?;
-                        }
-                        set_permissions(&path, Permissions::from_mode(old_mode))?;
-                    }
+                None => strip_dot_slash(&header.path) == path,
+            };
+            if found {
+                if link.is_none() && is_hard_link_without_contents(&header) {
+                    link = Some(header);
                     continue;
                 }
-            }
-            match entry.metadata.file_type()? {
-                FileType::Regular => {
-                    let mut file = File::create(&path)?;
-                    let n = entry.reader.copy_to(&mut file)?;
-                    debug_assert!(n == entry.metadata.file_size);
-                    if preserve_mtime {
-                        if let Ok(modified) = entry.metadata.modified() {
-                            file.set_modified(modified)?;
-                        }
-                    }
-                    if preserve_owner {
-                        std::os::unix::fs::lchown(
-                            &path,
-                            Some(entry.metadata.uid),
-                            Some(entry.metadata.gid),
-                        )?;
-                    }
-                    file.set_permissions(Permissions::from_mode(entry.metadata.file_mode()))?;
-                }
-                FileType::Directory => {
-                    // create directory with default permissions
-                    create_dir(&path)?;
-                    if preserve_mtime {
-                        if let Ok(modified) = entry.metadata.modified() {
-                            File::open(&path)?.set_modified(modified)?;
-                        }
-                    }
-                    if preserve_owner {
-                        std::os::unix::fs::lchown(
-                            &path,
-                            Some(entry.metadata.uid),
-                            Some(entry.metadata.gid),
-                        )?;
-                    }
-                    // apply proper permissions later when we have written all other files
-                    dirs.push((path, entry.metadata.file_mode()));
-                }
-                FileType::Fifo => {
-                    let path = path_to_c_string(path)?;
-                    mkfifo(&path, entry.metadata.mode as mode_t)?;
-                    if preserve_mtime {
-                        if let Ok(modified) = entry.metadata.modified() {
-                            set_file_modified_time(&path, modified)?;
-                        }
-                    }
-                    if preserve_owner {
-                        lchown(&path, entry.metadata.uid, entry.metadata.gid)?;
-                    }
-                }
-                FileType::Socket => {
-                    UnixDatagram::bind(&path)?;
-                    let path = path_to_c_string(path)?;
-                    if preserve_mtime {
-                        if let Ok(modified) = entry.metadata.modified() {
-                            set_file_modified_time(&path, modified)?;
-                        }
-                    }
-                    if preserve_owner {
-                        lchown(&path, entry.metadata.uid, entry.metadata.gid)?;
-                    }
-                }
-                FileType::BlockDevice | FileType::CharDevice => {
-                    let path = path_to_c_string(path)?;
-                    mknod(
-                        &path,
-                        entry.metadata.mode as mode_t,
-                        entry.metadata.rdev() as dev_t,
-                    )?;
-                    if preserve_mtime {
-                        if let Ok(modified) = entry.metadata.modified() {
-                            set_file_modified_time(&path, modified)?;
-                        }
-                    }
-                    if preserve_owner {
-                        lchown(&path, entry.metadata.uid, entry.metadata.gid)?;
-                    }
-                }
-                FileType::Symlink => {
-                    let mut original = Vec::new();
-                    entry.reader.read_to_end(&mut original)?;
-                    if let Some(0) = original.last() {
-                        original.pop();
-                    }
-                    let original: PathBuf = OsString::from_vec(original).into();
-                    symlink(original, &path)?;
-                    if preserve_owner {
-                        std::os::unix::fs::lchown(
-                            &path,
-                            Some(entry.metadata.uid),
-                            Some(entry.metadata.gid),
-                        )?;
-                    }
+                let index = header.index;
+                let mut entry = self.new_entry(header);
+                if let Some(link) = link {
+                    entry.path = link.path;
+                    entry.index = index;
                 }
+                return Ok(Some(entry));
             }
+            self.skip_contents(&mut (header, 0))?;
+        }
+    }
+
+    /// Copy the contents of the entry with the specified `path` to `writer`.
+    ///
+    /// This is the library equivalent of `--to-stdout` option of GNU cpio:
+    /// the file system is not modified.
+    /// The entry is found with [`find`](Self::find), i.e. the contents of hard links are resolved.
+    /// The contents of symbolic links are their targets, and other special files have no contents.
+    /// Returns the number of bytes copied or `None` if there is no such entry.
+    pub fn extract_to_writer<P: AsRef<Path>, W: Write>(
+        &mut self,
+        path: P,
+        writer: &mut W,
+    ) -> Result<Option<u64>, Error> {
+        let Some(mut entry) = self.find(path)? else {
+            return Ok(None);
+        };
+        let n = entry
+            .reader
+            .copy_to(writer)
+            .map_err(|e| entry_error(e, entry.index, &entry.path))?;
+        Ok(Some(n))
+    }
+
+    fn do_unpack(
+        mut self,
+        dirfd: RawFd,
+        directory: Option<&Path>,
+    ) -> Result<UnpackReport, UnpackError> {
+        let mut unpacker = self.unpacker(dirfd, directory)?;
+        if let Err(e) = self.unpack_entries(&mut unpacker) {
+            return Err(unpacker.into_error(e));
         }
-        dirs.sort_unstable_by(|a, b| b.0.cmp(&a.0));
-        for (path, mode) in dirs.into_iter() {
-            let perms = Permissions::from_mode(mode);
-            set_permissions(&path, perms)?;
+        unpacker.finish()
+    }
+
+    fn unpack_entries(&mut self, unpacker: &mut Unpacker<'_>) -> Result<(), Error> {
+        while let Some(mut entry) = self.next_entry()? {
+            let index = entry.index;
+            unpacker
+                .unpack_entry(&mut entry)
+                .map_err(|e| entry_error(e, index, &entry.path))?;
         }
         Ok(())
     }
 
+    fn unpacker<'a>(
+        &mut self,
+        dirfd: RawFd,
+        directory: Option<&'a Path>,
+    ) -> Result<Unpacker<'a>, Error> {
+        if self.unpack_options.sandbox {
+            #[cfg(all(target_os = "linux", feature = "landlock"))]
+            restrict_writes_to(dirfd)?;
+            #[cfg(not(all(target_os = "linux", feature = "landlock")))]
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "sandboxing requires `landlock` feature and Linux",
+            ));
+        }
+        let root = match (self.unpack_options.absolute_targets, directory) {
+            (AbsoluteTargetPolicy::Prefix, Some(directory)) => Some(directory.canonicalize()?),
+            _ => None,
+        };
+        Ok(Unpacker {
+            dirfd,
+            directory,
+            dirs: Vec::new(),
+            hard_links: HashMap::new(),
+            copies: HashMap::new(),
+            links: HashMap::new(),
+            hard_link_policy: self.unpack_options.hard_links,
+            on_hard_link: self.unpack_options.on_hard_link.take(),
+            symlink_policy: self.unpack_options.symlinks,
+            absolute_targets: self.unpack_options.absolute_targets,
+            root,
+            on_symlink: self.unpack_options.on_symlink.take(),
+            pending_symlinks: Vec::new(),
+            nlink_policy: self.nlink_policy,
+            preserve_mtime: self.unpack_options.preserve_mtime,
+            min_mtime: self.unpack_options.min_mtime,
+            preserve_owner: resolve_preserve_owner(self.unpack_options.preserve_owner),
+            dir_over_symlink: self.unpack_options.dir_over_symlink,
+            symlink_over_dir: self.unpack_options.symlink_over_dir,
+            dir_over_file: self.unpack_options.dir_over_file,
+            file_over_dir: self.unpack_options.file_over_dir,
+            update_existing_dirs: self.unpack_options.update_existing_dirs,
+            overwrite: self.unpack_options.overwrite,
+            mode_mask: self.unpack_options.mode_mask,
+            absolute_paths: self.unpack_options.absolute_paths,
+            strict_paths: self.unpack_options.strict_paths,
+            scanner: self.unpack_options.scanner.take(),
+            rejected: HashSet::new(),
+            sparse: self.unpack_options.sparse,
+            group_policy: self.unpack_options.groups,
+            owner_map: self.unpack_options.owner_map.clone(),
+            rootless: self.unpack_options.rootless,
+            placeholder: self.unpack_options.placeholder,
+            special_files: self.unpack_options.special_files,
+            on_special_file: self.unpack_options.on_special_file.take(),
+            patterns: self.unpack_options.patterns.clone(),
+            strip_components: self.unpack_options.strip_components,
+            rename: self.unpack_options.rename.take(),
+            xattrs: self.unpack_options.xattrs,
+            pending_xattrs: HashMap::new(),
+            keep_going: self.unpack_options.keep_going,
+            check_free_space: self.unpack_options.check_free_space,
+            report: UnpackReport::default(),
+        })
+    }
+
+    /// Compute the changes that unpacking the archive to the target `directory` would make
+    /// without modifying the file system.
+    ///
+    /// This is the dry-run mode of [`unpack`](Self::unpack):
+    /// the entries are parsed, their paths are resolved and checked for conflicts,
+    /// but nothing is written.
+    ///
+    /// The entries are compared with the existing files by their type, size and permissions,
+    /// and also by modification time and owner if they are preserved.
+    /// The `options` are applied the same way as in [`unpack`](Self::unpack)
+    /// except for [`UnpackOptions::scan_contents`] and [`UnpackOptions::sandbox`] that are ignored.
+    /// Fails if unpacking would fail because of the path or the options,
+    /// e.g. for absolute paths with [`AbsolutePathPolicy::Error`](crate::AbsolutePathPolicy::Error).
+    /// The archive is read to the end.
+    pub fn plan_unpack<P: AsRef<Path>>(
+        &mut self,
+        directory: P,
+        options: &UnpackOptions,
+    ) -> Result<Vec<PlannedChange>, Error> {
+        let mut planner = Planner {
+            directory: directory.as_ref(),
+            options,
+            nlink_policy: self.nlink_policy,
+            preserve_mtime: options.preserve_mtime,
+            preserve_owner: resolve_preserve_owner(options.preserve_owner),
+            hard_links: HashMap::new(),
+            changes: Vec::new(),
+        };
+        while let Some(entry) = self.next_entry()? {
+            planner
+                .plan_entry(&entry)
+                .map_err(|e| entry_error(e, entry.index, &entry.path))?;
+        }
+        Ok(planner.changes)
+    }
+
+    /// Find the regular entries with identical contents.
+    ///
+    /// Use this method to estimate how much smaller the archive would be
+    /// if it was created with [`Builder::dedup_contents`](crate::Builder::dedup_contents).
+    /// The contents are compared by their size and 64-bit hash, not byte by byte,
+    /// so a hash collision may group different files and the numbers in the report are estimates.
+    /// The builder itself verifies the contents before storing a hard link.
+    /// Empty files and the entries that are already hard links to the earlier entries are ignored.
+    /// The archive is read to the end.
+    pub fn dedup_report(&mut self) -> Result<DedupReport, Error> {
+        let mut finder = DuplicateFinder::default();
+        let mut links = HashSet::new();
+        let nlink_policy = self.nlink_policy;
+        while let Some(mut entry) = self.next_entry()? {
+            let metadata = &entry.metadata;
+            if !metadata.is_file() || metadata.file_size == 0 {
+                continue;
+            }
+            if nlink_policy.is_hard_link(metadata) && !links.insert(metadata.id()) {
+                continue;
+            }
+            let mut hasher = HashWriter::default();
+            entry
+                .reader
+                .copy_to(&mut hasher)
+                .map_err(|e| entry_error(e, entry.index, &entry.path))?;
+            finder.add(
+                entry.path.clone(),
+                entry.metadata.file_size,
+                hasher.finish(),
+            );
+        }
+        Ok(finder.finish())
+    }
+
+    /// Read the next entry from the archive.
+    ///
+    /// The archive is read sequentially, one entry at a time, and nothing is buffered
+    /// except for the contents of hard links and the contents that were read to verify the checksum.
+    /// The returned [`Entry`] borrows the archive mutably,
+    /// i.e. the previous entry has to be dropped before this method is called again.
+    /// Dropping the entry skips the unread contents and the padding that follows them,
+    /// so the next call returns the next entry regardless how much of the contents was read.
+    ///
+    /// Returns `Ok(None)` when the end of the archive is reached,
+    /// including the empty input and the archives that consist of the trailer only.
+    /// The underlying reader is then positioned right after the trailer,
+    /// e.g. at the padding that precedes the next segment of a multi-segment archive
+    /// (see [`Builder::finish_segment`](crate::Builder::finish_segment)).
+    /// In [`Format::Newc`] and [`Format::Crc`] the contents of hard links are stored only once:
+    /// the empty entries that refer to the already read link yield its contents.
+    ///
+    /// The error message includes the index of the entry.
+    /// The archive should not be read further after an error
+    /// because the position of the underlying reader is unspecified.
+    pub fn next_entry(&mut self) -> Result<Option<Entry<'_, R>>, Error> {
+        let index = self.num_entries;
+        self.do_read_entry()
+            .map_err(|e| Error::new(e.kind(), format!("entry #{}: {}", index, e)))
+    }
+
     /// Read the next entry from the archive.
     ///
-    /// Returns `Ok(None)` when the end of the archive is reached.
+    /// The same as [`next_entry`](Self::next_entry).
+    #[deprecated(note = "use `next_entry` instead")]
     pub fn read_entry(&mut self) -> Result<Option<Entry<'_, R>>, Error> {
+        self.next_entry()
+    }
+
+    /// Call `f` for each entry in the archive without allocating memory for each entry.
+    ///
+    /// Useful for listing archive contents:
+    /// the contents of the entries are skipped before calling `f`.
+    /// Stops when the end of the archive is reached or `f` returns [`ControlFlow::Break`].
+    pub fn for_each_raw<F>(&mut self, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&RawEntry<'_>) -> ControlFlow<()>,
+    {
+        let mut name = Vec::new();
+        loop {
+            let index = self.num_entries;
+            let entry = self
+                .read_raw_entry(&mut name)
+                .map_err(|e| Error::new(e.kind(), format!("entry #{}: {}", index, e)))?;
+            let Some((metadata, name_padding, file_padding)) = entry else {
+                return Ok(());
+            };
+            let entry = RawEntry {
+                name: self.strip_name(&name),
+                metadata: &metadata,
+                index,
+                name_padding,
+                file_padding,
+            };
+            if f(&entry).is_break() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Call `f` with the zero-based index of each entry and the entry itself.
+    ///
+    /// The index is the same as in [`for_each_raw`](Self::for_each_raw), in [`Entry::index`]
+    /// and in the error messages of the other methods,
+    /// i.e. "entry #N" refers to the same entry when the archive is listed, verified or unpacked.
+    /// The errors returned by `f` are prefixed with the index and the path of the entry.
+    /// Stops when the end of the archive is reached or `f` returns [`ControlFlow::Break`].
+    pub fn enumerate_entries<F>(&mut self, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(usize, &mut Entry<'_, R>) -> Result<ControlFlow<()>, Error>,
+    {
+        while let Some(mut entry) = self.next_entry()? {
+            let index = entry.index;
+            let flow = f(index, &mut entry).map_err(|e| entry_error(e, index, &entry.path))?;
+            if flow.is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn strip_name<'a>(&self, name: &'a [u8]) -> &'a [u8] {
+        match name.strip_prefix(b"./") {
+            Some(stripped) if self.strip_dot_slash => stripped,
+            _ => name,
+        }
+    }
+
+    fn read_raw_entry(
+        &mut self,
+        name: &mut Vec<u8>,
+    ) -> Result<Option<(EntryMetadata, Padding, Padding)>, Error> {
+        let Some((metadata, format)) = Metadata::read_some(self.reader.by_ref())? else {
+            return Ok(None);
+        };
+        let name_padding = read_path_bytes(
+            self.reader.by_ref(),
+            metadata.name_len as usize,
+            format,
+            name,
+        )?;
+        if name[..] == *TRAILER.to_bytes() {
+            return Ok(None);
+        }
+        self.num_entries += 1;
+        let n = copy_with_buffer(
+            &mut self.reader.by_ref().take(metadata.file_size),
+            &mut std::io::sink(),
+            &mut self.io_buffer,
+        )?;
+        if n != metadata.file_size {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        let file_padding =
+            read_file_padding(self.reader.by_ref(), metadata.file_size as usize, format)?;
+        Ok(Some((
+            EntryMetadata::new(metadata, format),
+            name_padding,
+            file_padding,
+        )))
+    }
+
+    fn do_read_entry(&mut self) -> Result<Option<Entry<'_, R>>, Error> {
+        let Some(header) = self.read_header()? else {
+            return Ok(None);
+        };
+        Ok(Some(self.new_entry(header)))
+    }
+
+    fn new_entry(&mut self, header: Header) -> Entry<'_, R> {
+        let reader = match header.contents {
+            Contents::Stream => {
+                InnerEntryReader::Stream(self.reader.by_ref().take(header.metadata.file_size))
+            }
+            Contents::HardLink(id) => {
+                let slice = self.contents.get(&id).map_or(&[][..], |x| x.as_slice());
+                InnerEntryReader::Slice(slice, self.reader.by_ref())
+            }
+            Contents::Verified => {
+                InnerEntryReader::Slice(&self.cur_contents[..], self.reader.by_ref())
+            }
+        };
+        Entry {
+            metadata: EntryMetadata::new(header.metadata, header.format),
+            path: header.path,
+            reader: EntryReader {
+                inner: reader,
+                buffer: &mut self.io_buffer,
+            },
+            format: header.format,
+            index: header.index,
+            name_padding: header.name_padding,
+            file_padding: &mut self.file_padding,
+        }
+    }
+
+    /// Read the next entry's header and path, and decide where its contents are read from.
+    fn read_header(&mut self) -> Result<Option<Header>, Error> {
         fn read_and_verify_crc(reader: &mut impl Read, check: u32) -> Result<Vec<u8>, Error> {
             let mut crc_writer = CrcWriter::new(Vec::new());
             std::io::copy(reader, &mut crc_writer)?;
@@ -276,11 +638,20 @@ impl<R: Read> Archive<R> {
         let Some((metadata, format)) = Metadata::read_some(self.reader.by_ref())? else {
             return Ok(None);
         };
-        let path = read_path_buf(self.reader.by_ref(), metadata.name_len as usize, format)?;
+        let (mut path, name_padding) =
+            read_path_buf(self.reader.by_ref(), metadata.name_len as usize, format)?;
         if path.as_os_str().as_bytes() == TRAILER.to_bytes() {
             return Ok(None);
         }
-        let reader = match format {
+        let index = self.num_entries;
+        self.num_entries += 1;
+        self.file_padding = Padding::default();
+        if self.strip_dot_slash {
+            if let Some(stripped) = path.as_os_str().as_bytes().strip_prefix(b"./") {
+                path = OsStr::from_bytes(stripped).into();
+            }
+        }
+        let contents = match format {
             Format::Newc | Format::Crc => {
                 let file_type = metadata.file_type()?;
                 let verify_crc = matches!(format, Format::Crc)
@@ -298,37 +669,445 @@ impl<R: Read> Archive<R> {
                     };
                     self.contents.insert(metadata.id(), contents);
                 }
-                let contents = self.contents.get(&metadata.id()).map(|x| x.as_slice());
-                match contents {
-                    Some(slice) => InnerEntryReader::Slice(slice, self.reader.by_ref()),
-                    None => {
-                        if verify_crc {
-                            let mut reader = self.reader.by_ref().take(metadata.file_size);
-                            self.cur_contents = read_and_verify_crc(&mut reader, metadata.check)?;
-                            InnerEntryReader::Slice(&self.cur_contents[..], self.reader.by_ref())
-                        } else {
-                            let reader = self.reader.by_ref().take(metadata.file_size);
-                            InnerEntryReader::Stream(reader)
-                        }
-                    }
+                let is_stored = match self.nlink_policy {
+                    NlinkPolicy::TrustNlink if metadata.nlink <= 1 => false,
+                    _ => self.contents.contains_key(&metadata.id()),
+                };
+                if is_stored {
+                    Contents::HardLink(metadata.id())
+                } else if verify_crc {
+                    let mut reader = self.reader.by_ref().take(metadata.file_size);
+                    self.cur_contents = read_and_verify_crc(&mut reader, metadata.check)?;
+                    Contents::Verified
+                } else {
+                    Contents::Stream
                 }
             }
-            Format::Odc | Format::Bin(..) => {
-                InnerEntryReader::Stream(self.reader.by_ref().take(metadata.file_size))
-            }
+            Format::Odc | Format::Bin(..) => Contents::Stream,
         };
-        Ok(Some(Entry {
+        Ok(Some(Header {
             metadata,
-            path,
-            reader: EntryReader { inner: reader },
             format,
+            path,
+            index,
+            name_padding,
+            contents,
         }))
     }
+
+    /// Returns a reader of the concatenated contents of the entries for which `filter` returns `true`.
+    ///
+    /// The entries are read on demand; the contents of each entry are streamed
+    /// except for hard links and verified checksums (see [`Archive::next_entry`]).
+    /// The contents of the other entries are skipped.
+    /// Dropping the reader skips the rest of the current entry,
+    /// i.e. the next call to [`Archive::next_entry`] returns the next entry.
+    pub fn concat<F>(&mut self, filter: F) -> ConcatReader<'_, R, F>
+    where
+        F: FnMut(&Path, &EntryMetadata) -> bool,
+    {
+        ConcatReader {
+            archive: self,
+            filter,
+            current: None,
+            finished: false,
+        }
+    }
+}
+
+/// The header of the entry that is being read.
+struct Header {
+    metadata: Metadata,
+    format: Format,
+    path: PathBuf,
+    index: usize,
+    name_padding: Padding,
+    contents: Contents,
+}
+
+/// Returns `true` if the contents of the hard link are stored in one of the subsequent links.
+fn is_hard_link_without_contents(header: &Header) -> bool {
+    matches!(header.format, Format::Newc | Format::Crc)
+        && matches!(header.contents, Contents::Stream)
+        && header.metadata.nlink > 1
+        && header.metadata.file_size == 0
+        && header.metadata.is_file()
+}
+
+/// Where the contents of the entry are read from.
+#[derive(Clone, Copy)]
+enum Contents {
+    /// The underlying reader.
+    Stream,
+    /// The contents of the hard link that were read earlier.
+    HardLink(MetadataId),
+    /// The current entry's contents that were read to verify the checksum.
+    Verified,
+}
+
+/// A reader of the concatenated contents of the selected entries.
+///
+/// Returned by [`Archive::concat`].
+pub struct ConcatReader<'a, R: Read, F> {
+    archive: &'a mut Archive<R>,
+    filter: F,
+    // the entry that is being read: header, contents and the number of bytes read so far
+    current: Option<(Header, u64)>,
+    finished: bool,
+}
+
+impl<'a, R: Read, F: FnMut(&Path, &EntryMetadata) -> bool> ConcatReader<'a, R, F> {
+    /// Move to the next entry that matches the filter.
+    ///
+    /// Returns `false` when the end of the archive is reached.
+    fn next_entry(&mut self) -> Result<bool, Error> {
+        while !self.finished {
+            let index = self.archive.num_entries;
+            let header = self
+                .archive
+                .read_header()
+                .map_err(|e| Error::new(e.kind(), format!("entry #{}: {}", index, e)))?;
+            let Some(header) = header else {
+                self.finished = true;
+                break;
+            };
+            let metadata = EntryMetadata::new(header.metadata.clone(), header.format);
+            let mut current = (header, 0);
+            if (self.filter)(&current.0.path, &metadata) {
+                self.current = Some(current);
+                return Ok(true);
+            }
+            self.archive.skip_contents(&mut current)?;
+        }
+        Ok(false)
+    }
+}
+
+impl<R: Read> Archive<R> {
+    /// Skip the remaining contents of the entry and the padding that follows them.
+    fn skip_contents(&mut self, (header, offset): &mut (Header, u64)) -> Result<(), Error> {
+        if let Contents::Stream = header.contents {
+            let remaining = header.metadata.file_size - *offset;
+            let n = copy_with_buffer(
+                &mut self.reader.by_ref().take(remaining),
+                &mut std::io::sink(),
+                &mut self.io_buffer,
+            )?;
+            *offset += n;
+            if n != remaining {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+        }
+        self.file_padding = read_file_padding(
+            self.reader.by_ref(),
+            header.metadata.file_size as usize,
+            header.format,
+        )?;
+        Ok(())
+    }
+
+    /// Read the contents of the entry starting from `offset`.
+    fn read_contents(
+        &mut self,
+        (header, offset): &mut (Header, u64),
+        buf: &mut [u8],
+    ) -> Result<usize, Error> {
+        let n = match header.contents {
+            Contents::Stream => {
+                let remaining = header.metadata.file_size - *offset;
+                let n = self.reader.by_ref().take(remaining).read(buf)?;
+                if n == 0 && remaining != 0 {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                n
+            }
+            Contents::HardLink(id) => {
+                let slice = self.contents.get(&id).map_or(&[][..], |x| x.as_slice());
+                read_at(slice, *offset, buf)
+            }
+            Contents::Verified => read_at(&self.cur_contents[..], *offset, buf),
+        };
+        *offset += n as u64;
+        Ok(n)
+    }
+}
+
+fn read_at(slice: &[u8], offset: u64, buf: &mut [u8]) -> usize {
+    let slice = slice.get(offset as usize..).unwrap_or_default();
+    let n = slice.len().min(buf.len());
+    buf[..n].copy_from_slice(&slice[..n]);
+    n
+}
+
+impl<'a, R: Read, F: FnMut(&Path, &EntryMetadata) -> bool> Read for ConcatReader<'a, R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let Some(current) = self.current.as_mut() else {
+                if self.next_entry()? {
+                    continue;
+                }
+                return Ok(0);
+            };
+            let index = current.0.index;
+            let n = self
+                .archive
+                .read_contents(current, buf)
+                .and_then(|n| {
+                    if n == 0 {
+                        self.archive.skip_contents(current)?;
+                    }
+                    Ok(n)
+                })
+                .map_err(|e| entry_error(e, index, &current.0.path))?;
+            if n != 0 {
+                return Ok(n);
+            }
+            self.current = None;
+        }
+    }
+}
+
+impl<'a, R: Read, F> Drop for ConcatReader<'a, R, F> {
+    fn drop(&mut self) {
+        if let Some(mut current) = self.current.take() {
+            let _ = self.archive.skip_contents(&mut current);
+        }
+    }
+}
+
+impl<R: Read + Seek> Archive<R> {
+    /// Estimate the disk space and the number of inodes that unpacking the archive requires.
+    ///
+    /// Only the headers are read, the contents are skipped via [`Seek`];
+    /// the archive is rewound to the current position afterwards.
+    /// Each file occupies at least one block of the specified size.
+    pub fn required_space(&mut self, block_size: u64) -> Result<RequiredSpace, Error> {
+        let position = self.reader.stream_position()?;
+        let num_entries = self.num_entries;
+        let result = self.do_required_space(block_size.max(1));
+        self.num_entries = num_entries;
+        self.reader.seek(SeekFrom::Start(position))?;
+        result
+    }
+
+    /// Fail with [`ErrorKind::StorageFull`] if the file system of the `directory`
+    /// doesn't have enough free space or inodes to unpack the archive.
+    ///
+    /// Uses [`required_space`](Self::required_space) to estimate the required space.
+    /// The directory doesn't need to exist: the nearest existing parent is checked instead.
+    pub fn check_free_space<P: AsRef<Path>>(&mut self, directory: P) -> Result<(), Error> {
+        let directory = directory.as_ref();
+        let existing = directory
+            .ancestors()
+            .find(|path| path.exists())
+            .unwrap_or(Path::new("."));
+        let stat = statvfs(existing)?;
+        #[allow(clippy::unnecessary_cast)]
+        let (block_size, free_blocks, free_inodes) = (
+            stat.f_frsize as u64,
+            stat.f_bavail as u64,
+            stat.f_favail as u64,
+        );
+        let required = self.required_space(block_size)?;
+        let free_bytes = free_blocks.saturating_mul(block_size);
+        if required.bytes > free_bytes {
+            return Err(Error::new(
+                ErrorKind::StorageFull,
+                format!(
+                    "not enough space in {:?}: {} bytes required, {} bytes available",
+                    existing, required.bytes, free_bytes
+                ),
+            ));
+        }
+        // some file systems allocate inodes dynamically and report zero
+        if free_inodes != 0 && required.inodes > free_inodes {
+            return Err(Error::new(
+                ErrorKind::StorageFull,
+                format!(
+                    "not enough inodes in {:?}: {} required, {} available",
+                    existing, required.inodes, free_inodes
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check whether there are no entries left in the archive starting from the current position.
+    ///
+    /// Returns `true` for the archives that consist of the trailer only
+    /// and for the empty input.
+    /// The archive is rewound to the current position afterwards.
+    pub fn is_empty(&mut self) -> Result<bool, Error> {
+        let position = self.reader.stream_position()?;
+        let result = self.do_is_empty();
+        self.reader.seek(SeekFrom::Start(position))?;
+        result
+    }
+
+    fn do_is_empty(&mut self) -> Result<bool, Error> {
+        let Some((metadata, format)) = Metadata::read_some(self.reader.by_ref())? else {
+            return Ok(true);
+        };
+        let mut name = Vec::new();
+        read_path_bytes(
+            self.reader.by_ref(),
+            metadata.name_len as usize,
+            format,
+            &mut name,
+        )?;
+        Ok(name[..] == *TRAILER.to_bytes())
+    }
+
+    fn do_required_space(&mut self, block_size: u64) -> Result<RequiredSpace, Error> {
+        let mut name = Vec::new();
+        let mut required = RequiredSpace::default();
+        let blocks = |file_size: u64| file_size.next_multiple_of(block_size).max(block_size);
+        // inode -> the largest file size of its hard links
+        // (the contents may be stored in any of them, e.g. in the last one in GNU cpio archives)
+        let mut inodes = HashMap::new();
+        while let Some((metadata, format)) = Metadata::read_some(self.reader.by_ref())? {
+            read_path_bytes(
+                self.reader.by_ref(),
+                metadata.name_len as usize,
+                format,
+                &mut name,
+            )?;
+            if name[..] == *TRAILER.to_bytes() {
+                break;
+            }
+            self.reader
+                .seek(SeekFrom::Current(metadata.file_size as i64))?;
+            read_file_padding(self.reader.by_ref(), metadata.file_size as usize, format)?;
+            if self.nlink_policy.is_hard_link(&metadata) {
+                let file_size = inodes.entry(metadata.id()).or_insert(0);
+                *file_size = metadata.file_size.max(*file_size);
+                continue;
+            }
+            required.inodes += 1;
+            required.bytes += blocks(metadata.file_size);
+        }
+        required.inodes += inodes.len() as u64;
+        required.bytes += inodes.into_values().map(blocks).sum::<u64>();
+        Ok(required)
+    }
+
+    /// Find the trailer of the archive that starts at the current position.
+    ///
+    /// Fails if the entries have different formats or if the archive is not terminated by the trailer.
+    /// The reader is left at an arbitrary position.
+    pub(crate) fn find_end(&mut self) -> Result<ArchiveEnd, Error> {
+        let start = self.reader.stream_position()?;
+        let mut end = ArchiveEnd {
+            offset: 0,
+            format: None,
+            max_inode: None,
+            dirs: Vec::new(),
+        };
+        let mut name = Vec::new();
+        loop {
+            let offset = self.reader.stream_position()? - start;
+            let Some((metadata, format)) = Metadata::read_some(self.reader.by_ref())? else {
+                if offset == 0 {
+                    // empty archive
+                    return Ok(end);
+                }
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "the archive is not terminated by the trailer",
+                ));
+            };
+            if end.format.is_some_and(|other| other != format) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("entry #{}: mixed formats", self.num_entries),
+                ));
+            }
+            end.format = Some(format);
+            read_path_bytes(
+                self.reader.by_ref(),
+                metadata.name_len as usize,
+                format,
+                &mut name,
+            )?;
+            if name[..] == *TRAILER.to_bytes() {
+                end.offset = offset;
+                return Ok(end);
+            }
+            self.num_entries += 1;
+            self.reader
+                .seek(SeekFrom::Current(metadata.file_size as i64))?;
+            read_file_padding(self.reader.by_ref(), metadata.file_size as usize, format)?;
+            end.max_inode = end.max_inode.max(Some(metadata.ino));
+            if metadata.is_dir() {
+                end.dirs.push(PathBuf::from(OsStr::from_bytes(&name)));
+            }
+        }
+    }
+}
+
+/// The end of the existing archive.
+///
+/// Returned by [`Archive::find_end`].
+pub(crate) struct ArchiveEnd {
+    /// The offset of the trailer from the start of the archive.
+    pub(crate) offset: u64,
+    /// The format of the entries or `None` for an empty archive.
+    pub(crate) format: Option<Format>,
+    /// The largest inode number in the archive.
+    pub(crate) max_inode: Option<u64>,
+    /// The paths of the directories.
+    pub(crate) dirs: Vec<PathBuf>,
+}
+
+/// How to detect hard links in archives with inconsistent number of links.
+///
+/// Some archivers write the same inode number for unrelated files with a single link,
+/// others keep the number of links from the file system
+/// even if only one of the links is archived.
+/// Directories are never treated as hard links.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum NlinkPolicy {
+    /// The entries with the same device id and inode number are hard links
+    /// regardless of the number of links in their headers.
+    #[default]
+    TrustDuplicateInodes,
+    /// Only the entries with more than one link in their headers
+    /// and with the same device id and inode number are hard links.
+    TrustNlink,
+    /// No entries are hard links.
+    ///
+    /// The links are unpacked as independent files with the same contents.
+    Independent,
+}
+
+impl NlinkPolicy {
+    pub(crate) fn is_hard_link(self, metadata: &Metadata) -> bool {
+        match self {
+            Self::TrustDuplicateInodes => !metadata.is_dir(),
+            Self::TrustNlink => !metadata.is_dir() && metadata.nlink > 1,
+            Self::Independent => false,
+        }
+    }
+}
+
+/// Disk space required to unpack an archive.
+///
+/// Returned by [`Archive::required_space`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct RequiredSpace {
+    /// The number of bytes.
+    pub bytes: u64,
+    /// The number of inodes.
+    pub inodes: u64,
 }
 
 /// A reader for a particular archive entry.
 pub struct EntryReader<'a, R: Read> {
     inner: InnerEntryReader<'a, R>,
+    buffer: &'a mut [u8],
 }
 
 enum InnerEntryReader<'a, R: Read> {
@@ -357,21 +1136,22 @@ impl<'a, R: Read> EntryReader<'a, R> {
 
     /// Copy the remaining contents to the specified `sink`.
     ///
-    /// Uses [`copy`](std::io::copy) for maximum efficiency.
+    /// Uses [`copy`](std::io::copy) for maximum efficiency
+    /// unless the buffer size is set via [`Archive::set_io_buffer_size`].
     pub fn copy_to<W: Write>(&mut self, sink: &mut W) -> Result<u64, Error> {
         use InnerEntryReader::*;
         match self.inner {
-            Stream(ref mut reader) => std::io::copy(reader, sink),
+            Stream(ref mut reader) => copy_with_buffer(reader, sink, self.buffer),
             Slice(ref mut slice, ref mut _reader) => std::io::copy(slice, sink),
         }
     }
 
-    fn discard(&mut self, metadata: &Metadata, format: Format) -> Result<(), Error> {
+    fn discard(&mut self, metadata: &Metadata, format: Format) -> Result<Padding, Error> {
         use InnerEntryReader::*;
         match self.inner {
             Stream(ref mut reader) => {
                 // discard the remaining bytes
-                std::io::copy(reader, &mut std::io::sink())?;
+                copy_with_buffer(reader, &mut std::io::sink(), self.buffer)?;
             }
             Slice(ref mut x, ..) => {
                 *x = &[];
@@ -379,8 +1159,7 @@ impl<'a, R: Read> EntryReader<'a, R> {
         }
         let reader = self.get_mut();
         // handle padding
-        read_file_padding(reader, metadata.file_size as usize, format)?;
-        Ok(())
+        read_file_padding(reader, metadata.file_size as usize, format)
     }
 }
 
@@ -429,23 +1208,70 @@ impl<'a, R: Read> Read for EntryReader<'a, R> {
 /// CPIO archive entry.
 pub struct Entry<'a, R: Read> {
     /// File's metadata.
-    pub metadata: Metadata,
+    pub metadata: EntryMetadata,
     /// File path in the archive.
     pub path: PathBuf,
     /// Entry reader.
     pub reader: EntryReader<'a, R>,
     /// Entry format.
     pub format: Format,
+    /// Zero-based index of the entry in the archive.
+    pub index: usize,
+    /// Padding that followed the file path.
+    pub name_padding: Padding,
+    file_padding: &'a mut Padding,
 }
 
 impl<'a, R: Read> Drop for Entry<'a, R> {
     fn drop(&mut self) {
-        let _ = self.reader.discard(&self.metadata, self.format);
+        if let Ok(padding) = self.reader.discard(&self.metadata, self.format) {
+            *self.file_padding = padding;
+        }
     }
 }
 
-fn is_writable(mode: u32) -> bool {
-    (((mode & FILE_MODE_MASK) >> 8) & FILE_WRITE_BIT) != 0
+/// An archive entry without its contents.
+///
+/// Passed to the callback of [`Archive::for_each_raw`].
+pub struct RawEntry<'a> {
+    /// File path in the archive without the terminating NUL byte.
+    pub name: &'a [u8],
+    /// File's metadata.
+    pub metadata: &'a EntryMetadata,
+    /// Zero-based index of the entry in the archive.
+    pub index: usize,
+    /// Padding that followed the file path.
+    pub name_padding: Padding,
+    /// Padding that followed the file contents.
+    pub file_padding: Padding,
+}
+
+/// Alignment bytes that follow file path or file contents.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct Padding {
+    size: u8,
+    nonzero: bool,
+}
+
+impl Padding {
+    pub(crate) fn new(bytes: &[u8]) -> Self {
+        Self {
+            size: bytes.len() as u8,
+            nonzero: bytes.iter().any(|b| *b != 0),
+        }
+    }
+
+    /// The number of padding bytes.
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    /// Returns `true` if any of the padding bytes is not zero.
+    ///
+    /// Some archivers leak uninitialized memory into padding.
+    pub fn is_nonzero(&self) -> bool {
+        self.nonzero
+    }
 }
 
 #[cfg(test)]
@@ -453,16 +1279,24 @@ mod tests {
 
     use std::fs::read_link;
     use std::fs::remove_dir_all;
+    use std::os::unix::ffi::OsStringExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::sync::Arc;
+    use std::sync::Mutex;
 
     use arbtest::arbtest;
+    use normalize_path::NormalizePath;
     use random_dir::list_dir_all;
     use random_dir::Dir;
     use tempfile::TempDir;
     use walkdir::WalkDir;
 
     use super::*;
+    use crate::testdata::patterned_data;
     use crate::Builder;
+    use crate::ByteOrder;
     use crate::DoNotEditMetadata;
+    use crate::EditMetadata;
 
     #[test]
     #[cfg_attr(miri, ignore)]
@@ -505,10 +1339,10 @@ mod tests {
             let mut archive = Archive::new(reader);
             let mut actual_headers = Vec::new();
             let mut actual_files = Vec::new();
-            while let Some(mut entry) = archive.read_entry().unwrap() {
+            while let Some(mut entry) = archive.next_entry().unwrap() {
                 let mut contents = Vec::new();
                 entry.reader.read_to_end(&mut contents).unwrap();
-                actual_headers.push((entry.path.clone(), entry.metadata.clone()));
+                actual_headers.push((entry.path.clone(), entry.metadata.clone().into_inner()));
                 actual_files.push(contents);
             }
             assert_eq!(expected_headers, actual_headers);
@@ -518,7 +1352,7 @@ mod tests {
             remove_dir_all(&unpack_dir).ok();
             let reader = File::open(&cpio_path).unwrap();
             let mut archive = Archive::new(reader);
-            archive.preserve_mtime(true);
+            archive.set_unpack_options(UnpackOptions::new().preserve_mtime(true));
             archive.unpack(&unpack_dir).unwrap();
             let files1 = list_dir_all(directory.path()).unwrap();
             let files2 = list_dir_all(&unpack_dir).unwrap();
@@ -544,7 +1378,7 @@ mod tests {
             remove_dir_all(&unpack_dir).ok();
             let reader = File::open(&cpio_path).unwrap();
             let mut archive = Archive::new(reader);
-            archive.preserve_mtime(true);
+            archive.set_unpack_options(UnpackOptions::new().preserve_mtime(true));
             archive.unpack(&unpack_dir).unwrap();
             let files1 = list_dir_all(directory.path()).unwrap();
             let files2 = list_dir_all(&unpack_dir).unwrap();
@@ -552,4 +1386,694 @@ mod tests {
             Ok(())
         });
     }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn cpio_unpack_in_dirfd() {
+        let workdir = TempDir::new().unwrap();
+        arbtest(|u| {
+            let directory: Dir = u.arbitrary()?;
+            let cpio_path = workdir.path().join("test.cpio");
+            Builder::pack(
+                File::create(&cpio_path).unwrap(),
+                DoNotEditMetadata,
+                directory.path(),
+            )
+            .unwrap();
+            let unpack_dir = workdir.path().join("unpacked");
+            remove_dir_all(&unpack_dir).ok();
+            create_dir_all(&unpack_dir).unwrap();
+            let dirfd = File::open(&unpack_dir).unwrap();
+            let mut archive = Archive::new(File::open(&cpio_path).unwrap());
+            archive.set_unpack_options(UnpackOptions::new().preserve_mtime(true));
+            archive.unpack_in_dirfd(dirfd).unwrap();
+            let files1 = list_dir_all(directory.path()).unwrap();
+            let files2 = list_dir_all(&unpack_dir).unwrap();
+            similar_asserts::assert_eq!(files1, files2);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn crc_write_verify() {
+        arbtest(|u| {
+            let contents: Vec<u8> = u.arbitrary()?;
+            let mut builder = Builder::new(Vec::new());
+            builder.set_format(Format::Crc);
+            builder
+                .append_entry(
+                    Metadata::regular(0o644, contents.len() as u64),
+                    "file",
+                    &contents[..],
+                )
+                .unwrap();
+            let mut bytes = builder.finish().unwrap();
+            let mut archive = Archive::new(&bytes[..]);
+            archive.verify_crc(true);
+            let mut entry = archive.next_entry().unwrap().unwrap();
+            let mut actual = Vec::new();
+            entry.reader.read_to_end(&mut actual).unwrap();
+            assert_eq!(contents, actual);
+            drop(entry);
+            if contents.is_empty() {
+                return Ok(());
+            }
+            // corrupt the first byte of the file contents
+            let offset = NEWC_HEADER_LEN + "file".len() + 2;
+            bytes[offset] = bytes[offset].wrapping_add(1);
+            let mut archive = Archive::new(&bytes[..]);
+            archive.verify_crc(true);
+            assert!(archive.next_entry().is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn crc_check_is_preserved_by_transcoding() {
+        let mut builder = Builder::new(Vec::new());
+        builder.set_format(Format::Crc);
+        builder
+            .append_entry(Metadata::regular(0o644, 5), "file", &b"hello"[..])
+            .unwrap();
+        let crc = builder.finish().unwrap();
+        let mut archive = Archive::new(&crc[..]);
+        let mut builder = Builder::new(Vec::new());
+        let mut expected_check = 0;
+        while let Some(mut entry) = archive.next_entry().unwrap() {
+            expected_check = entry.metadata.check();
+            builder
+                .append_entry(
+                    entry.metadata.clone().into_inner(),
+                    &entry.path,
+                    &mut entry.reader,
+                )
+                .unwrap();
+        }
+        assert_ne!(0, expected_check);
+        let newc = builder.finish().unwrap();
+        let mut archive = Archive::new(&newc[..]);
+        let entry = archive.next_entry().unwrap().unwrap();
+        assert_eq!(Format::Newc, entry.format);
+        assert_eq!(expected_check, entry.metadata.check());
+    }
+
+    #[test]
+    fn entry_index() {
+        let mut builder = Builder::new(Vec::new());
+        for name in ["a", "b", "c"] {
+            builder
+                .append_data(Metadata::regular(0o644, 0), name, b"123")
+                .unwrap();
+        }
+        let mut bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut indices = Vec::new();
+        while let Some(entry) = archive.next_entry().unwrap() {
+            indices.push(entry.index);
+        }
+        assert_eq!(vec![0, 1, 2], indices);
+        // corrupt the magic of the second entry:
+        // header + "a\0" + "123" + padding
+        let second = NEWC_HEADER_LEN + 2 + 3 + 1;
+        assert_eq!(NEWC_MAGIC, bytes[second..][..MAGIC_LEN]);
+        bytes[second] = b'x';
+        let mut archive = Archive::new(&bytes[..]);
+        assert!(archive.next_entry().unwrap().is_some());
+        let error = archive.next_entry().err().unwrap();
+        assert!(error.to_string().starts_with("entry #1:"), "{}", error);
+    }
+
+    #[test]
+    fn for_each_raw() {
+        let mut builder = Builder::new(Vec::new());
+        builder.append_dir("dir", 0o755).unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "dir/file", b"hello")
+            .unwrap();
+        builder
+            .append_data(Metadata::symlink("file"), "dir/link", b"file\0")
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut entries = Vec::new();
+        archive
+            .for_each_raw(|entry| {
+                entries.push((
+                    entry.index,
+                    entry.name.to_vec(),
+                    entry.metadata.file_type().unwrap(),
+                    entry.metadata.size(),
+                ));
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        assert_eq!(
+            vec![
+                (0, b"dir".to_vec(), FileType::Directory, 0),
+                (1, b"dir/file".to_vec(), FileType::Regular, 5),
+                (2, b"dir/link".to_vec(), FileType::Symlink, 5),
+            ],
+            entries
+        );
+        // stop early
+        let mut archive = Archive::new(&bytes[..]);
+        let mut num_entries = 0;
+        archive
+            .for_each_raw(|_| {
+                num_entries += 1;
+                ControlFlow::Break(())
+            })
+            .unwrap();
+        assert_eq!(1, num_entries);
+        let entry = archive.next_entry().unwrap().unwrap();
+        assert_eq!(Path::new("dir/file"), entry.path);
+    }
+
+    #[test]
+    fn required_space() {
+        let mut builder = Builder::new(Vec::new());
+        builder.append_dir("dir", 0o755).unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "dir/small", b"hello")
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "dir/large", &[0_u8; 5000])
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(std::io::Cursor::new(&bytes[..]));
+        assert_eq!(
+            RequiredSpace {
+                bytes: 4096 * 4,
+                inodes: 3
+            },
+            archive.required_space(4096).unwrap()
+        );
+        let workdir = TempDir::new().unwrap();
+        archive
+            .check_free_space(workdir.path().join("not/yet/created"))
+            .unwrap();
+        // the position is restored
+        let entry = archive.next_entry().unwrap().unwrap();
+        assert_eq!(Path::new("dir"), entry.path);
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().check_free_space(true));
+        let report = archive.unpack(workdir.path().join("unpacked")).unwrap();
+        assert_eq!(5005, report.bytes);
+    }
+
+    #[test]
+    fn required_space_hard_links() {
+        let workdir = TempDir::new().unwrap();
+        std::fs::write(workdir.path().join("a"), [0_u8; 5000]).unwrap();
+        std::fs::hard_link(workdir.path().join("a"), workdir.path().join("b")).unwrap();
+        for gnu_compat in [false, true] {
+            let mut builder = Builder::new(Vec::new());
+            builder.gnu_compat(gnu_compat);
+            for path in ["a", "b"] {
+                builder
+                    .append_path(workdir.path().join(path), path)
+                    .unwrap();
+            }
+            let bytes = builder.finish().unwrap();
+            let mut sizes = Vec::new();
+            Archive::new(&bytes[..])
+                .for_each_raw(|entry| {
+                    sizes.push(entry.metadata.file_size);
+                    ControlFlow::Continue(())
+                })
+                .unwrap();
+            // the contents are stored in the last link in GNU cpio layout
+            let expected = if gnu_compat { [0, 5000] } else { [5000, 0] };
+            assert_eq!(&expected[..], &sizes[..]);
+            let mut archive = Archive::new(std::io::Cursor::new(&bytes[..]));
+            assert_eq!(
+                RequiredSpace {
+                    bytes: 4096 * 2,
+                    inodes: 1
+                },
+                archive.required_space(4096).unwrap(),
+                "gnu_compat = {}",
+                gnu_compat
+            );
+        }
+    }
+
+    #[test]
+    fn padding() {
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_data(Metadata::regular(0o644, 0), "ab", b"123")
+            .unwrap();
+        let mut bytes = builder.finish().unwrap();
+        // header + "ab\0" + 3 bytes of padding + "123" + 1 byte of padding
+        let name_padding = NEWC_HEADER_LEN + 3;
+        let file_padding = name_padding + 3 + 3;
+        let mut archive = Archive::new(&bytes[..]);
+        let entry = archive.next_entry().unwrap().unwrap();
+        assert_eq!(3, entry.name_padding.size());
+        assert!(!entry.name_padding.is_nonzero());
+        drop(entry);
+        assert_eq!(1, archive.file_padding().size());
+        assert!(!archive.file_padding().is_nonzero());
+        bytes[name_padding + 1] = b'x';
+        bytes[file_padding] = b'y';
+        let mut archive = Archive::new(&bytes[..]);
+        let entry = archive.next_entry().unwrap().unwrap();
+        assert!(entry.name_padding.is_nonzero());
+        drop(entry);
+        assert!(archive.file_padding().is_nonzero());
+        assert!(archive.next_entry().unwrap().is_none());
+    }
+
+    #[test]
+    fn nlink_policy() {
+        struct SameInode;
+
+        impl EditMetadata for SameInode {
+            fn edit_metadata(&mut self, metadata: &mut Metadata) -> Result<(), Error> {
+                metadata.set_ino(5);
+                Ok(())
+            }
+        }
+
+        // unrelated files with the same inode
+        let mut builder = Builder::with_metadata_editor(Vec::new(), SameInode);
+        builder.set_format(Format::Odc);
+        builder
+            .append_data(Metadata::regular(0o644, 0), "a", b"x")
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "b", b"y")
+            .unwrap();
+        let same_inode = builder.finish().unwrap();
+        // proper hard links
+        let mut builder = Builder::new(Vec::new());
+        let mut metadata = Metadata::regular(0o644, 0);
+        metadata.set_ino(1);
+        metadata.set_nlink(2);
+        builder.append_data(metadata.clone(), "a", b"x").unwrap();
+        builder.append_data(metadata, "b", b"x").unwrap();
+        let hard_links = builder.finish().unwrap();
+        for (bytes, policy, expected_contents, expected_same_inode, expected_inodes) in [
+            (&same_inode, NlinkPolicy::TrustDuplicateInodes, "x", true, 1),
+            (&same_inode, NlinkPolicy::TrustNlink, "y", false, 2),
+            (&same_inode, NlinkPolicy::Independent, "y", false, 2),
+            (&hard_links, NlinkPolicy::TrustDuplicateInodes, "x", true, 1),
+            (&hard_links, NlinkPolicy::TrustNlink, "x", true, 1),
+            (&hard_links, NlinkPolicy::Independent, "x", false, 2),
+        ] {
+            let mut archive = Archive::new(std::io::Cursor::new(&bytes[..]));
+            archive.set_nlink_policy(policy);
+            assert_eq!(
+                expected_inodes,
+                archive.required_space(1).unwrap().inodes,
+                "{:?}",
+                policy
+            );
+            let workdir = TempDir::new().unwrap();
+            archive.unpack(workdir.path()).unwrap();
+            let a = workdir.path().join("a").metadata().unwrap();
+            let b = workdir.path().join("b").metadata().unwrap();
+            assert_eq!(expected_same_inode, a.ino() == b.ino(), "{:?}", policy);
+            assert_eq!(
+                expected_contents.as_bytes(),
+                &std::fs::read(workdir.path().join("b")).unwrap()[..],
+                "{:?}",
+                policy
+            );
+        }
+    }
+
+    #[test]
+    fn empty_archives() {
+        for format in [
+            Format::Newc,
+            Format::Crc,
+            Format::Odc,
+            Format::Bin(ByteOrder::LittleEndian),
+            Format::Bin(ByteOrder::BigEndian),
+        ] {
+            let mut builder = Builder::new(Vec::new());
+            builder.set_format(format);
+            let bytes = builder.finish().unwrap();
+            let mut archive = Archive::new(std::io::Cursor::new(&bytes[..]));
+            assert!(archive.is_empty().unwrap());
+            assert!(archive.next_entry().unwrap().is_none());
+            let workdir = TempDir::new().unwrap();
+            Archive::new(&bytes[..]).unpack(workdir.path()).unwrap();
+            assert!(list_dir_all(workdir.path()).unwrap().is_empty());
+        }
+        // no trailer
+        let mut archive = Archive::new(std::io::Cursor::new(&[][..]));
+        assert!(archive.is_empty().unwrap());
+        assert!(archive.next_entry().unwrap().is_none());
+        // the position is restored
+        let mut builder = Builder::new(Vec::new());
+        builder.append_dir("dir", 0o755).unwrap();
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(std::io::Cursor::new(&bytes[..]));
+        assert!(!archive.is_empty().unwrap());
+        assert_eq!(
+            Path::new("dir"),
+            archive.next_entry().unwrap().unwrap().path
+        );
+        assert!(archive.is_empty().unwrap());
+    }
+
+    #[test]
+    fn empty_names() {
+        let mut builder = Builder::new(Vec::new());
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            builder.append_dir("", 0o755).unwrap_err().kind()
+        );
+        let trailer = builder.finish().unwrap();
+        // empty name
+        let mut bytes = Vec::new();
+        let mut metadata = Metadata::directory(0o755);
+        metadata.name_len = 1;
+        metadata.write(&mut bytes, Format::Newc).unwrap();
+        write_path(&mut bytes, "", Format::Newc).unwrap();
+        bytes.extend_from_slice(&trailer);
+        let mut archive = Archive::new(&bytes[..]);
+        assert_eq!(Path::new(""), archive.next_entry().unwrap().unwrap().path);
+        assert!(archive.next_entry().unwrap().is_none());
+        let workdir = TempDir::new().unwrap();
+        Archive::new(&bytes[..]).unpack(workdir.path()).unwrap();
+        assert!(list_dir_all(workdir.path()).unwrap().is_empty());
+        // zero-length name without NUL byte
+        let mut bytes = Vec::new();
+        metadata.name_len = 0;
+        metadata.write(&mut bytes, Format::Newc).unwrap();
+        bytes.extend_from_slice(&trailer);
+        let mut archive = Archive::new(&bytes[..]);
+        assert_eq!(
+            ErrorKind::InvalidData,
+            archive.next_entry().err().unwrap().kind()
+        );
+    }
+
+    #[test]
+    fn empty_files_and_hard_links() {
+        for format in [Format::Newc, Format::Crc, Format::Odc] {
+            let mut builder = Builder::new(Vec::new());
+            builder.set_format(format);
+            let mut link = Metadata::regular(0o644, 0);
+            link.set_ino(1);
+            link.set_nlink(2);
+            let mut empty_link = Metadata::regular(0o644, 0);
+            empty_link.set_ino(2);
+            empty_link.set_nlink(2);
+            builder.append_data(link.clone(), "a", b"x").unwrap();
+            builder
+                .append_data(Metadata::regular(0o644, 0), "empty", b"")
+                .unwrap();
+            builder.append_data(empty_link.clone(), "c1", b"").unwrap();
+            builder.append_data(link, "b", b"x").unwrap();
+            builder.append_data(empty_link, "c2", b"").unwrap();
+            let bytes = builder.finish().unwrap();
+            let mut archive = Archive::new(&bytes[..]);
+            let mut entries = Vec::new();
+            while let Some(mut entry) = archive.next_entry().unwrap() {
+                let mut contents = Vec::new();
+                entry.reader.read_to_end(&mut contents).unwrap();
+                entries.push((entry.path.to_str().unwrap().to_string(), contents));
+            }
+            let expected: Vec<_> = [
+                ("a", "x"),
+                ("empty", ""),
+                ("c1", ""),
+                ("b", "x"),
+                ("c2", ""),
+            ]
+            .into_iter()
+            .map(|(path, contents)| (path.to_string(), contents.as_bytes().to_vec()))
+            .collect();
+            assert_eq!(expected, entries, "format = {:?}", format);
+            let workdir = TempDir::new().unwrap();
+            Archive::new(&bytes[..]).unpack(workdir.path()).unwrap();
+            let ino = |path: &str| workdir.path().join(path).metadata().unwrap().ino();
+            assert_eq!(ino("a"), ino("b"));
+            assert_eq!(ino("c1"), ino("c2"));
+            assert_ne!(ino("a"), ino("c1"));
+            assert_ne!(ino("empty"), ino("c1"));
+            assert_eq!(b"x", &std::fs::read(workdir.path().join("b")).unwrap()[..]);
+            assert!(std::fs::read(workdir.path().join("c2")).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn next_entry_skips_unread_contents() {
+        let mut builder = Builder::new(Vec::new());
+        for (path, contents) in [("a", &b"hello"[..]), ("b", b"world"), ("c", b"!")] {
+            builder
+                .append_data(Metadata::regular(0o644, 0), path, contents)
+                .unwrap();
+        }
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut entry = archive.next_entry().unwrap().unwrap();
+        let mut buf = [0_u8; 2];
+        entry.reader.read_exact(&mut buf).unwrap();
+        assert_eq!(b"he", &buf);
+        drop(entry);
+        assert_eq!(Path::new("b"), archive.next_entry().unwrap().unwrap().path);
+        let mut entry = archive.next_entry().unwrap().unwrap();
+        assert_eq!(Path::new("c"), entry.path);
+        let mut contents = Vec::new();
+        entry.reader.read_to_end(&mut contents).unwrap();
+        assert_eq!(b"!", &contents[..]);
+        drop(entry);
+        assert!(archive.next_entry().unwrap().is_none());
+    }
+
+    #[test]
+    fn concat() {
+        let workdir = TempDir::new().unwrap();
+        let dir = workdir.path().join("dir");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("a.conf"), b"a=1\n").unwrap();
+        std::fs::write(dir.join("b.txt"), b"text\n").unwrap();
+        std::fs::write(dir.join("c.conf"), b"c=2\n").unwrap();
+        std::fs::hard_link(dir.join("c.conf"), dir.join("d.conf")).unwrap();
+        for format in [
+            Format::Newc,
+            Format::Crc,
+            Format::Odc,
+            Format::Bin(ByteOrder::LittleEndian),
+        ] {
+            let mut builder = Builder::new(Vec::new());
+            builder.set_format(format);
+            for name in ["a.conf", "b.txt", "c.conf", "d.conf"] {
+                builder.append_path(dir.join(name), name).unwrap();
+            }
+            builder
+                .append_data(Metadata::regular(0o644, 0), "e.txt", b"more text\n")
+                .unwrap();
+            let bytes = builder.finish().unwrap();
+            let mut archive = Archive::new(&bytes[..]);
+            archive.verify_crc(true);
+            let mut actual = String::new();
+            archive
+                .concat(|path, _metadata| path.extension() == Some(OsStr::new("conf")))
+                .read_to_string(&mut actual)
+                .unwrap();
+            assert_eq!("a=1\nc=2\nc=2\n", actual, "format = {:?}", format);
+            assert!(archive.next_entry().unwrap().is_none());
+            // dropping the reader skips the rest of the entry
+            let mut archive = Archive::new(&bytes[..]);
+            let mut buf = [0_u8; 2];
+            archive
+                .concat(|path, _metadata| path == Path::new("b.txt"))
+                .read_exact(&mut buf)
+                .unwrap();
+            assert_eq!(b"te", &buf);
+            let entry = archive.next_entry().unwrap().unwrap();
+            assert_eq!(Path::new("c.conf"), entry.path);
+        }
+    }
+
+    #[test]
+    fn find_and_extract_path() {
+        // the contents of the hard links are stored in the last link like in GNU cpio
+        let mut bytes = Vec::new();
+        for (name, ino, nlink, data) in [
+            (&b"dir/a\0"[..], 1, 2, &b""[..]),
+            (b"other\0", 2, 1, b"other"),
+            (b"./dir/b\0", 1, 2, b"hello"),
+            (b"TRAILER!!!\0", 0, 1, b""),
+        ] {
+            let mut metadata = Metadata::regular(0o644, data.len() as u64);
+            metadata.set_ino(ino);
+            metadata.set_nlink(nlink);
+            metadata.set_name_len(name.len() as u32);
+            metadata.write(&mut bytes, Format::Newc).unwrap();
+            bytes.extend(name);
+            let len = (Format::Newc.header_size() + name.len()) as u64;
+            bytes.resize(bytes.len() + Format::Newc.padding(len) as usize, 0);
+            bytes.extend(data);
+            bytes.resize(
+                bytes.len() + Format::Newc.padding(data.len() as u64) as usize,
+                0,
+            );
+        }
+        let mut archive = Archive::new(&bytes[..]);
+        let mut entry = archive.find("./dir/a").unwrap().unwrap();
+        assert_eq!(Path::new("dir/a"), entry.path);
+        let mut contents = Vec::new();
+        entry.reader.read_to_end(&mut contents).unwrap();
+        assert_eq!(b"hello", &contents[..]);
+        drop(entry);
+        assert!(archive.find("other").unwrap().is_none());
+        let mut archive = Archive::new(&bytes[..]);
+        let mut entry = archive.find("other").unwrap().unwrap();
+        let mut contents = Vec::new();
+        entry.reader.read_to_end(&mut contents).unwrap();
+        assert_eq!(b"other", &contents[..]);
+        drop(entry);
+        let entry = archive.find("dir/b").unwrap().unwrap();
+        assert_eq!(Path::new("./dir/b"), entry.path);
+        drop(entry);
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        assert!(archive.extract_path("dir/a", workdir.path()).unwrap());
+        assert_eq!(
+            b"hello",
+            &std::fs::read(workdir.path().join("dir/a")).unwrap()[..]
+        );
+        assert!(!workdir.path().join("other").exists());
+        assert!(!workdir.path().join("dir/b").exists());
+        let mut archive = Archive::new(&bytes[..]);
+        assert!(!archive.extract_path("missing", workdir.path()).unwrap());
+    }
+
+    #[test]
+    fn extract_path_twice() {
+        let mut builder = Builder::new(Vec::new());
+        for path in ["a", "b"] {
+            builder
+                .append_data(Metadata::regular(0o644, 0), path, b"hello")
+                .unwrap();
+        }
+        let bytes = builder.finish().unwrap();
+        let scanned = Arc::new(Mutex::new(Vec::new()));
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(
+            UnpackOptions::new()
+                .rename(|path, _| Some(Path::new("renamed").join(path)))
+                .scan_contents({
+                    let scanned = scanned.clone();
+                    move |path, _, _| {
+                        scanned.lock().unwrap().push(path.to_path_buf());
+                        Ok(true)
+                    }
+                }),
+        );
+        // the hooks are applied to every call
+        assert!(archive.extract_path("a", workdir.path()).unwrap());
+        assert!(archive.extract_path("b", workdir.path()).unwrap());
+        for path in ["renamed/a", "renamed/b"] {
+            assert_eq!(
+                b"hello",
+                &std::fs::read(workdir.path().join(path)).unwrap()[..]
+            );
+        }
+        assert!(!workdir.path().join("b").exists());
+        assert_eq!(2, scanned.lock().unwrap().len());
+    }
+
+    #[test]
+    fn extract_to_writer() {
+        let mut builder = Builder::new(Vec::new());
+        let mut metadata = Metadata::regular(0o644, 0);
+        metadata.set_ino(1);
+        metadata.set_nlink(2);
+        builder
+            .append_data(metadata.clone(), "a", b"hello")
+            .unwrap();
+        builder.append_data(metadata, "b", b"hello").unwrap();
+        builder
+            .append_data(Metadata::symlink("a"), "link", b"a\0")
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut contents = Vec::new();
+        // the contents of the second link are stored only in the first one
+        assert_eq!(
+            Some(5),
+            archive.extract_to_writer("b", &mut contents).unwrap()
+        );
+        assert_eq!(b"hello", &contents[..]);
+        contents.clear();
+        assert_eq!(
+            Some(2),
+            archive.extract_to_writer("link", &mut contents).unwrap()
+        );
+        assert_eq!(b"a\0", &contents[..]);
+        assert_eq!(None, archive.extract_to_writer("a", &mut contents).unwrap());
+    }
+
+    #[test]
+    fn enumerate_entries() {
+        let mut builder = Builder::new(Vec::new());
+        for path in ["a", "b", "c"] {
+            builder
+                .append_data(Metadata::regular(0o644, 0), path, b"hello")
+                .unwrap();
+        }
+        let bytes = builder.finish().unwrap();
+        let mut entries = Vec::new();
+        Archive::new(&bytes[..])
+            .enumerate_entries(|index, entry| {
+                entries.push((index, entry.path.clone()));
+                Ok(if index == 1 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                })
+            })
+            .unwrap();
+        assert_eq!(vec![(0, "a".into()), (1, "b".into())], entries);
+        let e = Archive::new(&bytes[..])
+            .enumerate_entries(|index, _entry| {
+                if index == 2 {
+                    return Err(ErrorKind::Other.into());
+                }
+                Ok(ControlFlow::Continue(()))
+            })
+            .unwrap_err();
+        assert!(e.to_string().starts_with("entry #2 \"c\""), "{}", e);
+    }
+
+    #[test]
+    fn io_buffer_size() {
+        let workdir = TempDir::new().unwrap();
+        let contents = patterned_data(100_000);
+        for format in [Format::Newc, Format::Crc, Format::Odc] {
+            let mut builder = Builder::new(Vec::new());
+            builder.set_format(format);
+            builder.set_io_buffer_size(1000);
+            for name in ["skipped", "copied", "read"] {
+                builder
+                    .append_data(Metadata::regular(0o644, 0), name, &contents[..])
+                    .unwrap();
+            }
+            let bytes = builder.finish().unwrap();
+            let mut archive = Archive::new(&bytes[..]);
+            archive.set_io_buffer_size(7);
+            archive.next_entry().unwrap().unwrap();
+            let mut entry = archive.next_entry().unwrap().unwrap();
+            let mut copied = Vec::new();
+            assert_eq!(
+                contents.len() as u64,
+                entry.reader.copy_to(&mut copied).unwrap()
+            );
+            assert_eq!(contents, copied);
+            drop(entry);
+            let output = workdir.path().join(format!("{:?}", format));
+            archive.unpack(&output).unwrap();
+            assert_eq!(contents, std::fs::read(output.join("read")).unwrap());
+        }
+    }
 }
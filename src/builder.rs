@@ -1,21 +1,45 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ffi::CString;
 use std::fs::read_link;
 use std::fs::File;
 use std::io::Error;
 use std::io::ErrorKind;
+use std::io::IoSlice;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
+use std::os::fd::AsRawFd;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
+use std::path::PathBuf;
 
 use crate::constants::*;
+#[cfg(target_os = "linux")]
+use crate::encode_xattrs;
 use crate::io::*;
+#[cfg(target_os = "linux")]
+use crate::is_preserved_xattr;
+use crate::limits::*;
+use crate::makedev;
+#[cfg(target_os = "linux")]
+use crate::xattrs_entry_path;
+use crate::Archive;
+use crate::ByteOrder;
 use crate::CrcWriter;
+use crate::FileType;
 use crate::Format;
+use crate::HashWriter;
 use crate::Metadata;
 use crate::MetadataId;
-use crate::Walk;
+use crate::WalkerOptions;
+#[cfg(target_os = "linux")]
+use crate::Xattr;
 
 /// Modifies metadata read from the file system.
 pub trait EditMetadata {
@@ -32,19 +56,240 @@ impl EditMetadata for DoNotEditMetadata {
     }
 }
 
+/// Hard link which number of links doesn't match the number of entries in the archive.
+///
+/// Returned by [`Builder::nlink_mismatches`].
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct NlinkMismatch {
+    /// The path of the first entry.
+    pub path: PathBuf,
+    /// The number of links in the header.
+    pub nlink: u32,
+    /// The number of entries.
+    pub actual: u32,
+}
+
+/// What to do when a numeric field doesn't fit into the header.
+///
+/// Applies to user and group ids, modification time, device ids.
+/// Use [`Builder::set_overflow_policy`] to choose the policy.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum OverflowPolicy {
+    /// Fail with [`ErrorKind::InvalidData`].
+    #[default]
+    Error,
+    /// Replace the value with the maximum one supported by the format.
+    Saturate,
+    /// Replace the value with zero.
+    Zero,
+    /// Keep only the lower bits that fit into the field.
+    Truncate,
+}
+
+/// What to do when the number of bytes read from the source differs from the file size in the header.
+///
+/// This happens when the file is modified concurrently.
+/// Use [`Builder::set_size_mismatch_policy`] to choose the policy.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum SizeMismatchPolicy {
+    /// Fail with [`ErrorKind::InvalidData`].
+    #[default]
+    Error,
+    /// Pad the contents with zeros or truncate them to the file size in the header
+    /// and record the mismatch.
+    ///
+    /// Use [`Builder::size_mismatches`] to get the recorded mismatches.
+    PadOrTruncate,
+}
+
+/// What to do when the same file is appended more than once.
+///
+/// The files are identified by their device id and inode number,
+/// e.g. the files that are reachable from several roots of [`Builder::append_dir_all`]
+/// via bind mounts or overlapping directories.
+/// Use [`Builder::set_repeat_policy`] to choose the policy.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum RepeatPolicy {
+    /// Store the file again as if it was a different file.
+    #[default]
+    Store,
+    /// Do not append the file again.
+    Skip,
+    /// Store the file as a hard link to the first occurrence.
+    ///
+    /// The repeats are written with at least two links
+    /// whereas the first occurrence keeps its original number of links.
+    /// Directories are stored again because they can't be hard-linked.
+    ///
+    /// In [`Format::Newc`] and [`Format::Crc`] the contents are stored in the first occurrence only,
+    /// hence the output is not compatible with GNU cpio:
+    /// it expects the contents in the last link and the same number of links in all of them,
+    /// and extracts the repeats of a file with one link as empty files.
+    /// Such archives are unpacked correctly by [`Archive::unpack`](crate::Archive::unpack).
+    HardLink,
+}
+
+/// Entry which contents size differs from the file size in the header.
+///
+/// Returned by [`Builder::size_mismatches`].
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct SizeMismatch {
+    /// The path inside the archive.
+    pub path: PathBuf,
+    /// The file size in the header.
+    pub file_size: u64,
+    /// The number of bytes read from the source.
+    pub actual: u64,
+}
+
+/// Regular file with holes.
+///
+/// Returned by [`Builder::sparse_files`].
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct SparseFile {
+    /// The path inside the archive.
+    pub path: PathBuf,
+    /// The file size.
+    pub file_size: u64,
+    /// The total size of the data regions, i.e. the file size without the holes.
+    pub data_size: u64,
+}
+
+/// How the archive ends.
+///
+/// Use [`Builder::set_trailer`] to choose the trailer.
+/// [`Archive`](crate::Archive) recognizes only the entry named `TRAILER!!!` as the end of the archive,
+/// i.e. reading past the last regular entry of an archive with a custom trailer fails.
+#[derive(Default)]
+pub enum Trailer {
+    /// The entry named `TRAILER!!!`.
+    #[default]
+    Standard,
+    /// The standard trailer followed by zero bytes up to a multiple of 512 bytes like GNU cpio does.
+    ///
+    /// This is the same as [`Standard`](Self::Standard) with
+    /// [`pad_to_block_size(512)`](Builder::pad_to_block_size);
+    /// the block size set by the latter takes precedence.
+    GnuPadded,
+    /// The trailer entry with the specified name instead of `TRAILER!!!`.
+    Named(CString),
+    /// Call the function to write the trailer.
+    ///
+    /// The function receives the underlying writer and the archive size so far,
+    /// and returns the number of bytes written.
+    /// These bytes count towards the [maximum archive size](Builder::set_max_archive_size)
+    /// only after they are written.
+    /// The [block padding](Builder::pad_to_block_size) is written after them.
+    Custom(Box<WriteTrailer>),
+}
+
+type WriteTrailer = dyn FnMut(&mut dyn Write, u64) -> Result<u64, Error> + Send;
+
+/// The block size of GNU cpio.
+const GNU_BLOCK_SIZE: u64 = 512;
+
 /// CPIO archive writer.
 pub struct Builder<W: Write, E: EditMetadata> {
     writer: W,
     max_inode: u32,
     max_dev: u16,
     format: Format,
-    // (dev, inode) -> (inode, check) mapping.
-    inodes: HashMap<MetadataId, (u32, u32)>,
+    // (dev, inode) -> (inode, check, whether the contents were stored) mapping.
+    inodes: HashMap<MetadataId, (u32, u32, bool)>,
     // Long device ID -> short device ID.
     devices: HashMap<u64, u16>,
     metadata_editor: E,
+    // The number of bytes written so far.
+    archive_size: u64,
+    max_archive_size: u64,
+    path_mapper: Option<Box<PathMapper>>,
+    follow_symlinks: bool,
+    dedup_hard_links: bool,
+    dedup_contents: bool,
+    // The regular files that are written when all their duplicates are found.
+    deferred_files: Vec<DeferredFile>,
+    uid_mappings: HashMap<u32, u32>,
+    gid_mappings: HashMap<u32, u32>,
+    default_uid: Option<u32>,
+    default_gid: Option<u32>,
+    mode_mask: u32,
+    mode_bits: u32,
+    file_mode: Option<u32>,
+    dir_mode: Option<u32>,
+    prefix_dot_slash: bool,
+    // The template of automatically added parent directories.
+    parent_dirs: Option<Metadata>,
+    // The directories that were appended so far.
+    appended_dirs: HashSet<PathBuf>,
+    sort_dir_entries: bool,
+    preserve_dev_ids: bool,
+    overflow_policy: OverflowPolicy,
+    size_mismatch_policy: SizeMismatchPolicy,
+    size_mismatches: Vec<SizeMismatch>,
+    track_nlink: bool,
+    // (dev, inode) -> hard links written so far.
+    link_groups: HashMap<MetadataId, LinkGroup>,
+    block_size: usize,
+    // Header, name, padding and small contents of the current entry.
+    buffer: Vec<u8>,
+    buffer_size: usize,
+    // Called with the writer and the length of the entry before writing it.
+    prepare_entry: Option<PrepareEntry<W>>,
+    // Copies the contents of the file in the kernel.
+    copy_file: Option<CopyFile<W>>,
+    trailer: Trailer,
+    detect_sparse_files: bool,
+    sparse_files: Vec<SparseFile>,
+    // The buffer that is used to copy the contents of the entries.
+    io_buffer: Vec<u8>,
+    gnu_compat: bool,
+    preserve_xattrs: bool,
+    repeat_policy: RepeatPolicy,
+    // The (dev, inode) of the files appended by path so far.
+    appended_files: HashSet<MetadataId>,
+    // The hard links which headers are written after the other links are found.
+    deferred_links: Vec<DeferredLink>,
+}
+
+const DEFAULT_BUFFER_SIZE: usize = 8192;
+
+pub(crate) type PrepareEntry<W> = fn(&mut W, u64) -> Result<(), Error>;
+
+/// Copies at most the specified number of bytes from the file to the writer
+/// and returns the number of bytes copied.
+type CopyFile<W> = fn(&mut W, &File, u64) -> Result<u64, Error>;
+
+struct LinkGroup {
+    // The path of the first entry.
+    path: PathBuf,
+    // The number of links from the first entry's header.
+    nlink: u32,
+    // Header offsets relative to the start of the archive.
+    offsets: Vec<u64>,
+}
+
+/// The hard link that is written when all the other links are found (GNU cpio compatibility).
+struct DeferredLink {
+    // The file system path.
+    path: PathBuf,
+    inner_path: PathBuf,
+    metadata: Metadata,
 }
 
+/// The regular file that is written when all the files with the same contents are found.
+struct DeferredFile {
+    // The file system path.
+    path: PathBuf,
+    inner_path: PathBuf,
+    metadata: Metadata,
+    // The metadata of the file when it was hashed.
+    fs_metadata: std::fs::Metadata,
+    // (file size, contents hash)
+    key: (u64, u64),
+}
+
+type PathMapper = dyn FnMut(&Path) -> Option<PathBuf>;
+
 impl<W: Write> Builder<W, DoNotEditMetadata> {
     /// Create new CPIO archive writer using the underlying `writer`.
     pub fn new(writer: W) -> Self {
@@ -52,6 +297,41 @@ impl<W: Write> Builder<W, DoNotEditMetadata> {
     }
 }
 
+impl Builder<File, DoNotEditMetadata> {
+    /// Open the existing archive for appending new entries to it.
+    ///
+    /// The `file` is read from the start to find the trailer;
+    /// the trailer and everything after it is removed
+    /// and new entries are written in place of the trailer.
+    /// The format of the builder is set to the format of the existing entries,
+    /// and the new entries get inode numbers that are larger than the existing ones.
+    /// The new entries are never written as hard links to the existing ones.
+    /// An empty file is treated as an empty archive.
+    /// Call [`finish`](Self::finish) to write the new trailer.
+    ///
+    /// Fails if the entries have different formats or the archive is not terminated by the trailer.
+    /// This is the equivalent of `cpio -A`.
+    pub fn append_to(mut file: File) -> Result<Self, Error> {
+        file.seek(SeekFrom::Start(0))?;
+        let end = Archive::new(&mut file).find_end()?;
+        file.set_len(end.offset)?;
+        file.seek(SeekFrom::Start(end.offset))?;
+        let mut builder = Self::new(file);
+        if let Some(format) = end.format {
+            builder.format = format;
+        }
+        if let Some(max_inode) = end.max_inode {
+            builder.max_inode = u32::try_from(max_inode)
+                .ok()
+                .and_then(|inode| inode.checked_add(1))
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "too many inodes"))?;
+        }
+        builder.appended_dirs.extend(end.dirs);
+        builder.archive_size = end.offset;
+        Ok(builder)
+    }
+}
+
 impl<W: Write, E: EditMetadata> Builder<W, E> {
     /// Create new CPIO archive writer using the underlying `writer` and supplied metadata editor.
     ///
@@ -68,6 +348,45 @@ impl<W: Write, E: EditMetadata> Builder<W, E> {
             inodes: Default::default(),
             devices: Default::default(),
             metadata_editor,
+            archive_size: 0,
+            max_archive_size: u64::MAX,
+            path_mapper: None,
+            follow_symlinks: false,
+            dedup_hard_links: false,
+            dedup_contents: false,
+            deferred_files: Vec::new(),
+            uid_mappings: Default::default(),
+            gid_mappings: Default::default(),
+            default_uid: None,
+            default_gid: None,
+            mode_mask: 0,
+            mode_bits: 0,
+            file_mode: None,
+            dir_mode: None,
+            prefix_dot_slash: false,
+            parent_dirs: None,
+            appended_dirs: Default::default(),
+            sort_dir_entries: false,
+            preserve_dev_ids: false,
+            overflow_policy: Default::default(),
+            size_mismatch_policy: Default::default(),
+            size_mismatches: Vec::new(),
+            track_nlink: false,
+            block_size: 0,
+            buffer: Vec::new(),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            prepare_entry: None,
+            copy_file: None,
+            trailer: Default::default(),
+            detect_sparse_files: false,
+            sparse_files: Vec::new(),
+            io_buffer: Vec::new(),
+            gnu_compat: false,
+            preserve_xattrs: false,
+            repeat_policy: Default::default(),
+            appended_files: Default::default(),
+            deferred_links: Vec::new(),
+            link_groups: Default::default(),
         }
     }
 
@@ -81,81 +400,1182 @@ impl<W: Write, E: EditMetadata> Builder<W, E> {
         self.format
     }
 
+    /// Set the maximum size of the archive in bytes including the trailer.
+    ///
+    /// Appending an entry that doesn't fit fails with [`ErrorKind::FileTooLarge`]
+    /// before anything is written, i.e. the archive can still be finished.
+    ///
+    /// Unlimited by default.
+    pub fn set_max_archive_size(&mut self, size: u64) {
+        self.max_archive_size = size;
+    }
+
+    /// Pad the archive with zero bytes after the trailer to a multiple of `block_size` bytes.
+    ///
+    /// GNU cpio uses 512-byte blocks; tape tools and some boot loaders expect this padding.
+    /// The padding is written in [`finish`](Self::finish) and counts towards the
+    /// [maximum archive size](Self::set_max_archive_size).
+    /// This is the only padding after the trailer:
+    /// [`Trailer::GnuPadded`] and [`gnu_compat`](Self::gnu_compat) change the default block size to 512 bytes.
+    ///
+    /// No padding by default (0).
+    pub fn pad_to_block_size(&mut self, block_size: usize) {
+        self.block_size = block_size;
+    }
+
+    /// Detect the regular files with holes when appending files from the file system (Linux only).
+    ///
+    /// CPIO has no notion of holes, i.e. the holes are stored as zero bytes.
+    /// The detected files are recorded, use [`sparse_files`](Self::sparse_files) to get them,
+    /// e.g. to warn about the archive being larger than the files on disk.
+    /// Use [`UnpackOptions::sparse`](crate::UnpackOptions::sparse) to recreate the holes when unpacking.
+    /// On other platforms no files are detected.
+    ///
+    /// `false` by default.
+    pub fn detect_sparse_files(&mut self, value: bool) {
+        self.detect_sparse_files = value;
+    }
+
+    /// Store the extended attributes of the files appended from the file system (Linux only).
+    ///
+    /// The attributes in `user` namespace and `security.capability` are stored
+    /// in a separate entry that precedes the file (see [`XATTRS_PREFIX`](crate::XATTRS_PREFIX)).
+    /// Use [`UnpackOptions::xattrs`](crate::UnpackOptions::xattrs) to restore them when unpacking.
+    /// On other platforms the attributes are not stored.
+    ///
+    /// `false` by default.
+    pub fn preserve_xattrs(&mut self, value: bool) {
+        self.preserve_xattrs = value;
+    }
+
+    /// Get the regular files with holes that were appended so far.
+    ///
+    /// See [`detect_sparse_files`](Self::detect_sparse_files).
+    pub fn sparse_files(&self) -> &[SparseFile] {
+        &self.sparse_files
+    }
+
+    /// Set how the archive ends.
+    ///
+    /// The trailer is written in [`finish`](Self::finish) and [`finish_segment`](Self::finish_segment).
+    /// Some embedded boot loaders expect a specific name of the last entry
+    /// or extra zero bytes after it.
+    ///
+    /// [`Trailer::Standard`] by default.
+    pub fn set_trailer(&mut self, trailer: Trailer) {
+        self.trailer = trailer;
+    }
+
+    /// Reproduce the output of GNU cpio (`cpio -o`) byte-for-byte.
+    ///
+    /// When enabled the builder sticks to the conventions of GNU cpio:
+    /// - the original inode numbers and device ids are stored instead of the sequential ones;
+    /// - the device id of the files other than the devices, the fifos and the sockets is zero;
+    /// - the directories' number of links is taken from the file system as is;
+    /// - the symbolic link targets are not terminated by NUL byte;
+    /// - in [`Format::Newc`] and [`Format::Crc`] the contents of a regular file with multiple hard links
+    ///   are stored in the last link: the other links are written right before it
+    ///   (in the reverse order) with zero size,
+    ///   and the groups of links that are not complete are written in [`finish`](Self::finish);
+    /// - the number of links in the trailer's header is one;
+    /// - the archive is padded with zeros to a multiple of 512 bytes after the trailer
+    ///   unless [`pad_to_block_size`](Self::pad_to_block_size) sets another block size.
+    ///
+    /// The hard links are deferred only by [`append_path`](Self::append_path)
+    /// and [`append_dir_all`](Self::append_dir_all);
+    /// [`dedup_contents`](Self::dedup_contents) and the options that modify the metadata
+    /// are applied as usual and produce the output that differs from GNU cpio.
+    ///
+    /// `false` by default.
+    pub fn gnu_compat(&mut self, value: bool) {
+        self.gnu_compat = value;
+    }
+
+    /// Set the size of the internal buffer in bytes.
+    ///
+    /// The header, the name, the padding and the contents of each entry are collected in the buffer
+    /// and are written to the underlying writer in a single call
+    /// if the contents fit in the buffer, otherwise the contents are written directly.
+    /// The checksum of [`Format::Crc`] entries appended from the file system is computed
+    /// in a separate pass over the file, then the contents are written as in the other formats.
+    /// The contents of the other [`Format::Crc`] entries are kept in memory to compute the checksum,
+    /// hence the large entries are written in a single [vectored](Write::write_vectored) call.
+    /// This reduces the number of writes for unbuffered writers, e.g. pipes and sockets.
+    /// The buffer is always emptied before the method that appends the entry returns,
+    /// i.e. the underlying writer contains all the entries appended so far.
+    ///
+    /// 8 KiB by default.
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.buffer_size = size;
+    }
+
+    /// Set the size of the buffer in bytes that is used to copy the contents of the entries.
+    ///
+    /// The buffer is allocated once and is reused for all the entries.
+    /// Larger buffers reduce the number of system calls on fast storage and network file systems.
+    /// Zero means [`copy`](std::io::copy) with its default buffer
+    /// that uses zero-copy system calls for some reader-writer pairs.
+    ///
+    /// 0 by default.
+    pub fn set_io_buffer_size(&mut self, size: usize) {
+        self.io_buffer = vec![0; size];
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()
+    }
+
+    /// Store the original device ids of the files in [`Format::Odc`] and [`Format::Bin`] archives.
+    ///
+    /// These formats have narrow device id fields,
+    /// hence the ids are replaced with sequential numbers by default.
+    /// Use [`device_mappings`](Self::device_mappings) to get the replacements.
+    /// When this option is enabled, the ids are stored as is
+    /// and the ids that don't fit into the header are handled according to
+    /// [`set_overflow_policy`](Self::set_overflow_policy).
+    /// [`Format::Newc`] and [`Format::Crc`] always store the original ids.
+    ///
+    /// `false` by default.
+    pub fn preserve_dev_ids(&mut self, value: bool) {
+        self.preserve_dev_ids = value;
+    }
+
+    /// Set the policy for the numeric fields that exceed the limits of the format.
+    ///
+    /// See [`limits`](crate::limits) for the limits.
+    /// The policy is applied after [`edit_metadata`](EditMetadata::edit_metadata).
+    /// In [`Format::Bin`] device major and minor numbers are handled separately.
+    ///
+    /// [`OverflowPolicy::Error`] by default.
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.overflow_policy = policy;
+    }
+
+    /// Set the policy for the files that are appended more than once.
+    ///
+    /// Applies to [`append_path`](Self::append_path) and [`append_dir_all`](Self::append_dir_all)
+    /// across all the calls until the archive or the [segment](Self::finish_segment) is finished.
+    ///
+    /// [`RepeatPolicy::Store`] by default.
+    pub fn set_repeat_policy(&mut self, policy: RepeatPolicy) {
+        self.repeat_policy = policy;
+    }
+
+    /// Set the policy for the entries which contents size differs from the file size in the header.
+    ///
+    /// Applies to [`append_entry`](Self::append_entry) and the methods that use it,
+    /// e.g. [`append_path`](Self::append_path) that detects the files
+    /// that shrank or grew after their metadata was obtained.
+    /// Regardless of the policy the entry is padded or truncated
+    /// once its header is written to keep the archive consistent,
+    /// i.e. the archive can still be finished after the error.
+    ///
+    /// [`SizeMismatchPolicy::Error`] by default.
+    pub fn set_size_mismatch_policy(&mut self, policy: SizeMismatchPolicy) {
+        self.size_mismatch_policy = policy;
+    }
+
+    /// Get the entries which contents were padded or truncated so far.
+    ///
+    /// Requires [`SizeMismatchPolicy::PadOrTruncate`].
+    pub fn size_mismatches(&self) -> &[SizeMismatch] {
+        &self.size_mismatches
+    }
+
+    /// Get the original device ids and their replacements stored in the archive.
+    ///
+    /// The ids are only replaced in [`Format::Odc`] and [`Format::Bin`] archives
+    /// unless [`preserve_dev_ids`](Self::preserve_dev_ids) is enabled.
+    pub fn device_mappings(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.devices.iter().map(|(from, to)| (*from, *to as u64))
+    }
+
+    /// Store the targets of symbolic links instead of the links themselves.
+    ///
+    /// Applies to [`append_path`](Self::append_path) and [`append_dir_all`](Self::append_dir_all);
+    /// the latter also descends into the linked directories.
+    ///
+    /// `false` by default.
+    pub fn follow_symlinks(&mut self, value: bool) {
+        self.follow_symlinks = value;
+    }
+
+    /// Store the contents of hard links only once in [`Format::Odc`] and [`Format::Bin`].
+    ///
+    /// The subsequent links to the same file are written with zero size.
+    /// Such archives are unpacked correctly by this crate,
+    /// but other implementations may create empty files instead of links.
+    /// The contents are always stored once in [`Format::Newc`] and [`Format::Crc`].
+    ///
+    /// `false` by default.
+    pub fn dedup_hard_links(&mut self, value: bool) {
+        self.dedup_hard_links = value;
+    }
+
+    /// Store regular files with identical contents as hard links to each other.
+    ///
+    /// Applies to [`append_path`](Self::append_path) and [`append_dir_all`](Self::append_dir_all).
+    /// The contents are hashed when the file is appended,
+    /// however, the file is written only in [`finish`](Self::finish)
+    /// (or [`finish_segment`](Self::finish_segment)) when all its duplicates are known.
+    /// Then each file is reopened, checked to be unchanged since it was hashed,
+    /// and compared byte-by-byte to the first file with the same size and hash;
+    /// the contents are copied from the same opened file they were compared with.
+    /// Each group of identical files is written as hard links with the number of links equal to the size of the group.
+    /// In [`Format::Newc`] and [`Format::Crc`] the contents are stored in the last link like GNU cpio does;
+    /// in the other formats see [`dedup_hard_links`](Self::dedup_hard_links).
+    /// Empty files and the files that already have multiple hard links are appended as usual.
+    ///
+    /// `false` by default.
+    pub fn dedup_contents(&mut self, value: bool) {
+        self.dedup_contents = value;
+    }
+
+    /// Replace user ID `from` with `to` in every appended entry.
+    pub fn add_uid_mapping(&mut self, from: u32, to: u32) {
+        self.uid_mappings.insert(from, to);
+    }
+
+    /// Replace group ID `from` with `to` in every appended entry.
+    pub fn add_gid_mapping(&mut self, from: u32, to: u32) {
+        self.gid_mappings.insert(from, to);
+    }
+
+    /// Set user ID for the entries which user ID has no mapping.
+    ///
+    /// The original user ID is kept if `None`, which is the default.
+    pub fn set_default_uid(&mut self, uid: Option<u32>) {
+        self.default_uid = uid;
+    }
+
+    /// Set group ID for the entries which group ID has no mapping.
+    ///
+    /// The original group ID is kept if `None`, which is the default.
+    pub fn set_default_gid(&mut self, gid: Option<u32>) {
+        self.default_gid = gid;
+    }
+
+    /// Clear the specified permission bits of every appended entry except symbolic links.
+    ///
+    /// E.g. use `0o6000` to strip setuid and setgid bits and `0o022` to remove write permissions
+    /// for group and others.
+    /// Applied after [`set_file_mode`](Self::set_file_mode) and [`set_dir_mode`](Self::set_dir_mode).
+    ///
+    /// Zero by default.
+    pub fn set_mode_mask(&mut self, mask: u32) {
+        self.mode_mask = mask & FILE_MODE_MASK;
+    }
+
+    /// Set the specified permission bits of every appended entry except symbolic links.
+    ///
+    /// Applied after [`set_mode_mask`](Self::set_mode_mask).
+    ///
+    /// Zero by default.
+    pub fn set_mode_bits(&mut self, bits: u32) {
+        self.mode_bits = bits & FILE_MODE_MASK;
+    }
+
+    /// Override the permissions of regular files.
+    ///
+    /// The original permissions are kept if `None`, which is the default.
+    pub fn set_file_mode(&mut self, mode: Option<u32>) {
+        self.file_mode = mode.map(|mode| mode & FILE_MODE_MASK);
+    }
+
+    /// Override the permissions of directories.
+    ///
+    /// The original permissions are kept if `None`, which is the default.
+    pub fn set_dir_mode(&mut self, mode: Option<u32>) {
+        self.dir_mode = mode.map(|mode| mode & FILE_MODE_MASK);
+    }
+
+    /// Prepend `./` to the paths inside the archive, e.g. `usr/bin/sh` becomes `./usr/bin/sh`.
+    ///
+    /// This is the naming convention of RPM payloads and some initramfs tools.
+    /// The paths that already start with `./` and absolute paths are kept as is.
+    ///
+    /// `false` by default.
+    pub fn prefix_dot_slash(&mut self, value: bool) {
+        self.prefix_dot_slash = value;
+    }
+
+    /// Automatically append the missing parent directories of each entry.
+    ///
+    /// E.g. appending `usr/share/doc/readme` results in
+    /// `usr`, `usr/share` and `usr/share/doc` entries being written first
+    /// unless these directories were already appended.
+    /// This is required by extractors that don't create missing directories
+    /// (e.g. `cpio -i` without `-d`).
+    /// The directories are created using `metadata` as the template
+    /// that has to describe a directory;
+    /// the appending fails otherwise.
+    /// Disabled if `None`, which is the default.
+    pub fn set_parent_dirs(&mut self, metadata: Option<Metadata>) {
+        self.parent_dirs = metadata;
+    }
+
+    /// Make the order of the entries appended by [`append_dir_all`](Self::append_dir_all) stable.
+    ///
+    /// The directory tree is always traversed breadth-first,
+    /// i.e. each directory is appended before its contents.
+    /// When this option is enabled, the entries of each directory are additionally
+    /// sorted by their file names (byte-wise), i.e. the resulting order only depends
+    /// on the directory tree but not on the file system.
+    /// Otherwise the entries are appended in the order returned by the operating system.
+    ///
+    /// `false` by default.
+    pub fn sort_dir_entries(&mut self, value: bool) {
+        self.sort_dir_entries = value;
+    }
+
+    /// Track the hard links that were actually written to the archive.
+    ///
+    /// The number of links in the headers may not match the number of entries
+    /// when the entries come from different sources or only some of the links are appended.
+    /// Use [`nlink_mismatches`](Self::nlink_mismatches) to find such entries
+    /// and [`fix_nlink`](Self::fix_nlink) to rewrite the headers in seekable archives.
+    /// Directories are not tracked.
+    ///
+    /// `false` by default.
+    pub fn track_nlink(&mut self, value: bool) {
+        self.track_nlink = value;
+    }
+
+    /// Get the hard links which number in the headers doesn't match
+    /// the number of entries written so far.
+    ///
+    /// Requires [`track_nlink`](Self::track_nlink).
+    pub fn nlink_mismatches(&self) -> Vec<NlinkMismatch> {
+        let mut mismatches: Vec<_> = self
+            .link_groups
+            .values()
+            .filter(|group| group.nlink as usize != group.offsets.len())
+            .map(|group| NlinkMismatch {
+                path: group.path.clone(),
+                nlink: group.nlink,
+                actual: group.offsets.len() as u32,
+            })
+            .collect();
+        mismatches.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+        mismatches
+    }
+
+    /// Set the function that transforms paths inside the archive.
+    ///
+    /// The mapper returns either the new path or `None` to keep the path as is;
+    /// the entries which path is mapped to an empty one are skipped.
+    /// It is applied to the inner paths by all the methods that derive them from file names:
+    /// [`append_path`](Self::append_path), [`check_path`](Self::check_path),
+    /// [`append_dir_all`](Self::append_dir_all), [`append_roots`](Self::append_roots),
+    /// [`append_path_records`](Self::append_path_records), [`append_spec`](Self::append_spec),
+    /// [`append_device_table`](Self::append_device_table) and [`append_mtree`](Self::append_mtree).
+    /// The methods that take the metadata explicitly, e.g. [`append_entry`](Self::append_entry),
+    /// use the paths as is.
+    pub fn set_path_mapper<F>(&mut self, mapper: F)
+    where
+        F: FnMut(&Path) -> Option<PathBuf> + 'static,
+    {
+        self.path_mapper = Some(Box::new(mapper));
+    }
+
+    /// Get the number of bytes written so far.
+    pub fn archive_size(&self) -> u64 {
+        self.archive_size
+    }
+
     /// Append raw entry.
     pub fn append_entry<P: AsRef<Path>, R: Read>(
         &mut self,
-        mut metadata: Metadata,
+        metadata: Metadata,
         inner_path: P,
+        data: R,
+    ) -> Result<Metadata, Error> {
+        self.do_append_entry(metadata, inner_path.as_ref(), data, None)
+    }
+
+    /// Append a batch of raw entries.
+    ///
+    /// The entries are sorted by their paths before they are written
+    /// so that parent directories precede their contents and the output doesn't depend on the order
+    /// in which the entries were generated; the entries with the same path keep their relative order.
+    /// The inodes are remapped as in [`append_entry`](Self::append_entry).
+    /// The error that occurs while appending an entry includes the path of the entry.
+    pub fn append_entries<I, P, R>(&mut self, entries: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (Metadata, P, R)>,
+        P: AsRef<Path>,
+        R: Read,
+    {
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        entries.sort_by(|a, b| a.1.as_ref().cmp(b.1.as_ref()));
+        for (metadata, inner_path, data) in entries.into_iter() {
+            let inner_path = inner_path.as_ref();
+            self.append_entry(metadata, inner_path, data)
+                .map_err(|e| Error::new(e.kind(), format!("{:?}: {}", inner_path, e)))?;
+        }
+        Ok(())
+    }
+
+    /// Append raw entry which contents are read from `data`.
+    ///
+    /// `file` is the same file as `data`, it is used to copy the contents in the kernel.
+    fn do_append_entry<R: Read>(
+        &mut self,
+        mut metadata: Metadata,
+        inner_path: &Path,
         mut data: R,
+        file: Option<&File>,
     ) -> Result<Metadata, Error> {
-        let is_hard_link = self.fix_header(&mut metadata, inner_path.as_ref())?;
+        let inner_path = self.prefix_path(inner_path.as_ref());
+        self.append_parent_dirs(&inner_path, metadata.is_dir())?;
+        let is_hard_link = self.fix_header(&mut metadata, &inner_path)?;
         let is_crc = matches!(self.format, Format::Crc) && metadata.is_file() && !is_hard_link;
-        let file_contents = if is_crc {
-            let mut crc_writer = CrcWriter::new(Vec::new());
-            std::io::copy(&mut data, &mut crc_writer)?;
-            metadata.check = crc_writer.sum();
+        // the contents are kept in memory only if they can't be read twice
+        let in_memory = is_crc && file.is_none();
+        let mut file_contents = Vec::new();
+        if is_crc {
+            if let Some(mut file) = file {
+                // compute the checksum in the first pass and stream the contents in the second one
+                let offset = file.stream_position()?;
+                let mut crc_writer = CrcWriter::new(std::io::sink());
+                copy_exact(
+                    &mut file,
+                    &mut crc_writer,
+                    metadata.file_size,
+                    &mut self.io_buffer,
+                )?;
+                metadata.check = crc_writer.sum();
+                file.seek(SeekFrom::Start(offset))?;
+            } else {
+                let mut crc_writer = CrcWriter::new(Vec::new());
+                let n = copy_exact(
+                    &mut data,
+                    &mut crc_writer,
+                    metadata.file_size,
+                    &mut self.io_buffer,
+                )?;
+                self.check_size(&inner_path, metadata.file_size, n)?;
+                metadata.check = crc_writer.sum();
+                file_contents = crc_writer.into_inner();
+            }
             if let Some(entry) = self.inodes.get_mut(&metadata.id()) {
                 // update crc
                 entry.1 = metadata.check;
             }
-            crc_writer.into_inner()
+        }
+        self.metadata_editor.edit_metadata(&mut metadata)?;
+        self.fix_overflow(&mut metadata)?;
+        self.add_link(&metadata, &inner_path);
+        self.reserve(&metadata)?;
+        self.buffer.clear();
+        metadata.write(&mut self.buffer, self.format)?;
+        write_path(&mut self.buffer, &inner_path, self.format)?;
+        let fits_in_buffer =
+            self.buffer.len() as u64 + metadata.file_size <= self.buffer_size as u64;
+        let n = if in_memory && fits_in_buffer {
+            self.buffer.extend_from_slice(&file_contents);
+            metadata.file_size
+        } else if in_memory {
+            // the header, the contents and the padding in one call
+            let padding = self.format.padding(metadata.file_size) as usize;
+            write_all_vectored(
+                self.writer.by_ref(),
+                &mut [
+                    IoSlice::new(&self.buffer),
+                    IoSlice::new(&file_contents),
+                    IoSlice::new(&PADDING[..padding]),
+                ],
+            )?;
+            return Ok(metadata);
+        } else if is_hard_link {
+            // the contents were written for the first link
+            0
+        } else if fits_in_buffer {
+            copy_exact(
+                &mut data,
+                &mut self.buffer,
+                metadata.file_size,
+                &mut self.io_buffer,
+            )?
         } else {
-            Vec::new()
+            self.writer.write_all(&self.buffer)?;
+            self.buffer.clear();
+            match (file, self.copy_file) {
+                (Some(mut file), Some(copy_file)) => {
+                    let n = copy_file(&mut self.writer, file, metadata.file_size)?;
+                    copy_exact(
+                        &mut file,
+                        self.writer.by_ref(),
+                        metadata.file_size - n,
+                        &mut self.io_buffer,
+                    )? + n
+                }
+                _ => copy_exact(
+                    &mut data,
+                    self.writer.by_ref(),
+                    metadata.file_size,
+                    &mut self.io_buffer,
+                )?,
+            }
         };
-        self.metadata_editor.edit_metadata(&mut metadata)?;
-        metadata.write(self.writer.by_ref(), self.format)?;
-        write_path(self.writer.by_ref(), inner_path.as_ref(), self.format)?;
-        if metadata.file_size != 0 {
-            let n = if is_crc {
-                self.writer.write_all(&file_contents)?;
-                file_contents.len() as u64
-            } else {
-                std::io::copy(&mut data, self.writer.by_ref())?
-            };
-            if n != metadata.file_size {
-                return Err(ErrorKind::InvalidData.into());
+        write_file_padding(&mut self.buffer, metadata.file_size, self.format)?;
+        self.writer.write_all(&self.buffer)?;
+        self.check_size(&inner_path, metadata.file_size, n)?;
+        Ok(metadata)
+    }
+
+    /// Apply the size mismatch policy.
+    fn check_size(&mut self, inner_path: &Path, file_size: u64, actual: u64) -> Result<(), Error> {
+        if file_size == actual {
+            return Ok(());
+        }
+        match self.size_mismatch_policy {
+            SizeMismatchPolicy::Error => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "read {} bytes instead of {} bytes declared in the header",
+                    actual, file_size
+                ),
+            )),
+            SizeMismatchPolicy::PadOrTruncate => {
+                self.size_mismatches.push(SizeMismatch {
+                    path: inner_path.to_path_buf(),
+                    file_size,
+                    actual,
+                });
+                Ok(())
             }
-            write_file_padding(self.writer.by_ref(), n, self.format)?;
         }
-        Ok(metadata)
+    }
+
+    /// Append an entry with in-memory contents.
+    ///
+    /// The file size in `metadata` is set to the length of `data`.
+    pub fn append_data<P: AsRef<Path>>(
+        &mut self,
+        mut metadata: Metadata,
+        inner_path: P,
+        data: &[u8],
+    ) -> Result<Metadata, Error> {
+        metadata.file_size = data.len() as u64;
+        self.append_entry(metadata, inner_path, data)
+    }
+
+    /// Append directory entry with the specified permissions.
+    pub fn append_dir<P: AsRef<Path>>(
+        &mut self,
+        inner_path: P,
+        mode: u32,
+    ) -> Result<Metadata, Error> {
+        self.append_dir_with_metadata(Metadata::directory(mode), inner_path)
+    }
+
+    /// Append directory entry with the specified metadata.
+    ///
+    /// Fails if `metadata` doesn't describe a directory.
+    pub fn append_dir_with_metadata<P: AsRef<Path>>(
+        &mut self,
+        mut metadata: Metadata,
+        inner_path: P,
+    ) -> Result<Metadata, Error> {
+        if !metadata.is_dir() {
+            return Err(Error::new(ErrorKind::InvalidInput, "not a directory"));
+        }
+        metadata.file_size = 0;
+        self.append_entry(metadata, inner_path, std::io::empty())
+    }
+
+    /// Append block device, character device, FIFO or socket entry.
+    ///
+    /// No files on disk are accessed, i.e. device nodes can be added without root privileges.
+    /// `rdev` is ignored for FIFOs and sockets.
+    pub fn append_special<P: AsRef<Path>>(
+        &mut self,
+        inner_path: P,
+        file_type: FileType,
+        mode: u32,
+        rdev: u64,
+    ) -> Result<Metadata, Error> {
+        let metadata = match file_type {
+            FileType::BlockDevice => Metadata::block_device(mode, rdev),
+            FileType::CharDevice => Metadata::char_device(mode, rdev),
+            FileType::Fifo => Metadata::fifo(mode),
+            FileType::Socket => Metadata::socket(mode),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "not a device, fifo or socket",
+                ))
+            }
+        };
+        self.append_entry(metadata, inner_path, std::io::empty())
+    }
+
+    /// Append an entry which contents are streamed via the returned writer.
+    ///
+    /// The header is written immediately, i.e. `metadata` has to contain the final file size
+    /// and exactly that many bytes have to be written to the [`EntryWriter`].
+    /// Use [`EntryWriter::metadata`] to get the actual header:
+    /// the contents of hard links after the first one are not stored for some formats.
+    ///
+    /// Regular files can't be written in [`Format::Crc`] this way
+    /// because the checksum is stored in the header.
+    pub fn append_writer<P: AsRef<Path>>(
+        &mut self,
+        mut metadata: Metadata,
+        inner_path: P,
+    ) -> Result<EntryWriter<'_, W>, Error> {
+        if matches!(self.format, Format::Crc) && metadata.is_file() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "can't stream regular files in crc format",
+            ));
+        }
+        let inner_path = self.prefix_path(inner_path.as_ref());
+        self.append_parent_dirs(&inner_path, metadata.is_dir())?;
+        self.fix_header(&mut metadata, &inner_path)?;
+        self.metadata_editor.edit_metadata(&mut metadata)?;
+        self.fix_overflow(&mut metadata)?;
+        self.add_link(&metadata, &inner_path);
+        self.reserve(&metadata)?;
+        self.buffer.clear();
+        metadata.write(&mut self.buffer, self.format)?;
+        write_path(&mut self.buffer, &inner_path, self.format)?;
+        self.writer.write_all(&self.buffer)?;
+        Ok(EntryWriter {
+            writer: self.writer.by_ref(),
+            remaining: metadata.file_size,
+            metadata,
+            format: self.format,
+            finished: false,
+        })
     }
 
     /// Append file or directory specified by `path`.
+    ///
+    /// If the [path mapper](Self::set_path_mapper) skips the entry, nothing is written
+    /// and the metadata of the file is returned as is.
     pub fn append_path<P1: AsRef<Path>, P2: AsRef<Path>>(
         &mut self,
         path: P1,
         inner_path: P2,
     ) -> Result<(Metadata, std::fs::Metadata), Error> {
         let path = path.as_ref();
-        let fs_metadata = path.symlink_metadata()?;
+        let Some(inner_path) = self.map_path(inner_path.as_ref()) else {
+            let fs_metadata = if self.follow_symlinks {
+                path.metadata()?
+            } else {
+                path.symlink_metadata()?
+            };
+            return Ok(((&fs_metadata).try_into()?, fs_metadata));
+        };
+        self.append_path_unmapped(path, &inner_path, &mut |_| {})
+    }
+
+    /// Find the values that don't fit into the header of the entry
+    /// that [`append_path`](Self::append_path) would write for the file.
+    ///
+    /// Nothing is written to the archive.
+    /// The path mapper, the owner and permission overrides are applied
+    /// as well as the `./` prefix and the overflow policy,
+    /// whereas [`edit_metadata`](EditMetadata::edit_metadata) is not called.
+    /// Inode numbers are reported only with [`gnu_compat`](Self::gnu_compat)
+    /// because otherwise they are replaced by the builder.
+    /// Returns an empty vector if the file can be appended or is skipped by the path mapper.
+    pub fn check_path<P1: AsRef<Path>, P2: AsRef<Path>>(
+        &mut self,
+        path: P1,
+        inner_path: P2,
+    ) -> Result<Vec<LimitViolation>, Error> {
+        let path = path.as_ref();
+        let fs_metadata = if self.follow_symlinks {
+            path.metadata()?
+        } else {
+            path.symlink_metadata()?
+        };
+        let mut metadata: Metadata = (&fs_metadata).try_into()?;
+        if fs_metadata.is_symlink() {
+            let target_len = read_link(path)?.as_os_str().as_bytes().len() as u64;
+            // the target is terminated by NUL byte unless GNU cpio is mimicked
+            metadata.file_size = target_len + u64::from(!self.gnu_compat);
+        } else if !fs_metadata.is_file() {
+            metadata.file_size = 0;
+        }
+        let Some(inner_path) = self.map_path(inner_path.as_ref()) else {
+            return Ok(Vec::new());
+        };
+        let inner_path = inner_path.into_owned();
+        let inner_path = self.prefix_path(&inner_path);
+        self.remap_owner(&mut metadata);
+        self.remap_mode(&mut metadata);
+        let mut violations = self.format.check(&metadata, &inner_path);
+        let dev_remapped = matches!(self.format, Format::Odc | Format::Bin(..))
+            && !self.preserve_dev_ids
+            && !self.gnu_compat;
+        let overflow_fixed = self.overflow_policy != OverflowPolicy::Error;
+        violations.retain(|violation| match violation.field {
+            HeaderField::Ino => self.gnu_compat,
+            HeaderField::Dev if dev_remapped => false,
+            HeaderField::Uid
+            | HeaderField::Gid
+            | HeaderField::Mtime
+            | HeaderField::Dev
+            | HeaderField::Rdev => !overflow_fixed,
+            HeaderField::Name | HeaderField::FileSize | HeaderField::Nlink => true,
+        });
+        Ok(violations)
+    }
+
+    /// Append the file without applying the path mapper.
+    ///
+    /// `edit` is applied to the metadata obtained from the file system.
+    pub(crate) fn append_path_unmapped(
+        &mut self,
+        path: &Path,
+        inner_path: &Path,
+        edit: &mut dyn FnMut(&mut Metadata),
+    ) -> Result<(Metadata, std::fs::Metadata), Error> {
+        let (fs_metadata, flags) = if self.follow_symlinks {
+            (path.metadata()?, 0)
+        } else {
+            (path.symlink_metadata()?, libc::O_NOFOLLOW)
+        };
+        if fs_metadata.is_file() {
+            // use the metadata of the opened file to not race with file system modifications
+            let file = File::options().read(true).custom_flags(flags).open(path)?;
+            let fs_metadata = file.metadata()?;
+            let mut cpio_metadata: Metadata = (&fs_metadata).try_into()?;
+            let Some(is_first) = self.check_repeat(&mut cpio_metadata) else {
+                return Ok((cpio_metadata, fs_metadata));
+            };
+            let id = cpio_metadata.id();
+            edit(&mut cpio_metadata);
+            self.append_xattrs(path, Some(&file), inner_path)?;
+            #[cfg(target_os = "linux")]
+            let data_size = if self.detect_sparse_files {
+                crate::sparse_data_len(&file, fs_metadata.len())?
+            } else {
+                None
+            };
+            if self.defer_link(path, inner_path, &cpio_metadata)? {
+                return Ok((cpio_metadata, fs_metadata));
+            }
+            let cpio_metadata = if !is_first
+                && self.defer_file(path, inner_path, &file, &fs_metadata, &cpio_metadata)?
+            {
+                cpio_metadata
+            } else {
+                self.do_append_entry(cpio_metadata, inner_path, &file, Some(&file))?
+            };
+            if is_first {
+                self.add_link_target(id, &cpio_metadata);
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(data_size) = data_size {
+                self.sparse_files.push(SparseFile {
+                    path: inner_path.to_path_buf(),
+                    file_size: fs_metadata.len(),
+                    data_size,
+                });
+            }
+            return Ok((cpio_metadata, fs_metadata));
+        }
         let mut cpio_metadata: Metadata = (&fs_metadata).try_into()?;
+        let Some(is_first) = self.check_repeat(&mut cpio_metadata) else {
+            return Ok((cpio_metadata, fs_metadata));
+        };
+        let id = cpio_metadata.id();
+        edit(&mut cpio_metadata);
+        self.append_xattrs(path, None, inner_path)?;
         let cpio_metadata = if fs_metadata.is_symlink() {
             let target = read_link(path)?;
             let mut target = target.into_os_string().into_vec();
-            target.push(0_u8);
+            self.terminate_link_target(&mut target);
             cpio_metadata.file_size = target.len() as u64;
             self.append_entry(cpio_metadata, inner_path, &target[..])?
-        } else if fs_metadata.is_file() {
-            self.append_entry(cpio_metadata, inner_path, File::open(path)?)?
         } else {
             // directory, block/character device, socket, fifo
             cpio_metadata.file_size = 0;
             self.append_entry(cpio_metadata, inner_path, std::io::empty())?
         };
+        if is_first && !cpio_metadata.is_dir() {
+            self.add_link_target(id, &cpio_metadata);
+        }
         Ok((cpio_metadata, fs_metadata))
     }
 
-    /// Append all files in the `directory` recursively.
-    pub fn append_dir_all<P: AsRef<Path>>(&mut self, directory: P) -> Result<(), Error> {
-        let directory = directory.as_ref();
-        for entry in directory.walk()? {
+    /// Append the entry with the extended attributes of the file if enabled.
+    ///
+    /// The attributes are read from the opened `file` if any.
+    fn append_xattrs(
+        &mut self,
+        path: &Path,
+        file: Option<&File>,
+        inner_path: &Path,
+    ) -> Result<(), Error> {
+        if !self.preserve_xattrs {
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        {
+            let xattrs = match file {
+                Some(file) => crate::fgetxattrs(file.as_raw_fd())?,
+                None => {
+                    let path = CString::new(path.as_os_str().as_bytes())?;
+                    crate::getxattrs(&path, self.follow_symlinks)?
+                }
+            };
+            let xattrs: Vec<_> = xattrs
+                .into_iter()
+                .filter(|(name, _)| is_preserved_xattr(name))
+                .map(|(name, value)| Xattr { name, value })
+                .collect();
+            if xattrs.is_empty() {
+                return Ok(());
+            }
+            let data = encode_xattrs(&xattrs)?;
+            self.append_entry(
+                Metadata::regular(0o600, data.len() as u64),
+                xattrs_entry_path(inner_path),
+                &data[..],
+            )?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = (path, file, inner_path);
+        Ok(())
+    }
+
+    /// Apply the repeat policy to the file that is appended by path.
+    ///
+    /// Returns `None` if the file has to be skipped
+    /// and `Some(true)` if the file has to be registered as a hard link target after it is written.
+    fn check_repeat(&mut self, metadata: &mut Metadata) -> Option<bool> {
+        if self.repeat_policy == RepeatPolicy::Store {
+            return Some(false);
+        }
+        if self.appended_files.insert(metadata.id()) {
+            return Some(self.repeat_policy == RepeatPolicy::HardLink);
+        }
+        match self.repeat_policy {
+            RepeatPolicy::Skip => None,
+            RepeatPolicy::HardLink if !metadata.is_dir() => {
+                metadata.nlink = metadata.nlink.max(2);
+                Some(false)
+            }
+            _ => Some(false),
+        }
+    }
+
+    /// Register the written file as the target of the subsequent hard links with the same original `id`.
+    fn add_link_target(&mut self, id: MetadataId, written: &Metadata) {
+        let mapped_id = (self.mapped_dev(id.0), id.1);
+        self.inodes.entry(mapped_id).or_insert((
+            written.ino as u32,
+            written.check,
+            written.file_size != 0,
+        ));
+    }
+
+    /// Defer writing the hard link until the last link is found (GNU cpio compatibility).
+    ///
+    /// When the last link is found the other links are written with zero size.
+    /// Returns `true` if the link was deferred.
+    fn defer_link(
+        &mut self,
+        path: &Path,
+        inner_path: &Path,
+        metadata: &Metadata,
+    ) -> Result<bool, Error> {
+        if !self.gnu_compat
+            || !matches!(self.format, Format::Newc | Format::Crc)
+            || metadata.nlink <= 1
+        {
+            return Ok(false);
+        }
+        let id = metadata.id();
+        let num_deferred = self
+            .deferred_links
+            .iter()
+            .filter(|link| link.metadata.id() == id)
+            .count();
+        if num_deferred + 1 < metadata.nlink as usize {
+            self.deferred_links.push(DeferredLink {
+                path: path.to_path_buf(),
+                inner_path: inner_path.to_path_buf(),
+                metadata: metadata.clone(),
+            });
+            return Ok(true);
+        }
+        let mut i = self.deferred_links.len();
+        while i != 0 {
+            i -= 1;
+            if self.deferred_links[i].metadata.id() == id {
+                let link = self.deferred_links.remove(i);
+                let mut metadata = link.metadata;
+                metadata.file_size = 0;
+                self.append_entry(metadata, &link.inner_path, std::io::empty())?;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Write the hard links which other links were not found.
+    ///
+    /// The contents are stored in the first link of each group.
+    fn write_deferred_links(&mut self) -> Result<(), Error> {
+        while let Some(link) = self.deferred_links.pop() {
+            let id = link.metadata.id();
+            let mut metadata = link.metadata;
+            if self
+                .deferred_links
+                .iter()
+                .any(|other| other.metadata.id() == id)
+            {
+                metadata.file_size = 0;
+                self.append_entry(metadata, &link.inner_path, std::io::empty())?;
+            } else {
+                let file = File::open(&link.path)?;
+                self.do_append_entry(metadata, &link.inner_path, &file, Some(&file))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Defer writing the regular file until all the files with the same contents are found.
+    ///
+    /// Returns `true` if the file was deferred.
+    fn defer_file(
+        &mut self,
+        path: &Path,
+        inner_path: &Path,
+        file: &File,
+        fs_metadata: &std::fs::Metadata,
+        metadata: &Metadata,
+    ) -> Result<bool, Error> {
+        if !self.dedup_contents || metadata.file_size == 0 || metadata.nlink > 1 {
+            return Ok(false);
+        }
+        let key = (metadata.file_size, hash_contents(file)?);
+        self.deferred_files.push(DeferredFile {
+            path: path.to_path_buf(),
+            inner_path: inner_path.to_path_buf(),
+            metadata: metadata.clone(),
+            fs_metadata: fs_metadata.clone(),
+            key,
+        });
+        Ok(true)
+    }
+
+    /// Write the deferred regular files storing the identical ones as hard links.
+    ///
+    /// The groups are written in the order in which their first files were appended.
+    fn write_deferred_files(&mut self) -> Result<(), Error> {
+        let files = std::mem::take(&mut self.deferred_files);
+        let mut same_key: HashMap<(u64, u64), Vec<usize>> = HashMap::new();
+        for (i, file) in files.iter().enumerate() {
+            same_key.entry(file.key).or_default().push(i);
+        }
+        let mut written = vec![false; files.len()];
+        for i in 0..files.len() {
+            if written[i] {
+                continue;
+            }
+            let first = self.reopen_deferred_file(&files[i])?;
+            let mut group = vec![i];
+            for j in same_key[&files[i].key].iter().copied().filter(|j| *j > i) {
+                if written[j] {
+                    continue;
+                }
+                let other = self.reopen_deferred_file(&files[j])?;
+                let equal = contents_equal(&first, &other);
+                (&first).seek(SeekFrom::Start(0))?;
+                if equal? {
+                    group.push(j);
+                }
+            }
+            let id = files[i].metadata.id();
+            let nlink = group.len() as u32;
+            for (k, j) in group.iter().copied().enumerate() {
+                written[j] = true;
+                let file = &files[j];
+                let mut metadata = file.metadata.clone();
+                if nlink > 1 {
+                    (metadata.dev, metadata.ino) = id;
+                    metadata.nlink = nlink;
+                }
+                if k + 1 != group.len() && matches!(self.format, Format::Newc | Format::Crc) {
+                    // the contents are stored in the last link
+                    metadata.file_size = 0;
+                    self.do_append_entry(metadata, &file.inner_path, std::io::empty(), None)?;
+                } else {
+                    (&first).seek(SeekFrom::Start(0))?;
+                    self.do_append_entry(metadata, &file.inner_path, &first, Some(&first))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Open the deferred file and check that it has not changed since it was hashed.
+    fn reopen_deferred_file(&self, file: &DeferredFile) -> Result<File, Error> {
+        let flags = if self.follow_symlinks {
+            0
+        } else {
+            libc::O_NOFOLLOW
+        };
+        let opened = File::options()
+            .read(true)
+            .custom_flags(flags)
+            .open(&file.path)?;
+        let new = opened.metadata()?;
+        let old = &file.fs_metadata;
+        if (
+            new.dev(),
+            new.ino(),
+            new.len(),
+            new.mtime(),
+            new.mtime_nsec(),
+        ) != (
+            old.dev(),
+            old.ino(),
+            old.len(),
+            old.mtime(),
+            old.mtime_nsec(),
+        ) || (new.ctime(), new.ctime_nsec()) != (old.ctime(), old.ctime_nsec())
+        {
+            return Err(Error::other(format!(
+                "{:?} changed while the archive was being created",
+                file.path
+            )));
+        }
+        Ok(opened)
+    }
+
+    /// Append the contents of the already opened `file` using the supplied `metadata`.
+    ///
+    /// Exactly [`size`](Metadata::size) bytes are read starting from the current position of the file;
+    /// the file is never reopened by path.
+    /// Use [`File::metadata`] to obtain the metadata of the opened file.
+    pub fn append_file<P: AsRef<Path>>(
+        &mut self,
+        file: &File,
+        metadata: Metadata,
+        inner_path: P,
+    ) -> Result<Metadata, Error> {
+        let size = metadata.file_size;
+        self.append_entry(metadata, inner_path, file.take(size))
+    }
+
+    /// Append all files in the `directory` recursively.
+    pub fn append_dir_all<P: AsRef<Path>>(&mut self, directory: P) -> Result<(), Error> {
+        self.append_dir_all_filtered(directory, |_, _| true)
+    }
+
+    /// Append the files in the `directory` recursively for which `filter` returns `true`.
+    ///
+    /// The filter receives the path relative to the `directory`
+    /// and the metadata of the file
+    /// (symbolic links are resolved only if [`follow_symlinks`](Self::follow_symlinks) is set).
+    /// The contents of excluded directories are not traversed.
+    pub fn append_dir_all_filtered<P, F>(
+        &mut self,
+        directory: P,
+        mut filter: F,
+    ) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&Path, &std::fs::Metadata) -> bool,
+    {
+        self.append_tree(directory.as_ref(), Path::new(""), &mut filter, None)
+    }
+
+    /// Append all files in each of the `roots` recursively.
+    ///
+    /// Each root is a pair of the directory on the file system and the prefix inside the archive,
+    /// e.g. `[("rootfs", ""), ("firmware", "lib/firmware")]`.
+    /// The roots are appended in the specified order;
+    /// the directory itself is appended as the prefix unless the prefix is empty.
+    /// The directories that occur in several roots are appended only once,
+    /// whereas the other files are appended as many times as they occur,
+    /// i.e. the files from the later roots overwrite the earlier ones when unpacked.
+    /// Use [`sort_dir_entries`](Self::sort_dir_entries) to make the output deterministic
+    /// and [`set_parent_dirs`](Self::set_parent_dirs) to add the parents of the prefixes.
+    pub fn append_roots<I, P1, P2>(&mut self, roots: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (P1, P2)>,
+        P1: AsRef<Path>,
+        P2: AsRef<Path>,
+    {
+        let mut dirs = HashSet::new();
+        for (directory, prefix) in roots {
+            self.append_tree(
+                directory.as_ref(),
+                prefix.as_ref(),
+                &mut |_, _| true,
+                Some(&mut dirs),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Append the files in the `directory` recursively under the `prefix`.
+    ///
+    /// Skips the directories that are already in `dirs` if specified.
+    fn append_tree<F>(
+        &mut self,
+        directory: &Path,
+        prefix: &Path,
+        filter: &mut F,
+        mut dirs: Option<&mut HashSet<PathBuf>>,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(&Path, &std::fs::Metadata) -> bool,
+    {
+        let mut walker = WalkerOptions::default()
+            .follow_symlinks(self.follow_symlinks)
+            .sort(self.sort_dir_entries)
+            .walk(directory)?;
+        if prefix != Path::new("") {
+            // the directory itself
+            if let Some(inner_path) = self.map_path(prefix).map(Cow::into_owned) {
+                if dirs
+                    .as_mut()
+                    .is_none_or(|dirs| dirs.insert(inner_path.clone()))
+                {
+                    self.append_path_unmapped(directory, &inner_path, &mut |_| {})?;
+                }
+            }
+        }
+        while let Some(entry) = walker.next() {
             let entry = entry?;
             let outer_path = entry.path();
-            let inner_path = outer_path.strip_prefix(directory).map_err(Error::other)?;
-            if inner_path == Path::new("") {
+            let relative_path = outer_path.strip_prefix(directory).map_err(Error::other)?;
+            if relative_path == Path::new("") {
+                continue;
+            }
+            let inner_path = prefix.join(relative_path);
+            let inner_path = inner_path.as_path();
+            let metadata = if self.follow_symlinks {
+                outer_path.metadata()?
+            } else {
+                entry.metadata()?
+            };
+            if !filter(inner_path, &metadata) {
+                if metadata.is_dir() {
+                    walker.skip_current_dir();
+                }
+                continue;
+            }
+            let Some(inner_path) = self.map_path(inner_path) else {
                 continue;
+            };
+            if let Some(dirs) = dirs.as_mut() {
+                if metadata.is_dir() && !dirs.insert(inner_path.to_path_buf()) {
+                    continue;
+                }
             }
-            self.append_path(&outer_path, inner_path)?;
+            self.append_path_unmapped(&outer_path, &inner_path, &mut |_| {})?;
         }
         Ok(())
     }
@@ -172,6 +1592,20 @@ impl<W: Write, E: EditMetadata> Builder<W, E> {
         builder.finish()
     }
 
+    /// Create an archive from the files in each of the `roots`.
+    ///
+    /// See [`append_roots`](Self::append_roots) and [`pack`](Self::pack).
+    pub fn pack_roots<I, P1, P2>(writer: W, metadata_editor: E, roots: I) -> Result<W, Error>
+    where
+        I: IntoIterator<Item = (P1, P2)>,
+        P1: AsRef<Path>,
+        P2: AsRef<Path>,
+    {
+        let mut builder = Self::with_metadata_editor(writer, metadata_editor);
+        builder.append_roots(roots)?;
+        builder.finish()
+    }
+
     /// Get mutable reference to the underyling writer.
     pub fn get_mut(&mut self) -> &mut W {
         self.writer.by_ref()
@@ -186,41 +1620,250 @@ impl<W: Write, E: EditMetadata> Builder<W, E> {
     ///
     /// This methods appends the so-called trailer entry to the archive.
     pub fn finish(mut self) -> Result<W, Error> {
+        self.write_deferred_links()?;
+        self.write_deferred_files()?;
         self.write_trailer()?;
+        self.write_padding(self.block_size())?;
         Ok(self.writer)
     }
 
+    /// Finalize the current logical archive and start the new one in the same stream.
+    ///
+    /// Writes the trailer followed by zero bytes that align the next archive
+    /// to a multiple of `alignment` bytes (no padding if `alignment` is 0 or 1).
+    /// This is the layout of multi-segment initramfs images,
+    /// e.g. an uncompressed microcode archive followed by the main archive.
+    /// The hard links never cross segment boundaries,
+    /// i.e. call [`fix_nlink`](Self::fix_nlink) before this method if needed.
+    ///
+    /// Returns the offset of the next archive relative to the start of the stream.
+    pub fn finish_segment(&mut self, alignment: u64) -> Result<u64, Error> {
+        self.write_deferred_links()?;
+        self.write_deferred_files()?;
+        self.write_trailer()?;
+        self.write_padding(alignment)?;
+        self.inodes.clear();
+        self.appended_dirs.clear();
+        self.appended_files.clear();
+        self.link_groups.clear();
+        Ok(self.archive_size)
+    }
+
+    /// Write zero bytes that align the archive size to a multiple of `alignment` bytes.
+    fn write_padding(&mut self, alignment: u64) -> Result<(), Error> {
+        if alignment <= 1 {
+            return Ok(());
+        }
+        let padding = self.archive_size.next_multiple_of(alignment) - self.archive_size;
+        if self.archive_size.saturating_add(padding) > self.max_archive_size {
+            return Err(Error::new(
+                ErrorKind::FileTooLarge,
+                format!(
+                    "archive size limit of {} bytes exceeded",
+                    self.max_archive_size
+                ),
+            ));
+        }
+        std::io::copy(&mut std::io::repeat(0).take(padding), self.writer.by_ref())?;
+        self.archive_size += padding;
+        Ok(())
+    }
+
     fn write_trailer(&mut self) -> Result<(), Error> {
-        let len = TRAILER.to_bytes_with_nul().len();
+        let name = match self.trailer {
+            Trailer::Standard | Trailer::GnuPadded => TRAILER,
+            Trailer::Named(ref name) => name.as_c_str(),
+            Trailer::Custom(ref mut write_trailer) => {
+                let n = write_trailer(&mut self.writer, self.archive_size)?;
+                self.archive_size += n;
+                return Ok(());
+            }
+        };
+        let len = name.to_bytes_with_nul().len();
         let metadata = Metadata {
             dev: 0,
             ino: 0,
             mode: 0,
             uid: 0,
             gid: 0,
-            nlink: 0,
+            nlink: u32::from(self.gnu_compat),
             rdev: 0,
             mtime: 0,
             name_len: len as u32,
             file_size: 0,
             check: 0,
         };
-        metadata.write(self.writer.by_ref(), self.format)?;
-        write_path_c_str(self.writer.by_ref(), TRAILER, self.format)?;
+        let trailer_len = entry_len(len, 0, self.format);
+        if let Some(prepare_entry) = self.prepare_entry {
+            prepare_entry(&mut self.writer, trailer_len)?;
+        }
+        self.buffer.clear();
+        metadata.write(&mut self.buffer, self.format)?;
+        write_path_c_str(&mut self.buffer, name, self.format)?;
+        self.writer.write_all(&self.buffer)?;
+        self.archive_size += trailer_len;
+        Ok(())
+    }
+
+    /// The block size of the padding that [`finish`](Self::finish) writes after the trailer.
+    fn block_size(&self) -> u64 {
+        match self.block_size {
+            0 if matches!(self.trailer, Trailer::GnuPadded) || self.gnu_compat => GNU_BLOCK_SIZE,
+            block_size => block_size as u64,
+        }
+    }
+
+    /// The size of the trailer entry and the block padding in bytes
+    /// if the archive is finished at `archive_size`.
+    ///
+    /// The size of the custom trailer is not known until it is written.
+    fn trailer_len(&self, archive_size: u64) -> u64 {
+        let len = match self.trailer {
+            Trailer::Standard | Trailer::GnuPadded => self.format.trailer_size(),
+            Trailer::Named(ref name) => entry_len(name.to_bytes_with_nul().len(), 0, self.format),
+            Trailer::Custom(..) => 0,
+        };
+        let end = archive_size.saturating_add(len);
+        let block_size = self.block_size();
+        if block_size <= 1 {
+            return len;
+        }
+        let padded_end = end.checked_next_multiple_of(block_size).unwrap_or(u64::MAX);
+        len + (padded_end - end)
+    }
+
+    fn mapped_dev(&self, dev: u64) -> u64 {
+        match self.format {
+            Format::Odc | Format::Bin(..) => {
+                self.devices.get(&dev).map(|dev| *dev as u64).unwrap_or(dev)
+            }
+            Format::Newc | Format::Crc => dev,
+        }
+    }
+
+    /// Append the missing parent directories of `path` if enabled.
+    fn append_parent_dirs(&mut self, path: &Path, is_dir: bool) -> Result<(), Error> {
+        let Some(template) = self.parent_dirs.as_ref() else {
+            return Ok(());
+        };
+        let mut template = template.clone();
+        template.nlink = 1;
+        if is_dir {
+            self.appended_dirs.insert(path.to_path_buf());
+        }
+        let mut missing = Vec::new();
+        for dir in path.ancestors().skip(1) {
+            if matches!(dir.as_os_str().as_bytes(), b"" | b"." | b"/") {
+                continue;
+            }
+            if self.appended_dirs.contains(dir) {
+                // the parents of this directory were appended as well
+                break;
+            }
+            missing.push(dir);
+        }
+        for dir in missing.into_iter().rev() {
+            self.appended_dirs.insert(dir.to_path_buf());
+            self.append_dir_with_metadata(template.clone(), dir)?;
+        }
         Ok(())
     }
 
+    fn prefix_path<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        let bytes = path.as_os_str().as_bytes();
+        if !self.prefix_dot_slash
+            || path.is_absolute()
+            || bytes.starts_with(b"./")
+            || matches!(bytes, b"." | b"")
+        {
+            return path.into();
+        }
+        Path::new(".").join(path).into()
+    }
+
+    /// Terminate the symbolic link target by NUL byte unless GNU cpio is mimicked.
+    pub(crate) fn terminate_link_target(&self, target: &mut Vec<u8>) {
+        if !self.gnu_compat {
+            target.push(0_u8);
+        }
+    }
+
+    /// Apply the path mapper, `None` means that the entry is skipped.
+    pub(crate) fn map_path<'a>(&mut self, path: &'a Path) -> Option<Cow<'a, Path>> {
+        match self.path_mapper.as_mut().and_then(|mapper| mapper(path)) {
+            Some(new_path) if new_path.as_os_str().is_empty() => None,
+            Some(new_path) => Some(new_path.into()),
+            None => Some(path.into()),
+        }
+    }
+
+    /// Remember the header offset of the hard link if enabled.
+    ///
+    /// Has to be called before [`reserve`](Self::reserve).
+    fn add_link(&mut self, metadata: &Metadata, path: &Path) {
+        if !self.track_nlink || metadata.is_dir() || metadata.nlink <= 1 {
+            return;
+        }
+        let offset = self.archive_size;
+        self.link_groups
+            .entry(metadata.id())
+            .or_insert_with(|| LinkGroup {
+                path: path.to_path_buf(),
+                nlink: metadata.nlink,
+                offsets: Vec::new(),
+            })
+            .offsets
+            .push(offset);
+    }
+
+    /// Account for the entry in the archive size.
+    fn reserve(&mut self, metadata: &Metadata) -> Result<(), Error> {
+        let len = entry_len(metadata.name_len as usize, metadata.file_size, self.format);
+        let new_size = self.archive_size.saturating_add(len);
+        let new_size = new_size.saturating_add(self.trailer_len(new_size));
+        if new_size > self.max_archive_size {
+            return Err(Error::new(
+                ErrorKind::FileTooLarge,
+                format!(
+                    "archive size limit of {} bytes exceeded",
+                    self.max_archive_size
+                ),
+            ));
+        }
+        if let Some(prepare_entry) = self.prepare_entry {
+            prepare_entry(&mut self.writer, len)?;
+        }
+        self.archive_size += len;
+        Ok(())
+    }
+
+    pub(crate) fn set_prepare_entry(&mut self, prepare_entry: Option<PrepareEntry<W>>) {
+        self.prepare_entry = prepare_entry;
+    }
+
     fn fix_header(&mut self, metadata: &mut Metadata, name: &Path) -> Result<bool, Error> {
+        self.remap_owner(metadata);
+        self.remap_mode(metadata);
         self.remap_device_id(metadata);
         let is_hard_link = self.remap_inode(metadata);
+        if self.gnu_compat
+            && matches!(
+                metadata.file_type(),
+                Ok(FileType::Regular | FileType::Directory | FileType::Symlink)
+            )
+        {
+            metadata.rdev = 0;
+        }
         let name_len = name.as_os_str().as_bytes().len();
+        if name_len == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "empty path"));
+        }
         let max = match self.format {
-            Format::Newc | Format::Crc => MAX_8,
-            Format::Odc => MAX_6,
-            Format::Bin(..) => u16::MAX as u32,
+            Format::Newc | Format::Crc => NEWC_MAX_NAME_LEN,
+            Format::Odc => ODC_MAX_NAME_LEN,
+            Format::Bin(..) => BIN_MAX_NAME_LEN,
         };
-        // -1 due to null byte
-        if name_len > max as usize - 1 {
+        if name_len > max {
             return Err(ErrorKind::InvalidData.into());
         }
         // +1 due to null byte
@@ -228,10 +1871,48 @@ impl<W: Write, E: EditMetadata> Builder<W, E> {
         Ok(is_hard_link)
     }
 
+    fn remap_owner(&self, metadata: &mut Metadata) {
+        if let Some(uid) = self
+            .uid_mappings
+            .get(&metadata.uid)
+            .copied()
+            .or(self.default_uid)
+        {
+            metadata.uid = uid;
+        }
+        if let Some(gid) = self
+            .gid_mappings
+            .get(&metadata.gid)
+            .copied()
+            .or(self.default_gid)
+        {
+            metadata.gid = gid;
+        }
+    }
+
+    fn remap_mode(&self, metadata: &mut Metadata) {
+        if metadata.is_symlink() {
+            return;
+        }
+        let override_mode = if metadata.is_file() {
+            self.file_mode
+        } else if metadata.is_dir() {
+            self.dir_mode
+        } else {
+            None
+        };
+        let mode = override_mode.unwrap_or(metadata.mode & FILE_MODE_MASK);
+        let mode = (mode & !self.mode_mask) | self.mode_bits;
+        metadata.mode = (metadata.mode & !FILE_MODE_MASK) | mode;
+    }
+
     /// Remap device id if needed.
     fn remap_device_id(&mut self, metadata: &mut Metadata) {
         use std::collections::hash_map::Entry::*;
         match self.format {
+            Format::Odc | Format::Bin(..) if self.preserve_dev_ids || self.gnu_compat => {
+                // overflows are handled later
+            }
             Format::Odc | Format::Bin(..) => {
                 let dev = match self.devices.entry(metadata.dev) {
                     Vacant(v) => {
@@ -250,29 +1931,1690 @@ impl<W: Write, E: EditMetadata> Builder<W, E> {
         };
     }
 
+    /// Handle the values that don't fit into the header.
+    fn fix_overflow(&self, metadata: &mut Metadata) -> Result<(), Error> {
+        let policy = self.overflow_policy;
+        let format = self.format;
+        let fix = |name: &str, value: u64, max: u64| -> Result<u64, Error> {
+            if value <= max {
+                return Ok(value);
+            }
+            match policy {
+                OverflowPolicy::Error => Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "{} {} exceeds the maximum of {} supported by {:?} format",
+                        name, value, max, format
+                    ),
+                )),
+                OverflowPolicy::Saturate => Ok(max),
+                OverflowPolicy::Zero => Ok(0),
+                OverflowPolicy::Truncate => Ok(value & max),
+            }
+        };
+        let (max_id, max_mtime) = match format {
+            Format::Newc | Format::Crc => (NEWC_MAX_ID, NEWC_MAX_MTIME),
+            Format::Odc => (ODC_MAX_ID, ODC_MAX_MTIME),
+            Format::Bin(..) => (BIN_MAX_ID, BIN_MAX_MTIME),
+        };
+        metadata.uid = fix("user id", metadata.uid as u64, max_id as u64)? as u32;
+        metadata.gid = fix("group id", metadata.gid as u64, max_id as u64)? as u32;
+        metadata.mtime = fix("modification time", metadata.mtime, max_mtime)?;
+        match format {
+            Format::Odc => {
+                metadata.dev = fix("device id", metadata.dev, MAX_6 as u64)?;
+                metadata.rdev = fix("device id", metadata.rdev, MAX_6 as u64)?;
+            }
+            Format::Bin(..) => {
+                let max = u8::MAX as u64;
+                for dev in [&mut metadata.dev, &mut metadata.rdev] {
+                    let major = libc::major(*dev as libc::dev_t) as u64;
+                    let minor = libc::minor(*dev as libc::dev_t) as u64;
+                    let major = fix("device major number", major, max)?;
+                    let minor = fix("device minor number", minor, max)?;
+                    *dev = makedev(major, minor);
+                }
+            }
+            Format::Newc | Format::Crc => {}
+        }
+        Ok(())
+    }
+
     /// Always remap inode.
+    ///
+    /// The entries with a single link get a new inode even if their device id and inode repeat,
+    /// e.g. synthesized entries that all have zero inode, i.e. only the entries with multiple links
+    /// are recognized as hard links.
     fn remap_inode(&mut self, metadata: &mut Metadata) -> bool {
         use std::collections::hash_map::Entry::*;
+        if self.gnu_compat {
+            // keep the original inode, the contents of hard links are stored by `defer_link`
+            return false;
+        }
+        if metadata.nlink <= 1 {
+            // not a hard link
+            metadata.ino = self.max_inode as u64;
+            self.max_inode += 1;
+            return false;
+        }
         let mut is_hard_link = false;
         let inode = match self.inodes.entry(metadata.id()) {
             Vacant(v) => {
                 let inode = self.max_inode;
                 self.max_inode += 1;
-                v.insert((inode, 0));
+                v.insert((inode, 0, metadata.file_size != 0));
                 inode
             }
-            Occupied(o) => {
-                let (inode, check) = *o.get();
-                if matches!(self.format, Format::Newc | Format::Crc) {
-                    // the data is only stored for the first hard link
+            Occupied(mut o) => {
+                let (inode, check, stored) = o.get_mut();
+                let dedup =
+                    matches!(self.format, Format::Newc | Format::Crc) || self.dedup_hard_links;
+                if dedup && *stored {
+                    // the data is only stored for the first hard link that has any
                     metadata.file_size = 0;
-                    metadata.check = check;
+                    metadata.check = *check;
                     is_hard_link = true;
+                } else if metadata.file_size != 0 {
+                    *stored = true;
                 }
-                inode
+                *inode
             }
         };
         metadata.ino = inode as u64;
         is_hard_link
     }
 }
+
+impl<W: Write + AsRawFd, E: EditMetadata> Builder<W, E> {
+    /// Copy the contents of the regular files appended from the file system
+    /// in the kernel without copying them to the userspace (Linux only).
+    ///
+    /// Uses `copy_file_range` or `sendfile`
+    /// and transparently falls back to the regular copying if they are not supported
+    /// for the particular pair of files.
+    /// Applies to the files that are larger than the [buffer](Self::set_buffer_size).
+    /// On other platforms the contents are always copied in the userspace.
+    ///
+    /// `false` by default.
+    pub fn zero_copy(&mut self, value: bool) {
+        #[cfg(target_os = "linux")]
+        {
+            self.copy_file = value.then_some(copy_file_in_kernel::<W>);
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = value;
+    }
+}
+
+/// Add the path of the entry that couldn't be appended to the error message.
+pub(crate) fn append_error(path: &Path, e: Error) -> Error {
+    Error::new(e.kind(), format!("failed to append {:?}: {}", path, e))
+}
+
+#[cfg(target_os = "linux")]
+fn copy_file_in_kernel<W: AsRawFd>(writer: &mut W, file: &File, len: u64) -> Result<u64, Error> {
+    crate::kernel_copy(file.as_raw_fd(), writer.as_raw_fd(), len)
+}
+
+impl<W: Write + Seek, E: EditMetadata> Builder<W, E> {
+    /// Rewrite the number of links in the headers of the hard links written so far
+    /// to match the actual number of entries.
+    ///
+    /// Requires [`track_nlink`](Self::track_nlink).
+    /// The archive has to start at the position of the writer at which the builder was created.
+    /// The position is restored afterwards.
+    pub fn fix_nlink(&mut self) -> Result<(), Error> {
+        let end = self.writer.stream_position()?;
+        let start = end - self.archive_size;
+        let nlink_offset = match self.format {
+            Format::Newc | Format::Crc => NEWC_NLINK_OFFSET,
+            Format::Odc => ODC_NLINK_OFFSET,
+            Format::Bin(..) => BIN_NLINK_OFFSET,
+        };
+        for group in self.link_groups.values_mut() {
+            let nlink = group.offsets.len() as u32;
+            if group.nlink == nlink {
+                continue;
+            }
+            for offset in group.offsets.iter() {
+                self.writer
+                    .seek(SeekFrom::Start(start + offset + nlink_offset))?;
+                match self.format {
+                    Format::Newc | Format::Crc => write_hex_8(self.writer.by_ref(), nlink)?,
+                    Format::Odc => write_octal_6(self.writer.by_ref(), nlink)?,
+                    Format::Bin(byte_order) => {
+                        let nlink: u16 = nlink.try_into().map_err(|_| ErrorKind::InvalidData)?;
+                        match byte_order {
+                            ByteOrder::LittleEndian => {
+                                write_binary_u16_le(self.writer.by_ref(), nlink)?
+                            }
+                            ByteOrder::BigEndian => {
+                                write_binary_u16_be(self.writer.by_ref(), nlink)?
+                            }
+                        }
+                    }
+                }
+            }
+            group.nlink = nlink;
+        }
+        self.writer.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+}
+
+/// Hash the contents of the file and rewind it.
+fn hash_contents(mut file: &File) -> Result<u64, Error> {
+    let mut hasher = HashWriter::default();
+    std::io::copy(&mut file, &mut hasher)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(hasher.finish())
+}
+
+fn contents_equal(mut file1: &File, mut file2: &File) -> Result<bool, Error> {
+    let mut buf1 = [0_u8; 4096];
+    let mut buf2 = [0_u8; 4096];
+    loop {
+        let n = file1.read(&mut buf1[..])?;
+        if n == 0 {
+            return Ok(file2.read(&mut buf2[..1])? == 0);
+        }
+        if file2.read_exact(&mut buf2[..n]).is_err() || buf1[..n] != buf2[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// A writer for the contents of a particular archive entry.
+///
+/// Returned by [`Builder::append_writer`].
+pub struct EntryWriter<'a, W: Write> {
+    writer: &'a mut W,
+    metadata: Metadata,
+    remaining: u64,
+    format: Format,
+    finished: bool,
+}
+
+impl<'a, W: Write> EntryWriter<'a, W> {
+    /// Get entry's metadata as it was written to the archive.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Get the number of bytes that still have to be written.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Finalize the entry.
+    ///
+    /// Fails if fewer bytes than declared in the header were written.
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.finished = true;
+        if self.remaining != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "fewer bytes were written than declared in the header",
+            ));
+        }
+        write_file_padding(self.writer.by_ref(), self.metadata.file_size, self.format)?;
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> Write for EntryWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "more bytes were written than declared in the header",
+            ));
+        }
+        let n = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let n = self.writer.write(&buf[..n])?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()
+    }
+}
+
+impl<'a, W: Write> Drop for EntryWriter<'a, W> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        // keep the archive structurally valid
+        let _ = std::io::copy(
+            &mut std::io::repeat(0).take(self.remaining),
+            self.writer.by_ref(),
+        );
+        let _ = write_file_padding(self.writer.by_ref(), self.metadata.file_size, self.format);
+    }
+}
+
+/// Copy exactly `file_size` bytes from `reader` to `writer`
+/// padding the contents with zeros or truncating them.
+///
+/// Returns the number of bytes read.
+fn copy_exact<R: Read, W: Write>(
+    reader: &mut R,
+    mut writer: W,
+    file_size: u64,
+    buf: &mut [u8],
+) -> Result<u64, Error> {
+    let n = copy_with_buffer(&mut reader.take(file_size), &mut writer, buf)?;
+    if n < file_size {
+        std::io::copy(&mut std::io::repeat(0).take(file_size - n), &mut writer)?;
+        return Ok(n);
+    }
+    let extra = copy_with_buffer(reader, &mut std::io::sink(), buf)?;
+    Ok(n + extra)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::fs::create_dir_all;
+    use std::ops::ControlFlow;
+    use std::os::unix::fs::PermissionsExt;
+
+    use arbtest::arbtest;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::testdata::patterned_data;
+    use crate::UnpackOptions;
+
+    #[test]
+    fn append_writer_read() {
+        arbtest(|u| {
+            let contents: Vec<u8> = u.arbitrary()?;
+            let format: Format = u.arbitrary()?;
+            if format == Format::Crc {
+                return Ok(());
+            }
+            let mut builder = Builder::new(Vec::new());
+            builder.set_format(format);
+            let mut writer = builder
+                .append_writer(Metadata::regular(0o644, contents.len() as u64), "file")
+                .unwrap();
+            for chunk in contents.chunks(7) {
+                writer.write_all(chunk).unwrap();
+            }
+            assert!(writer.write_all(b"x").is_err());
+            writer.finish().unwrap();
+            builder
+                .append_writer(Metadata::directory(0o755), "dir")
+                .unwrap()
+                .finish()
+                .unwrap();
+            let bytes = builder.finish().unwrap();
+            let mut archive = Archive::new(&bytes[..]);
+            let mut entry = archive.next_entry().unwrap().unwrap();
+            let mut actual = Vec::new();
+            entry.reader.read_to_end(&mut actual).unwrap();
+            assert_eq!(contents, actual);
+            drop(entry);
+            let entry = archive.next_entry().unwrap().unwrap();
+            assert_eq!(Path::new("dir"), entry.path);
+            drop(entry);
+            assert!(archive.next_entry().unwrap().is_none());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn append_file_uses_metadata_size() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"hello world").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_file(&file, Metadata::regular(0o644, 5), "file")
+            .unwrap();
+        assert!(builder
+            .append_file(&file, Metadata::regular(0o644, 100), "file")
+            .is_err());
+        let bytes = builder.get_ref().clone();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut entry = archive.next_entry().unwrap().unwrap();
+        let mut actual = Vec::new();
+        entry.reader.read_to_end(&mut actual).unwrap();
+        assert_eq!(b"hello", &actual[..]);
+    }
+
+    #[test]
+    fn single_link_entries_get_distinct_inodes() {
+        // the synthesized entries have the same (dev, ino)
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_data(Metadata::regular(0o644, 3), "a", b"aaa")
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 3), "b", b"bbb")
+            .unwrap();
+        let mut link = Metadata::regular(0o644, 3);
+        link.set_nlink(2);
+        builder.append_data(link.clone(), "c", b"ccc").unwrap();
+        builder.append_data(link, "d", b"ccc").unwrap();
+        let bytes = builder.finish().unwrap();
+        let mut inodes = Vec::new();
+        let mut archive = Archive::new(&bytes[..]);
+        while let Some(mut entry) = archive.next_entry().unwrap() {
+            let mut data = Vec::new();
+            entry.reader.read_to_end(&mut data).unwrap();
+            inodes.push((entry.metadata.ino(), data));
+        }
+        assert_eq!(
+            vec![
+                (0, b"aaa".to_vec()),
+                (1, b"bbb".to_vec()),
+                (2, b"ccc".to_vec()),
+                (2, b"ccc".to_vec()),
+            ],
+            inodes
+        );
+    }
+
+    #[test]
+    fn append_data_sets_file_size() {
+        let mut builder = Builder::new(Vec::new());
+        let metadata = builder
+            .append_data(Metadata::regular(0o755, 0), "init", b"#!/bin/sh\n")
+            .unwrap();
+        assert_eq!(10, metadata.size());
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut entry = archive.next_entry().unwrap().unwrap();
+        let mut actual = Vec::new();
+        entry.reader.read_to_end(&mut actual).unwrap();
+        assert_eq!(b"#!/bin/sh\n", &actual[..]);
+    }
+
+    #[test]
+    fn append_dir_unpack() {
+        let workdir = TempDir::new().unwrap();
+        let mut builder = Builder::new(Vec::new());
+        builder.append_dir("dir", 0o750).unwrap();
+        let mut metadata = Metadata::directory(0o700);
+        metadata.set_mtime(1_000_000);
+        builder
+            .append_dir_with_metadata(metadata, "dir/subdir")
+            .unwrap();
+        assert!(builder
+            .append_dir_with_metadata(Metadata::regular(0o644, 0), "file")
+            .is_err());
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().preserve_mtime(true));
+        archive.unpack(workdir.path()).unwrap();
+        let metadata = workdir.path().join("dir").metadata().unwrap();
+        assert!(metadata.is_dir());
+        assert_eq!(0o750, metadata.permissions().mode() & 0o777);
+        let metadata = workdir.path().join("dir/subdir").metadata().unwrap();
+        assert_eq!(0o700, metadata.permissions().mode() & 0o777);
+        assert_eq!(1_000_000, metadata.mtime());
+    }
+
+    #[test]
+    fn append_dir_all_filtered() {
+        let workdir = TempDir::new().unwrap();
+        for dir in [".git/objects", "src"] {
+            create_dir_all(workdir.path().join(dir)).unwrap();
+        }
+        for file in [".git/objects/1", "src/lib.rs", "src/lib.rs.tmp", "README"] {
+            std::fs::write(workdir.path().join(file), file).unwrap();
+        }
+        let mut builder = Builder::new(Vec::new());
+        let mut visited = Vec::new();
+        builder
+            .append_dir_all_filtered(workdir.path(), |path, metadata| {
+                visited.push(path.to_path_buf());
+                !(metadata.is_dir() && path == Path::new(".git")
+                    || path.extension() == Some("tmp".as_ref()))
+            })
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        assert!(!visited.contains(&PathBuf::from(".git/objects")));
+        let mut archive = Archive::new(&bytes[..]);
+        let mut paths = Vec::new();
+        while let Some(entry) = archive.next_entry().unwrap() {
+            paths.push(entry.path.clone());
+        }
+        paths.sort();
+        assert_eq!(
+            vec![
+                PathBuf::from("README"),
+                PathBuf::from("src"),
+                PathBuf::from("src/lib.rs")
+            ],
+            paths
+        );
+    }
+
+    #[test]
+    fn dedup_hard_links() {
+        let workdir = TempDir::new().unwrap();
+        let data = [b'x'; 1000];
+        std::fs::write(workdir.path().join("file"), data).unwrap();
+        std::fs::hard_link(workdir.path().join("file"), workdir.path().join("link")).unwrap();
+        for format in [Format::Odc, Format::Bin(ByteOrder::BigEndian)] {
+            let mut sizes = Vec::new();
+            for dedup in [false, true] {
+                let mut builder = Builder::new(Vec::new());
+                builder.set_format(format);
+                builder.dedup_hard_links(dedup);
+                builder.append_dir_all(workdir.path()).unwrap();
+                let bytes = builder.finish().unwrap();
+                sizes.push(bytes.len());
+                let unpack_dir = TempDir::new().unwrap();
+                Archive::new(&bytes[..]).unpack(unpack_dir.path()).unwrap();
+                let file = unpack_dir.path().join("file");
+                let link = unpack_dir.path().join("link");
+                assert_eq!(&data[..], &std::fs::read(&file).unwrap()[..]);
+                assert_eq!(&data[..], &std::fs::read(&link).unwrap()[..]);
+                assert_eq!(
+                    file.metadata().unwrap().ino(),
+                    link.metadata().unwrap().ino()
+                );
+            }
+            assert!(sizes[1] + data.len() <= sizes[0], "{:?}", sizes);
+        }
+    }
+
+    #[test]
+    fn dedup_contents() {
+        let workdir = TempDir::new().unwrap();
+        create_dir_all(workdir.path().join("dir")).unwrap();
+        let data1 = [b'x'; 1000];
+        let data2 = [b'y'; 1000];
+        for (path, data) in [("a", data1), ("b", data1), ("c", data2), ("dir/d", data1)] {
+            std::fs::write(workdir.path().join(path), data).unwrap();
+        }
+        for format in [Format::Newc, Format::Crc, Format::Odc] {
+            let mut sizes = Vec::new();
+            for dedup in [false, true] {
+                let mut builder = Builder::new(Vec::new());
+                builder.set_format(format);
+                builder.dedup_contents(dedup);
+                builder.dedup_hard_links(true);
+                builder.append_dir_all(workdir.path()).unwrap();
+                let bytes = builder.finish().unwrap();
+                sizes.push(bytes.len());
+                let unpack_dir = TempDir::new().unwrap();
+                Archive::new(&bytes[..]).unpack(unpack_dir.path()).unwrap();
+                let mut inodes = Vec::new();
+                for (path, data) in [("a", data1), ("b", data1), ("c", data2), ("dir/d", data1)] {
+                    let path = unpack_dir.path().join(path);
+                    assert_eq!(&data[..], &std::fs::read(&path).unwrap()[..]);
+                    inodes.push(path.metadata().unwrap().ino());
+                }
+                assert_ne!(inodes[0], inodes[2]);
+                if dedup {
+                    assert_eq!(inodes[0], inodes[1]);
+                    assert_eq!(inodes[0], inodes[3]);
+                } else {
+                    assert_ne!(inodes[0], inodes[1]);
+                }
+                // all the links of the group have the same number of links,
+                // the contents are stored in the last link in newc and crc
+                let mut entries = Vec::new();
+                Archive::new(&bytes[..])
+                    .for_each_raw(|entry| {
+                        if entry.metadata.is_file() {
+                            let name = OsStr::from_bytes(entry.name).to_os_string();
+                            let metadata = entry.metadata;
+                            entries.push((name, metadata.nlink(), metadata.size()));
+                        }
+                        ControlFlow::Continue(())
+                    })
+                    .unwrap();
+                let entries: Vec<_> = entries
+                    .into_iter()
+                    .filter(|(_, nlink, _)| *nlink != 1)
+                    .collect();
+                if !dedup {
+                    assert!(entries.is_empty(), "{:?}", entries);
+                    continue;
+                }
+                assert_eq!(3, entries.len(), "{:?}", entries);
+                for (i, (_, nlink, size)) in entries.iter().enumerate() {
+                    assert_eq!(3, *nlink, "{:?}", entries);
+                    let has_data = match format {
+                        Format::Newc | Format::Crc => i == 2,
+                        _ => i == 0,
+                    };
+                    let expected = if has_data { data1.len() as u64 } else { 0 };
+                    assert_eq!(expected, *size, "{:?} {:?}", format, entries);
+                }
+            }
+            assert!(sizes[1] + 2 * data1.len() <= sizes[0], "{:?}", sizes);
+        }
+    }
+
+    #[test]
+    fn dedup_contents_changed_file() {
+        let workdir = TempDir::new().unwrap();
+        let path = workdir.path().join("file");
+        std::fs::write(&path, [b'x'; 1000]).unwrap();
+        let mut builder = Builder::new(Vec::new());
+        builder.dedup_contents(true);
+        builder.append_path(&path, "file").unwrap();
+        // the file is hashed but not written yet
+        std::fs::write(&path, [b'y'; 1000]).unwrap();
+        assert!(builder.finish().is_err());
+    }
+
+    #[test]
+    fn follow_symlinks() {
+        let workdir = TempDir::new().unwrap();
+        create_dir_all(workdir.path().join("sub")).unwrap();
+        std::fs::write(workdir.path().join("sub/file"), b"hello").unwrap();
+        std::os::unix::fs::symlink("sub/file", workdir.path().join("link")).unwrap();
+        std::os::unix::fs::symlink("sub", workdir.path().join("dirlink")).unwrap();
+        std::os::unix::fs::symlink("..", workdir.path().join("sub/loop")).unwrap();
+        let mut builder = Builder::new(Vec::new());
+        builder.follow_symlinks(true);
+        builder.append_dir_all(workdir.path()).unwrap();
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut entries = Vec::new();
+        while let Some(mut entry) = archive.next_entry().unwrap() {
+            let mut contents = Vec::new();
+            entry.reader.read_to_end(&mut contents).unwrap();
+            entries.push((
+                entry.path.clone(),
+                entry.metadata.file_type().unwrap(),
+                contents,
+            ));
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            vec![
+                (PathBuf::from("dirlink"), FileType::Directory, vec![]),
+                (
+                    PathBuf::from("dirlink/file"),
+                    FileType::Regular,
+                    b"hello".to_vec()
+                ),
+                (PathBuf::from("dirlink/loop"), FileType::Directory, vec![]),
+                (PathBuf::from("link"), FileType::Regular, b"hello".to_vec()),
+                (PathBuf::from("sub"), FileType::Directory, vec![]),
+                (
+                    PathBuf::from("sub/file"),
+                    FileType::Regular,
+                    b"hello".to_vec()
+                ),
+                (PathBuf::from("sub/loop"), FileType::Directory, vec![]),
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn owner_mappings() {
+        let mut builder = Builder::new(Vec::new());
+        builder.add_uid_mapping(1000, 0);
+        builder.add_gid_mapping(1000, 0);
+        builder.add_gid_mapping(1001, 10);
+        builder.set_default_uid(Some(65534));
+        for (uid, gid) in [(1000, 1000), (1001, 1001), (1002, 1002)] {
+            let mut metadata = Metadata::regular(0o644, 0);
+            metadata.set_uid(uid);
+            metadata.set_gid(gid);
+            builder
+                .append_data(metadata, format!("{}", uid), b"")
+                .unwrap();
+        }
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut owners = Vec::new();
+        while let Some(entry) = archive.next_entry().unwrap() {
+            owners.push((entry.metadata.uid(), entry.metadata.gid()));
+        }
+        assert_eq!(vec![(0, 0), (65534, 10), (65534, 1002)], owners);
+    }
+
+    #[test]
+    fn builder_mode_options() {
+        let mut builder = Builder::new(Vec::new());
+        builder.set_file_mode(Some(0o644));
+        builder.set_dir_mode(Some(0o755));
+        builder.set_mode_mask(0o6022);
+        builder.set_mode_bits(0o400);
+        builder.append_dir("dir", 0o700).unwrap();
+        builder
+            .append_data(Metadata::regular(0o4777, 0), "dir/file", b"")
+            .unwrap();
+        builder
+            .append_special("dir/fifo", FileType::Fifo, 0o6266, 0)
+            .unwrap();
+        builder
+            .append_data(Metadata::symlink("file"), "dir/link", b"file\0")
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut modes = Vec::new();
+        while let Some(entry) = archive.next_entry().unwrap() {
+            modes.push((
+                entry.metadata.file_type().unwrap(),
+                entry.metadata.file_mode(),
+            ));
+        }
+        assert_eq!(
+            vec![
+                (FileType::Directory, 0o755),
+                (FileType::Regular, 0o644),
+                (FileType::Fifo, 0o644),
+                (FileType::Symlink, 0o777),
+            ],
+            modes
+        );
+    }
+
+    #[test]
+    fn append_roots() {
+        let workdir = TempDir::new().unwrap();
+        let rootfs = workdir.path().join("rootfs");
+        let overlay = workdir.path().join("overlay");
+        let firmware = workdir.path().join("firmware");
+        for dir in [rootfs.join("etc"), overlay.join("etc"), firmware.clone()] {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        std::fs::write(rootfs.join("etc/hostname"), b"rootfs").unwrap();
+        std::fs::write(overlay.join("etc/hostname"), b"overlay").unwrap();
+        std::fs::write(overlay.join("etc/extra"), b"extra").unwrap();
+        std::fs::write(firmware.join("blob.bin"), b"blob").unwrap();
+        let mut builder = Builder::new(Vec::new());
+        builder.sort_dir_entries(true);
+        builder.set_parent_dirs(Some(Metadata::directory(0o755)));
+        builder
+            .append_roots([
+                (&rootfs, Path::new("")),
+                (&overlay, Path::new("")),
+                (&firmware, Path::new("lib/firmware")),
+            ])
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut paths = Vec::new();
+        while let Some(entry) = archive.next_entry().unwrap() {
+            paths.push(entry.path.to_str().unwrap().to_string());
+        }
+        assert_eq!(
+            vec![
+                "etc",
+                "etc/hostname",
+                "etc/extra",
+                "etc/hostname",
+                "lib",
+                "lib/firmware",
+                "lib/firmware/blob.bin"
+            ],
+            paths
+        );
+        let output = workdir.path().join("output");
+        Archive::new(&bytes[..]).unpack(&output).unwrap();
+        assert_eq!(
+            b"overlay",
+            &std::fs::read(output.join("etc/hostname")).unwrap()[..]
+        );
+        assert_eq!(
+            b"blob",
+            &std::fs::read(output.join("lib/firmware/blob.bin")).unwrap()[..]
+        );
+        let bytes =
+            Builder::pack_roots(Vec::new(), DoNotEditMetadata, [(&firmware, "lib/firmware")])
+                .unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut paths = Vec::new();
+        while let Some(entry) = archive.next_entry().unwrap() {
+            paths.push(entry.path.to_str().unwrap().to_string());
+        }
+        assert_eq!(vec!["lib/firmware", "lib/firmware/blob.bin"], paths);
+    }
+
+    #[test]
+    fn parent_dirs() {
+        let mut builder = Builder::new(Vec::new());
+        let mut template = Metadata::directory(0o750);
+        template.set_uid(1000);
+        template.set_gid(100);
+        builder.set_parent_dirs(Some(template));
+        builder.append_dir("usr", 0o755).unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "usr/share/doc/readme", b"")
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "usr/share/man", b"")
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "/etc/passwd", b"")
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut entries = Vec::new();
+        while let Some(entry) = archive.next_entry().unwrap() {
+            entries.push((
+                entry.path.clone(),
+                entry.metadata.file_mode(),
+                entry.metadata.uid(),
+            ));
+        }
+        assert_eq!(
+            vec![
+                (PathBuf::from("usr"), 0o755, 0),
+                (PathBuf::from("usr/share"), 0o750, 1000),
+                (PathBuf::from("usr/share/doc"), 0o750, 1000),
+                (PathBuf::from("usr/share/doc/readme"), 0o644, 0),
+                (PathBuf::from("usr/share/man"), 0o644, 0),
+                (PathBuf::from("/etc"), 0o750, 1000),
+                (PathBuf::from("/etc/passwd"), 0o644, 0),
+            ],
+            entries
+        );
+        let mut builder = Builder::new(Vec::new());
+        builder.set_parent_dirs(Some(Metadata::regular(0o644, 0)));
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            builder
+                .append_data(Metadata::regular(0o644, 0), "a/b", b"")
+                .unwrap_err()
+                .kind()
+        );
+    }
+
+    #[test]
+    fn dot_slash_prefix() {
+        let mut builder = Builder::new(Vec::new());
+        builder.prefix_dot_slash(true);
+        for path in ["usr/bin/sh", "./etc", ".", "/abs"] {
+            builder
+                .append_data(Metadata::regular(0o644, 0), path, b"")
+                .unwrap();
+        }
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut paths = Vec::new();
+        while let Some(entry) = archive.next_entry().unwrap() {
+            paths.push(entry.path.clone());
+        }
+        assert_eq!(
+            vec![
+                PathBuf::from("./usr/bin/sh"),
+                PathBuf::from("./etc"),
+                PathBuf::from("."),
+                PathBuf::from("/abs"),
+            ],
+            paths
+        );
+        let mut archive = Archive::new(&bytes[..]);
+        archive.strip_dot_slash(true);
+        let mut paths = Vec::new();
+        while let Some(entry) = archive.next_entry().unwrap() {
+            paths.push(entry.path.clone());
+        }
+        let mut archive = Archive::new(&bytes[..]);
+        archive.strip_dot_slash(true);
+        let mut names = Vec::new();
+        archive
+            .for_each_raw(|entry| {
+                names.push(PathBuf::from(OsStr::from_bytes(entry.name)));
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        let expected = vec![
+            PathBuf::from("usr/bin/sh"),
+            PathBuf::from("etc"),
+            PathBuf::from("."),
+            PathBuf::from("/abs"),
+        ];
+        assert_eq!(expected, paths);
+        assert_eq!(expected, names);
+    }
+
+    #[test]
+    fn path_mapper() {
+        let workdir = TempDir::new().unwrap();
+        create_dir_all(workdir.path().join("usr/bin")).unwrap();
+        std::fs::write(workdir.path().join("usr/bin/sh"), b"").unwrap();
+        let mut builder = Builder::new(Vec::new());
+        builder.set_path_mapper(|path| match path.to_str() {
+            Some("usr") => Some(PathBuf::new()),
+            Some("usr/bin/sh") => None,
+            _ => Some(Path::new("prefix").join(path)),
+        });
+        builder.append_dir_all(workdir.path()).unwrap();
+        builder
+            .append_path(workdir.path().join("usr"), "etc")
+            .unwrap();
+        // the empty path skips the entry regardless of the method
+        builder
+            .append_path(workdir.path().join("usr"), "usr")
+            .unwrap();
+        assert_eq!(
+            1,
+            builder
+                .append_spec(&b"dir usr 755 0 0\ndir var 755 0 0\n"[..])
+                .unwrap()
+        );
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut paths = Vec::new();
+        while let Some(entry) = archive.next_entry().unwrap() {
+            paths.push(entry.path.clone());
+        }
+        assert_eq!(
+            vec![
+                PathBuf::from("prefix/usr/bin"),
+                PathBuf::from("usr/bin/sh"),
+                PathBuf::from("prefix/etc"),
+                PathBuf::from("prefix/var"),
+            ],
+            paths
+        );
+    }
+
+    #[test]
+    fn max_archive_size() {
+        for format in [
+            Format::Newc,
+            Format::Crc,
+            Format::Odc,
+            Format::Bin(ByteOrder::LittleEndian),
+        ] {
+            let mut builder = Builder::new(Vec::new());
+            builder.set_format(format);
+            builder.append_dir("dir", 0o755).unwrap();
+            builder
+                .append_data(Metadata::regular(0o644, 0), "dir/file", b"hello")
+                .unwrap();
+            let size = builder.archive_size();
+            let bytes = builder.finish().unwrap();
+            let trailer_len = bytes.len() as u64 - size;
+            let mut builder = Builder::new(Vec::new());
+            builder.set_format(format);
+            builder.set_max_archive_size(bytes.len() as u64 - 1);
+            builder.append_dir("dir", 0o755).unwrap();
+            let error = builder
+                .append_data(Metadata::regular(0o644, 0), "dir/file", b"hello")
+                .unwrap_err();
+            assert_eq!(ErrorKind::FileTooLarge, error.kind());
+            let size = builder.archive_size();
+            let truncated = builder.finish().unwrap();
+            assert_eq!(size + trailer_len, truncated.len() as u64);
+            let mut archive = Archive::new(&truncated[..]);
+            assert!(archive.next_entry().unwrap().is_some());
+            assert!(archive.next_entry().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn append_special() {
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_special("dev/console", FileType::CharDevice, 0o600, 0x0501)
+            .unwrap();
+        builder
+            .append_special("dev/sda", FileType::BlockDevice, 0o660, 0x0800)
+            .unwrap();
+        builder
+            .append_special("fifo", FileType::Fifo, 0o644, 123)
+            .unwrap();
+        builder
+            .append_special("socket", FileType::Socket, 0o755, 0)
+            .unwrap();
+        assert!(builder
+            .append_special("file", FileType::Regular, 0o644, 0)
+            .is_err());
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut entries = Vec::new();
+        while let Some(entry) = archive.next_entry().unwrap() {
+            entries.push((
+                entry.path.clone(),
+                entry.metadata.file_type().unwrap(),
+                entry.metadata.file_mode(),
+                entry.metadata.rdev(),
+            ));
+        }
+        assert_eq!(
+            vec![
+                ("dev/console".into(), FileType::CharDevice, 0o600, 0x0501),
+                ("dev/sda".into(), FileType::BlockDevice, 0o660, 0x0800),
+                ("fifo".into(), FileType::Fifo, 0o644, 0),
+                ("socket".into(), FileType::Socket, 0o755, 0),
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn overflow_policy() {
+        let uid = ODC_MAX_ID + 10;
+        let mtime = BIN_MAX_MTIME + 10;
+        let dev = libc::makedev(300, 2) as u64;
+        for (policy, expected_uid, expected_mtime, expected_dev) in [
+            (
+                OverflowPolicy::Saturate,
+                ODC_MAX_ID,
+                BIN_MAX_MTIME,
+                libc::makedev(255, 2),
+            ),
+            (OverflowPolicy::Zero, 0, 0, libc::makedev(0, 2)),
+            (OverflowPolicy::Truncate, 9, 9, libc::makedev(44, 2)),
+        ] {
+            let mut builder = Builder::new(Vec::new());
+            builder.set_format(Format::Odc);
+            builder.set_overflow_policy(policy);
+            let mut metadata = Metadata::regular(0o644, 0);
+            metadata.set_uid(uid);
+            builder.append_data(metadata, "file", b"").unwrap();
+            let bytes = builder.finish().unwrap();
+            let mut archive = Archive::new(&bytes[..]);
+            let entry = archive.next_entry().unwrap().unwrap();
+            assert_eq!(expected_uid, entry.metadata.uid(), "{:?}", policy);
+            let mut builder = Builder::new(Vec::new());
+            builder.set_format(Format::Bin(ByteOrder::LittleEndian));
+            builder.set_overflow_policy(policy);
+            let mut metadata = Metadata::char_device(0o644, dev);
+            metadata.set_mtime(mtime);
+            builder.append_data(metadata, "dev", b"").unwrap();
+            let bytes = builder.finish().unwrap();
+            let mut archive = Archive::new(&bytes[..]);
+            let entry = archive.next_entry().unwrap().unwrap();
+            assert_eq!(expected_mtime, entry.metadata.mtime(), "{:?}", policy);
+            assert_eq!(expected_dev, entry.metadata.rdev(), "{:?}", policy);
+        }
+        let mut builder = Builder::new(Vec::new());
+        builder.set_format(Format::Odc);
+        let mut metadata = Metadata::regular(0o644, 0);
+        metadata.set_uid(uid);
+        assert_eq!(
+            ErrorKind::InvalidData,
+            builder
+                .append_data(metadata, "file", b"")
+                .unwrap_err()
+                .kind()
+        );
+    }
+
+    #[test]
+    fn preserve_dev_ids() {
+        let dev = libc::makedev(8, 1) as u64;
+        let mut metadata = Metadata::regular(0o644, 0);
+        metadata.set_dev(dev);
+        for format in [Format::Odc, Format::Bin(ByteOrder::LittleEndian)] {
+            let mut builder = Builder::new(Vec::new());
+            builder.set_format(format);
+            builder.append_data(metadata.clone(), "file", b"").unwrap();
+            assert_eq!(
+                vec![(dev, 0)],
+                builder.device_mappings().collect::<Vec<_>>()
+            );
+            let mut builder = Builder::new(Vec::new());
+            builder.set_format(format);
+            builder.preserve_dev_ids(true);
+            builder.append_data(metadata.clone(), "file", b"").unwrap();
+            assert_eq!(0, builder.device_mappings().count());
+            let mut large_dev = metadata.clone();
+            large_dev.set_dev(libc::makedev(3000, 1) as u64);
+            assert_eq!(
+                ErrorKind::InvalidData,
+                builder
+                    .append_data(large_dev, "large", b"")
+                    .unwrap_err()
+                    .kind()
+            );
+            let bytes = builder.finish().unwrap();
+            let mut archive = Archive::new(&bytes[..]);
+            let entry = archive.next_entry().unwrap().unwrap();
+            assert_eq!(dev, entry.metadata.dev(), "format = {:?}", format);
+        }
+    }
+
+    #[test]
+    fn sort_dir_entries() {
+        let workdir = TempDir::new().unwrap();
+        for path in ["b/z", "b/a/file", "a", "c/y/x"] {
+            let path = workdir.path().join(path);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        let mut builder = Builder::new(Vec::new());
+        builder.sort_dir_entries(true);
+        builder.append_dir_all(workdir.path()).unwrap();
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut paths = Vec::new();
+        while let Some(entry) = archive.next_entry().unwrap() {
+            paths.push(entry.path.clone());
+        }
+        let expected: Vec<PathBuf> = ["a", "b", "c", "b/a", "b/z", "c/y", "b/a/file", "c/y/x"]
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        assert_eq!(expected, paths);
+    }
+
+    #[test]
+    fn finish_segment() {
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_data(Metadata::regular(0o644, 0), "early", b"microcode")
+            .unwrap();
+        let offset = builder.finish_segment(512).unwrap();
+        assert_eq!(512, offset);
+        assert_eq!(offset, builder.get_ref().len() as u64);
+        let mut metadata = Metadata::regular(0o644, 0);
+        metadata.set_ino(1);
+        metadata.set_nlink(2);
+        builder
+            .append_data(metadata.clone(), "link1", b"hello")
+            .unwrap();
+        let offset = builder.finish_segment(0).unwrap();
+        assert_eq!(offset, builder.get_ref().len() as u64);
+        // the hard link must not refer to the previous segment
+        builder.append_data(metadata, "link2", b"hello").unwrap();
+        let bytes = builder.finish().unwrap();
+        assert!(bytes[..512].ends_with(&[0_u8; 4]));
+        let mut segments = Vec::new();
+        let mut start = 0;
+        while start < bytes.len() {
+            let mut archive = Archive::new(&bytes[start..]);
+            let mut entries = Vec::new();
+            while let Some(mut entry) = archive.next_entry().unwrap() {
+                let mut contents = Vec::new();
+                entry.reader.read_to_end(&mut contents).unwrap();
+                entries.push((entry.path.clone(), contents));
+            }
+            segments.push(entries);
+            let remaining = archive.into_inner();
+            start = bytes.len() - remaining.len();
+            start += bytes[start..].iter().take_while(|b| **b == 0).count();
+        }
+        assert_eq!(
+            vec![
+                vec![(PathBuf::from("early"), b"microcode".to_vec())],
+                vec![(PathBuf::from("link1"), b"hello".to_vec())],
+                vec![(PathBuf::from("link2"), b"hello".to_vec())],
+            ],
+            segments
+        );
+    }
+
+    #[test]
+    fn pad_to_block_size() {
+        for format in [
+            Format::Newc,
+            Format::Odc,
+            Format::Bin(ByteOrder::LittleEndian),
+        ] {
+            let mut builder = Builder::new(Vec::new());
+            builder.set_format(format);
+            builder.pad_to_block_size(512);
+            builder
+                .append_data(Metadata::regular(0o644, 0), "file", b"hello")
+                .unwrap();
+            let bytes = builder.finish().unwrap();
+            assert_eq!(512, bytes.len(), "{:?}", format);
+            let mut archive = Archive::new(&bytes[..]);
+            assert!(archive.next_entry().unwrap().is_some());
+            assert!(archive.next_entry().unwrap().is_none());
+            assert!(archive.into_inner().iter().all(|b| *b == 0));
+            // the padding counts towards the size limit
+            let mut builder = Builder::new(Vec::new());
+            builder.set_format(format);
+            builder.pad_to_block_size(512);
+            builder.set_max_archive_size(511);
+            assert_eq!(
+                ErrorKind::FileTooLarge,
+                builder
+                    .append_data(Metadata::regular(0o644, 0), "file", b"hello")
+                    .unwrap_err()
+                    .kind(),
+                "{:?}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn append_entries() {
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_entries([
+                (Metadata::regular(0o644, 5), "dir/b", &b"hello"[..]),
+                (Metadata::directory(0o755), "dir", &b""[..]),
+                (Metadata::regular(0o644, 5), "a", &b"world"[..]),
+            ])
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut paths = Vec::new();
+        let mut inodes = Vec::new();
+        while let Some(entry) = archive.next_entry().unwrap() {
+            paths.push(entry.path.clone());
+            inodes.push(entry.metadata.ino());
+        }
+        assert_eq!(
+            vec![PathBuf::from("a"), "dir".into(), "dir/b".into()],
+            paths
+        );
+        assert_eq!(vec![0, 1, 2], inodes);
+        let mut builder = Builder::new(Vec::new());
+        let error = builder
+            .append_entries([(Metadata::regular(0o644, 10), "short", &b"hello"[..])])
+            .unwrap_err();
+        assert!(error.to_string().contains("\"short\""), "{}", error);
+    }
+
+    #[test]
+    fn append_to() {
+        let workdir = TempDir::new().unwrap();
+        let path = workdir.path().join("archive.cpio");
+        for format in [Format::Newc, Format::Crc, Format::Odc] {
+            let mut builder = Builder::new(File::create(&path).unwrap());
+            builder.set_format(format);
+            builder.pad_to_block_size(512);
+            builder
+                .append_data(Metadata::regular(0o644, 0), "a", b"hello")
+                .unwrap();
+            builder.finish().unwrap();
+            let file = File::options().read(true).write(true).open(&path).unwrap();
+            let mut builder = Builder::append_to(file).unwrap();
+            builder
+                .append_data(Metadata::regular(0o644, 0), "b", b"world")
+                .unwrap();
+            builder.finish().unwrap();
+            let bytes = std::fs::read(&path).unwrap();
+            let mut archive = Archive::new(&bytes[..]);
+            let mut actual = Vec::new();
+            while let Some(mut entry) = archive.next_entry().unwrap() {
+                let mut contents = Vec::new();
+                entry.reader.read_to_end(&mut contents).unwrap();
+                actual.push((
+                    entry.path.clone(),
+                    entry.format,
+                    entry.metadata.ino(),
+                    contents,
+                ));
+            }
+            assert_eq!(
+                vec![
+                    ("a".into(), format, 0, b"hello".to_vec()),
+                    ("b".into(), format, 1, b"world".to_vec()),
+                ],
+                actual
+            );
+        }
+        // empty file
+        std::fs::write(&path, b"").unwrap();
+        let file = File::options().read(true).write(true).open(&path).unwrap();
+        Builder::append_to(file).unwrap().finish().unwrap();
+        let mut archive = Archive::new(File::open(&path).unwrap());
+        assert!(archive.next_entry().unwrap().is_none());
+        // no trailer
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_data(Metadata::regular(0o644, 0), "a", b"hello")
+            .unwrap();
+        std::fs::write(&path, builder.get_ref()).unwrap();
+        let file = File::options().read(true).write(true).open(&path).unwrap();
+        assert_eq!(
+            ErrorKind::UnexpectedEof,
+            Builder::append_to(file).err().unwrap().kind()
+        );
+    }
+
+    #[test]
+    fn gnu_compat() {
+        let workdir = TempDir::new().unwrap();
+        let input = workdir.path().join("input");
+        std::fs::create_dir(&input).unwrap();
+        std::fs::write(input.join("a"), b"hello").unwrap();
+        std::fs::hard_link(input.join("a"), input.join("b")).unwrap();
+        std::fs::hard_link(input.join("a"), input.join("c")).unwrap();
+        std::fs::write(input.join("d"), b"world").unwrap();
+        std::fs::hard_link(input.join("d"), input.join("e")).unwrap();
+        std::os::unix::fs::symlink("target", input.join("s")).unwrap();
+        let mut builder = Builder::new(Vec::new());
+        builder.gnu_compat(true);
+        for name in ["a", "d", "e", "b", "s"] {
+            builder.append_path(input.join(name), name).unwrap();
+        }
+        let bytes = builder.finish().unwrap();
+        assert_eq!(0, bytes.len() % 512);
+        let mut entries = Vec::new();
+        Archive::new(&bytes[..])
+            .for_each_raw(|entry| {
+                entries.push((
+                    String::from_utf8(entry.name.to_vec()).unwrap(),
+                    entry.metadata.size(),
+                    entry.metadata.ino(),
+                    entry.metadata.rdev(),
+                ));
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        let ino =
+            |name: &str| std::fs::symlink_metadata(input.join(name)).unwrap().ino() as u32 as u64;
+        assert_eq!(
+            vec![
+                ("d".into(), 0, ino("d"), 0),
+                ("e".into(), 5, ino("d"), 0),
+                ("s".into(), 6, ino("s"), 0),
+                ("b".into(), 0, ino("a"), 0),
+                ("a".into(), 5, ino("a"), 0),
+            ],
+            entries
+        );
+        let trailer_offset = bytes.len() - bytes.iter().rev().take_while(|b| **b == 0).count();
+        let trailer = &bytes[..trailer_offset];
+        let trailer = &trailer[trailer.len() - NEWC_HEADER_LEN - "TRAILER!!!".len()..];
+        assert_eq!(
+            &b"0707010000000000000000000000000000000000000001"[..],
+            &trailer[..46]
+        );
+        let output = workdir.path().join("output");
+        Archive::new(&bytes[..]).unpack(&output).unwrap();
+        for name in ["a", "b"] {
+            assert_eq!(b"hello", &std::fs::read(output.join(name)).unwrap()[..]);
+        }
+        for name in ["d", "e"] {
+            assert_eq!(b"world", &std::fs::read(output.join(name)).unwrap()[..]);
+        }
+        assert_eq!(
+            Path::new("target"),
+            std::fs::read_link(output.join("s")).unwrap()
+        );
+    }
+
+    #[test]
+    fn repeat_policy() {
+        let workdir = TempDir::new().unwrap();
+        let file = workdir.path().join("file");
+        std::fs::write(&file, b"hello").unwrap();
+        for policy in [
+            RepeatPolicy::Store,
+            RepeatPolicy::Skip,
+            RepeatPolicy::HardLink,
+        ] {
+            let mut builder = Builder::new(Vec::new());
+            builder.set_repeat_policy(policy);
+            builder.append_path(workdir.path(), "dir1").unwrap();
+            builder.append_path(&file, "dir1/file").unwrap();
+            builder.append_path(workdir.path(), "dir2").unwrap();
+            builder.append_path(&file, "dir2/file").unwrap();
+            let bytes = builder.finish().unwrap();
+            let mut entries = Vec::new();
+            Archive::new(&bytes[..])
+                .for_each_raw(|entry| {
+                    entries.push((
+                        String::from_utf8(entry.name.to_vec()).unwrap(),
+                        entry.metadata.size(),
+                    ));
+                    ControlFlow::Continue(())
+                })
+                .unwrap();
+            let entries: Vec<_> = entries
+                .iter()
+                .map(|(name, size)| (name.as_str(), *size))
+                .collect();
+            let expected = match policy {
+                RepeatPolicy::Store => {
+                    vec![("dir1", 0), ("dir1/file", 5), ("dir2", 0), ("dir2/file", 5)]
+                }
+                RepeatPolicy::Skip => vec![("dir1", 0), ("dir1/file", 5)],
+                RepeatPolicy::HardLink => {
+                    vec![("dir1", 0), ("dir1/file", 5), ("dir2", 0), ("dir2/file", 0)]
+                }
+            };
+            assert_eq!(expected, entries, "{:?}", policy);
+            let output = workdir.path().join(format!("{:?}", policy));
+            Archive::new(&bytes[..]).unpack(&output).unwrap();
+            let file1 = std::fs::metadata(output.join("dir1/file")).unwrap();
+            assert_eq!(
+                b"hello",
+                &std::fs::read(output.join("dir1/file")).unwrap()[..]
+            );
+            if policy != RepeatPolicy::Skip {
+                let file2 = std::fs::metadata(output.join("dir2/file")).unwrap();
+                assert_eq!(
+                    b"hello",
+                    &std::fs::read(output.join("dir2/file")).unwrap()[..]
+                );
+                assert_eq!(
+                    policy == RepeatPolicy::HardLink,
+                    file1.ino() == file2.ino(),
+                    "{:?}",
+                    policy
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn size_mismatch_policy() {
+        for format in [Format::Newc, Format::Crc, Format::Odc] {
+            for policy in [SizeMismatchPolicy::Error, SizeMismatchPolicy::PadOrTruncate] {
+                let mut builder = Builder::new(Vec::new());
+                builder.set_format(format);
+                builder.set_size_mismatch_policy(policy);
+                let short =
+                    builder.append_entry(Metadata::regular(0o644, 8), "short", &b"hello"[..]);
+                let long = builder.append_entry(Metadata::regular(0o644, 3), "long", &b"hello"[..]);
+                builder
+                    .append_data(Metadata::regular(0o644, 0), "good", b"hello")
+                    .unwrap();
+                match policy {
+                    SizeMismatchPolicy::Error => {
+                        assert_eq!(ErrorKind::InvalidData, short.unwrap_err().kind());
+                        assert_eq!(ErrorKind::InvalidData, long.unwrap_err().kind());
+                        assert!(builder.size_mismatches().is_empty());
+                    }
+                    SizeMismatchPolicy::PadOrTruncate => {
+                        short.unwrap();
+                        long.unwrap();
+                        assert_eq!(
+                            &[
+                                SizeMismatch {
+                                    path: "short".into(),
+                                    file_size: 8,
+                                    actual: 5
+                                },
+                                SizeMismatch {
+                                    path: "long".into(),
+                                    file_size: 3,
+                                    actual: 5
+                                },
+                            ],
+                            builder.size_mismatches()
+                        );
+                    }
+                }
+                let bytes = builder.finish().unwrap();
+                let mut archive = Archive::new(&bytes[..]);
+                archive.verify_crc(true);
+                let mut entries = Vec::new();
+                while let Some(mut entry) = archive.next_entry().unwrap() {
+                    let mut contents = Vec::new();
+                    entry.reader.read_to_end(&mut contents).unwrap();
+                    entries.push((entry.path.clone(), contents));
+                }
+                // in crc format the contents are read before the header is written
+                let written = format != Format::Crc || policy == SizeMismatchPolicy::PadOrTruncate;
+                let mut expected_entries = Vec::new();
+                if written {
+                    expected_entries.push((PathBuf::from("short"), b"hello\0\0\0".to_vec()));
+                    expected_entries.push((PathBuf::from("long"), b"hel".to_vec()));
+                }
+                expected_entries.push((PathBuf::from("good"), b"hello".to_vec()));
+                assert_eq!(expected_entries, entries, "{:?} {:?}", format, policy);
+            }
+        }
+    }
+
+    #[test]
+    fn trailer() {
+        let build = |trailer: Trailer| {
+            let mut builder = Builder::new(Vec::new());
+            builder.set_trailer(trailer);
+            builder
+                .append_data(Metadata::regular(0o644, 0), "file", b"hello")
+                .unwrap();
+            builder.finish().unwrap()
+        };
+        let standard = build(Trailer::Standard);
+        let entry_len = Format::Newc.entry_size(4, 5) as usize;
+        assert_eq!(
+            entry_len as u64 + Format::Newc.trailer_size(),
+            standard.len() as u64
+        );
+        let padded = build(Trailer::GnuPadded);
+        assert_eq!(512, padded.len());
+        assert_eq!(&standard[..], &padded[..standard.len()]);
+        assert!(padded[standard.len()..].iter().all(|b| *b == 0));
+        // the block size takes precedence
+        let mut builder = Builder::new(Vec::new());
+        builder.set_trailer(Trailer::GnuPadded);
+        builder.pad_to_block_size(1024);
+        builder
+            .append_data(Metadata::regular(0o644, 0), "file", b"hello")
+            .unwrap();
+        assert_eq!(1024, builder.finish().unwrap().len());
+        // the padding counts towards the size limit
+        let mut builder = Builder::new(Vec::new());
+        builder.set_trailer(Trailer::GnuPadded);
+        builder.set_max_archive_size(511);
+        assert_eq!(
+            ErrorKind::FileTooLarge,
+            builder
+                .append_data(Metadata::regular(0o644, 0), "file", b"hello")
+                .unwrap_err()
+                .kind()
+        );
+        let named = build(Trailer::Named(c"END".into()));
+        let mut names = Vec::new();
+        Archive::new(&named[..])
+            .for_each_raw(|entry| {
+                names.push(entry.name.to_vec());
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        assert_eq!(vec![b"file".to_vec(), b"END".to_vec()], names);
+        let custom = build(Trailer::Custom(Box::new(move |writer, archive_size| {
+            assert_eq!(entry_len as u64, archive_size);
+            writer.write_all(&[0_u8; 16])?;
+            Ok(16)
+        })));
+        assert_eq!(entry_len + 16, custom.len());
+        assert_eq!(&standard[..entry_len], &custom[..entry_len]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn sparse_files() {
+        let workdir = TempDir::new().unwrap();
+        let path = workdir.path().join("sparse");
+        let file_size = 1024 * 1024;
+        let file = File::create(&path).unwrap();
+        file.set_len(file_size).unwrap();
+        (&file).seek(SeekFrom::Start(file_size / 2)).unwrap();
+        (&file).write_all(b"data").unwrap();
+        drop(file);
+        let mut builder = Builder::new(Vec::new());
+        builder.detect_sparse_files(true);
+        builder.append_path(&path, "sparse").unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "dense", b"data")
+            .unwrap();
+        let sparse_files = builder.sparse_files().to_vec();
+        assert_eq!(1, sparse_files.len(), "{:?}", sparse_files);
+        assert_eq!(Path::new("sparse"), sparse_files[0].path);
+        assert_eq!(file_size, sparse_files[0].file_size);
+        assert!(sparse_files[0].data_size < file_size);
+        let bytes = builder.finish().unwrap();
+        let output = workdir.path().join("output");
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().sparse(true));
+        archive.unpack(&output).unwrap();
+        let expected = std::fs::read(&path).unwrap();
+        let actual = std::fs::read(output.join("sparse")).unwrap();
+        assert!(expected == actual);
+        let metadata = std::fs::metadata(output.join("sparse")).unwrap();
+        assert!(metadata.blocks() * 512 < file_size);
+        assert_eq!(b"data", &std::fs::read(output.join("dense")).unwrap()[..]);
+    }
+
+    #[test]
+    fn zero_copy() {
+        let workdir = TempDir::new().unwrap();
+        let input = workdir.path().join("input");
+        let contents = patterned_data(100_000);
+        std::fs::write(&input, &contents).unwrap();
+        for append in [false, true] {
+            for format in [
+                Format::Newc,
+                // the checksum is computed in a separate pass
+                Format::Crc,
+                Format::Odc,
+                Format::Bin(ByteOrder::LittleEndian),
+            ] {
+                let output = workdir.path().join("output.cpio");
+                let _ = std::fs::remove_file(&output);
+                let file = File::options()
+                    .write(true)
+                    .create_new(true)
+                    .append(append)
+                    .open(&output)
+                    .unwrap();
+                let mut builder = Builder::new(file);
+                builder.set_format(format);
+                builder.zero_copy(true);
+                builder.append_path(&input, "input").unwrap();
+                builder
+                    .append_data(Metadata::regular(0o644, 0), "small", b"hello")
+                    .unwrap();
+                builder.finish().unwrap();
+                let bytes = std::fs::read(&output).unwrap();
+                let mut archive = Archive::new(&bytes[..]);
+                archive.verify_crc(true);
+                let mut actual = Vec::new();
+                while let Some(mut entry) = archive.next_entry().unwrap() {
+                    let mut buf = Vec::new();
+                    entry.reader.read_to_end(&mut buf).unwrap();
+                    actual.push(buf);
+                }
+                assert_eq!(vec![contents.clone(), b"hello".to_vec()], actual);
+            }
+        }
+    }
+
+    #[test]
+    fn buffering() {
+        #[derive(Default)]
+        struct CountWrites {
+            bytes: Vec<u8>,
+            num_writes: usize,
+        }
+
+        impl Write for CountWrites {
+            fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+                self.num_writes += 1;
+                self.bytes.write(buf)
+            }
+
+            fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize, Error> {
+                self.num_writes += 1;
+                self.bytes.write_vectored(bufs)
+            }
+
+            fn flush(&mut self) -> Result<(), Error> {
+                Ok(())
+            }
+        }
+
+        for format in [Format::Newc, Format::Crc, Format::Odc] {
+            // odc format doesn't pad the contents
+            let unbuffered_writes = match format {
+                Format::Odc => 2,
+                // the header, the contents and the padding are written in one vectored call
+                Format::Crc => 1,
+                _ => 3,
+            };
+            for (buffer_size, writes_per_entry) in [(8192, 1), (0, unbuffered_writes)] {
+                let mut builder = Builder::new(CountWrites::default());
+                builder.set_format(format);
+                builder.set_buffer_size(buffer_size);
+                for i in 0..10 {
+                    builder
+                        .append_data(Metadata::regular(0o644, 0), format!("file-{}", i), b"hello")
+                        .unwrap();
+                    assert_eq!(
+                        (i + 1) * writes_per_entry,
+                        builder.get_ref().num_writes,
+                        "{:?}",
+                        format
+                    );
+                }
+                let writer = builder.finish().unwrap();
+                let mut archive = Archive::new(&writer.bytes[..]);
+                let mut num_entries = 0;
+                while let Some(mut entry) = archive.next_entry().unwrap() {
+                    let mut contents = Vec::new();
+                    entry.reader.read_to_end(&mut contents).unwrap();
+                    assert_eq!(b"hello", &contents[..]);
+                    num_entries += 1;
+                }
+                assert_eq!(10, num_entries);
+            }
+        }
+    }
+
+    #[test]
+    fn fix_nlink() {
+        for format in [
+            Format::Newc,
+            Format::Crc,
+            Format::Odc,
+            Format::Bin(ByteOrder::LittleEndian),
+            Format::Bin(ByteOrder::BigEndian),
+        ] {
+            let mut builder = Builder::new(std::io::Cursor::new(Vec::new()));
+            builder.set_format(format);
+            builder.track_nlink(true);
+            let mut metadata = Metadata::regular(0o644, 0);
+            metadata.set_ino(1);
+            metadata.set_nlink(3);
+            builder
+                .append_data(metadata.clone(), "link1", b"hello")
+                .unwrap();
+            builder.append_data(metadata, "link2", b"hello").unwrap();
+            let mut metadata = Metadata::regular(0o644, 0);
+            metadata.set_ino(2);
+            metadata.set_nlink(2);
+            builder.append_data(metadata.clone(), "ok1", b"").unwrap();
+            builder.append_data(metadata, "ok2", b"").unwrap();
+            builder.append_dir("dir", 0o755).unwrap();
+            assert_eq!(
+                vec![NlinkMismatch {
+                    path: "link1".into(),
+                    nlink: 3,
+                    actual: 2
+                }],
+                builder.nlink_mismatches()
+            );
+            builder.fix_nlink().unwrap();
+            assert!(builder.nlink_mismatches().is_empty());
+            let bytes = builder.finish().unwrap().into_inner();
+            let mut archive = Archive::new(&bytes[..]);
+            let mut entries = Vec::new();
+            while let Some(mut entry) = archive.next_entry().unwrap() {
+                let mut contents = Vec::new();
+                entry.reader.read_to_end(&mut contents).unwrap();
+                entries.push((entry.path.clone(), entry.metadata.nlink()));
+            }
+            assert_eq!(
+                vec![
+                    (PathBuf::from("link1"), 2),
+                    (PathBuf::from("link2"), 2),
+                    (PathBuf::from("ok1"), 2),
+                    (PathBuf::from("ok2"), 2),
+                    (PathBuf::from("dir"), 1),
+                ],
+                entries,
+                "format = {:?}",
+                format
+            );
+        }
+    }
+}
@@ -1,6 +1,12 @@
 use std::ffi::CStr;
 
 pub const NEWC_HEADER_LEN: usize = 6 + 13 * 8;
+pub const ODC_HEADER_LEN: usize = 6 + 8 * 6 + 2 * 11;
+pub const BIN_HEADER_LEN: usize = 13 * 2;
+// The offsets of the number of links in the headers.
+pub const NEWC_NLINK_OFFSET: u64 = 6 + 8 * 4;
+pub const ODC_NLINK_OFFSET: u64 = 6 + 6 * 5;
+pub const BIN_NLINK_OFFSET: u64 = 2 + 2 * 5;
 pub const NEWC_ALIGN: usize = 4;
 pub const BIN_ALIGN: usize = 2;
 pub const PADDING: [u8; NEWC_ALIGN] = [0_u8; NEWC_ALIGN];
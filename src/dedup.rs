@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::hash::DefaultHasher;
+use std::hash::Hasher;
+use std::io::Error;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Regular entries with identical contents.
+///
+/// Returned by [`Archive::dedup_report`](crate::Archive::dedup_report).
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct DuplicateGroup {
+    /// The size of each file in bytes.
+    pub size: u64,
+    /// The paths of the entries in the archive in the order they were read.
+    pub paths: Vec<PathBuf>,
+}
+
+impl DuplicateGroup {
+    /// The number of bytes that would be saved if the files were stored as hard links.
+    pub fn savings(&self) -> u64 {
+        self.size
+            .saturating_mul(self.paths.len().saturating_sub(1) as u64)
+    }
+}
+
+/// The regular entries that would be stored as hard links to each other
+/// by [`Builder::dedup_contents`](crate::Builder::dedup_contents).
+///
+/// The entries are grouped by the hash of their contents, hence all the numbers are estimates.
+///
+/// Returned by [`Archive::dedup_report`](crate::Archive::dedup_report).
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct DedupReport {
+    /// The groups of two or more entries with identical contents
+    /// sorted by the savings in descending order.
+    pub groups: Vec<DuplicateGroup>,
+}
+
+impl DedupReport {
+    /// The estimated total number of bytes that would be saved.
+    ///
+    /// This is the size of the contents only; the headers and the padding are not included.
+    pub fn savings(&self) -> u64 {
+        self.groups
+            .iter()
+            .fold(0_u64, |sum, group| sum.saturating_add(group.savings()))
+    }
+
+    /// The estimated number of entries that would be stored as hard links.
+    pub fn num_duplicates(&self) -> usize {
+        self.groups.iter().map(|group| group.paths.len() - 1).sum()
+    }
+}
+
+/// Groups the entries by their contents.
+#[derive(Default)]
+pub(crate) struct DuplicateFinder {
+    // (file size, contents hash) -> paths mapping.
+    groups: HashMap<(u64, u64), Vec<PathBuf>>,
+}
+
+impl DuplicateFinder {
+    pub(crate) fn add(&mut self, path: PathBuf, size: u64, hash: u64) {
+        self.groups.entry((size, hash)).or_default().push(path);
+    }
+
+    pub(crate) fn finish(self) -> DedupReport {
+        let mut groups: Vec<_> = self
+            .groups
+            .into_iter()
+            .filter(|(_, paths)| paths.len() > 1)
+            .map(|((size, _), paths)| DuplicateGroup { size, paths })
+            .collect();
+        groups.sort_unstable_by(|a, b| {
+            b.savings()
+                .cmp(&a.savings())
+                .then_with(|| a.paths.cmp(&b.paths))
+        });
+        DedupReport { groups }
+    }
+}
+
+/// Hashes the written bytes.
+///
+/// Used both by the dedup report and by the builder to find the candidates for deduplication.
+#[derive(Default)]
+pub(crate) struct HashWriter(DefaultHasher);
+
+impl HashWriter {
+    pub(crate) fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+}
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Archive;
+    use crate::Builder;
+    use crate::Metadata;
+    use crate::NlinkPolicy;
+
+    #[test]
+    fn dedup_report() {
+        let mut builder = Builder::new(Vec::new());
+        let mut link = Metadata::regular(0o644, 5);
+        link.nlink = 2;
+        link.ino = 100;
+        for (metadata, path, contents) in [
+            (Metadata::regular(0o644, 5), "a", &b"hello"[..]),
+            (Metadata::regular(0o644, 5), "b", &b"hello"[..]),
+            (Metadata::regular(0o644, 5), "c", &b"world"[..]),
+            (Metadata::regular(0o644, 0), "empty-1", &b""[..]),
+            (Metadata::regular(0o644, 0), "empty-2", &b""[..]),
+            (Metadata::regular(0o644, 3), "d", &b"foo"[..]),
+            (link.clone(), "e", &b"hello"[..]),
+            (link, "f", &b"hello"[..]),
+            (Metadata::regular(0o644, 3), "g", &b"foo"[..]),
+            (Metadata::regular(0o644, 3), "h", &b"foo"[..]),
+        ] {
+            builder.append_entry(metadata, path, contents).unwrap();
+        }
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_nlink_policy(NlinkPolicy::TrustNlink);
+        let report = archive.dedup_report().unwrap();
+        assert_eq!(
+            vec![
+                DuplicateGroup {
+                    size: 5,
+                    paths: vec!["a".into(), "b".into(), "e".into()],
+                },
+                DuplicateGroup {
+                    size: 3,
+                    paths: vec!["d".into(), "g".into(), "h".into()],
+                },
+            ],
+            report.groups
+        );
+        assert_eq!(16, report.savings());
+        assert_eq!(4, report.num_duplicates());
+    }
+}
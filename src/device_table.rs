@@ -0,0 +1,251 @@
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::io::BufRead;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::append_error;
+use crate::makedev;
+use crate::spec::parse_number;
+use crate::Builder;
+use crate::EditMetadata;
+use crate::FileType;
+use crate::Metadata;
+
+impl<W: Write, E: EditMetadata> Builder<W, E> {
+    /// Append the entries described by the device table read from `reader`.
+    ///
+    /// This is the format of `makedevs` tool from Buildroot:
+    /// each line consists of whitespace-separated fields
+    /// `<path> <type> <mode> <uid> <gid> <major> <minor> <start> <inc> <count>`, e.g.
+    /// `/dev/ttyS c 666 0 0 4 64 0 1 4`.
+    /// The type is `d` (directory), `c` (character device), `b` (block device) or `p` (FIFO).
+    /// Unlike `makedevs` that changes the mode and the owner of the existing paths,
+    /// each line appends a new entry: an existing directory is overridden on unpacking
+    /// by the directory entry that comes later in the archive.
+    /// The type `f` is rejected because it only changes the metadata of the existing file,
+    /// and the contents of the file are not known here.
+    /// The mode is octal, the other fields are decimal, and `-` stands for zero.
+    /// Non-zero `count` produces `count` entries which paths are suffixed
+    /// with the numbers from `start` onwards and which minor numbers are incremented by `inc`,
+    /// e.g. the above line produces `dev/ttyS0`–`dev/ttyS3` with minor numbers 64–67.
+    /// The trailing fields may be omitted.
+    /// Empty lines and the lines starting with `#` are ignored.
+    /// The leading `/` is removed from the paths.
+    ///
+    /// No files on disk are accessed, i.e. device nodes can be added without root privileges.
+    /// Returns the number of appended entries.
+    pub fn append_device_table<R: BufRead>(&mut self, reader: R) -> Result<u64, Error> {
+        let mut num_entries = 0;
+        for (i, line) in reader.split(b'\n').enumerate() {
+            let line = line?;
+            let invalid_line = |e: &str| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("device table line {}: {}", i + 1, e),
+                )
+            };
+            let Some(spec) = DeviceSpec::parse(&line).map_err(invalid_line)? else {
+                continue;
+            };
+            for j in 0..spec.count.max(1) {
+                let mut path = spec.path.to_vec();
+                let mut metadata = spec.metadata.clone();
+                if spec.count != 0 {
+                    let suffix = spec
+                        .start
+                        .checked_add(j)
+                        .ok_or_else(|| invalid_line("start is too large"))?;
+                    path.extend_from_slice(suffix.to_string().as_bytes());
+                }
+                if metadata.is_block_device() || metadata.is_char_device() {
+                    let minor = j
+                        .checked_mul(spec.inc)
+                        .and_then(|offset| offset.checked_add(spec.minor))
+                        .and_then(|minor| u32::try_from(minor).ok())
+                        .ok_or_else(|| invalid_line("minor number is too large"))?;
+                    metadata.rdev = makedev(spec.major.into(), minor.into());
+                }
+                let path = Path::new(OsStr::from_bytes(&path));
+                let Some(inner_path) = self.map_path(path).map(Cow::into_owned) else {
+                    continue;
+                };
+                self.append_entry(metadata, &inner_path, std::io::empty())
+                    .map_err(|e| append_error(path, e))?;
+                num_entries += 1;
+            }
+        }
+        Ok(num_entries)
+    }
+}
+
+/// Parsed line of the device table.
+struct DeviceSpec<'a> {
+    path: &'a [u8],
+    metadata: Metadata,
+    major: u32,
+    minor: u64,
+    start: u64,
+    inc: u64,
+    count: u64,
+}
+
+impl<'a> DeviceSpec<'a> {
+    /// Returns `None` for empty lines and comments.
+    fn parse(line: &'a [u8]) -> Result<Option<Self>, &'static str> {
+        let mut fields = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|field| !field.is_empty());
+        let Some(path) = fields.next() else {
+            return Ok(None);
+        };
+        if path.starts_with(b"#") {
+            return Ok(None);
+        }
+        let path = path.strip_prefix(b"/").unwrap_or(path);
+        if path.is_empty() {
+            return Err("empty path");
+        }
+        let file_type = match fields.next() {
+            Some(b"f") => return Err("regular files are not supported"),
+            Some(b"d") => FileType::Directory,
+            Some(b"c") => FileType::CharDevice,
+            Some(b"b") => FileType::BlockDevice,
+            Some(b"p") => FileType::Fifo,
+            Some(..) => return Err("unsupported file type"),
+            None => return Err("missing file type"),
+        };
+        let mode = parse_field(fields.next().ok_or("missing mode")?, 8).ok_or("invalid mode")?;
+        if mode > 0o7777 {
+            return Err("invalid mode");
+        }
+        let mut metadata = Metadata::new(file_type, mode as u32);
+        let mut numbers = [0_u64; 7];
+        for (number, name) in numbers.iter_mut().zip([
+            "invalid uid",
+            "invalid gid",
+            "invalid major number",
+            "invalid minor number",
+            "invalid start",
+            "invalid inc",
+            "invalid count",
+        ]) {
+            if let Some(field) = fields.next() {
+                *number = parse_field(field, 10).ok_or(name)?;
+            }
+        }
+        if fields.next().is_some() {
+            return Err("too many fields");
+        }
+        let [uid, gid, major, minor, start, inc, count] = numbers;
+        metadata.set_uid(uid.try_into().map_err(|_| "invalid uid")?);
+        metadata.set_gid(gid.try_into().map_err(|_| "invalid gid")?);
+        Ok(Some(Self {
+            path,
+            metadata,
+            major: major.try_into().map_err(|_| "invalid major number")?,
+            minor,
+            start,
+            inc,
+            count,
+        }))
+    }
+}
+
+/// Parse the number in the specified radix; `-` is parsed as zero.
+fn parse_field(field: &[u8], radix: u32) -> Option<u64> {
+    if field == b"-" {
+        return Some(0);
+    }
+    parse_number(field, radix)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Archive;
+    use crate::Builder;
+    use crate::FileType;
+
+    #[test]
+    fn append_device_table() {
+        let table = b"# <name> <type> <mode> <uid> <gid> <major> <minor> <start> <inc> <count>
+/dev d 755 0 0 - - - - -
+/dev/console c 600 0 5 5 1 - - -
+
+/dev/ttyS c 666 0 0 4 64 0 1 3
+/dev/sda b 640 0 6 8 0
+/dev/initctl p 600 0 0
+";
+        let mut builder = Builder::new(Vec::new());
+        assert_eq!(7, builder.append_device_table(&table[..]).unwrap());
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut entries = Vec::new();
+        while let Some(entry) = archive.next_entry().unwrap() {
+            let metadata = &entry.metadata;
+            entries.push((
+                entry.path.to_str().unwrap().to_string(),
+                metadata.file_type().unwrap(),
+                metadata.file_mode(),
+                metadata.gid(),
+                metadata.rdev(),
+            ));
+        }
+        let makedev = |major, minor| libc::makedev(major, minor) as u64;
+        assert_eq!(
+            vec![
+                ("dev".into(), FileType::Directory, 0o755, 0, 0),
+                (
+                    "dev/console".into(),
+                    FileType::CharDevice,
+                    0o600,
+                    5,
+                    makedev(5, 1)
+                ),
+                (
+                    "dev/ttyS0".into(),
+                    FileType::CharDevice,
+                    0o666,
+                    0,
+                    makedev(4, 64)
+                ),
+                (
+                    "dev/ttyS1".into(),
+                    FileType::CharDevice,
+                    0o666,
+                    0,
+                    makedev(4, 65)
+                ),
+                (
+                    "dev/ttyS2".into(),
+                    FileType::CharDevice,
+                    0o666,
+                    0,
+                    makedev(4, 66)
+                ),
+                (
+                    "dev/sda".into(),
+                    FileType::BlockDevice,
+                    0o640,
+                    6,
+                    makedev(8, 0)
+                ),
+                ("dev/initctl".into(), FileType::Fifo, 0o600, 0, 0),
+            ],
+            entries
+        );
+        for table in [
+            &b"/dev/null x 666 0 0 1 3"[..],
+            b"/etc/passwd f 644 1 2",
+            b"/dev/null c 999 0 0 1 3",
+            b"/dev/null c",
+            b"/dev/null c 666 0 0 1 3 - - - -",
+        ] {
+            let mut builder = Builder::new(Vec::new());
+            assert!(builder.append_device_table(table).is_err());
+        }
+    }
+}
@@ -0,0 +1,402 @@
+//! Command-line interface driver.
+//!
+//! The functions in this module implement the `kpea` command's modes of operation.
+//! Use them to embed the exact command-line behavior into other programs
+//! without spawning a subprocess.
+
+use std::ffi::OsString;
+use std::io::BufRead;
+use std::io::Error;
+use std::io::Read;
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::AbsolutePathPolicy;
+use crate::Archive;
+use crate::Builder;
+use crate::Change;
+use crate::Format;
+use crate::UnpackOptions;
+
+/// Archive creation options.
+///
+/// Used by [`copy_out`].
+#[derive(Clone, Debug)]
+pub struct CopyOutOptions {
+    /// CPIO format.
+    ///
+    /// [`Format::Newc`] by default.
+    pub format: Format,
+    /// Archive the files that symbolic links point to instead of the links themselves.
+    pub dereference: bool,
+    /// Paths are delimited by NUL character instead of the newline.
+    pub null_terminated: bool,
+    /// Fail if the created archive would be larger than the specified size in bytes.
+    pub max_size: Option<u64>,
+    /// Block size in bytes.
+    ///
+    /// The archive is padded to a multiple of this size (see [`Builder::pad_to_block_size`]).
+    ///
+    /// [`DEFAULT_BLOCK_SIZE`] by default.
+    pub block_size: usize,
+    /// Produce the same output as GNU cpio (see [`Builder::gnu_compat`]).
+    pub gnu_compat: bool,
+    /// Each path may be followed by TAB character and space-separated metadata overrides,
+    /// e.g. `etc/shadow\tmode=600 uid=0 gid=0`
+    /// (see [`Builder::append_path_records`] for the syntax).
+    pub metadata_overrides: bool,
+}
+
+impl Default for CopyOutOptions {
+    fn default() -> Self {
+        Self {
+            format: Format::Newc,
+            dereference: false,
+            null_terminated: false,
+            max_size: None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            gnu_compat: false,
+            metadata_overrides: false,
+        }
+    }
+}
+
+/// Archive extraction options.
+///
+/// Used by [`copy_in`].
+#[derive(Clone, Debug)]
+pub struct CopyInOptions {
+    /// Preserve file modification time.
+    pub preserve_mtime: bool,
+    /// Preserve file's user and group IDs.
+    ///
+    /// `None` means preserve them only when running as root (see [`UnpackOptions::preserve_owner`]).
+    pub preserve_owner: Option<bool>,
+    /// Restrict file system modifications to the target directory (Linux only).
+    ///
+    /// See [`UnpackOptions::sandbox`].
+    pub sandbox: bool,
+    /// How to extract the files with absolute paths.
+    pub absolute_paths: AbsolutePathPolicy,
+    /// Extract only the entries that match at least one of the patterns.
+    ///
+    /// See [`UnpackOptions::include_patterns`].
+    pub include_patterns: Vec<OsString>,
+    /// Don't extract the entries that match any of the patterns.
+    ///
+    /// See [`UnpackOptions::exclude_patterns`].
+    pub exclude_patterns: Vec<OsString>,
+    /// Verify files' checksum without unpacking them.
+    pub only_verify_crc: bool,
+    /// Block size in bytes that is used to count the blocks read.
+    ///
+    /// [`DEFAULT_BLOCK_SIZE`] by default.
+    pub block_size: usize,
+}
+
+impl Default for CopyInOptions {
+    fn default() -> Self {
+        Self {
+            preserve_mtime: false,
+            preserve_owner: None,
+            sandbox: false,
+            absolute_paths: Default::default(),
+            include_patterns: Vec::new(),
+            exclude_patterns: Vec::new(),
+            only_verify_crc: false,
+            block_size: DEFAULT_BLOCK_SIZE,
+        }
+    }
+}
+
+/// The block size of GNU cpio.
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+
+/// Create an archive from the file paths read from `paths` and write it to `writer`.
+///
+/// Each path is used both as the file system path and the path inside the archive
+/// (see [`CopyOutOptions::metadata_overrides`] for the extended syntax).
+/// Reading stops at the first empty path or at the end of the input.
+/// Returns the number of blocks written.
+pub fn copy_out<R: BufRead, W: Write>(
+    mut paths: R,
+    writer: W,
+    options: &CopyOutOptions,
+) -> Result<u64, Error> {
+    let mut writer = Counter::new(writer);
+    let mut builder = Builder::new(&mut writer);
+    builder.set_format(options.format);
+    builder.pad_to_block_size(options.block_size);
+    builder.follow_symlinks(options.dereference);
+    builder.gnu_compat(options.gnu_compat);
+    if let Some(max_size) = options.max_size {
+        builder.set_max_archive_size(max_size);
+    }
+    let delimiter = if options.null_terminated { 0_u8 } else { b'\n' };
+    loop {
+        let mut line = Vec::new();
+        paths.read_until(delimiter, &mut line)?;
+        if let Some(ch) = line.last() {
+            if *ch == delimiter {
+                line.pop();
+            }
+        }
+        if line.is_empty() {
+            break;
+        }
+        if options.metadata_overrides {
+            builder.append_path_record(&line)?;
+            continue;
+        }
+        let line = OsString::from_vec(line);
+        let path: PathBuf = line.into();
+        builder
+            .append_path(&path, &path)
+            .map_err(|e| Error::other(format!("failed to process {:?}: {}", path, e)))?;
+    }
+    builder.finish()?;
+    Ok(writer.num_blocks(options.block_size))
+}
+
+/// Extract the archive read from `reader` to the target `directory`.
+///
+/// When [`CopyInOptions::only_verify_crc`] is set, the entries are read and verified
+/// but nothing is extracted.
+/// Returns the number of blocks read.
+pub fn copy_in<R: Read, P: AsRef<Path>>(
+    reader: R,
+    directory: P,
+    options: &CopyInOptions,
+) -> Result<u64, Error> {
+    let mut reader = Counter::new(reader);
+    let mut archive = Archive::new(&mut reader);
+    if options.only_verify_crc {
+        archive.verify_crc(true);
+        while archive.next_entry()?.is_some() {}
+    } else {
+        archive.set_unpack_options(unpack_options(options));
+        archive.unpack(directory)?;
+    }
+    Ok(reader.num_blocks(options.block_size))
+}
+
+/// Write the changes that extracting the archive read from `reader`
+/// to the target `directory` would make to `writer`, one change per line.
+///
+/// The file system is not modified.
+/// Each line consists of the action (`create`, `overwrite`, `unchanged`, `replace`,
+/// `link`, `exists` or `skip`) and the path of the entry in the archive.
+/// The links are followed by ` => ` and the path of the original file,
+/// and the skipped entries are followed by the reason in parentheses
+/// (see [`SkipReason::as_str`](crate::SkipReason::as_str)), e.g. `skip usr (existing-dir)`.
+/// Returns the number of blocks read.
+pub fn plan_copy_in<R: Read, P: AsRef<Path>, W: Write>(
+    reader: R,
+    directory: P,
+    mut writer: W,
+    options: &CopyInOptions,
+) -> Result<u64, Error> {
+    let mut reader = Counter::new(reader);
+    let mut archive = Archive::new(&mut reader);
+    let changes = archive.plan_unpack(directory, &unpack_options(options))?;
+    for change in changes.iter() {
+        let action = match change.change {
+            Change::Create => "create",
+            Change::Overwrite(..) => "overwrite",
+            Change::Unchanged => "unchanged",
+            Change::Replace => "replace",
+            Change::Link(..) => "link",
+            Change::AlreadyExists => "exists",
+            Change::Skip(..) => "skip",
+        };
+        write!(writer, "{} ", action)?;
+        writer.write_all(change.entry_path.as_os_str().as_bytes())?;
+        match change.change {
+            Change::Link(ref original) => {
+                writer.write_all(b" => ")?;
+                writer.write_all(original.as_os_str().as_bytes())?;
+            }
+            Change::Skip(reason) => write!(writer, " ({})", reason.as_str())?,
+            _ => {}
+        }
+        writer.write_all(b"\n")?;
+    }
+    Ok(reader.num_blocks(options.block_size))
+}
+
+fn unpack_options(options: &CopyInOptions) -> UnpackOptions {
+    let unpack_options = UnpackOptions::new()
+        .sandbox(options.sandbox)
+        .absolute_paths(options.absolute_paths)
+        .preserve_mtime(options.preserve_mtime)
+        .include_patterns(options.include_patterns.iter())
+        .exclude_patterns(options.exclude_patterns.iter());
+    match options.preserve_owner {
+        Some(value) => unpack_options.preserve_owner(value),
+        None => unpack_options,
+    }
+}
+
+/// Write the paths of the entries of the archive read from `reader` to `writer`,
+/// one path per line.
+///
+/// Returns the number of blocks of `block_size` bytes read.
+pub fn list_contents<R: Read, W: Write>(
+    reader: R,
+    mut writer: W,
+    block_size: usize,
+) -> Result<u64, Error> {
+    let mut reader = Counter::new(reader);
+    let mut archive = Archive::new(&mut reader);
+    let mut result = Ok(());
+    archive.for_each_raw(|entry| {
+        result = writer
+            .write_all(entry.name)
+            .and_then(|_| writer.write_all(b"\n"));
+        match result {
+            Ok(()) => ControlFlow::Continue(()),
+            Err(_) => ControlFlow::Break(()),
+        }
+    })?;
+    result?;
+    Ok(reader.num_blocks(block_size))
+}
+
+/// Counts the bytes read or written.
+struct Counter<T> {
+    inner: T,
+    num_bytes: u64,
+}
+
+impl<T> Counter<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            num_bytes: 0,
+        }
+    }
+
+    /// The number of blocks rounded up.
+    fn num_blocks(&self, block_size: usize) -> u64 {
+        self.num_bytes.div_ceil((block_size as u64).max(1))
+    }
+}
+
+impl<R: Read> Read for Counter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = self.inner.read(buf)?;
+        self.num_bytes += n as u64;
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let n = self.inner.write(buf)?;
+        self.num_bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn copy_out_list_copy_in() {
+        let workdir = TempDir::new().unwrap();
+        let input = workdir.path().join("input");
+        std::fs::create_dir(&input).unwrap();
+        std::fs::write(input.join("file"), b"hello").unwrap();
+        let paths = format!("{0}\0{0}/file\0", input.display());
+        let mut cpio = Vec::new();
+        let num_blocks = copy_out(
+            paths.as_bytes(),
+            &mut cpio,
+            &CopyOutOptions {
+                null_terminated: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(1, num_blocks);
+        assert_eq!(DEFAULT_BLOCK_SIZE, cpio.len());
+        let mut listing = Vec::new();
+        assert_eq!(
+            1,
+            list_contents(&cpio[..], &mut listing, DEFAULT_BLOCK_SIZE).unwrap()
+        );
+        assert_eq!(
+            format!("{0}\n{0}/file\n", input.display()).as_bytes(),
+            &listing[..]
+        );
+        let output = workdir.path().join("output");
+        let options = CopyInOptions {
+            block_size: 100,
+            ..Default::default()
+        };
+        // the padding after the trailer is not read
+        let name_len = input.as_os_str().len();
+        let archive_size = Format::Newc.entry_size(name_len, 0)
+            + Format::Newc.entry_size(name_len + "/file".len(), 5)
+            + Format::Newc.trailer_size();
+        let num_blocks = copy_in(&cpio[..], &output, &options).unwrap();
+        assert_eq!(archive_size.div_ceil(100), num_blocks);
+        let relative = input.strip_prefix("/").unwrap();
+        assert_eq!(
+            b"hello",
+            &std::fs::read(output.join(relative).join("file")).unwrap()[..]
+        );
+        let mut plan = Vec::new();
+        plan_copy_in(&cpio[..], &output, &mut plan, &options).unwrap();
+        assert_eq!(
+            format!(
+                "skip {0} (existing-dir)\nunchanged {0}/file\n",
+                input.display()
+            )
+            .as_bytes(),
+            &plan[..]
+        );
+    }
+
+    #[test]
+    fn copy_out_metadata_overrides() {
+        let workdir = TempDir::new().unwrap();
+        let file = workdir.path().join("file");
+        std::fs::write(&file, b"hello").unwrap();
+        let paths = format!("{0}\tmode=600 uid=1 gid=2 mtime=3\n{0}\n", file.display());
+        let mut cpio = Vec::new();
+        let options = CopyOutOptions {
+            metadata_overrides: true,
+            ..Default::default()
+        };
+        copy_out(paths.as_bytes(), &mut cpio, &options).unwrap();
+        let mut archive = Archive::new(&cpio[..]);
+        let mut entries = Vec::new();
+        while let Some(entry) = archive.next_entry().unwrap() {
+            entries.push((entry.path.clone(), entry.metadata.clone()));
+        }
+        assert_eq!(2, entries.len());
+        assert!(entries.iter().all(|(path, _)| path == &file));
+        let metadata = &entries[0].1;
+        assert_eq!(0o600, metadata.file_mode());
+        assert_eq!(
+            (1, 2, 3),
+            (metadata.uid(), metadata.gid(), metadata.mtime())
+        );
+        assert_ne!(3, entries[1].1.mtime());
+        // invalid overrides
+        let paths = format!("{}\tmode=abc\n", file.display());
+        assert!(copy_out(paths.as_bytes(), Vec::new(), &options).is_err());
+    }
+}
@@ -45,6 +45,14 @@ impl FileType {
             _ => Err(ErrorKind::InvalidData.into()),
         }
     }
+
+    /// Returns `true` for block and character devices, FIFOs and sockets.
+    pub fn is_special(self) -> bool {
+        matches!(
+            self,
+            Self::BlockDevice | Self::CharDevice | Self::Fifo | Self::Socket
+        )
+    }
 }
 
 impl TryFrom<u32> for FileType {
@@ -57,3 +65,7 @@ impl TryFrom<u32> for FileType {
 pub(crate) fn mode_to_file_type(mode: u32) -> u8 {
     ((mode & FILE_TYPE_MASK) >> 12) as u8
 }
+
+pub(crate) fn file_type_to_mode(file_type: FileType) -> u32 {
+    (file_type as u32) << 12
+}
@@ -2,6 +2,7 @@ use std::ffi::CStr;
 use std::ffi::OsStr;
 use std::io::Error;
 use std::io::ErrorKind;
+use std::io::IoSlice;
 use std::io::Read;
 use std::io::Write;
 use std::os::unix::ffi::OsStrExt;
@@ -11,6 +12,7 @@ use std::str::from_utf8;
 
 use crate::constants::*;
 use crate::Format;
+use crate::Padding;
 
 pub fn write_path<W: Write, P: AsRef<Path>>(
     mut writer: W,
@@ -25,13 +27,34 @@ pub fn write_path<W: Write, P: AsRef<Path>>(
     Ok(())
 }
 
-pub fn read_path_buf<R: Read>(mut reader: R, len: usize, format: Format) -> Result<PathBuf, Error> {
-    let mut buf = vec![0_u8; len];
+pub fn read_path_buf<R: Read>(
+    reader: R,
+    len: usize,
+    format: Format,
+) -> Result<(PathBuf, Padding), Error> {
+    let mut buf = Vec::new();
+    let padding = read_path_bytes(reader, len, format, &mut buf)?;
+    let os_str = OsStr::from_bytes(&buf);
+    Ok((os_str.into(), padding))
+}
+
+/// Read the path into the supplied buffer without the terminating NUL byte.
+pub fn read_path_bytes<R: Read>(
+    mut reader: R,
+    len: usize,
+    format: Format,
+    buf: &mut Vec<u8>,
+) -> Result<Padding, Error> {
+    if len == 0 {
+        // even empty names are terminated by NUL byte
+        return Err(Error::new(ErrorKind::InvalidData, "zero-length name"));
+    }
+    buf.clear();
+    buf.resize(len, 0_u8);
     reader.read_exact(&mut buf[..])?;
-    let c_str = CStr::from_bytes_with_nul(&buf).map_err(|_| ErrorKind::InvalidData)?;
-    read_path_padding(reader, len, format)?;
-    let os_str = OsStr::from_bytes(c_str.to_bytes());
-    Ok(os_str.into())
+    CStr::from_bytes_with_nul(buf).map_err(|_| ErrorKind::InvalidData)?;
+    buf.pop();
+    read_path_padding(reader, len, format)
 }
 
 pub fn write_path_c_str<W: Write>(
@@ -45,13 +68,12 @@ pub fn write_path_c_str<W: Write>(
     Ok(())
 }
 
-pub fn read_path_padding<R: Read>(reader: R, len: usize, format: Format) -> Result<(), Error> {
+pub fn read_path_padding<R: Read>(reader: R, len: usize, format: Format) -> Result<Padding, Error> {
     match format {
-        Format::Newc | Format::Crc => read_padding(reader, NEWC_HEADER_LEN + len)?,
-        Format::Bin(..) => read_padding_bin(reader, len)?,
-        Format::Odc => {}
+        Format::Newc | Format::Crc => read_padding(reader, NEWC_HEADER_LEN + len),
+        Format::Bin(..) => read_padding_bin(reader, len),
+        Format::Odc => Ok(Padding::default()),
     }
-    Ok(())
 }
 
 pub fn write_path_padding<W: Write>(writer: W, len: usize, format: Format) -> Result<(), Error> {
@@ -63,13 +85,12 @@ pub fn write_path_padding<W: Write>(writer: W, len: usize, format: Format) -> Re
     Ok(())
 }
 
-pub fn read_file_padding<R: Read>(reader: R, len: usize, format: Format) -> Result<(), Error> {
+pub fn read_file_padding<R: Read>(reader: R, len: usize, format: Format) -> Result<Padding, Error> {
     match format {
-        Format::Newc | Format::Crc => read_padding(reader, len)?,
-        Format::Bin(..) => read_padding_bin(reader, len)?,
-        Format::Odc => {}
+        Format::Newc | Format::Crc => read_padding(reader, len),
+        Format::Bin(..) => read_padding_bin(reader, len),
+        Format::Odc => Ok(Padding::default()),
     }
-    Ok(())
 }
 
 pub fn write_file_padding<W: Write>(
@@ -85,14 +106,72 @@ pub fn write_file_padding<W: Write>(
     Ok(())
 }
 
-pub fn read_padding<R: Read>(mut reader: R, len: usize) -> Result<(), Error> {
+/// The number of bytes that an entry occupies in the archive.
+///
+/// `name_len` includes the terminating NUL byte.
+pub fn entry_len(name_len: usize, file_size: u64, format: Format) -> u64 {
+    let (header_len, align) = match format {
+        Format::Newc | Format::Crc => (NEWC_HEADER_LEN, NEWC_ALIGN),
+        Format::Odc => (ODC_HEADER_LEN, 1),
+        Format::Bin(..) => (BIN_HEADER_LEN, BIN_ALIGN),
+    };
+    let align = align as u64;
+    ((header_len + name_len) as u64)
+        .next_multiple_of(align)
+        .saturating_add(file_size.next_multiple_of(align))
+}
+
+/// Write all the buffers using as few [`write_vectored`](Write::write_vectored) calls as possible.
+pub fn write_all_vectored<W: Write>(
+    mut writer: W,
+    mut bufs: &mut [IoSlice<'_>],
+) -> Result<(), Error> {
+    // skip empty buffers
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match writer.write_vectored(bufs) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Copy the contents of `reader` to `writer` using the provided buffer.
+///
+/// Falls back to [`copy`](std::io::copy) if the buffer is empty.
+pub fn copy_with_buffer<R: Read + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+    buf: &mut [u8],
+) -> Result<u64, Error> {
+    if buf.is_empty() {
+        return std::io::copy(reader, writer);
+    }
+    let mut total: u64 = 0;
+    loop {
+        let n = match reader.read(buf) {
+            Ok(0) => return Ok(total),
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..n])?;
+        total += n as u64;
+    }
+}
+
+pub fn read_padding<R: Read>(mut reader: R, len: usize) -> Result<Padding, Error> {
     let remainder = len % NEWC_ALIGN;
     if remainder != 0 {
         let padding = NEWC_ALIGN - remainder;
         let mut buf = [0_u8; NEWC_ALIGN];
         reader.read_exact(&mut buf[..padding])?;
+        return Ok(Padding::new(&buf[..padding]));
     }
-    Ok(())
+    Ok(Padding::default())
 }
 
 fn write_padding_newc<W: Write>(mut writer: W, len: usize) -> Result<(), Error> {
@@ -104,13 +183,14 @@ fn write_padding_newc<W: Write>(mut writer: W, len: usize) -> Result<(), Error>
     Ok(())
 }
 
-pub fn read_padding_bin<R: Read>(mut reader: R, len: usize) -> Result<(), Error> {
+pub fn read_padding_bin<R: Read>(mut reader: R, len: usize) -> Result<Padding, Error> {
     let remainder = len % BIN_ALIGN;
     if remainder != 0 {
         let mut buf = [0_u8; 1];
         reader.read_exact(&mut buf[..])?;
+        return Ok(Padding::new(&buf[..]));
     }
-    Ok(())
+    Ok(Padding::default())
 }
 
 pub fn write_padding_bin<W: Write>(mut writer: W, len: usize) -> Result<(), Error> {
@@ -133,8 +213,7 @@ pub fn write_octal_6<W: Write>(mut writer: W, value: u32) -> Result<(), Error> {
     if value > MAX_6 {
         return Err(ErrorKind::InvalidData.into());
     }
-    let s = format!("{:06o}", value);
-    writer.write_all(s.as_bytes())
+    writer.write_all(&encode::<6>(value as u64, 8))
 }
 
 pub fn read_hex_8<R: Read>(mut reader: R) -> Result<u32, Error> {
@@ -146,8 +225,7 @@ pub fn read_hex_8<R: Read>(mut reader: R) -> Result<u32, Error> {
 }
 
 pub fn write_hex_8<W: Write>(mut writer: W, value: u32) -> Result<(), Error> {
-    let s = format!("{:08x}", value);
-    writer.write_all(s.as_bytes())
+    writer.write_all(&encode::<8>(value as u64, 16))
 }
 
 pub fn read_octal_11<R: Read>(mut reader: R) -> Result<u64, Error> {
@@ -162,8 +240,20 @@ pub fn write_octal_11<W: Write>(mut writer: W, value: u64) -> Result<(), Error>
     if value > MAX_11 {
         return Err(ErrorKind::InvalidData.into());
     }
-    let s = format!("{:011o}", value);
-    writer.write_all(s.as_bytes())
+    writer.write_all(&encode::<11>(value, 8))
+}
+
+/// Encode `value` as `N` zero-padded lowercase digits in the specified `radix`.
+///
+/// The higher digits that don't fit are discarded.
+fn encode<const N: usize>(mut value: u64, radix: u64) -> [u8; N] {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut digits = [b'0'; N];
+    for digit in digits.iter_mut().rev() {
+        *digit = DIGITS[(value % radix) as usize];
+        value /= radix;
+    }
+    digits
 }
 
 pub fn read_binary_u16_le<R: Read>(mut reader: R) -> Result<u16, Error> {
@@ -286,4 +376,21 @@ mod tests {
     }
 
     use test_symmetry_v2;
+
+    #[test]
+    fn encode_matches_format() {
+        arbtest(|u| {
+            let value: u32 = u.arbitrary()?;
+            assert_eq!(
+                format!("{:08x}", value).as_bytes(),
+                &encode::<8>(value as u64, 16)
+            );
+            let value = u.int_in_range(0..=MAX_11)?;
+            assert_eq!(
+                format!("{:011o}", value).as_bytes(),
+                &encode::<11>(value, 8)
+            );
+            Ok(())
+        });
+    }
 }
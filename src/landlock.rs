@@ -0,0 +1,122 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::fd::RawFd;
+
+use libc::SYS_landlock_add_rule;
+use libc::SYS_landlock_create_ruleset;
+use libc::SYS_landlock_restrict_self;
+
+/// Restrict file system modifications of the current thread to the directory `dirfd`.
+///
+/// Newly created threads inherit the restriction, and it can't be lifted.
+pub fn restrict_writes_to(dirfd: RawFd) -> Result<(), Error> {
+    let abi = unsafe {
+        libc::syscall(
+            SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0_usize,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 0 {
+        let e = Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => Err(Error::new(
+                ErrorKind::Unsupported,
+                "landlock is not supported by the kernel",
+            )),
+            _ => Err(e),
+        };
+    }
+    let mut access = ACCESS_FS_WRITE;
+    if abi >= 2 {
+        access |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        access |= ACCESS_FS_TRUNCATE;
+    }
+    let attr = RulesetAttr {
+        handled_access_fs: access,
+    };
+    let ret = unsafe {
+        libc::syscall(
+            SYS_landlock_create_ruleset,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0_u32,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    let ruleset = unsafe { OwnedFd::from_raw_fd(ret as RawFd) };
+    let rule = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: dirfd,
+    };
+    let ret = unsafe {
+        libc::syscall(
+            SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &rule as *const PathBeneathAttr,
+            0_u32,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    let ret = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    let ret = unsafe { libc::syscall(SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0_u32) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+// ABI v2
+const ACCESS_FS_REFER: u64 = 1 << 13;
+// ABI v3
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+// All the accesses that modify the file system and are available since ABI v1.
+const ACCESS_FS_WRITE: u64 = ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_REMOVE_DIR
+    | ACCESS_FS_REMOVE_FILE
+    | ACCESS_FS_MAKE_CHAR
+    | ACCESS_FS_MAKE_DIR
+    | ACCESS_FS_MAKE_REG
+    | ACCESS_FS_MAKE_SOCK
+    | ACCESS_FS_MAKE_FIFO
+    | ACCESS_FS_MAKE_BLOCK
+    | ACCESS_FS_MAKE_SYM;
@@ -0,0 +1,95 @@
+use crate::constants::TRAILER;
+use crate::io::entry_len;
+use crate::limits::*;
+use crate::Format;
+
+impl Format {
+    /// The size of the header in bytes.
+    pub const fn header_size(&self) -> usize {
+        match self {
+            Self::Newc | Self::Crc => NEWC_HEADER_SIZE,
+            Self::Odc => ODC_HEADER_SIZE,
+            Self::Bin(..) => BIN_HEADER_SIZE,
+        }
+    }
+
+    /// Header, file name and file contents alignment in bytes.
+    ///
+    /// The header followed by the file name is padded to this alignment,
+    /// then the contents are padded to this alignment.
+    pub const fn alignment(&self) -> usize {
+        match self {
+            Self::Newc | Self::Crc => NEWC_ALIGNMENT,
+            Self::Odc => ODC_ALIGNMENT,
+            Self::Bin(..) => BIN_ALIGNMENT,
+        }
+    }
+
+    /// The number of zero bytes that follow `len` bytes to align them.
+    pub const fn padding(&self, len: u64) -> u64 {
+        let alignment = self.alignment() as u64;
+        (alignment - len % alignment) % alignment
+    }
+
+    /// The number of bytes that an entry occupies in the archive
+    /// including the header, the file name and the padding.
+    ///
+    /// `name_len` excludes the terminating NUL byte.
+    /// The size of the hard links which contents are stored in another entry
+    /// is obtained with `file_size` equal to zero.
+    pub fn entry_size(&self, name_len: usize, file_size: u64) -> u64 {
+        entry_len(name_len.saturating_add(1), file_size, *self)
+    }
+
+    /// The number of bytes that the trailer occupies in the archive.
+    ///
+    /// The archive size is the sum of the entry sizes and the trailer size
+    /// without the [block padding](crate::Builder::pad_to_block_size).
+    pub fn trailer_size(&self) -> u64 {
+        entry_len(TRAILER.to_bytes_with_nul().len(), 0, *self)
+    }
+}
+
+/// The number of bytes that an entry occupies in [`Format::Newc`] archive.
+///
+/// See [`Format::entry_size`].
+pub fn newc_entry_size(name_len: usize, file_size: u64) -> u64 {
+    Format::Newc.entry_size(name_len, file_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Builder;
+    use crate::ByteOrder;
+    use crate::Metadata;
+
+    #[test]
+    fn entry_size() {
+        for format in [
+            Format::Newc,
+            Format::Crc,
+            Format::Odc,
+            Format::Bin(ByteOrder::LittleEndian),
+        ] {
+            for (name, file_size) in [("a", 0), ("ab", 1), ("abc", 2), ("abcd", 3), ("abcde", 4)] {
+                let mut builder = Builder::new(Vec::new());
+                builder.set_format(format);
+                builder
+                    .append_data(
+                        Metadata::regular(0o644, 0),
+                        name,
+                        &vec![0_u8; file_size][..],
+                    )
+                    .unwrap();
+                let len = builder.get_ref().len() as u64;
+                assert_eq!(len, format.entry_size(name.len(), file_size as u64));
+                assert_eq!(0, format.padding(len));
+                let bytes = builder.finish().unwrap();
+                assert_eq!(bytes.len() as u64, len + format.trailer_size());
+            }
+        }
+        assert_eq!(112, newc_entry_size(1, 0));
+        assert_eq!(3, Format::Newc.padding(5));
+    }
+}
@@ -4,18 +4,48 @@ mod archive;
 mod builder;
 mod constants;
 mod crc;
+mod dedup;
+mod device_table;
+pub mod driver;
 mod file_type;
 mod io;
+#[cfg(all(target_os = "linux", feature = "landlock"))]
+mod landlock;
+mod layout;
+pub mod limits;
 mod metadata;
 mod mk;
+mod mtree;
+mod pattern;
+mod plan;
+mod records;
+mod spec;
+mod split;
+mod tee;
+#[cfg(any(test, feature = "testdata"))]
+pub mod testdata;
+mod unpack;
+mod validate;
 mod walk;
+mod xattr;
 
 pub use self::archive::*;
 pub use self::builder::*;
 pub(crate) use self::crc::*;
+pub use self::dedup::*;
 pub use self::file_type::*;
+#[cfg(all(target_os = "linux", feature = "landlock"))]
+pub(crate) use self::landlock::*;
+pub use self::layout::*;
 pub use self::metadata::*;
 pub(crate) use self::mk::*;
+pub(crate) use self::pattern::*;
+pub use self::plan::*;
+pub use self::records::*;
+pub use self::split::*;
+pub use self::unpack::*;
+pub use self::validate::*;
 pub(crate) use self::walk::*;
+pub use self::xattr::*;
 
 // TODO fuzz-test against MacOS cpio
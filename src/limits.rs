@@ -0,0 +1,278 @@
+//! Format limits.
+//!
+//! Use these constants to validate the input before passing it to [`Builder`](crate::Builder).
+//! Name lengths exclude the terminating NUL byte.
+//! Writing a header with any field exceeding its limit fails with [`ErrorKind::InvalidData`](std::io::ErrorKind::InvalidData).
+
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::constants::*;
+use crate::makedev;
+use crate::Metadata;
+
+/// Max. file name length in [`Format::Newc`](crate::Format::Newc) and [`Format::Crc`](crate::Format::Crc).
+pub const NEWC_MAX_NAME_LEN: usize = MAX_8 as usize - 1;
+/// Max. file size in [`Format::Newc`](crate::Format::Newc) and [`Format::Crc`](crate::Format::Crc).
+pub const NEWC_MAX_FILE_SIZE: u64 = MAX_8 as u64;
+/// Max. user/group ID in [`Format::Newc`](crate::Format::Newc) and [`Format::Crc`](crate::Format::Crc).
+pub const NEWC_MAX_ID: u32 = MAX_8;
+/// Max. modification time in [`Format::Newc`](crate::Format::Newc) and [`Format::Crc`](crate::Format::Crc).
+///
+/// The field is unsigned, i.e. the format is not affected by the year 2038 problem
+/// and supports timestamps up to 2106-02-07T06:28:15Z.
+pub const NEWC_MAX_MTIME: u64 = MAX_8 as u64;
+/// Header, file name and file contents alignment in [`Format::Newc`](crate::Format::Newc) and [`Format::Crc`](crate::Format::Crc).
+pub const NEWC_ALIGNMENT: usize = NEWC_ALIGN;
+/// Header size in bytes in [`Format::Newc`](crate::Format::Newc) and [`Format::Crc`](crate::Format::Crc).
+pub const NEWC_HEADER_SIZE: usize = NEWC_HEADER_LEN;
+
+/// Max. file name length in [`Format::Odc`](crate::Format::Odc).
+pub const ODC_MAX_NAME_LEN: usize = MAX_6 as usize - 1;
+/// Max. file size in [`Format::Odc`](crate::Format::Odc).
+pub const ODC_MAX_FILE_SIZE: u64 = MAX_11;
+/// Max. user/group ID in [`Format::Odc`](crate::Format::Odc).
+pub const ODC_MAX_ID: u32 = MAX_6;
+/// Max. modification time in [`Format::Odc`](crate::Format::Odc).
+///
+/// The field has 11 octal digits, i.e. 33 bits, that covers timestamps up to 2242-03-16T12:56:31Z.
+pub const ODC_MAX_MTIME: u64 = MAX_11;
+/// Entries are not aligned in [`Format::Odc`](crate::Format::Odc).
+pub const ODC_ALIGNMENT: usize = 1;
+/// Header size in bytes in [`Format::Odc`](crate::Format::Odc).
+pub const ODC_HEADER_SIZE: usize = ODC_HEADER_LEN;
+
+/// Max. file name length in [`Format::Bin`](crate::Format::Bin).
+pub const BIN_MAX_NAME_LEN: usize = u16::MAX as usize - 1;
+/// Max. file size in [`Format::Bin`](crate::Format::Bin).
+pub const BIN_MAX_FILE_SIZE: u64 = u32::MAX as u64;
+/// Max. user/group ID in [`Format::Bin`](crate::Format::Bin).
+pub const BIN_MAX_ID: u32 = u16::MAX as u32;
+/// Max. modification time in [`Format::Bin`](crate::Format::Bin).
+///
+/// The field is unsigned, i.e. the format supports timestamps up to 2106-02-07T06:28:15Z.
+pub const BIN_MAX_MTIME: u64 = u32::MAX as u64;
+/// Header, file name and file contents alignment in [`Format::Bin`](crate::Format::Bin).
+pub const BIN_ALIGNMENT: usize = BIN_ALIGN;
+/// Header size in bytes in [`Format::Bin`](crate::Format::Bin).
+pub const BIN_HEADER_SIZE: usize = BIN_HEADER_LEN;
+
+/// Header field which value exceeds the limit of the format.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum HeaderField {
+    /// File name length in bytes.
+    Name,
+    /// File size.
+    FileSize,
+    /// User ID.
+    Uid,
+    /// Group ID.
+    Gid,
+    /// Modification time.
+    Mtime,
+    /// The number of hard links.
+    Nlink,
+    /// Inode number.
+    Ino,
+    /// The id of the device that contains the file.
+    Dev,
+    /// The id of the device file itself.
+    Rdev,
+}
+
+impl HeaderField {
+    /// Get human-readable name of the field.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Name => "file name length",
+            Self::FileSize => "file size",
+            Self::Uid => "user id",
+            Self::Gid => "group id",
+            Self::Mtime => "modification time",
+            Self::Nlink => "number of hard links",
+            Self::Ino => "inode number",
+            Self::Dev => "device id",
+            Self::Rdev => "device file id",
+        }
+    }
+}
+
+/// The value that doesn't fit into the header.
+///
+/// Returned by [`Format::check`](crate::Format::check) and [`Builder::check_path`](crate::Builder::check_path).
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct LimitViolation {
+    /// The field.
+    pub field: HeaderField,
+    /// The actual value.
+    pub value: u64,
+    /// The maximum value supported by the format.
+    ///
+    /// Device major and minor numbers are limited separately in [`Format::Bin`](crate::Format::Bin),
+    /// hence the device id is reported as is with the maximum device id that fits into the header.
+    pub max: u64,
+}
+
+impl Display for LimitViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} exceeds the maximum of {}",
+            self.field.as_str(),
+            self.value,
+            self.max
+        )
+    }
+}
+
+impl crate::Format {
+    /// Find the values that don't fit into the header of this format.
+    ///
+    /// Checks `metadata` and `name` as is, i.e. [`Builder`](crate::Builder) might still
+    /// be able to write the entry by replacing inode numbers and device ids.
+    /// Use [`Builder::check_path`](crate::Builder::check_path) to account for that.
+    /// Returns an empty vector if the entry can be written.
+    pub fn check(&self, metadata: &Metadata, name: &Path) -> Vec<LimitViolation> {
+        let (max_name_len, max_file_size, max_id, max_mtime, max_u32) = match self {
+            Self::Newc | Self::Crc => (
+                NEWC_MAX_NAME_LEN,
+                NEWC_MAX_FILE_SIZE,
+                NEWC_MAX_ID,
+                NEWC_MAX_MTIME,
+                MAX_8,
+            ),
+            Self::Odc => (
+                ODC_MAX_NAME_LEN,
+                ODC_MAX_FILE_SIZE,
+                ODC_MAX_ID,
+                ODC_MAX_MTIME,
+                MAX_6,
+            ),
+            Self::Bin(..) => (
+                BIN_MAX_NAME_LEN,
+                BIN_MAX_FILE_SIZE,
+                BIN_MAX_ID,
+                BIN_MAX_MTIME,
+                u16::MAX as u32,
+            ),
+        };
+        let mut violations = Vec::new();
+        let mut check = |field, value: u64, max: u64| {
+            if value > max {
+                violations.push(LimitViolation { field, value, max });
+            }
+        };
+        let name_len = name.as_os_str().as_bytes().len();
+        check(HeaderField::Name, name_len as u64, max_name_len as u64);
+        check(HeaderField::FileSize, metadata.size(), max_file_size);
+        check(HeaderField::Uid, metadata.uid() as u64, max_id as u64);
+        check(HeaderField::Gid, metadata.gid() as u64, max_id as u64);
+        check(HeaderField::Mtime, metadata.mtime(), max_mtime);
+        check(HeaderField::Nlink, metadata.nlink() as u64, max_u32 as u64);
+        check(HeaderField::Ino, metadata.ino(), max_u32 as u64);
+        match self {
+            Self::Newc | Self::Crc => {
+                // major and minor numbers are stored as u32
+            }
+            Self::Odc => {
+                check(HeaderField::Dev, metadata.dev(), MAX_6 as u64);
+                check(HeaderField::Rdev, metadata.rdev(), MAX_6 as u64);
+            }
+            Self::Bin(..) => {
+                let max = makedev(u8::MAX as u64, u8::MAX as u64);
+                for (field, dev) in [
+                    (HeaderField::Dev, metadata.dev()),
+                    (HeaderField::Rdev, metadata.rdev()),
+                ] {
+                    let major = libc::major(dev as libc::dev_t) as u64;
+                    let minor = libc::minor(dev as libc::dev_t) as u64;
+                    if major > u8::MAX as u64 || minor > u8::MAX as u64 {
+                        violations.push(LimitViolation {
+                            field,
+                            value: dev,
+                            max,
+                        });
+                    }
+                }
+            }
+        }
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::Builder;
+    use crate::ByteOrder;
+    use crate::Format;
+    use crate::OverflowPolicy;
+
+    #[test]
+    fn check_limits() {
+        let metadata = Metadata::regular(0o644, NEWC_MAX_FILE_SIZE + 1);
+        assert_eq!(
+            vec![LimitViolation {
+                field: HeaderField::FileSize,
+                value: NEWC_MAX_FILE_SIZE + 1,
+                max: NEWC_MAX_FILE_SIZE,
+            }],
+            Format::Newc.check(&metadata, Path::new("file"))
+        );
+        assert!(Format::Odc.check(&metadata, Path::new("file")).is_empty());
+        let mut metadata = Metadata::regular(0o644, 0);
+        metadata.set_uid(ODC_MAX_ID + 1);
+        let long_name = "x".repeat(ODC_MAX_NAME_LEN + 1);
+        assert_eq!(
+            vec![HeaderField::Name, HeaderField::Uid],
+            Format::Odc
+                .check(&metadata, Path::new(&long_name))
+                .into_iter()
+                .map(|violation| violation.field)
+                .collect::<Vec<_>>()
+        );
+        assert!(Format::Newc
+            .check(&metadata, Path::new(&long_name))
+            .is_empty());
+        let metadata = Metadata::char_device(0o644, libc::makedev(300, 1));
+        assert_eq!(
+            vec![HeaderField::Rdev],
+            Format::Bin(ByteOrder::LittleEndian)
+                .check(&metadata, Path::new("dev"))
+                .into_iter()
+                .map(|violation| violation.field)
+                .collect::<Vec<_>>()
+        );
+        // builder
+        let workdir = TempDir::new().unwrap();
+        let path = workdir.path().join("file");
+        std::fs::write(&path, b"hello").unwrap();
+        let mut builder = Builder::new(Vec::new());
+        builder.set_format(Format::Odc);
+        builder.set_default_uid(Some(ODC_MAX_ID + 1));
+        let violations = builder.check_path(&path, "file").unwrap();
+        assert_eq!(
+            vec![HeaderField::Uid],
+            violations
+                .iter()
+                .map(|violation| violation.field)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            format!(
+                "user id {} exceeds the maximum of {}",
+                ODC_MAX_ID + 1,
+                ODC_MAX_ID
+            ),
+            violations[0].to_string()
+        );
+        builder.set_overflow_policy(OverflowPolicy::Saturate);
+        assert!(builder.check_path(&path, "file").unwrap().is_empty());
+        assert!(builder.get_ref().is_empty());
+    }
+}
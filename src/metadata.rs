@@ -2,7 +2,9 @@ use std::io::Error;
 use std::io::ErrorKind;
 use std::io::Read;
 use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::MetadataExt;
+use std::path::Path;
 use std::time::Duration;
 use std::time::SystemTime;
 
@@ -11,7 +13,9 @@ use libc::makedev;
 use libc::minor;
 
 use crate::constants::*;
+use crate::file_type_to_mode;
 use crate::io::*;
+use crate::limits::*;
 use crate::mode_to_file_type;
 use crate::FileType;
 
@@ -35,6 +39,70 @@ pub struct Metadata {
 }
 
 impl Metadata {
+    /// Create metadata for a file of the specified type and permissions.
+    ///
+    /// The number of hard links is set to one, all the other fields are zeroed.
+    pub fn new(file_type: FileType, mode: u32) -> Self {
+        Self {
+            dev: 0,
+            ino: 0,
+            mode: file_type_to_mode(file_type) | (mode & FILE_MODE_MASK),
+            uid: 0,
+            gid: 0,
+            nlink: 1,
+            rdev: 0,
+            mtime: 0,
+            name_len: 0,
+            file_size: 0,
+            check: 0,
+        }
+    }
+
+    /// Create metadata for a regular file of the specified size in bytes.
+    pub fn regular(mode: u32, size: u64) -> Self {
+        let mut metadata = Self::new(FileType::Regular, mode);
+        metadata.file_size = size;
+        metadata
+    }
+
+    /// Create metadata for a directory.
+    pub fn directory(mode: u32) -> Self {
+        Self::new(FileType::Directory, mode)
+    }
+
+    /// Create metadata for a symbolic link that points to `target`.
+    ///
+    /// The entry's contents are expected to be the target path followed by a NUL byte.
+    pub fn symlink<P: AsRef<Path>>(target: P) -> Self {
+        let mut metadata = Self::new(FileType::Symlink, 0o777);
+        metadata.file_size = target.as_ref().as_os_str().as_bytes().len() as u64 + 1;
+        metadata
+    }
+
+    /// Create metadata for a block device with the specified device ID.
+    pub fn block_device(mode: u32, rdev: u64) -> Self {
+        let mut metadata = Self::new(FileType::BlockDevice, mode);
+        metadata.rdev = rdev;
+        metadata
+    }
+
+    /// Create metadata for a character device with the specified device ID.
+    pub fn char_device(mode: u32, rdev: u64) -> Self {
+        let mut metadata = Self::new(FileType::CharDevice, mode);
+        metadata.rdev = rdev;
+        metadata
+    }
+
+    /// Create metadata for a named pipe.
+    pub fn fifo(mode: u32) -> Self {
+        Self::new(FileType::Fifo, mode)
+    }
+
+    /// Create metadata for a Unix-domain socket.
+    pub fn socket(mode: u32) -> Self {
+        Self::new(FileType::Socket, mode)
+    }
+
     /// Get file type bits from the mode.
     pub fn file_type(&self) -> Result<FileType, Error> {
         self.mode.try_into()
@@ -90,6 +158,84 @@ impl Metadata {
         self.mtime
     }
 
+    /// Get the checksum of the file contents.
+    ///
+    /// Only [`Format::Crc`] archives contain meaningful values;
+    /// the value is preserved as is when the entry is written in [`Format::Newc`].
+    pub fn check(&self) -> u32 {
+        self.check
+    }
+
+    /// Get the length of the file path in bytes including the terminating NUL byte
+    /// as stored in the header.
+    pub fn name_len(&self) -> u32 {
+        self.name_len
+    }
+
+    /// Set file mode with file type bits.
+    pub fn set_mode(&mut self, mode: u32) {
+        self.mode = mode;
+    }
+
+    /// Set user ID of the file owner.
+    pub fn set_uid(&mut self, uid: u32) {
+        self.uid = uid;
+    }
+
+    /// Set group ID of the file owner.
+    pub fn set_gid(&mut self, gid: u32) {
+        self.gid = gid;
+    }
+
+    /// Set last modification time in seconds since Unix epoch.
+    pub fn set_mtime(&mut self, mtime: u64) {
+        self.mtime = mtime;
+    }
+
+    /// Set the id of the device that contains the file.
+    pub fn set_dev(&mut self, dev: u64) {
+        self.dev = dev;
+    }
+
+    /// Set inode number.
+    ///
+    /// Entries with the same device id and inode number are hard links to the same file
+    /// when the number of links is greater than one.
+    pub fn set_ino(&mut self, ino: u64) {
+        self.ino = ino;
+    }
+
+    /// Set the number of hard links.
+    pub fn set_nlink(&mut self, nlink: u32) {
+        self.nlink = nlink;
+    }
+
+    /// Set device id of the file itself (if it is a device file).
+    pub fn set_rdev(&mut self, rdev: u64) {
+        self.rdev = rdev;
+    }
+
+    /// Set file size in bytes.
+    pub fn set_file_size(&mut self, file_size: u64) {
+        self.file_size = file_size;
+    }
+
+    /// Set the length of the file path in bytes including the terminating NUL byte.
+    ///
+    /// Only needed for the headers written with [`write`](Self::write);
+    /// the builder sets the length itself.
+    pub fn set_name_len(&mut self, name_len: u32) {
+        self.name_len = name_len;
+    }
+
+    /// Set the checksum of the file contents.
+    ///
+    /// Only needed for the headers written with [`write`](Self::write);
+    /// the builder computes the checksum itself.
+    pub fn set_check(&mut self, check: u32) {
+        self.check = check;
+    }
+
     /// Last modification time.
     pub fn modified(&self) -> Result<SystemTime, Error> {
         let dt = Duration::from_secs(self.mtime);
@@ -151,7 +297,17 @@ impl Metadata {
         (self.dev, self.ino)
     }
 
-    pub(crate) fn read_some<R: Read>(mut reader: R) -> Result<Option<(Self, Format)>, Error> {
+    /// Read the header from `reader` and detect its format.
+    ///
+    /// Only the header is read, i.e. the file path, the contents and the padding
+    /// that follow it have to be read by the caller:
+    /// the path is [`name_len`](Self::name_len) bytes long including the terminating NUL byte
+    /// and is padded with [`Format::padding`] of the header size plus the path length;
+    /// the contents are [`size`](Self::size) bytes long and are padded with [`Format::padding`] of their size.
+    /// The trailer is not treated specially.
+    ///
+    /// Returns `None` if the input ends before the magic number.
+    pub fn read_some<R: Read>(mut reader: R) -> Result<Option<(Self, Format)>, Error> {
         let format = {
             // read 2 bytes
             let mut magic = [0_u8; MAGIC_LEN];
@@ -193,13 +349,25 @@ impl Metadata {
         .map(|metadata| (metadata, format))
     }
 
-    pub(crate) fn write<W: Write>(&self, writer: W, format: Format) -> Result<(), Error> {
+    /// Write the header to `writer` in the specified format.
+    ///
+    /// Only the header is written (see [`read_some`](Self::read_some) for the rest of the entry).
+    /// No fields are changed: the caller sets the [path length](Self::set_name_len),
+    /// the [checksum](Self::set_check) and unique inode numbers.
+    /// Fails with [`ErrorKind::InvalidData`] if some value doesn't fit into the header.
+    pub fn write<W: Write>(&self, mut writer: W, format: Format) -> Result<(), Error> {
+        // The header is encoded on the stack and written in one call.
+        let mut header = [0_u8; NEWC_HEADER_LEN];
+        let len = format.header_size();
+        let mut buf = &mut header[..len];
         match format {
-            Format::Bin(byte_order) => self.write_bin(writer, byte_order),
-            Format::Odc => self.write_odc(writer),
-            Format::Newc => self.write_newc(writer, &NEWC_MAGIC[..]),
-            Format::Crc => self.write_newc(writer, &CRC_MAGIC[..]),
+            Format::Bin(byte_order) => self.write_bin(&mut buf, byte_order)?,
+            Format::Odc => self.write_odc(&mut buf)?,
+            Format::Newc => self.write_newc(&mut buf, &NEWC_MAGIC[..])?,
+            Format::Crc => self.write_newc(&mut buf, &CRC_MAGIC[..])?,
         }
+        debug_assert!(buf.is_empty());
+        writer.write_all(&header[..len])
     }
 
     #[allow(unused_unsafe)]
@@ -293,7 +461,7 @@ impl Metadata {
                 $write16(writer.by_ref(), dev64_to_dev16(self.rdev)?)?;
                 $write32(
                     writer.by_ref(),
-                    self.mtime.try_into().map_err(|_| ErrorKind::InvalidData)?,
+                    check_mtime(self.mtime, BIN_MAX_MTIME)? as u32,
                 )?;
                 $write16(
                     writer.by_ref(),
@@ -366,7 +534,7 @@ impl Metadata {
             writer.by_ref(),
             self.rdev.try_into().map_err(|_| ErrorKind::InvalidData)?,
         )?;
-        write_octal_11(writer.by_ref(), zero_on_overflow(self.mtime, MAX_11))?;
+        write_octal_11(writer.by_ref(), check_mtime(self.mtime, ODC_MAX_MTIME)?)?;
         write_octal_6(writer.by_ref(), self.name_len)?;
         write_octal_11(writer.by_ref(), self.file_size)?;
         Ok(())
@@ -414,7 +582,7 @@ impl Metadata {
         write_hex_8(writer.by_ref(), self.nlink)?;
         write_hex_8(
             writer.by_ref(),
-            zero_on_overflow(self.mtime, MAX_8 as u64) as u32,
+            check_mtime(self.mtime, NEWC_MAX_MTIME)? as u32,
         )?;
         write_hex_8(
             writer.by_ref(),
@@ -443,7 +611,12 @@ impl TryFrom<&std::fs::Metadata> for Metadata {
             gid: other.gid(),
             nlink: other.nlink() as u32,
             rdev: other.rdev(),
-            mtime: other.mtime() as u64,
+            mtime: other.mtime().try_into().map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "modification time before Unix epoch",
+                )
+            })?,
             name_len: 0,
             file_size: other.size(),
             check: 0,
@@ -451,6 +624,48 @@ impl TryFrom<&std::fs::Metadata> for Metadata {
     }
 }
 
+/// Read-only metadata of an entry read from an archive.
+///
+/// Dereferences to [`Metadata`] for getters.
+/// Use [`into_inner`](Self::into_inner) to obtain the owned metadata
+/// that can be modified and appended to another archive.
+#[derive(Clone, Debug)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+pub struct EntryMetadata {
+    metadata: Metadata,
+    format: Format,
+}
+
+impl EntryMetadata {
+    pub(crate) fn new(metadata: Metadata, format: Format) -> Self {
+        Self { metadata, format }
+    }
+
+    /// Get the format of the entry's header.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Convert into the owned metadata.
+    pub fn into_inner(self) -> Metadata {
+        self.metadata
+    }
+}
+
+impl std::ops::Deref for EntryMetadata {
+    type Target = Metadata;
+
+    fn deref(&self) -> &Self::Target {
+        &self.metadata
+    }
+}
+
+impl From<EntryMetadata> for Metadata {
+    fn from(other: EntryMetadata) -> Self {
+        other.metadata
+    }
+}
+
 pub(crate) type MetadataId = (u64, u64);
 
 /// CPIO archive format.
@@ -497,12 +712,17 @@ impl Default for ByteOrder {
     }
 }
 
-const fn zero_on_overflow(value: u64, max: u64) -> u64 {
-    if value > max {
-        0
-    } else {
-        value
-    }
+fn check_mtime(mtime: u64, max: u64) -> Result<u64, Error> {
+    if mtime > max {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "modification time {} exceeds the maximum of {} supported by the format",
+                mtime, max
+            ),
+        ));
+    }
+    Ok(mtime)
 }
 
 #[cfg(test)]
@@ -513,6 +733,88 @@ mod tests {
     use arbtest::arbtest;
 
     use super::*;
+    use crate::Archive;
+
+    #[test]
+    fn constructors() {
+        let metadata = Metadata::regular(0o100644, 123);
+        assert!(metadata.is_file());
+        assert_eq!(0o644, metadata.file_mode());
+        assert_eq!(123, metadata.size());
+        assert_eq!(1, metadata.nlink());
+        assert!(Metadata::directory(0o755).is_dir());
+        assert_eq!(7, Metadata::symlink("target").size());
+        assert!(Metadata::symlink("target").is_symlink());
+        assert!(Metadata::fifo(0o644).is_fifo());
+        assert!(Metadata::socket(0o644).is_socket());
+        let metadata = Metadata::char_device(0o600, 1234);
+        assert!(metadata.is_char_device());
+        assert_eq!(1234, metadata.rdev());
+        assert!(Metadata::block_device(0o600, 1234).is_block_device());
+    }
+
+    #[test]
+    fn entry_metadata() {
+        let mut metadata = Metadata::regular(0o644, 10);
+        metadata.set_dev(1);
+        metadata.set_ino(2);
+        metadata.set_nlink(3);
+        let entry_metadata = EntryMetadata::new(metadata.clone(), Format::Odc);
+        assert_eq!(Format::Odc, entry_metadata.format());
+        assert_eq!(
+            (1, 2, 3),
+            (
+                entry_metadata.dev(),
+                entry_metadata.ino(),
+                entry_metadata.nlink()
+            )
+        );
+        assert_eq!(metadata, entry_metadata.into_inner());
+    }
+
+    #[test]
+    fn mtime_limits() {
+        // 2038-01-19T03:14:08Z, the first timestamp that doesn't fit into signed 32-bit integer
+        const Y2038: u64 = 1 << 31;
+        // 2106-02-07T06:28:15Z, the last timestamp that fits into unsigned 32-bit integer
+        const Y2106: u64 = u32::MAX as u64;
+        let formats = [
+            Format::Newc,
+            Format::Crc,
+            Format::Odc,
+            Format::Bin(ByteOrder::LittleEndian),
+            Format::Bin(ByteOrder::BigEndian),
+        ];
+        for format in formats {
+            for mtime in [Y2038 - 1, Y2038, Y2106] {
+                let mut expected = Metadata::regular(0o644, 0);
+                expected.set_mtime(mtime);
+                let mut bytes = Vec::new();
+                expected.write(&mut bytes, format).unwrap();
+                let (actual, _) = Metadata::read(&bytes[..]).unwrap();
+                assert_eq!(mtime, actual.mtime(), "format = {:?}", format);
+            }
+            let max = match format {
+                Format::Newc | Format::Crc => NEWC_MAX_MTIME,
+                Format::Odc => ODC_MAX_MTIME,
+                Format::Bin(..) => BIN_MAX_MTIME,
+            };
+            let mut metadata = Metadata::regular(0o644, 0);
+            metadata.set_mtime(max);
+            let mut bytes = Vec::new();
+            metadata.write(&mut bytes, format).unwrap();
+            assert_eq!(max, Metadata::read(&bytes[..]).unwrap().0.mtime());
+            metadata.set_mtime(max + 1);
+            assert_eq!(
+                ErrorKind::InvalidData,
+                metadata.write(Vec::new(), format).unwrap_err().kind(),
+                "format = {:?}",
+                format
+            );
+        }
+        // odc supports 33-bit timestamps
+        assert_eq!((1_u64 << 33) - 1, ODC_MAX_MTIME);
+    }
 
     #[test]
     fn bin_header_write_read_symmetry() {
@@ -608,6 +910,39 @@ mod tests {
         });
     }
 
+    #[test]
+    fn external_framing() {
+        // write the entries without the builder
+        let mut bytes = Vec::new();
+        for format in [Format::Newc, Format::Odc, Format::Bin(ByteOrder::native())] {
+            bytes.clear();
+            for (name, data) in [(&b"file\0"[..], &b"hello"[..]), (b"TRAILER!!!\0", b"")] {
+                let mut metadata = Metadata::regular(0o644, data.len() as u64);
+                metadata.set_name_len(name.len() as u32);
+                metadata.write(&mut bytes, format).unwrap();
+                bytes.extend(name);
+                let len = (format.header_size() + name.len()) as u64;
+                bytes.resize(bytes.len() + format.padding(len) as usize, 0);
+                bytes.extend(data);
+                bytes.resize(bytes.len() + format.padding(data.len() as u64) as usize, 0);
+            }
+            let mut archive = Archive::new(&bytes[..]);
+            let mut entry = archive.next_entry().unwrap().unwrap();
+            assert_eq!(Path::new("file"), entry.path);
+            assert_eq!(5, entry.metadata.name_len());
+            let mut data = Vec::new();
+            entry.reader.read_to_end(&mut data).unwrap();
+            assert_eq!(b"hello", &data[..]);
+            drop(entry);
+            assert!(archive.next_entry().unwrap().is_none());
+            // read the header only
+            let (metadata, actual_format) = Metadata::read_some(&bytes[..]).unwrap().unwrap();
+            assert_eq!(format, actual_format);
+            assert_eq!((5, 5), (metadata.name_len(), metadata.size()));
+        }
+        assert!(Metadata::read_some(&b""[..]).unwrap().is_none());
+    }
+
     #[derive(Debug, PartialEq, Eq)]
     struct NewcHeader(Metadata);
 
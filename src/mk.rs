@@ -1,35 +1,183 @@
 use std::ffi::CStr;
 use std::ffi::CString;
+use std::fs::File;
 use std::io::Error;
+use std::io::ErrorKind;
+use std::mem::MaybeUninit;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::IntoRawFd;
+use std::os::fd::RawFd;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::ffi::OsStringExt;
+use std::path::Component;
+use std::path::Path;
 use std::path::PathBuf;
 use std::time::SystemTime;
 
+use libc::c_int;
 use libc::dev_t;
 use libc::gid_t;
 use libc::mode_t;
 use libc::uid_t;
-use libc::AT_FDCWD;
+use libc::AT_REMOVEDIR;
 use libc::AT_SYMLINK_NOFOLLOW;
+use libc::O_DIRECTORY;
+use libc::O_NOFOLLOW;
+use libc::O_RDONLY;
+use libc::S_IFDIR;
+use libc::S_IFLNK;
+use libc::S_IFMT;
 use libc::UTIME_OMIT;
 
-pub fn mkfifo(path: &CStr, mode: mode_t) -> Result<(), Error> {
-    let ret = unsafe { libc::mkfifo(path.as_ptr(), mode) };
+// Opening the directory only to look up the entries doesn't require read permission.
+#[cfg(target_os = "linux")]
+const O_SEARCH: c_int = libc::O_PATH;
+#[cfg(not(target_os = "linux"))]
+const O_SEARCH: c_int = O_RDONLY;
+
+/// Make the device id from its `major` and `minor` numbers.
+#[allow(unused_unsafe)]
+pub fn makedev(major: u64, minor: u64) -> u64 {
+    unsafe { libc::makedev(major as _, minor as _) as u64 }
+}
+
+pub fn mkfifoat(dirfd: RawFd, path: &CStr, mode: mode_t) -> Result<(), Error> {
+    let ret = unsafe { libc::mkfifoat(dirfd, path.as_ptr(), mode) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn mknodat(dirfd: RawFd, path: &CStr, mode: mode_t, dev: dev_t) -> Result<(), Error> {
+    let ret = unsafe { libc::mknodat(dirfd, path.as_ptr(), mode, dev) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn mkdirat(dirfd: RawFd, path: &CStr, mode: mode_t) -> Result<(), Error> {
+    let ret = unsafe { libc::mkdirat(dirfd, path.as_ptr(), mode) };
     if ret < 0 {
         return Err(Error::last_os_error());
     }
     Ok(())
 }
 
-pub fn mknod(path: &CStr, mode: mode_t, dev: dev_t) -> Result<(), Error> {
-    let ret = unsafe { libc::mknod(path.as_ptr(), mode, dev) };
+/// Create all missing directories in the `path` relative to `dirfd`.
+///
+/// Calls `on_create` for each newly created directory.
+pub fn create_dir_all_at<F: FnMut(&CStr)>(
+    dirfd: RawFd,
+    path: &Path,
+    on_create: &mut F,
+) -> Result<(), Error> {
+    if path == Path::new("") {
+        return Ok(());
+    }
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    match mkdirat(dirfd, &c_path, 0o777) {
+        Ok(()) => {
+            on_create(&c_path);
+            return Ok(());
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => return Ok(()),
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        Err(_) => {}
+    }
+    if let Some(parent) = path.parent() {
+        create_dir_all_at(dirfd, parent, on_create)?;
+    }
+    match mkdirat(dirfd, &c_path, 0o777) {
+        Ok(()) => {
+            on_create(&c_path);
+            Ok(())
+        }
+        Err(e) if e.kind() != ErrorKind::AlreadyExists => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Find the first component of the `path` relative to `dirfd` that is a symbolic link.
+///
+/// Each component is opened with `O_NOFOLLOW`; the search stops at the first component
+/// that is missing or is not a directory.
+/// The `path` has to be relative: the root directory component is ignored.
+pub fn find_symlink_at(dirfd: RawFd, path: &Path) -> Result<Option<PathBuf>, Error> {
+    let mut dir: Option<File> = None;
+    let mut prefix = PathBuf::new();
+    for component in path.components() {
+        let Component::Normal(name) = component else {
+            continue;
+        };
+        prefix.push(name);
+        let fd = dir.as_ref().map_or(dirfd, |dir| dir.as_raw_fd());
+        let c_name = CString::new(name.as_bytes())?;
+        match openat(fd, &c_name, O_SEARCH | O_DIRECTORY | O_NOFOLLOW, 0) {
+            Ok(file) => dir = Some(file),
+            Err(_) => {
+                return match fstatat(fd, &c_name) {
+                    Ok(stat) if stat.st_mode & S_IFMT == S_IFLNK => Ok(Some(prefix)),
+                    _ => Ok(None),
+                };
+            }
+        }
+    }
+    Ok(None)
+}
+
+pub fn symlinkat(target: &CStr, dirfd: RawFd, path: &CStr) -> Result<(), Error> {
+    let ret = unsafe { libc::symlinkat(target.as_ptr(), dirfd, path.as_ptr()) };
     if ret < 0 {
         return Err(Error::last_os_error());
     }
     Ok(())
 }
 
-pub fn set_file_modified_time(path: &CStr, t: SystemTime) -> Result<(), Error> {
+pub fn linkat(dirfd: RawFd, original: &CStr, path: &CStr) -> Result<(), Error> {
+    let ret = unsafe { libc::linkat(dirfd, original.as_ptr(), dirfd, path.as_ptr(), 0) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn openat(dirfd: RawFd, path: &CStr, flags: c_int, mode: mode_t) -> Result<File, Error> {
+    let fd = unsafe {
+        libc::openat(
+            dirfd,
+            path.as_ptr(),
+            flags | libc::O_CLOEXEC,
+            mode as libc::c_uint,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+pub fn fstatat(dirfd: RawFd, path: &CStr) -> Result<libc::stat, Error> {
+    let mut stat = MaybeUninit::<libc::stat>::uninit();
+    let ret =
+        unsafe { libc::fstatat(dirfd, path.as_ptr(), stat.as_mut_ptr(), AT_SYMLINK_NOFOLLOW) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(unsafe { stat.assume_init() })
+}
+
+pub fn fchmodat(dirfd: RawFd, path: &CStr, mode: mode_t) -> Result<(), Error> {
+    let ret = unsafe { libc::fchmodat(dirfd, path.as_ptr(), mode, 0) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn set_file_modified_time(dirfd: RawFd, path: &CStr, t: SystemTime) -> Result<(), Error> {
     let Ok(d) = t.duration_since(SystemTime::UNIX_EPOCH) else {
         return Ok(());
     };
@@ -43,22 +191,293 @@ pub fn set_file_modified_time(path: &CStr, t: SystemTime) -> Result<(), Error> {
             tv_nsec: d.subsec_nanos() as libc::c_long,
         },
     ];
-    let ret =
-        unsafe { libc::utimensat(AT_FDCWD, path.as_ptr(), times.as_ptr(), AT_SYMLINK_NOFOLLOW) };
+    let ret = unsafe { libc::utimensat(dirfd, path.as_ptr(), times.as_ptr(), AT_SYMLINK_NOFOLLOW) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Change the owner of the file without following symbolic links.
+///
+/// `None` means unchanged.
+pub fn lchownat(
+    dirfd: RawFd,
+    path: &CStr,
+    uid: Option<uid_t>,
+    gid: Option<gid_t>,
+) -> Result<(), Error> {
+    // -1 means unchanged
+    let uid = uid.unwrap_or(uid_t::MAX);
+    let gid = gid.unwrap_or(gid_t::MAX);
+    let ret = unsafe { libc::fchownat(dirfd, path.as_ptr(), uid, gid, AT_SYMLINK_NOFOLLOW) };
     if ret < 0 {
         return Err(Error::last_os_error());
     }
     Ok(())
 }
 
-pub fn lchown(path: &CStr, uid: uid_t, gid: gid_t) -> Result<(), Error> {
-    let ret = unsafe { libc::lchown(path.as_ptr(), uid, gid) };
+pub fn unlinkat(dirfd: RawFd, path: &CStr, flags: c_int) -> Result<(), Error> {
+    let ret = unsafe { libc::unlinkat(dirfd, path.as_ptr(), flags) };
     if ret < 0 {
         return Err(Error::last_os_error());
     }
     Ok(())
 }
 
+pub fn renameat(dirfd: RawFd, old_path: &CStr, new_path: &CStr) -> Result<(), Error> {
+    let ret = unsafe { libc::renameat(dirfd, old_path.as_ptr(), dirfd, new_path.as_ptr()) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Remove the directory `path` relative to `dirfd` with all its contents.
+///
+/// Symbolic links are never followed.
+pub fn remove_dir_all_at(dirfd: RawFd, path: &CStr) -> Result<(), Error> {
+    let dir = openat(dirfd, path, O_RDONLY | O_DIRECTORY | O_NOFOLLOW, 0)?;
+    let dir = unsafe { libc::fdopendir(dir.into_raw_fd()) };
+    if dir.is_null() {
+        return Err(Error::last_os_error());
+    }
+    let result = remove_dir_contents(dir);
+    unsafe { libc::closedir(dir) };
+    result?;
+    unlinkat(dirfd, path, AT_REMOVEDIR)
+}
+
+fn remove_dir_contents(dir: *mut libc::DIR) -> Result<(), Error> {
+    let fd = unsafe { libc::dirfd(dir) };
+    loop {
+        let entry = unsafe { libc::readdir(dir) };
+        if entry.is_null() {
+            break;
+        }
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+        if matches!(name.to_bytes(), b"." | b"..") {
+            continue;
+        }
+        if (fstatat(fd, name)?.st_mode & S_IFMT) == S_IFDIR {
+            remove_dir_all_at(fd, name)?;
+        } else {
+            unlinkat(fd, name, 0)?;
+        }
+    }
+    Ok(())
+}
+
+/// The total size of the data regions of the `file` of `file_size` bytes.
+///
+/// Returns `None` if the file has no holes or the file system doesn't report them.
+/// The file position is preserved.
+#[cfg(target_os = "linux")]
+pub fn sparse_data_len(file: &File, file_size: u64) -> Result<Option<u64>, Error> {
+    use std::os::fd::AsRawFd;
+    fn lseek(fd: RawFd, offset: u64, whence: c_int) -> Result<u64, Error> {
+        let ret = unsafe { libc::lseek(fd, offset as libc::off_t, whence) };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(ret as u64)
+    }
+    let fd = file.as_raw_fd();
+    let position = lseek(fd, 0, libc::SEEK_CUR)?;
+    let mut data_len = 0;
+    let mut offset = 0;
+    let result = loop {
+        if offset >= file_size {
+            break Ok(());
+        }
+        let data = match lseek(fd, offset, libc::SEEK_DATA) {
+            Ok(data) => data,
+            // no more data
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        let hole = match lseek(fd, data, libc::SEEK_HOLE) {
+            Ok(hole) => hole.min(file_size),
+            Err(e) => break Err(e),
+        };
+        data_len += hole.saturating_sub(data);
+        offset = hole;
+    };
+    lseek(fd, position, libc::SEEK_SET)?;
+    match result {
+        Ok(()) if data_len < file_size => Ok(Some(data_len)),
+        Ok(()) => Ok(None),
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Copy at most `len` bytes from file descriptor `from` to `to` in the kernel
+/// starting from their current offsets.
+///
+/// Tries `copy_file_range` first, then `sendfile`.
+/// Returns the number of bytes copied which is less than `len`
+/// if the end of the file is reached or none of the system calls are supported.
+#[cfg(target_os = "linux")]
+pub fn kernel_copy(from: RawFd, to: RawFd, len: u64) -> Result<u64, Error> {
+    // the maximum number of bytes per call
+    const MAX_LEN: u64 = 1 << 30;
+    let mut copied = 0;
+    let mut use_copy_file_range = true;
+    while copied < len {
+        let n = (len - copied).min(MAX_LEN) as usize;
+        let ret = unsafe {
+            if use_copy_file_range {
+                libc::copy_file_range(from, std::ptr::null_mut(), to, std::ptr::null_mut(), n, 0)
+            } else {
+                libc::sendfile(to, from, std::ptr::null_mut(), n)
+            }
+        };
+        if ret < 0 {
+            let e = Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(
+                    libc::EXDEV | libc::ENOSYS | libc::EOPNOTSUPP | libc::EINVAL | libc::EBADF,
+                ) if use_copy_file_range && copied == 0 => {
+                    use_copy_file_range = false;
+                    continue;
+                }
+                Some(libc::ENOSYS | libc::EINVAL) if copied == 0 => break,
+                _ => return Err(e),
+            }
+        }
+        if ret == 0 {
+            break;
+        }
+        copied += ret as u64;
+    }
+    Ok(copied)
+}
+
+/// Get the names and the values of the extended attributes of the file descriptor.
+#[cfg(target_os = "linux")]
+pub fn fgetxattrs(fd: RawFd) -> Result<Vec<(CString, Vec<u8>)>, Error> {
+    get_xattrs(
+        |buf, len| unsafe { libc::flistxattr(fd, buf, len) },
+        |name, buf, len| unsafe { libc::fgetxattr(fd, name, buf, len) },
+    )
+}
+
+/// Get the names and the values of the extended attributes of the file.
+///
+/// Symbolic links are followed only if `follow` is `true`.
+#[cfg(target_os = "linux")]
+pub fn getxattrs(path: &CStr, follow: bool) -> Result<Vec<(CString, Vec<u8>)>, Error> {
+    let path = path.as_ptr();
+    get_xattrs(
+        |buf, len| unsafe {
+            if follow {
+                libc::listxattr(path, buf, len)
+            } else {
+                libc::llistxattr(path, buf, len)
+            }
+        },
+        |name, buf, len| unsafe {
+            if follow {
+                libc::getxattr(path, name, buf, len)
+            } else {
+                libc::lgetxattr(path, name, buf, len)
+            }
+        },
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn get_xattrs<L, G>(list: L, get: G) -> Result<Vec<(CString, Vec<u8>)>, Error>
+where
+    L: Fn(*mut libc::c_char, libc::size_t) -> libc::ssize_t,
+    G: Fn(*const libc::c_char, *mut libc::c_void, libc::size_t) -> libc::ssize_t,
+{
+    let Some(names) = read_xattr_buf(|buf, len| list(buf.cast(), len))? else {
+        return Ok(Vec::new());
+    };
+    let mut xattrs = Vec::new();
+    for name in names.split(|ch| *ch == 0).filter(|name| !name.is_empty()) {
+        let name = CString::new(name)?;
+        // the attribute might have been removed concurrently
+        if let Some(value) = read_xattr_buf(|buf, len| get(name.as_ptr(), buf, len))? {
+            xattrs.push((name, value));
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Call `f` with the buffer of the required size until the value fits.
+///
+/// Returns `None` if extended attributes are not supported or the attribute doesn't exist.
+#[cfg(target_os = "linux")]
+fn read_xattr_buf<F>(f: F) -> Result<Option<Vec<u8>>, Error>
+where
+    F: Fn(*mut libc::c_void, libc::size_t) -> libc::ssize_t,
+{
+    loop {
+        let len = f(std::ptr::null_mut(), 0);
+        let len = match check_xattr_ret(len)? {
+            Some(0) => return Ok(Some(Vec::new())),
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let mut buf = vec![0_u8; len];
+        match check_xattr_ret(f(buf.as_mut_ptr().cast(), buf.len())) {
+            Ok(Some(n)) => {
+                buf.truncate(n);
+                return Ok(Some(buf));
+            }
+            Ok(None) => return Ok(None),
+            // the value has grown, retry
+            Err(e) if e.raw_os_error() == Some(libc::ERANGE) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_xattr_ret(ret: libc::ssize_t) -> Result<Option<usize>, Error> {
+    if ret < 0 {
+        let e = Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENOTSUP | libc::ENODATA) => Ok(None),
+            _ => Err(e),
+        };
+    }
+    Ok(Some(ret as usize))
+}
+
+/// Set the extended attribute of the file descriptor.
+#[cfg(target_os = "linux")]
+pub fn fsetxattr(fd: RawFd, name: &CStr, value: &[u8]) -> Result<(), Error> {
+    let ret = unsafe { libc::fsetxattr(fd, name.as_ptr(), value.as_ptr().cast(), value.len(), 0) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn statvfs(path: &Path) -> Result<libc::statvfs, Error> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    let ret = unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(unsafe { stat.assume_init() })
+}
+
+pub fn fstatvfs(fd: RawFd) -> Result<libc::statvfs, Error> {
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    let ret = unsafe { libc::fstatvfs(fd, stat.as_mut_ptr()) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(unsafe { stat.assume_init() })
+}
+
 pub fn path_to_c_string(path: PathBuf) -> Result<CString, Error> {
     Ok(CString::new(path.into_os_string().into_vec())?)
 }
@@ -0,0 +1,431 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::append_error;
+use crate::makedev;
+use crate::spec::parse_number;
+use crate::Builder;
+use crate::EditMetadata;
+use crate::FileType;
+use crate::Metadata;
+
+impl<W: Write, E: EditMetadata> Builder<W, E> {
+    /// Append the entries described by the mtree(5) specification read from `reader`.
+    ///
+    /// Each entry is a path followed by space-separated `keyword=value` pairs.
+    /// The following keywords are supported:
+    /// `type` (`file`, `dir`, `link`, `block`, `char`, `fifo` or `socket`; `file` by default),
+    /// `mode` (octal), `uid`, `gid`, `time` (seconds since Unix epoch, the fraction is ignored),
+    /// `link` (symbolic link target), `device` (`<format>,<major>,<minor>` or the raw number)
+    /// and `contents` (the file that stores the contents of a regular file).
+    /// The other keywords (`uname`, `gname`, `size`, checksums, etc.) are ignored,
+    /// i.e. the owner is specified only by numeric ids.
+    /// `/set` and `/unset` change the default keywords of the subsequent entries.
+    ///
+    /// The paths that contain `/` are relative to the root of the archive.
+    /// The other paths are relative to the last directory specified this way,
+    /// and `..` goes back to the parent directory.
+    /// The paths and the link targets may contain `\ooo` octal and `\\` escapes.
+    /// The contents of the regular files are read from `contents` or from the path itself
+    /// and are resolved relative to `directory`.
+    /// Empty lines and the lines starting with `#` are ignored,
+    /// the backslash at the end of the line continues it onto the next line.
+    ///
+    /// Returns the number of appended entries.
+    pub fn append_mtree<R: BufRead, P: AsRef<Path>>(
+        &mut self,
+        reader: R,
+        directory: P,
+    ) -> Result<u64, Error> {
+        let directory = directory.as_ref();
+        let mut state = MtreeState::default();
+        let mut num_entries = 0;
+        let mut line = Vec::new();
+        let mut line_number = 0;
+        for (i, physical_line) in reader.split(b'\n').enumerate() {
+            let physical_line = physical_line?;
+            if line.is_empty() {
+                line_number = i + 1;
+            }
+            match physical_line.strip_suffix(b"\\") {
+                Some(prefix) => {
+                    line.extend_from_slice(prefix);
+                    line.push(b' ');
+                    continue;
+                }
+                None => line.extend_from_slice(&physical_line),
+            }
+            let invalid_line = |e: &str| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("mtree line {}: {}", line_number, e),
+                )
+            };
+            let entry = state.parse_line(&line).map_err(invalid_line)?;
+            line.clear();
+            let Some(MtreeEntry {
+                path,
+                mut metadata,
+                contents,
+            }) = entry
+            else {
+                continue;
+            };
+            let Some(inner_path) = self.map_path(&path).map(Cow::into_owned) else {
+                continue;
+            };
+            let result = match contents {
+                Contents::File(location) => {
+                    let location = directory.join(location);
+                    let file = File::open(&location).map_err(|e| {
+                        Error::new(e.kind(), format!("failed to open {:?}: {}", location, e))
+                    })?;
+                    metadata.file_size = file.metadata()?.len();
+                    self.append_entry(metadata, &inner_path, file)
+                }
+                Contents::Data(mut data) => {
+                    if metadata.is_symlink() {
+                        self.terminate_link_target(&mut data);
+                    }
+                    metadata.file_size = data.len() as u64;
+                    self.append_entry(metadata, &inner_path, &data[..])
+                }
+            };
+            result.map_err(|e| append_error(&path, e))?;
+            num_entries += 1;
+        }
+        if !line.is_empty() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("mtree line {}: unterminated line", line_number),
+            ));
+        }
+        Ok(num_entries)
+    }
+}
+
+/// Parsed entry of the specification.
+struct MtreeEntry {
+    path: PathBuf,
+    metadata: Metadata,
+    contents: Contents,
+}
+
+/// The source of the entry's contents.
+enum Contents {
+    /// Regular file contents are stored in the file.
+    File(PathBuf),
+    /// In-memory contents, e.g. symbolic link target.
+    Data(Vec<u8>),
+}
+
+/// Mtree parser state.
+#[derive(Default)]
+struct MtreeState {
+    /// The keywords set by `/set`.
+    defaults: HashMap<Vec<u8>, Vec<u8>>,
+    /// The current directory.
+    cwd: PathBuf,
+}
+
+impl MtreeState {
+    /// Returns `None` for empty lines, comments and commands.
+    fn parse_line(&mut self, line: &[u8]) -> Result<Option<MtreeEntry>, &'static str> {
+        let mut words = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty());
+        let Some(name) = words.next() else {
+            return Ok(None);
+        };
+        if name.starts_with(b"#") {
+            return Ok(None);
+        }
+        match name {
+            b"/set" => {
+                for word in words {
+                    let (key, value) = split_keyword(word);
+                    self.defaults.insert(key.to_vec(), value.to_vec());
+                }
+                return Ok(None);
+            }
+            b"/unset" => {
+                for word in words {
+                    if word == b"all" {
+                        self.defaults.clear();
+                    } else {
+                        self.defaults.remove(word);
+                    }
+                }
+                return Ok(None);
+            }
+            b".." => {
+                if !self.cwd.pop() {
+                    return Err("`..` outside of the root directory");
+                }
+                return Ok(None);
+            }
+            _ if name.starts_with(b"/") => return Err("unknown command"),
+            _ => {}
+        }
+        let mut keywords = self.defaults.clone();
+        for word in words {
+            let (key, value) = split_keyword(word);
+            keywords.insert(key.to_vec(), value.to_vec());
+        }
+        let name = unescape(name)?;
+        let is_full_path = name.contains(&b'/');
+        let name = name.strip_prefix(b"./").unwrap_or(&name);
+        let name = Path::new(OsStr::from_bytes(name));
+        let path = if is_full_path {
+            name.to_path_buf()
+        } else if name == Path::new(".") && !self.cwd.as_os_str().is_empty() {
+            self.cwd.clone()
+        } else {
+            self.cwd.join(name)
+        };
+        let keyword = |key: &[u8]| keywords.get(key).map(|value| value.as_slice());
+        let number = |key: &[u8], radix, what| match keyword(key) {
+            Some(value) => parse_number(value, radix).map(Some).ok_or(what),
+            None => Ok(None),
+        };
+        let file_type = match keyword(b"type").unwrap_or(b"file") {
+            b"file" => FileType::Regular,
+            b"dir" => FileType::Directory,
+            b"link" => FileType::Symlink,
+            b"block" => FileType::BlockDevice,
+            b"char" => FileType::CharDevice,
+            b"fifo" => FileType::Fifo,
+            b"socket" => FileType::Socket,
+            _ => return Err("unknown file type"),
+        };
+        let default_mode = match file_type {
+            FileType::Directory => 0o755,
+            FileType::Symlink => 0o777,
+            _ => 0o644,
+        };
+        let mode = number(b"mode", 8, "invalid mode")?.unwrap_or(default_mode);
+        if mode > 0o7777 {
+            return Err("invalid mode");
+        }
+        let mut metadata = Metadata::new(file_type, mode as u32);
+        let id = |value: Option<u64>| value.unwrap_or(0).try_into().ok();
+        metadata.uid = id(number(b"uid", 10, "invalid uid")?).ok_or("invalid uid")?;
+        metadata.gid = id(number(b"gid", 10, "invalid gid")?).ok_or("invalid gid")?;
+        if let Some(time) = keyword(b"time") {
+            let seconds = time.split(|b| *b == b'.').next().unwrap_or(time);
+            metadata.mtime = std::str::from_utf8(seconds)
+                .ok()
+                .and_then(|seconds| seconds.parse().ok())
+                .ok_or("invalid time")?;
+        }
+        let contents = match file_type {
+            FileType::Regular => {
+                let location = match keyword(b"contents").or_else(|| keyword(b"content")) {
+                    Some(location) => PathBuf::from(OsStr::from_bytes(&unescape(location)?)),
+                    None => path.clone(),
+                };
+                Contents::File(location)
+            }
+            FileType::Symlink => {
+                // the target is terminated by `append_mtree` as in `append_path`
+                let target = unescape(keyword(b"link").ok_or("missing link target")?)?;
+                Contents::Data(target)
+            }
+            FileType::BlockDevice | FileType::CharDevice => {
+                metadata.rdev = parse_device(keyword(b"device").ok_or("missing device")?)
+                    .ok_or("invalid device")?;
+                Contents::Data(Vec::new())
+            }
+            _ => Contents::Data(Vec::new()),
+        };
+        if file_type == FileType::Directory && !is_full_path && path != Path::new(".") {
+            self.cwd = path.clone();
+        }
+        Ok(Some(MtreeEntry {
+            path,
+            metadata,
+            contents,
+        }))
+    }
+}
+
+/// Split `key=value` pair; the value is empty if there is no `=`.
+fn split_keyword(word: &[u8]) -> (&[u8], &[u8]) {
+    match word.iter().position(|b| *b == b'=') {
+        Some(i) => (&word[..i], &word[i + 1..]),
+        None => (word, b""),
+    }
+}
+
+/// Decode `\ooo` and `\\` escapes.
+fn unescape(s: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut output = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        if s[i] != b'\\' {
+            output.push(s[i]);
+            i += 1;
+            continue;
+        }
+        match s.get(i + 1..i + 4) {
+            Some(digits) if digits.iter().all(|b| (b'0'..=b'7').contains(b)) => {
+                let value = digits
+                    .iter()
+                    .fold(0_u32, |value, b| value * 8 + (b - b'0') as u32);
+                output.push(u8::try_from(value).map_err(|_| "invalid escape")?);
+                i += 4;
+            }
+            _ => match s.get(i + 1) {
+                Some(b'\\') => {
+                    output.push(b'\\');
+                    i += 2;
+                }
+                _ => return Err("invalid escape"),
+            },
+        }
+    }
+    Ok(output)
+}
+
+/// Parse `<format>,<major>,<minor>` or the raw device number.
+fn parse_device(value: &[u8]) -> Option<u64> {
+    let value = std::str::from_utf8(value).ok()?;
+    let fields: Vec<&str> = value.split(',').collect();
+    match fields[..] {
+        [raw] => raw.parse().ok(),
+        [_format, major, minor] => {
+            let major: u32 = major.parse().ok()?;
+            let minor: u32 = minor.parse().ok()?;
+            Some(makedev(major.into(), minor.into()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use tempfile::TempDir;
+
+    use crate::Archive;
+    use crate::Builder;
+    use crate::FileType;
+
+    #[test]
+    fn append_mtree() {
+        let workdir = TempDir::new().unwrap();
+        std::fs::create_dir(workdir.path().join("bin")).unwrap();
+        std::fs::write(workdir.path().join("bin/busybox"), b"elf").unwrap();
+        std::fs::write(workdir.path().join("motd.txt"), b"hello").unwrap();
+        let mtree = br"#mtree
+/set type=file uid=0 gid=0 mode=644
+. type=dir mode=755
+bin type=dir mode=755 time=1700000000.123456789
+    busybox mode=755 \
+        size=3 sha256digest=abc
+    sh type=link link=busybox mode=777
+..
+dev type=dir mode=755
+    console type=char mode=600 device=native,5,1
+..
+./etc/motd uid=1 contents=motd.txt
+./my\040file contents=motd.txt
+";
+        let mut builder = Builder::new(Vec::new());
+        assert_eq!(8, builder.append_mtree(&mtree[..], workdir.path()).unwrap());
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut entries = Vec::new();
+        while let Some(mut entry) = archive.next_entry().unwrap() {
+            let mut data = Vec::new();
+            entry.reader.read_to_end(&mut data).unwrap();
+            let metadata = &entry.metadata;
+            entries.push((
+                entry.path.to_str().unwrap().to_string(),
+                metadata.file_type().unwrap(),
+                metadata.file_mode(),
+                metadata.uid(),
+                data,
+            ));
+        }
+        assert_eq!(
+            vec![
+                (".".into(), FileType::Directory, 0o755, 0, vec![]),
+                ("bin".into(), FileType::Directory, 0o755, 0, vec![]),
+                (
+                    "bin/busybox".into(),
+                    FileType::Regular,
+                    0o755,
+                    0,
+                    b"elf".to_vec()
+                ),
+                (
+                    "bin/sh".into(),
+                    FileType::Symlink,
+                    0o777,
+                    0,
+                    b"busybox\0".to_vec()
+                ),
+                ("dev".into(), FileType::Directory, 0o755, 0, vec![]),
+                ("dev/console".into(), FileType::CharDevice, 0o600, 0, vec![]),
+                (
+                    "etc/motd".into(),
+                    FileType::Regular,
+                    0o644,
+                    1,
+                    b"hello".to_vec()
+                ),
+                (
+                    "my file".into(),
+                    FileType::Regular,
+                    0o644,
+                    0,
+                    b"hello".to_vec()
+                ),
+            ],
+            entries
+        );
+        let mut archive = Archive::new(&bytes[..]);
+        let entry = archive.next_entry().unwrap().unwrap();
+        drop(entry);
+        assert_eq!(
+            1_700_000_000,
+            archive.next_entry().unwrap().unwrap().metadata.mtime()
+        );
+        for mtree in [
+            &b"file type=door"[..],
+            b"..",
+            b"file mode=999",
+            b"/bad",
+            b"link type=link",
+            b"dev type=char",
+            b"missing",
+            b"file \\",
+        ] {
+            let mut builder = Builder::new(Vec::new());
+            assert!(builder.append_mtree(mtree, workdir.path()).is_err());
+        }
+        // the target is not terminated by NUL byte as in `append_path`
+        let mut builder = Builder::new(Vec::new());
+        builder.gnu_compat(true);
+        builder
+            .append_mtree(&b"sh type=link link=busybox"[..], workdir.path())
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut entry = archive.next_entry().unwrap().unwrap();
+        let mut target = Vec::new();
+        entry.reader.read_to_end(&mut target).unwrap();
+        assert_eq!(b"busybox", &target[..]);
+    }
+}
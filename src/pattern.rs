@@ -0,0 +1,189 @@
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Include and exclude patterns.
+#[derive(Clone, Default)]
+pub(crate) struct Patterns {
+    pub(crate) include: Vec<Vec<u8>>,
+    pub(crate) exclude: Vec<Vec<u8>>,
+}
+
+impl Patterns {
+    /// Returns `true` if the path matches any of the include patterns (or there are none)
+    /// and doesn't match any of the exclude patterns.
+    pub(crate) fn is_selected(&self, path: &Path) -> bool {
+        let path = path.as_os_str().as_bytes();
+        (self.include.is_empty() || self.include.iter().any(|p| glob_match(p, path)))
+            && !self.exclude.iter().any(|p| glob_match(p, path))
+    }
+}
+
+pub(crate) fn to_patterns<I, P>(patterns: I) -> impl Iterator<Item = Vec<u8>>
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<OsStr>,
+{
+    patterns
+        .into_iter()
+        .map(|pattern| pattern.as_ref().as_bytes().to_vec())
+}
+
+/// Match `name` against shell wildcard `pattern`.
+///
+/// `*` matches any sequence of bytes including `/`, `?` matches any byte,
+/// `[...]` matches any of the enclosed bytes or ranges (`[!...]` and `[^...]` negate the set),
+/// and `\` escapes the next byte.
+/// This is how `fnmatch(3)` works without `FNM_PATHNAME`.
+pub(crate) fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // the position after the last `*` in the pattern and the matching position in the name
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                backtrack = Some((p, n));
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+            Some(b'[') => {
+                if let Some((matches, len)) = match_class(&pattern[p..], name[n]) {
+                    if matches {
+                        p += len;
+                        n += 1;
+                        continue;
+                    }
+                } else if name[n] == b'[' {
+                    // unterminated class is a literal `[`
+                    p += 1;
+                    n += 1;
+                    continue;
+                }
+            }
+            Some(b'\\') if pattern.get(p + 1) == Some(&name[n]) => {
+                p += 2;
+                n += 1;
+                continue;
+            }
+            // the escaped byte doesn't match
+            Some(b'\\') if p + 1 < pattern.len() => {}
+            Some(b) if *b == name[n] => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match backtrack {
+            Some((star_p, star_n)) => {
+                p = star_p;
+                n = star_n + 1;
+                backtrack = Some((star_p, star_n + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|b| *b == b'*')
+}
+
+/// Match the byte against the class at the beginning of the pattern.
+///
+/// Returns whether the byte matches and the length of the class
+/// or `None` if the class is not terminated.
+fn match_class(pattern: &[u8], byte: u8) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negate = matches!(pattern.get(i), Some(b'!' | b'^'));
+    if negate {
+        i += 1;
+    }
+    let mut matches = false;
+    let mut first = true;
+    loop {
+        let mut start = *pattern.get(i)?;
+        if start == b']' && !first {
+            return Some((matches != negate, i + 1));
+        }
+        first = false;
+        if start == b'\\' {
+            i += 1;
+            start = *pattern.get(i)?;
+        }
+        i += 1;
+        let mut end = start;
+        if pattern.get(i) == Some(&b'-') && pattern.get(i + 1).is_some_and(|b| *b != b']') {
+            end = pattern[i + 1];
+            if end == b'\\' {
+                end = *pattern.get(i + 2)?;
+                i += 1;
+            }
+            i += 2;
+        }
+        if (start..=end).contains(&byte) {
+            matches = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        for (pattern, name, expected) in [
+            ("", "", true),
+            ("", "a", false),
+            ("*", "", true),
+            ("*", "usr/bin/sh", true),
+            ("usr/*", "usr/bin/sh", true),
+            ("usr/*", "usr", false),
+            ("*.so", "lib/libc.so", true),
+            ("*.so", "lib/libc.so.6", false),
+            ("*.so*", "lib/libc.so.6", true),
+            ("a*b*c", "aXbYbZc", true),
+            ("a*b*c", "aXbYbZ", false),
+            ("?", "a", true),
+            ("?", "ab", false),
+            ("dev/tty?", "dev/tty1", true),
+            ("[abc]", "b", true),
+            ("[abc]", "d", false),
+            ("[!abc]", "d", true),
+            ("[^abc]", "a", false),
+            ("[a-c]x", "bx", true),
+            ("[a-c]x", "dx", false),
+            ("[]]", "]", true),
+            ("[a-]", "-", true),
+            ("[", "[", true),
+            ("a[", "a[", true),
+            ("\\*", "*", true),
+            ("\\*", "a", false),
+            ("[\\]]", "]", true),
+        ] {
+            assert_eq!(
+                expected,
+                glob_match(pattern.as_bytes(), name.as_bytes()),
+                "pattern = {:?}, name = {:?}",
+                pattern,
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn include_exclude() {
+        let patterns = Patterns {
+            include: to_patterns(["etc/*", "usr/bin/*"]).collect(),
+            exclude: to_patterns(["*.bak"]).collect(),
+        };
+        assert!(patterns.is_selected(Path::new("etc/passwd")));
+        assert!(patterns.is_selected(Path::new("usr/bin/sh")));
+        assert!(!patterns.is_selected(Path::new("etc/passwd.bak")));
+        assert!(!patterns.is_selected(Path::new("usr/lib/libc.so")));
+        assert!(Patterns::default().is_selected(Path::new("anything")));
+    }
+}
@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::constants::*;
+use crate::xattrs_target;
+use crate::Entry;
+use crate::EntryRules;
+use crate::FileType;
+use crate::HardLinkPolicy;
+use crate::MetadataId;
+use crate::NlinkPolicy;
+use crate::Selection;
+use crate::UnpackOptions;
+
+/// The change that unpacking an entry would make.
+///
+/// Returned by [`Archive::plan_unpack`](crate::Archive::plan_unpack).
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct PlannedChange {
+    /// The path of the entry in the archive.
+    pub entry_path: PathBuf,
+    /// The path of the file relative to the target directory.
+    ///
+    /// The path is absolute for the absolute paths kept by [`AbsolutePathPolicy::Keep`](crate::AbsolutePathPolicy::Keep).
+    /// Empty for skipped entries that point outside of the target directory,
+    /// have no components left after stripping, or are excluded by the patterns or the rename hook.
+    pub path: PathBuf,
+    /// The change.
+    pub change: Change,
+}
+
+/// The kind of the [`PlannedChange`].
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Change {
+    /// The file doesn't exist and would be created.
+    Create,
+    /// The file already exists and would be updated.
+    Overwrite(Differences),
+    /// The file already exists and matches the entry.
+    ///
+    /// The contents are not compared.
+    Unchanged,
+    /// The existing file would be removed, i.e. the file of another type
+    /// according to [`ConflictPolicy::Replace`](crate::ConflictPolicy::Replace)
+    /// or the symbolic link or the special file
+    /// according to [`OverwritePolicy`](crate::OverwritePolicy).
+    Replace,
+    /// The entry would be unpacked as a hard link to the specified path.
+    Link(PathBuf),
+    /// The file already exists and unpacking would fail.
+    AlreadyExists,
+    /// The entry would be skipped.
+    Skip(SkipReason),
+}
+
+/// The attributes of the existing file that differ from the entry.
+///
+/// The modification time, the owner and the group are compared only if unpacking would set them.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct Differences {
+    /// File size differs.
+    pub size: bool,
+    /// Modification time differs.
+    pub modified: bool,
+    /// Permission bits differ.
+    pub mode: bool,
+    /// User or group id differs.
+    pub owner: bool,
+}
+
+impl Differences {
+    /// Returns `true` if any of the attributes differs.
+    pub fn any(&self) -> bool {
+        self.size || self.modified || self.mode || self.owner
+    }
+}
+
+/// Why the entry would be skipped.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum SkipReason {
+    /// The path points outside of the target directory.
+    Outside,
+    /// The path points to the target directory itself.
+    Target,
+    /// The directory already exists and [`UnpackOptions::update_existing_dirs`] is not set.
+    ExistingDir,
+    /// The entry conflicts with the existing file
+    /// and [`ConflictPolicy::Skip`](crate::ConflictPolicy::Skip) is used.
+    Conflict,
+    /// Symbolic links are skipped according to [`SymlinkPolicy::Skip`](crate::SymlinkPolicy::Skip).
+    Symlink,
+    /// The path has no components left after [`UnpackOptions::strip_components`].
+    Stripped,
+    /// [`UnpackOptions::rename`] returned `None`.
+    Rename,
+    /// The path doesn't match [`UnpackOptions::include_patterns`]
+    /// or matches [`UnpackOptions::exclude_patterns`].
+    Excluded,
+    /// Devices, FIFOs and sockets are skipped according to
+    /// [`SpecialFilePolicy::Skip`](crate::SpecialFilePolicy::Skip)
+    /// or [`Placeholder::Skip`](crate::Placeholder::Skip).
+    Special,
+    /// The file already exists and [`OverwritePolicy::Never`](crate::OverwritePolicy::Never) is used.
+    Existing,
+    /// The existing file is not older than the entry
+    /// and [`OverwritePolicy::IfNewer`](crate::OverwritePolicy::IfNewer) is used.
+    NotNewer,
+    /// The contents were rejected by [`UnpackOptions::scan_contents`].
+    ///
+    /// This includes the hard links to the rejected files.
+    /// Only unpacking reports this reason because planning doesn't read the contents.
+    Rejected,
+}
+
+impl SkipReason {
+    /// Get the short name of the reason, e.g. `existing-dir`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Outside => "outside",
+            Self::Target => "target",
+            Self::ExistingDir => "existing-dir",
+            Self::Conflict => "conflict",
+            Self::Symlink => "symlink",
+            Self::Stripped => "stripped",
+            Self::Rename => "rename",
+            Self::Excluded => "excluded",
+            Self::Special => "special",
+            Self::Existing => "existing",
+            Self::NotNewer => "not-newer",
+            Self::Rejected => "rejected",
+        }
+    }
+}
+
+/// The state of the planning process.
+pub(crate) struct Planner<'a> {
+    pub(crate) directory: &'a Path,
+    pub(crate) options: &'a UnpackOptions,
+    pub(crate) nlink_policy: NlinkPolicy,
+    pub(crate) preserve_mtime: bool,
+    pub(crate) preserve_owner: bool,
+    // (dev, inode) -> path mapping.
+    pub(crate) hard_links: HashMap<MetadataId, PathBuf>,
+    pub(crate) changes: Vec<PlannedChange>,
+}
+
+impl<'a> Planner<'a> {
+    pub(crate) fn plan_entry<R: Read>(&mut self, entry: &Entry<'_, R>) -> Result<(), Error> {
+        if self.options.xattrs && xattrs_target(&entry.path).is_some() {
+            // not a file
+            return Ok(());
+        }
+        let metadata = &entry.metadata;
+        let rules = EntryRules::new(self.options, self.preserve_owner);
+        let (path, file_type, owner) = match rules.select(&entry.path, metadata)? {
+            Selection::Unpack {
+                path,
+                file_type,
+                owner,
+            } => (path, file_type, owner),
+            Selection::Skip(path, reason) => return self.push(entry, path, Change::Skip(reason)),
+            Selection::Rejected(e) => return Err(e),
+        };
+        let (uid, gid) = owner.unwrap_or_default();
+        let existing = match std::fs::symlink_metadata(self.directory.join(&path)) {
+            Ok(existing) => Some(existing),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let existing_file_type = match existing.as_ref() {
+            Some(existing) => Some(FileType::new(existing.mode())?),
+            None => None,
+        };
+        let conflict = existing_file_type.and_then(|existing| rules.conflict(file_type, existing));
+        if let Some(policy) = conflict {
+            use crate::ConflictPolicy::*;
+            let change = match policy {
+                Error => Change::AlreadyExists,
+                Replace => Change::Replace,
+                Skip => Change::Skip(SkipReason::Conflict),
+            };
+            return self.push(entry, path, change);
+        }
+        if let Some(existing) = existing
+            .as_ref()
+            .filter(|_| file_type != FileType::Directory)
+        {
+            if let Some(reason) = rules.overwrite(metadata, existing.mtime()) {
+                return self.push(entry, path, Change::Skip(reason));
+            }
+        }
+        if self.nlink_policy.is_hard_link(metadata) {
+            use std::collections::hash_map::Entry::*;
+            match self.hard_links.entry(metadata.id()) {
+                Vacant(v) => {
+                    v.insert(path.clone());
+                }
+                Occupied(o) if self.options.hard_links != HardLinkPolicy::Copy => {
+                    let change = Change::Link(o.get().clone());
+                    return self.push(entry, path, change);
+                }
+                Occupied(..) => {}
+            }
+        }
+        let Some(existing) = existing else {
+            return self.push(entry, path, Change::Create);
+        };
+        let change = match (file_type, existing_file_type) {
+            (FileType::Directory, Some(FileType::Directory))
+                if !self.options.update_existing_dirs =>
+            {
+                Change::Skip(SkipReason::ExistingDir)
+            }
+            (FileType::Directory, Some(FileType::Directory))
+            | (FileType::Regular, Some(FileType::Regular)) => {
+                let mode = match self.options.mode_mask {
+                    Some(mask) => metadata.file_mode() & !mask,
+                    None => metadata.file_mode(),
+                };
+                let differences = Differences {
+                    size: file_type == FileType::Regular && existing.len() != metadata.file_size,
+                    modified: self.preserve_mtime
+                        && metadata.mtime() >= self.options.min_mtime
+                        && u64::try_from(existing.mtime()).ok() != Some(metadata.mtime()),
+                    mode: (existing.mode() & FILE_MODE_MASK) != mode,
+                    owner: uid.is_some_and(|uid| existing.uid() != uid)
+                        || gid.is_some_and(|gid| existing.gid() != gid),
+                };
+                if differences.any() {
+                    Change::Overwrite(differences)
+                } else {
+                    Change::Unchanged
+                }
+            }
+            // the other conflicts are resolved above
+            _ => Change::Replace,
+        };
+        self.push(entry, path, change)
+    }
+
+    fn push<R: Read>(
+        &mut self,
+        entry: &Entry<'_, R>,
+        path: PathBuf,
+        change: Change,
+    ) -> Result<(), Error> {
+        self.changes.push(PlannedChange {
+            entry_path: entry.path.clone(),
+            path,
+            change,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::Archive;
+    use crate::Builder;
+    use crate::Metadata;
+    use crate::SpecialFilePolicy;
+    use crate::UnmappedIdPolicy;
+
+    #[test]
+    fn plan_unpack() {
+        let mut builder = Builder::new(Vec::new());
+        builder.append_dir("d", 0o755).unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "d/a", b"hello")
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "d/b", b"world")
+            .unwrap();
+        let mut link = Metadata::regular(0o644, 0);
+        link.set_ino(7);
+        link.set_nlink(2);
+        builder.append_data(link.clone(), "d/c", b"link").unwrap();
+        builder.append_data(link, "d/e", b"link").unwrap();
+        builder
+            .append_data(Metadata::new(FileType::Symlink, 0o777), "l", b"d/a")
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "../x", b"outside")
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let workdir = TempDir::new().unwrap();
+        let plan = |changes: &[Change]| {
+            let actual = Archive::new(&bytes[..])
+                .plan_unpack(workdir.path(), &UnpackOptions::new())
+                .unwrap();
+            let actual: Vec<_> = actual.into_iter().map(|x| x.change).collect();
+            assert_eq!(changes, actual);
+        };
+        plan(&[
+            Change::Create,
+            Change::Create,
+            Change::Create,
+            Change::Create,
+            Change::Link("d/c".into()),
+            Change::Create,
+            Change::Skip(SkipReason::Outside),
+        ]);
+        // the plan doesn't touch the file system
+        assert_eq!(0, std::fs::read_dir(workdir.path()).unwrap().count());
+        Archive::new(&bytes[..]).unpack(workdir.path()).unwrap();
+        std::fs::write(workdir.path().join("d/b"), b"world!").unwrap();
+        plan(&[
+            Change::Skip(SkipReason::ExistingDir),
+            Change::Unchanged,
+            Change::Overwrite(Differences {
+                size: true,
+                ..Default::default()
+            }),
+            Change::Unchanged,
+            Change::Link("d/c".into()),
+            Change::Replace,
+            Change::Skip(SkipReason::Outside),
+        ]);
+    }
+
+    #[test]
+    fn plan_unmapped_ids() {
+        let mut builder = Builder::new(Vec::new());
+        let mut metadata = Metadata::regular(0o644, 0);
+        metadata.set_uid(1000);
+        builder.append_data(metadata, "file", b"").unwrap();
+        let bytes = builder.finish().unwrap();
+        let workdir = TempDir::new().unwrap();
+        for options in [
+            UnpackOptions::new().add_uid_mapping(0, 1),
+            // the policy applies without any mappings
+            UnpackOptions::new(),
+        ] {
+            let options = options
+                .preserve_owner(true)
+                .unmapped_ids(UnmappedIdPolicy::Error);
+            let mut archive = Archive::new(&bytes[..]);
+            let error = archive.plan_unpack(workdir.path(), &options).unwrap_err();
+            assert_eq!(ErrorKind::InvalidData, error.kind());
+        }
+    }
+
+    #[test]
+    fn plan_matches_unpack() {
+        let mut builder = Builder::new(Vec::new());
+        let mut metadata = Metadata::regular(0o644, 0);
+        metadata.set_uid(1000);
+        builder.append_data(metadata, "file", b"").unwrap();
+        // the unmapped id doesn't matter for the skipped entries
+        let mut metadata = Metadata::fifo(0o644);
+        metadata.set_uid(1001);
+        builder.append_data(metadata, "fifo", b"").unwrap();
+        let bytes = builder.finish().unwrap();
+        let options = || {
+            UnpackOptions::new()
+                .preserve_owner(true)
+                .add_uid_mapping(1000, unsafe { libc::geteuid() })
+                .add_gid_mapping(0, unsafe { libc::getegid() })
+                .unmapped_ids(UnmappedIdPolicy::Error)
+                .fifos(SpecialFilePolicy::Skip)
+        };
+        let workdir = TempDir::new().unwrap();
+        let changes: Vec<_> = Archive::new(&bytes[..])
+            .plan_unpack(workdir.path(), &options())
+            .unwrap()
+            .into_iter()
+            .map(|change| change.change)
+            .collect();
+        assert_eq!(
+            vec![Change::Create, Change::Skip(SkipReason::Special)],
+            changes
+        );
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(options());
+        let report = archive.unpack(workdir.path()).unwrap();
+        assert_eq!(
+            vec![(PathBuf::from("fifo"), SkipReason::Special)],
+            report.skipped
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use std::ffi::OsStr;
+use std::io::BufRead;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::append_error;
+use crate::constants::*;
+use crate::Builder;
+use crate::EditMetadata;
+use crate::Metadata;
+
+/// How path records are delimited in the input stream.
+///
+/// Used by [`Builder::append_path_records`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum RecordFormat {
+    /// Records are terminated by the newline character.
+    Newline,
+    /// Records are terminated by NUL character.
+    Nul,
+    /// Each record is preceded by its length in bytes encoded as big-endian 32-bit integer.
+    LengthPrefixed,
+}
+
+impl<W: Write, E: EditMetadata> Builder<W, E> {
+    /// Append the files which paths are read from `reader` as they arrive.
+    ///
+    /// Each record contains the file path optionally followed by TAB character
+    /// and space-separated metadata overrides:
+    /// `mode=<octal permissions>`, `uid=<id>`, `gid=<id>`, `mtime=<seconds since Unix epoch>`,
+    /// e.g. `etc/shadow\tmode=600 uid=0 gid=0`.
+    /// The path is used both as the file system path and the path inside the archive.
+    ///
+    /// The underlying writer is flushed after each entry,
+    /// i.e. the reader can be a pipe that is fed continuously.
+    /// Returns the number of appended entries when the end of the input is reached.
+    pub fn append_path_records<R: BufRead>(
+        &mut self,
+        mut reader: R,
+        format: RecordFormat,
+    ) -> Result<u64, Error> {
+        let mut record = Vec::new();
+        let mut num_records = 0;
+        while read_record(reader.by_ref(), format, &mut record)? {
+            if record.is_empty() {
+                continue;
+            }
+            if self.append_path_record(&record)? {
+                self.flush()?;
+                num_records += 1;
+            }
+        }
+        Ok(num_records)
+    }
+
+    /// Append the file specified by the non-empty record
+    /// (see [`append_path_records`](Self::append_path_records)).
+    ///
+    /// Returns `false` if the path mapper skips the file.
+    pub(crate) fn append_path_record(&mut self, record: &[u8]) -> Result<bool, Error> {
+        let (path, overrides) = parse_record(record)?;
+        let Some(inner_path) = self.map_path(path) else {
+            return Ok(false);
+        };
+        self.append_path_unmapped(path, &inner_path, &mut |metadata| overrides.apply(metadata))
+            .map_err(|e| append_error(path, e))?;
+        Ok(true)
+    }
+}
+
+/// Returns `false` when the end of the input is reached.
+fn read_record<R: BufRead>(
+    mut reader: R,
+    format: RecordFormat,
+    record: &mut Vec<u8>,
+) -> Result<bool, Error> {
+    record.clear();
+    let delimiter = match format {
+        RecordFormat::Newline => b'\n',
+        RecordFormat::Nul => 0_u8,
+        RecordFormat::LengthPrefixed => {
+            let mut len = [0_u8; 4];
+            let mut nread = 0;
+            while nread != len.len() {
+                match reader.read(&mut len[nread..])? {
+                    0 if nread == 0 => return Ok(false),
+                    0 => return Err(ErrorKind::UnexpectedEof.into()),
+                    n => nread += n,
+                }
+            }
+            let len = u32::from_be_bytes(len) as u64;
+            let n = reader.take(len).read_to_end(record)?;
+            if n as u64 != len {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            return Ok(true);
+        }
+    };
+    if reader.read_until(delimiter, record)? == 0 {
+        return Ok(false);
+    }
+    if record.last() == Some(&delimiter) {
+        record.pop();
+    }
+    Ok(true)
+}
+
+fn parse_record(record: &[u8]) -> Result<(&Path, Overrides), Error> {
+    let (path, overrides) = match record.iter().position(|b| *b == b'\t') {
+        Some(i) => (&record[..i], parse_overrides(&record[i + 1..])?),
+        None => (record, Overrides::default()),
+    };
+    Ok((Path::new(OsStr::from_bytes(path)), overrides))
+}
+
+fn parse_overrides(s: &[u8]) -> Result<Overrides, Error> {
+    let invalid_override = |o: &[u8]| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid override `{}`", String::from_utf8_lossy(o)),
+        )
+    };
+    let mut overrides = Overrides::default();
+    for o in s.split(|b| *b == b' ').filter(|o| !o.is_empty()) {
+        let o_str = std::str::from_utf8(o).map_err(|_| invalid_override(o))?;
+        let Some((key, value)) = o_str.split_once('=') else {
+            return Err(invalid_override(o));
+        };
+        match key {
+            "mode" => {
+                let mode = u32::from_str_radix(value, 8).map_err(|_| invalid_override(o))?;
+                overrides.mode = Some(mode & FILE_MODE_MASK);
+            }
+            "uid" => overrides.uid = Some(value.parse().map_err(|_| invalid_override(o))?),
+            "gid" => overrides.gid = Some(value.parse().map_err(|_| invalid_override(o))?),
+            "mtime" => overrides.mtime = Some(value.parse().map_err(|_| invalid_override(o))?),
+            _ => return Err(invalid_override(o)),
+        }
+    }
+    Ok(overrides)
+}
+
+#[derive(Default)]
+struct Overrides {
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mtime: Option<u64>,
+}
+
+impl Overrides {
+    fn apply(&self, metadata: &mut Metadata) {
+        if let Some(mode) = self.mode {
+            metadata.mode = (metadata.mode & !FILE_MODE_MASK) | mode;
+        }
+        if let Some(uid) = self.uid {
+            metadata.uid = uid;
+        }
+        if let Some(gid) = self.gid {
+            metadata.gid = gid;
+        }
+        if let Some(mtime) = self.mtime {
+            metadata.mtime = mtime;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::Archive;
+
+    #[test]
+    fn append_path_records() {
+        let workdir = TempDir::new().unwrap();
+        let file1 = workdir.path().join("file1");
+        let file2 = workdir.path().join("file 2");
+        std::fs::write(&file1, b"1").unwrap();
+        std::fs::write(&file2, b"2").unwrap();
+        let mut records = Vec::new();
+        for (format, delimiter) in [
+            (RecordFormat::Newline, Some(b'\n')),
+            (RecordFormat::Nul, Some(0_u8)),
+            (RecordFormat::LengthPrefixed, None),
+        ] {
+            let mut input = Vec::new();
+            for record in [
+                file1.as_os_str().as_bytes().to_vec(),
+                [
+                    file2.as_os_str().as_bytes(),
+                    b"\tmode=600 uid=1 gid=2 mtime=3",
+                ]
+                .concat(),
+            ] {
+                match delimiter {
+                    Some(delimiter) => {
+                        input.extend(&record);
+                        input.push(delimiter);
+                    }
+                    None => {
+                        input.extend((record.len() as u32).to_be_bytes());
+                        input.extend(&record);
+                    }
+                }
+            }
+            let mut builder = Builder::new(Vec::new());
+            assert_eq!(2, builder.append_path_records(&input[..], format).unwrap());
+            let bytes = builder.finish().unwrap();
+            let mut archive = Archive::new(&bytes[..]);
+            let mut entries = Vec::new();
+            while let Some(entry) = archive.next_entry().unwrap() {
+                entries.push((
+                    entry.path.clone(),
+                    entry.metadata.file_mode(),
+                    entry.metadata.uid(),
+                    entry.metadata.gid(),
+                    entry.metadata.mtime(),
+                ));
+            }
+            records.push(entries);
+        }
+        assert_eq!(records[0], records[1]);
+        assert_eq!(records[0], records[2]);
+        assert_eq!(file1, records[0][0].0);
+        assert_eq!((file2.clone(), 0o600, 1, 2, 3), records[0][1]);
+        let mut builder = Builder::new(Vec::new());
+        let input = [file1.as_os_str().as_bytes(), b"\tsize=1\n"].concat();
+        assert!(builder
+            .append_path_records(&input[..], RecordFormat::Newline)
+            .is_err());
+    }
+}
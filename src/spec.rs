@@ -0,0 +1,345 @@
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::append_error;
+use crate::makedev;
+use crate::Builder;
+use crate::EditMetadata;
+use crate::FileType;
+use crate::Metadata;
+
+impl<W: Write, E: EditMetadata> Builder<W, E> {
+    /// Append the entries described by the spec file read from `reader`.
+    ///
+    /// This is the format of `usr/gen_init_cpio` tool from the Linux kernel:
+    /// each line describes one entry with whitespace-separated fields.
+    ///
+    /// ```text
+    /// file <name> <location> <mode> <uid> <gid> [<hard links>...]
+    /// dir <name> <mode> <uid> <gid>
+    /// nod <name> <mode> <uid> <gid> <dev_type> <major> <minor>
+    /// slink <name> <target> <mode> <uid> <gid>
+    /// pipe <name> <mode> <uid> <gid>
+    /// sock <name> <mode> <uid> <gid>
+    /// ```
+    ///
+    /// The mode is octal, `dev_type` is `c` (character device) or `b` (block device).
+    /// The contents of `file` entries are read from `location`
+    /// and their modification time is taken from it;
+    /// the hard links, if any, are appended right after the file.
+    /// The other entries have zero modification time.
+    /// Empty lines and the lines starting with `#` are ignored.
+    /// The leading `/` is removed from the names.
+    ///
+    /// Returns the number of appended entries.
+    pub fn append_spec<R: BufRead>(&mut self, reader: R) -> Result<u64, Error> {
+        let mut num_entries = 0;
+        for (i, line) in reader.split(b'\n').enumerate() {
+            let line = line?;
+            let entry = SpecEntry::parse(&line).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("spec line {}: {}", i + 1, e),
+                )
+            })?;
+            match entry {
+                Some(SpecEntry::File {
+                    names,
+                    location,
+                    metadata,
+                }) => {
+                    num_entries += self.append_spec_file(location, &names, metadata)?;
+                }
+                Some(SpecEntry::Other {
+                    name,
+                    mut metadata,
+                    mut data,
+                }) => {
+                    if metadata.is_symlink() {
+                        self.terminate_link_target(&mut data);
+                    }
+                    metadata.file_size = data.len() as u64;
+                    let Some(inner_path) = self.map_path(name).map(Cow::into_owned) else {
+                        continue;
+                    };
+                    self.append_entry(metadata, &inner_path, &data[..])
+                        .map_err(|e| append_error(name, e))?;
+                    num_entries += 1;
+                }
+                None => {}
+            }
+        }
+        Ok(num_entries)
+    }
+
+    /// Append the regular file and its hard links.
+    fn append_spec_file(
+        &mut self,
+        location: &Path,
+        names: &[&Path],
+        mut metadata: Metadata,
+    ) -> Result<u64, Error> {
+        let fs_metadata = location
+            .metadata()
+            .map_err(|e| Error::new(e.kind(), format!("failed to read {:?}: {}", location, e)))?;
+        if !fs_metadata.is_file() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{:?} is not a regular file", location),
+            ));
+        }
+        metadata.file_size = fs_metadata.len();
+        metadata.mtime = fs_metadata.mtime().try_into().unwrap_or(0);
+        let mut inner_paths = Vec::with_capacity(names.len());
+        for name in names.iter() {
+            if let Some(inner_path) = self.map_path(name) {
+                inner_paths.push((*name, inner_path.into_owned()));
+            }
+        }
+        if inner_paths.len() > 1 {
+            metadata.dev = fs_metadata.dev();
+            metadata.ino = fs_metadata.ino();
+            metadata.nlink = inner_paths.len().try_into().unwrap_or(u32::MAX);
+        }
+        for (name, inner_path) in inner_paths.iter() {
+            let file = File::open(location)?;
+            self.append_entry(metadata.clone(), inner_path, file)
+                .map_err(|e| append_error(name, e))?;
+        }
+        Ok(inner_paths.len() as u64)
+    }
+}
+
+/// Parsed line of the spec file.
+enum SpecEntry<'a> {
+    /// Regular file and its hard links.
+    File {
+        names: Vec<&'a Path>,
+        location: &'a Path,
+        metadata: Metadata,
+    },
+    /// Any other entry type.
+    Other {
+        name: &'a Path,
+        metadata: Metadata,
+        data: Vec<u8>,
+    },
+}
+
+impl<'a> SpecEntry<'a> {
+    /// Returns `None` for empty lines and comments.
+    fn parse(line: &'a [u8]) -> Result<Option<Self>, &'static str> {
+        let fields: Vec<&[u8]> = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|field| !field.is_empty())
+            .collect();
+        let Some((kind, args)) = fields.split_first() else {
+            return Ok(None);
+        };
+        if kind.starts_with(b"#") {
+            return Ok(None);
+        }
+        let num_args = match *kind {
+            b"file" => args.len().max(5),
+            b"dir" | b"pipe" | b"sock" => 4,
+            b"nod" => 7,
+            b"slink" => 5,
+            _ => return Err("unknown entry type"),
+        };
+        if args.len() < num_args {
+            return Err("too few fields");
+        }
+        if args.len() > num_args {
+            return Err("too many fields");
+        }
+        let name = to_path(args[0])?;
+        let entry = match *kind {
+            b"file" => {
+                let mut names = vec![name];
+                for link in args[5..].iter() {
+                    names.push(to_path(link)?);
+                }
+                Self::File {
+                    names,
+                    location: Path::new(OsStr::from_bytes(args[1])),
+                    metadata: parse_metadata(FileType::Regular, &args[2..5])?,
+                }
+            }
+            b"nod" => {
+                let file_type = match args[4] {
+                    b"c" => FileType::CharDevice,
+                    b"b" => FileType::BlockDevice,
+                    _ => return Err("invalid device type"),
+                };
+                let mut metadata = parse_metadata(file_type, &args[1..4])?;
+                let major = parse_number(args[5], 10).ok_or("invalid major number")?;
+                let minor = parse_number(args[6], 10).ok_or("invalid minor number")?;
+                metadata.rdev = makedev(major, minor);
+                Self::Other {
+                    name,
+                    metadata,
+                    data: Vec::new(),
+                }
+            }
+            b"slink" => Self::Other {
+                name,
+                metadata: parse_metadata(FileType::Symlink, &args[2..5])?,
+                // the target is terminated by `append_spec` as in `append_path`
+                data: args[1].to_vec(),
+            },
+            _ => {
+                let file_type = match *kind {
+                    b"dir" => FileType::Directory,
+                    b"pipe" => FileType::Fifo,
+                    _ => FileType::Socket,
+                };
+                Self::Other {
+                    name,
+                    metadata: parse_metadata(file_type, &args[1..4])?,
+                    data: Vec::new(),
+                }
+            }
+        };
+        Ok(Some(entry))
+    }
+}
+
+/// Parse `<mode> <uid> <gid>` fields.
+fn parse_metadata(file_type: FileType, fields: &[&[u8]]) -> Result<Metadata, &'static str> {
+    let mode = parse_number(fields[0], 8)
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or("invalid mode")?;
+    let uid = parse_number(fields[1], 10)
+        .and_then(|uid| u32::try_from(uid).ok())
+        .ok_or("invalid uid")?;
+    let gid = parse_number(fields[2], 10)
+        .and_then(|gid| u32::try_from(gid).ok())
+        .ok_or("invalid gid")?;
+    let mut metadata = Metadata::new(file_type, mode as u32);
+    metadata.uid = uid;
+    metadata.gid = gid;
+    Ok(metadata)
+}
+
+pub(crate) fn parse_number(field: &[u8], radix: u32) -> Option<u64> {
+    let field = std::str::from_utf8(field).ok()?;
+    u64::from_str_radix(field, radix).ok()
+}
+
+fn to_path(name: &[u8]) -> Result<&Path, &'static str> {
+    let name = name.strip_prefix(b"/").unwrap_or(name);
+    if name.is_empty() {
+        return Err("empty name");
+    }
+    Ok(Path::new(OsStr::from_bytes(name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    use tempfile::TempDir;
+
+    use crate::Archive;
+    use crate::Builder;
+    use crate::FileType;
+
+    #[test]
+    fn append_spec() {
+        let workdir = TempDir::new().unwrap();
+        let source = workdir.path().join("init.sh");
+        std::fs::write(&source, b"#!/bin/sh\n").unwrap();
+        let spec = format!(
+            "# comment
+dir /dev 755 0 0
+nod /dev/console 600 0 0 c 5 1
+
+slink /bin/sh busybox 777 0 0
+pipe /run/initctl 600 0 0
+sock /run/socket 600 0 0
+dir /sbin 700 1 2
+file /init {} 755 0 0 /sbin/init
+",
+            source.display()
+        );
+        let mut builder = Builder::new(Vec::new());
+        assert_eq!(8, builder.append_spec(spec.as_bytes()).unwrap());
+        let bytes = builder.finish().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut entries = Vec::new();
+        while let Some(entry) = archive.next_entry().unwrap() {
+            let metadata = &entry.metadata;
+            entries.push((
+                entry.path.to_str().unwrap().to_string(),
+                metadata.file_type().unwrap(),
+                metadata.file_mode(),
+                metadata.rdev(),
+            ));
+        }
+        assert_eq!(
+            vec![
+                ("dev".into(), FileType::Directory, 0o755, 0),
+                (
+                    "dev/console".into(),
+                    FileType::CharDevice,
+                    0o600,
+                    libc::makedev(5, 1) as u64
+                ),
+                ("bin/sh".into(), FileType::Symlink, 0o777, 0),
+                ("run/initctl".into(), FileType::Fifo, 0o600, 0),
+                ("run/socket".into(), FileType::Socket, 0o600, 0),
+                ("sbin".into(), FileType::Directory, 0o700, 0),
+                ("init".into(), FileType::Regular, 0o755, 0),
+                ("sbin/init".into(), FileType::Regular, 0o755, 0),
+            ],
+            entries
+        );
+        let output = workdir.path().join("output");
+        Archive::new(&bytes[..]).unpack(&output).unwrap();
+        assert_eq!(
+            Path::new("busybox"),
+            std::fs::read_link(output.join("bin/sh")).unwrap()
+        );
+        let init = std::fs::metadata(output.join("init")).unwrap();
+        let sbin_init = std::fs::metadata(output.join("sbin/init")).unwrap();
+        assert_eq!(init.ino(), sbin_init.ino());
+        assert_eq!(
+            b"#!/bin/sh\n",
+            &std::fs::read(output.join("sbin/init")).unwrap()[..]
+        );
+        for spec in [
+            &b"dir /dev 755 0"[..],
+            b"dir /dev 755 0 0 0",
+            b"nod /dev/null 666 0 0 x 1 3",
+            b"link /a /b 777 0 0",
+            b"pipe / 600 0 0",
+            b"sock /run/socket 800 0 0",
+        ] {
+            let mut builder = Builder::new(Vec::new());
+            assert!(builder.append_spec(spec).is_err());
+        }
+        for (gnu_compat, expected) in [(false, &b"busybox\0"[..]), (true, b"busybox")] {
+            let mut builder = Builder::new(Vec::new());
+            builder.gnu_compat(gnu_compat);
+            builder
+                .append_spec(&b"slink /bin/sh busybox 777 0 0"[..])
+                .unwrap();
+            let bytes = builder.finish().unwrap();
+            let mut archive = Archive::new(&bytes[..]);
+            let mut entry = archive.next_entry().unwrap().unwrap();
+            let mut target = Vec::new();
+            entry.reader.read_to_end(&mut target).unwrap();
+            assert_eq!(expected, &target[..]);
+        }
+    }
+}
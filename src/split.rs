@@ -0,0 +1,349 @@
+use std::io::Error;
+use std::io::Read;
+use std::io::Write;
+
+use crate::Builder;
+use crate::EditMetadata;
+
+/// Writes the archive to multiple volumes of fixed size.
+///
+/// The volumes are created on demand by calling `new_volume` with zero-based volume index.
+/// Use [`Builder::split_at_entry_boundaries`] to avoid splitting the entries between the volumes
+/// and [`SplitReader`] to read the archive back.
+pub struct SplitWriter<W: Write, F: FnMut(usize) -> Result<W, Error>> {
+    volume: Option<W>,
+    new_volume: F,
+    volume_size: u64,
+    // The number of bytes written to the current volume.
+    num_bytes: u64,
+    num_volumes: usize,
+}
+
+impl<W: Write, F: FnMut(usize) -> Result<W, Error>> SplitWriter<W, F> {
+    /// Create new writer that writes at most `volume_size` bytes to each volume.
+    pub fn new(volume_size: u64, new_volume: F) -> Self {
+        Self {
+            volume: None,
+            new_volume,
+            volume_size: volume_size.max(1),
+            num_bytes: 0,
+            num_volumes: 0,
+        }
+    }
+
+    /// The number of volumes created so far.
+    pub fn num_volumes(&self) -> usize {
+        self.num_volumes
+    }
+
+    /// The number of bytes that can be written to the current volume.
+    pub fn remaining(&self) -> u64 {
+        self.volume_size - self.num_bytes
+    }
+
+    /// Flush and close the current volume.
+    ///
+    /// The next write creates a new volume.
+    pub fn next_volume(&mut self) -> Result<(), Error> {
+        if let Some(mut volume) = self.volume.take() {
+            volume.flush()?;
+        }
+        self.num_bytes = 0;
+        Ok(())
+    }
+
+    /// Start a new volume if the entry of `len` bytes doesn't fit in the current one
+    /// but fits in an empty volume.
+    fn prepare_entry(&mut self, len: u64) -> Result<(), Error> {
+        if self.volume.is_some() && len > self.remaining() && len <= self.volume_size {
+            self.next_volume()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the current volume and the number of bytes that can be written to it.
+    fn volume(&mut self) -> Result<(&mut W, u64), Error> {
+        if self.num_bytes == self.volume_size {
+            self.next_volume()?;
+        }
+        let volume = match self.volume.take() {
+            Some(volume) => volume,
+            None => {
+                let volume = (self.new_volume)(self.num_volumes)?;
+                self.num_volumes += 1;
+                volume
+            }
+        };
+        let remaining = self.volume_size - self.num_bytes;
+        Ok((self.volume.insert(volume), remaining))
+    }
+}
+
+impl<W: Write, F: FnMut(usize) -> Result<W, Error>> Write for SplitWriter<W, F> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let (volume, remaining) = self.volume()?;
+        let n = buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        let n = volume.write(&buf[..n])?;
+        self.num_bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self.volume.as_mut() {
+            Some(volume) => volume.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Splits the written bytes into chunks of fixed size.
+///
+/// `on_chunk` is called with zero-based chunk index and the contents of each completed chunk,
+/// e.g. to upload the archive part by part without buffering the whole output.
+/// All the chunks except the last one are exactly `chunk_size` bytes long.
+/// The last chunk is passed to `on_chunk` by [`finish`](Self::finish) only;
+/// neither [`flush`](Write::flush) nor dropping the writer emits incomplete chunks.
+pub struct ChunkedWriter<F: FnMut(usize, &[u8]) -> Result<(), Error>> {
+    buffer: Vec<u8>,
+    chunk_size: usize,
+    on_chunk: F,
+    num_chunks: usize,
+}
+
+impl<F: FnMut(usize, &[u8]) -> Result<(), Error>> ChunkedWriter<F> {
+    /// Create new writer that calls `on_chunk` with each `chunk_size` bytes written.
+    pub fn new(chunk_size: usize, on_chunk: F) -> Self {
+        let chunk_size = chunk_size.max(1);
+        Self {
+            buffer: Vec::with_capacity(chunk_size),
+            chunk_size,
+            on_chunk,
+            num_chunks: 0,
+        }
+    }
+
+    /// The number of chunks passed to the callback so far.
+    pub fn num_chunks(&self) -> usize {
+        self.num_chunks
+    }
+
+    /// The number of bytes written that are not passed to the callback yet.
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Pass the remaining bytes to the callback as the last chunk.
+    ///
+    /// The callback is not called if there are no remaining bytes.
+    /// Returns the total number of chunks.
+    pub fn finish(mut self) -> Result<usize, Error> {
+        if !self.buffer.is_empty() {
+            let buffer = std::mem::take(&mut self.buffer);
+            self.emit(&buffer)?;
+        }
+        Ok(self.num_chunks)
+    }
+
+    fn emit(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        (self.on_chunk)(self.num_chunks, chunk)?;
+        self.num_chunks += 1;
+        Ok(())
+    }
+}
+
+impl<F: FnMut(usize, &[u8]) -> Result<(), Error>> Write for ChunkedWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if self.buffer.is_empty() && buf.len() >= self.chunk_size {
+            // pass the whole chunk without copying
+            self.emit(&buf[..self.chunk_size])?;
+            return Ok(self.chunk_size);
+        }
+        let n = buf.len().min(self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == self.chunk_size {
+            let buffer = std::mem::take(&mut self.buffer);
+            let result = self.emit(&buffer);
+            // reuse the allocation
+            self.buffer = buffer;
+            match result {
+                Ok(()) => self.buffer.clear(),
+                Err(e) => {
+                    // the bytes are not written
+                    self.buffer.truncate(self.chunk_size - n);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Reads the archive from multiple volumes.
+///
+/// The volumes are opened on demand by calling `next_volume` with zero-based volume index
+/// until it returns `None`.
+/// Use with [`Archive`](crate::Archive) to read the archives written by [`SplitWriter`].
+pub struct SplitReader<R: Read, F: FnMut(usize) -> Result<Option<R>, Error>> {
+    volume: Option<R>,
+    next_volume: F,
+    num_volumes: usize,
+    finished: bool,
+}
+
+impl<R: Read, F: FnMut(usize) -> Result<Option<R>, Error>> SplitReader<R, F> {
+    /// Create new reader.
+    pub fn new(next_volume: F) -> Self {
+        Self {
+            volume: None,
+            next_volume,
+            num_volumes: 0,
+            finished: false,
+        }
+    }
+
+    /// The number of volumes opened so far.
+    pub fn num_volumes(&self) -> usize {
+        self.num_volumes
+    }
+}
+
+impl<R: Read, F: FnMut(usize) -> Result<Option<R>, Error>> Read for SplitReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while !self.finished {
+            let volume = match self.volume.as_mut() {
+                Some(volume) => volume,
+                None => match (self.next_volume)(self.num_volumes)? {
+                    Some(volume) => {
+                        self.num_volumes += 1;
+                        self.volume.insert(volume)
+                    }
+                    None => {
+                        self.finished = true;
+                        break;
+                    }
+                },
+            };
+            match volume.read(buf)? {
+                0 => self.volume = None,
+                n => return Ok(n),
+            }
+        }
+        Ok(0)
+    }
+}
+
+impl<W: Write, F: FnMut(usize) -> Result<W, Error>, E: EditMetadata> Builder<SplitWriter<W, F>, E> {
+    /// Start a new volume before the entry that doesn't fit in the current one.
+    ///
+    /// The entries that are larger than the volume size are still split between the volumes.
+    ///
+    /// `false` by default.
+    pub fn split_at_entry_boundaries(&mut self, value: bool) {
+        self.set_prepare_entry(value.then_some(SplitWriter::prepare_entry));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::ErrorKind;
+    use std::path::PathBuf;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::constants::*;
+    use crate::Archive;
+    use crate::Metadata;
+
+    #[test]
+    fn split_volumes() {
+        for split_at_entry_boundaries in [false, true] {
+            let workdir = TempDir::new().unwrap();
+            let volume_path = |i: usize| workdir.path().join(format!("volume-{}", i));
+            let mut builder = Builder::new(SplitWriter::new(512, |i| File::create(volume_path(i))));
+            builder.split_at_entry_boundaries(split_at_entry_boundaries);
+            let mut expected_entries = Vec::new();
+            for i in 0..20 {
+                // the last file doesn't fit in a volume
+                let contents = vec![i as u8; if i == 19 { 2000 } else { 10 * i }];
+                let path = PathBuf::from(format!("file-{}", i));
+                builder
+                    .append_data(Metadata::regular(0o644, 0), &path, &contents[..])
+                    .unwrap();
+                expected_entries.push((path, contents));
+            }
+            let mut writer = builder.finish().unwrap();
+            writer.flush().unwrap();
+            let num_volumes = writer.num_volumes();
+            drop(writer);
+            for i in 0..num_volumes {
+                let volume = std::fs::read(volume_path(i)).unwrap();
+                assert!(volume.len() <= 512);
+                if split_at_entry_boundaries && i < 10 {
+                    // the small entries are not split
+                    assert!(volume.starts_with(&NEWC_MAGIC), "volume {}", i);
+                }
+            }
+            let reader = SplitReader::new(|i| match File::open(volume_path(i)) {
+                Ok(file) => Ok(Some(file)),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            });
+            let mut archive = Archive::new(reader);
+            let mut actual_entries = Vec::new();
+            while let Some(mut entry) = archive.next_entry().unwrap() {
+                let mut contents = Vec::new();
+                entry.reader.read_to_end(&mut contents).unwrap();
+                actual_entries.push((entry.path.clone(), contents));
+            }
+            assert_eq!(expected_entries, actual_entries);
+            assert_eq!(num_volumes, archive.into_inner().num_volumes());
+        }
+    }
+
+    #[test]
+    fn chunked_writer() {
+        let mut chunks: Vec<(usize, Vec<u8>)> = Vec::new();
+        let mut builder = Builder::new(ChunkedWriter::new(100, |i, chunk| {
+            chunks.push((i, chunk.to_vec()));
+            Ok(())
+        }));
+        let contents = vec![0xcc_u8; 1000];
+        builder
+            .append_data(Metadata::regular(0o644, 0), "small", b"hello")
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "large", &contents[..])
+            .unwrap();
+        let writer = builder.finish().unwrap();
+        assert!(writer.pending() < 100);
+        let num_chunks = writer.finish().unwrap();
+        assert_eq!(num_chunks, chunks.len());
+        let (last, rest) = chunks.split_last().unwrap();
+        assert!(rest.iter().all(|(_, chunk)| chunk.len() == 100));
+        assert!(!last.1.is_empty() && last.1.len() <= 100);
+        assert!(chunks.iter().enumerate().all(|(i, (j, _))| i == *j));
+        let bytes: Vec<u8> = chunks.into_iter().flat_map(|(_, chunk)| chunk).collect();
+        let mut archive = Archive::new(&bytes[..]);
+        let mut entry = archive.next_entry().unwrap().unwrap();
+        let mut actual = Vec::new();
+        entry.reader.read_to_end(&mut actual).unwrap();
+        assert_eq!(b"hello", &actual[..]);
+        drop(entry);
+        let mut entry = archive.next_entry().unwrap().unwrap();
+        let mut actual = Vec::new();
+        entry.reader.read_to_end(&mut actual).unwrap();
+        assert_eq!(contents, actual);
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::Error;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::entry_error;
+use crate::Archive;
+use crate::Builder;
+use crate::EditMetadata;
+use crate::Format;
+use crate::Metadata;
+use crate::MetadataId;
+
+impl<R: Read> Archive<R> {
+    /// Read the archive once and append each entry to all `builders`.
+    ///
+    /// Useful to produce the same archive in several formats
+    /// or to write compressed and uncompressed copies in one pass.
+    /// Use `Builder<Box<dyn Write>, _>` to combine different writers.
+    /// Each builder maintains its own hard link state,
+    /// i.e. the contents of hard links are stored according to the builder's format
+    /// regardless of how they are stored in this archive.
+    ///
+    /// The contents of the entries are streamed except for hard links
+    /// and for regular files when one of the builders writes [`Format::Crc`]:
+    /// their contents are kept in memory.
+    /// The hard links without contents that precede the link with the contents
+    /// (e.g. in the archives written by GNU cpio) are appended right before that link.
+    /// The builders are not finished.
+    /// Returns the number of copied entries.
+    pub fn tee<W: Write, E: EditMetadata>(
+        &mut self,
+        builders: &mut [Builder<W, E>],
+    ) -> Result<u64, Error> {
+        let any_crc = builders
+            .iter()
+            .any(|builder| matches!(builder.format(), Format::Crc));
+        let mut num_entries = 0;
+        // the hard links that wait for the link with the contents
+        let mut pending: HashMap<MetadataId, Vec<(usize, PathBuf, Metadata)>> = HashMap::new();
+        // the hard links with the contents that were already appended
+        let mut appended: HashSet<MetadataId> = HashSet::new();
+        while let Some(mut entry) = self.next_entry()? {
+            let index = entry.index;
+            let mut metadata = entry.metadata.clone().into_inner();
+            let is_hard_link = metadata.nlink() > 1 && !metadata.is_dir();
+            let result = if (any_crc && metadata.is_file()) || is_hard_link {
+                let mut contents = Vec::new();
+                entry.reader.read_to_end(&mut contents)?;
+                // the contents of a hard link might be stored in another entry
+                metadata.set_file_size(contents.len() as u64);
+                if is_hard_link && !appended.contains(&metadata.id()) {
+                    if contents.is_empty() {
+                        pending.entry(metadata.id()).or_default().push((
+                            index,
+                            entry.path.clone(),
+                            metadata,
+                        ));
+                        continue;
+                    }
+                    appended.insert(metadata.id());
+                    for (index, path, mut metadata) in
+                        pending.remove(&metadata.id()).unwrap_or_default()
+                    {
+                        metadata.set_file_size(contents.len() as u64);
+                        append_all(builders, &metadata, &path, &contents)
+                            .map_err(|e| entry_error(e, index, &path))?;
+                        num_entries += 1;
+                    }
+                }
+                append_all(builders, &metadata, &entry.path, &contents)
+            } else {
+                let path = &entry.path;
+                let reader = &mut entry.reader;
+                builders
+                    .iter_mut()
+                    .map(|builder| builder.append_writer(metadata.clone(), path))
+                    .collect::<Result<Vec<_>, _>>()
+                    .and_then(|mut writers| {
+                        let mut buf = [0_u8; 4096];
+                        loop {
+                            let n = reader.read(&mut buf)?;
+                            if n == 0 {
+                                break;
+                            }
+                            for writer in writers.iter_mut() {
+                                writer.write_all(&buf[..n])?;
+                            }
+                        }
+                        writers.into_iter().try_for_each(|writer| writer.finish())
+                    })
+            };
+            result.map_err(|e| entry_error(e, index, &entry.path))?;
+            num_entries += 1;
+        }
+        // the remaining links are empty files
+        let mut pending: Vec<_> = pending.into_values().flatten().collect();
+        pending.sort_unstable_by_key(|(index, ..)| *index);
+        for (index, path, metadata) in pending {
+            append_all(builders, &metadata, &path, &[])
+                .map_err(|e| entry_error(e, index, &path))?;
+            num_entries += 1;
+        }
+        Ok(num_entries)
+    }
+}
+
+fn append_all<W: Write, E: EditMetadata>(
+    builders: &mut [Builder<W, E>],
+    metadata: &Metadata,
+    path: &Path,
+    contents: &[u8],
+) -> Result<(), Error> {
+    builders.iter_mut().try_for_each(|builder| {
+        builder
+            .append_entry(metadata.clone(), path, contents)
+            .map(|_| ())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    use tempfile::TempDir;
+
+    use crate::Archive;
+    use crate::Builder;
+    use crate::Format;
+    use crate::Metadata;
+
+    #[test]
+    fn tee() {
+        for format in [Format::Odc, Format::Newc] {
+            tee_from(format, false);
+        }
+        // the contents of the hard links are stored in the last link
+        tee_from(Format::Newc, true);
+    }
+
+    fn tee_from(source_format: Format, gnu_compat: bool) {
+        let mut builder = Builder::new(Vec::new());
+        builder.set_format(source_format);
+        builder.append_dir("dir", 0o755).unwrap();
+        let input = TempDir::new().unwrap();
+        if gnu_compat {
+            builder.gnu_compat(true);
+            std::fs::write(input.path().join("link1"), b"hello").unwrap();
+            std::fs::hard_link(input.path().join("link1"), input.path().join("link2")).unwrap();
+            for name in ["link1", "link2"] {
+                builder
+                    .append_path(input.path().join(name), Path::new("dir").join(name))
+                    .unwrap();
+            }
+        } else {
+            let mut metadata = Metadata::regular(0o644, 0);
+            metadata.set_ino(1);
+            metadata.set_nlink(2);
+            builder
+                .append_data(metadata.clone(), "dir/link1", b"hello")
+                .unwrap();
+            builder
+                .append_data(metadata, "dir/link2", b"hello")
+                .unwrap();
+        }
+        builder
+            .append_data(Metadata::symlink("link1"), "symlink", b"link1\0")
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let formats = [Format::Newc, Format::Crc, Format::Odc];
+        let mut builders: Vec<_> = formats
+            .iter()
+            .map(|format| {
+                let mut builder = Builder::new(Vec::new());
+                builder.set_format(*format);
+                builder
+            })
+            .collect();
+        let mut archive = Archive::new(&bytes[..]);
+        assert_eq!(4, archive.tee(&mut builders).unwrap());
+        for (format, builder) in formats.into_iter().zip(builders) {
+            let bytes = builder.finish().unwrap();
+            let mut archive = Archive::new(&bytes[..]);
+            let mut entries = Vec::new();
+            while let Some(mut entry) = archive.next_entry().unwrap() {
+                assert_eq!(format, entry.metadata.format());
+                let mut contents = Vec::new();
+                entry.reader.read_to_end(&mut contents).unwrap();
+                entries.push((entry.path.clone(), contents));
+            }
+            assert_eq!(
+                vec![
+                    (PathBuf::from("dir"), b"".to_vec()),
+                    (PathBuf::from("dir/link1"), b"hello".to_vec()),
+                    (PathBuf::from("dir/link2"), b"hello".to_vec()),
+                    (PathBuf::from("symlink"), b"link1\0".to_vec()),
+                ],
+                entries,
+                "{:?} -> {:?}",
+                source_format,
+                format
+            );
+            let workdir = TempDir::new().unwrap();
+            Archive::new(&bytes[..]).unpack(workdir.path()).unwrap();
+            assert_eq!(
+                b"hello",
+                &std::fs::read(workdir.path().join("dir/link2")).unwrap()[..]
+            );
+        }
+    }
+}
@@ -0,0 +1,370 @@
+//! Canonical archives for conformance testing.
+//!
+//! The functions in this module generate tiny archives for every supported format and edge case.
+//! Use them to validate other CPIO implementations, integrations and fuzzers
+//! against the known-good bytes produced by this library.
+//! The output is deterministic, i.e. it doesn't depend on the file system, the time or the user.
+//! The size and the digest of every fixture are pinned in the tests of this module,
+//! so the bytes change only deliberately.
+//!
+//! Requires `testdata` feature.
+
+use std::fs::create_dir_all;
+use std::io::Error;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::limits::*;
+use crate::makedev;
+use crate::Builder;
+use crate::ByteOrder;
+use crate::FileType;
+use crate::Format;
+use crate::Metadata;
+
+/// The formats that fixtures are generated for.
+pub const FORMATS: [Format; 5] = [
+    Format::Newc,
+    Format::Crc,
+    Format::Odc,
+    Format::Bin(ByteOrder::LittleEndian),
+    Format::Bin(ByteOrder::BigEndian),
+];
+
+/// Edge case that a fixture covers.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Case {
+    /// No entries, only the trailer.
+    Empty,
+    /// Single regular file.
+    Regular,
+    /// Nested directories with a file.
+    Directories,
+    /// Symbolic link.
+    Symlink,
+    /// Two hard links to the same file.
+    HardLinks,
+    /// The path of the maximum length that Linux supports (4095 bytes).
+    LongName,
+    /// Block and character devices.
+    Devices,
+    /// FIFO and socket.
+    Special,
+    /// The file which size doesn't fit into 16 bits.
+    LargeFile,
+    /// The maximum user id, group id and modification time that the format supports.
+    MaxValues,
+}
+
+impl Case {
+    /// All cases.
+    pub const ALL: [Case; 10] = [
+        Case::Empty,
+        Case::Regular,
+        Case::Directories,
+        Case::Symlink,
+        Case::HardLinks,
+        Case::LongName,
+        Case::Devices,
+        Case::Special,
+        Case::LargeFile,
+        Case::MaxValues,
+    ];
+
+    /// Get the name of the case that is used in the file names.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Empty => "empty",
+            Self::Regular => "regular",
+            Self::Directories => "directories",
+            Self::Symlink => "symlink",
+            Self::HardLinks => "hard-links",
+            Self::LongName => "long-name",
+            Self::Devices => "devices",
+            Self::Special => "special",
+            Self::LargeFile => "large-file",
+            Self::MaxValues => "max-values",
+        }
+    }
+}
+
+/// Generated archive.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Fixture {
+    /// The edge case.
+    pub case: Case,
+    /// The format.
+    pub format: Format,
+    /// The archive.
+    pub bytes: Vec<u8>,
+}
+
+impl Fixture {
+    /// Get the file name of the fixture, e.g. `hard-links.newc.cpio`.
+    pub fn file_name(&self) -> String {
+        format!("{}.{}.cpio", self.case.as_str(), format_name(self.format))
+    }
+}
+
+/// Generate the archive for the specified case and format.
+pub fn fixture(case: Case, format: Format) -> Result<Fixture, Error> {
+    let mut builder = Builder::new(Vec::new());
+    builder.set_format(format);
+    let file = |mode, mtime| {
+        let mut metadata = Metadata::regular(mode, 0);
+        metadata.set_mtime(mtime);
+        metadata
+    };
+    match case {
+        Case::Empty => {}
+        Case::Regular => {
+            builder.append_data(file(0o644, MTIME), "file", b"hello\n")?;
+        }
+        Case::Directories => {
+            builder.append_dir_with_metadata(directory(0o755), "usr")?;
+            builder.append_dir_with_metadata(directory(0o755), "usr/share")?;
+            builder.append_data(file(0o644, MTIME), "usr/share/file", b"hello\n")?;
+        }
+        Case::Symlink => {
+            builder.append_data(file(0o644, MTIME), "target", b"hello\n")?;
+            let mut metadata = Metadata::symlink("target");
+            metadata.set_mtime(MTIME);
+            builder.append_data(metadata, "link", b"target\0")?;
+        }
+        Case::HardLinks => {
+            let mut metadata = file(0o644, MTIME);
+            metadata.set_ino(1);
+            metadata.set_nlink(2);
+            builder.append_data(metadata.clone(), "file", b"hello\n")?;
+            builder.append_data(metadata, "link", b"hello\n")?;
+        }
+        Case::LongName => {
+            // 4095 bytes: 15 directories of 255 bytes, a 255-byte file name and 15 slashes
+            let component = "a".repeat(255);
+            let mut path = PathBuf::new();
+            for _ in 0..15 {
+                path.push(&component);
+                builder.append_dir_with_metadata(directory(0o755), &path)?;
+            }
+            path.push(&component);
+            builder.append_data(file(0o644, MTIME), &path, b"hello\n")?;
+        }
+        Case::Devices => {
+            for (file_type, path, major, minor) in [
+                (FileType::CharDevice, "null", 1, 3),
+                (FileType::BlockDevice, "sda", 8, 0),
+            ] {
+                let mut metadata = Metadata::new(file_type, 0o600);
+                metadata.set_rdev(makedev(major, minor));
+                metadata.set_mtime(MTIME);
+                builder.append_data(metadata, path, b"")?;
+            }
+        }
+        Case::Special => {
+            for (file_type, path) in [(FileType::Fifo, "fifo"), (FileType::Socket, "socket")] {
+                let mut metadata = Metadata::new(file_type, 0o600);
+                metadata.set_mtime(MTIME);
+                builder.append_data(metadata, path, b"")?;
+            }
+        }
+        Case::LargeFile => {
+            let data = patterned_data(LARGE_FILE_SIZE);
+            builder.append_data(file(0o644, MTIME), "large", &data)?;
+        }
+        Case::MaxValues => {
+            let (max_id, max_mtime) = match format {
+                Format::Newc | Format::Crc => (NEWC_MAX_ID, NEWC_MAX_MTIME),
+                Format::Odc => (ODC_MAX_ID, ODC_MAX_MTIME),
+                Format::Bin(..) => (BIN_MAX_ID, BIN_MAX_MTIME),
+            };
+            let mut metadata = file(0o7777, max_mtime);
+            metadata.set_uid(max_id);
+            metadata.set_gid(max_id);
+            builder.append_data(metadata, "file", b"hello\n")?;
+        }
+    }
+    Ok(Fixture {
+        case,
+        format,
+        bytes: builder.finish()?,
+    })
+}
+
+/// Generate the archives for all cases and formats.
+pub fn fixtures() -> Result<Vec<Fixture>, Error> {
+    let mut fixtures = Vec::with_capacity(Case::ALL.len() * FORMATS.len());
+    for case in Case::ALL {
+        for format in FORMATS {
+            fixtures.push(fixture(case, format)?);
+        }
+    }
+    Ok(fixtures)
+}
+
+/// Write all the fixtures to the `directory` (see [`Fixture::file_name`]).
+///
+/// The directory is created if it doesn't exist.
+/// Returns the paths of the written files.
+pub fn write_fixtures<P: AsRef<Path>>(directory: P) -> Result<Vec<PathBuf>, Error> {
+    let directory = directory.as_ref();
+    create_dir_all(directory)?;
+    let mut paths = Vec::new();
+    for fixture in fixtures()? {
+        let path = directory.join(fixture.file_name());
+        std::fs::write(&path, &fixture.bytes)?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+fn directory(mode: u32) -> Metadata {
+    let mut metadata = Metadata::directory(mode);
+    metadata.set_mtime(MTIME);
+    metadata
+}
+
+fn format_name(format: Format) -> &'static str {
+    match format {
+        Format::Newc => "newc",
+        Format::Crc => "crc",
+        Format::Odc => "odc",
+        Format::Bin(ByteOrder::LittleEndian) => "bin-le",
+        Format::Bin(ByteOrder::BigEndian) => "bin-be",
+    }
+}
+
+/// Modification time of all entries: 2023-11-14T22:13:20Z.
+const MTIME: u64 = 1_700_000_000;
+
+const LARGE_FILE_SIZE: usize = u16::MAX as usize + 2;
+
+/// Generate `len` bytes that don't repeat with a period of any buffer size (251 is prime).
+pub(crate) fn patterned_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::Archive;
+
+    #[test]
+    fn fixtures_are_valid() {
+        let fixtures = fixtures().unwrap();
+        assert_eq!(Case::ALL.len() * FORMATS.len(), fixtures.len());
+        for fixture in fixtures.iter() {
+            let mut archive = Archive::new(&fixture.bytes[..]);
+            let mut num_entries = 0;
+            while let Some(mut entry) = archive.next_entry().unwrap() {
+                assert_eq!(
+                    fixture.format,
+                    entry.metadata.format(),
+                    "{}",
+                    fixture.file_name()
+                );
+                std::io::copy(&mut entry.reader, &mut std::io::sink()).unwrap();
+                num_entries += 1;
+            }
+            let expected = match fixture.case {
+                Case::Empty => 0,
+                Case::Regular | Case::LargeFile | Case::MaxValues => 1,
+                Case::Symlink | Case::HardLinks | Case::Devices | Case::Special => 2,
+                Case::Directories => 3,
+                Case::LongName => 16,
+            };
+            assert_eq!(expected, num_entries, "{}", fixture.file_name());
+        }
+        // deterministic output
+        assert_eq!(fixtures, super::fixtures().unwrap());
+    }
+
+    #[test]
+    fn write_fixtures_to_directory() {
+        let workdir = TempDir::new().unwrap();
+        let paths = write_fixtures(workdir.path().join("fixtures")).unwrap();
+        assert_eq!(Case::ALL.len() * FORMATS.len(), paths.len());
+        let expected = fixture(Case::HardLinks, Format::Newc).unwrap();
+        assert_eq!(
+            expected.bytes,
+            std::fs::read(workdir.path().join("fixtures/hard-links.newc.cpio")).unwrap()
+        );
+    }
+
+    #[test]
+    fn fixtures_are_pinned() {
+        // (file name, size, FNV-1a digest); any change to these bytes is a format change
+        let expected: [(&str, usize, u64); 50] = [
+            ("empty.newc.cpio", 124, 0x9cc1d6a4fa371d58),
+            ("empty.crc.cpio", 124, 0x0955336bbbf48a85),
+            ("empty.odc.cpio", 87, 0xe4a344c96ad3151c),
+            ("empty.bin-le.cpio", 38, 0x6173ff308d435562),
+            ("empty.bin-be.cpio", 38, 0xa255299621a60bd4),
+            ("regular.newc.cpio", 248, 0x372760122be736bb),
+            ("regular.crc.cpio", 248, 0x4fd5894d923ce5df),
+            ("regular.odc.cpio", 174, 0x03064a60d2bc45a0),
+            ("regular.bin-le.cpio", 76, 0x39d8a4a51a068fe8),
+            ("regular.bin-be.cpio", 76, 0x17275e914bed96c2),
+            ("directories.newc.cpio", 496, 0xd348bb4e97af8b8b),
+            ("directories.crc.cpio", 496, 0x58d0856d35e82ad7),
+            ("directories.odc.cpio", 350, 0xc7164ff6eea3b362),
+            ("directories.bin-le.cpio", 152, 0xe95e82eeb5a80070),
+            ("directories.bin-be.cpio", 152, 0x0295d931965cb450),
+            ("symlink.newc.cpio", 376, 0x5617d7638068a344),
+            ("symlink.crc.cpio", 376, 0x91c247fbe9a92969),
+            ("symlink.odc.cpio", 264, 0x059c01bde4774513),
+            ("symlink.bin-le.cpio", 118, 0x94f45293b4704c09),
+            ("symlink.bin-be.cpio", 118, 0xb8025a4ea2c28db1),
+            ("hard-links.newc.cpio", 364, 0xda999f71df1d714e),
+            ("hard-links.crc.cpio", 364, 0xab2d11bca647c8bb),
+            ("hard-links.odc.cpio", 261, 0x3488a9bb96b731a0),
+            ("hard-links.bin-le.cpio", 114, 0xf7c0273b3d4a7840),
+            ("hard-links.bin-be.cpio", 114, 0xa5466536a9971d7e),
+            ("long-name.newc.cpio", 36740, 0x02b77c9fac4db151),
+            ("long-name.crc.cpio", 36740, 0xe4ab2a478ed05ab0),
+            ("long-name.odc.cpio", 36125, 0xd936b87dd335ebf3),
+            ("long-name.bin-le.cpio", 35276, 0x08d409027595e337),
+            ("long-name.bin-be.cpio", 35276, 0x555aa3851486d25b),
+            ("devices.newc.cpio", 356, 0xfadf004fdb927815),
+            ("devices.crc.cpio", 356, 0xda42b767bc9d6828),
+            ("devices.odc.cpio", 248, 0xc6ac718712f64a58),
+            ("devices.bin-le.cpio", 100, 0x5f750cdec0f3b975),
+            ("devices.bin-be.cpio", 100, 0xada8abc7589a63bf),
+            ("special.newc.cpio", 360, 0xe9bccaf8bdd8062c),
+            ("special.crc.cpio", 360, 0x698bc6f3b0462921),
+            ("special.odc.cpio", 251, 0xbb4581856d980bcc),
+            ("special.bin-le.cpio", 104, 0x9b682286cd1271be),
+            ("special.bin-be.cpio", 104, 0x9c8ee7883ad4eaae),
+            ("large-file.newc.cpio", 65780, 0xb337dd5e786c2a81),
+            ("large-file.crc.cpio", 65780, 0x53cd4fa17be65caf),
+            ("large-file.odc.cpio", 65706, 0x61897adcacc005e5),
+            ("large-file.bin-le.cpio", 65608, 0xb7af0d50a3778da4),
+            ("large-file.bin-be.cpio", 65608, 0x80d6dcd035b68868),
+            ("max-values.newc.cpio", 248, 0x376b7c3945230777),
+            ("max-values.crc.cpio", 248, 0xf0c2c2c0d97ed627),
+            ("max-values.odc.cpio", 174, 0x4f6252489d7bac7f),
+            ("max-values.bin-le.cpio", 76, 0x2d341b0b28bd131a),
+            ("max-values.bin-be.cpio", 76, 0xaf6dddb5e60a6a28),
+        ];
+        let actual = fixtures()
+            .unwrap()
+            .into_iter()
+            .map(|fixture| {
+                let digest = fnv1a(&fixture.bytes);
+                (fixture.file_name(), fixture.bytes.len(), digest)
+            })
+            .collect::<Vec<_>>();
+        let expected = expected
+            .into_iter()
+            .map(|(name, len, digest)| (name.to_string(), len, digest))
+            .collect::<Vec<_>>();
+        similar_asserts::assert_eq!(expected, actual);
+    }
+
+    fn fnv1a(bytes: &[u8]) -> u64 {
+        bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+}
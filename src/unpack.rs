@@ -0,0 +1,3539 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fs::File;
+use std::fs::Permissions;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+use std::os::fd::RawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::fchown;
+use std::os::unix::fs::PermissionsExt;
+#[cfg(not(target_os = "linux"))]
+use std::os::unix::net::UnixDatagram;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use libc::dev_t;
+use libc::mode_t;
+use libc::O_CREAT;
+use libc::O_DIRECTORY;
+use libc::O_EXCL;
+use libc::O_NOFOLLOW;
+use libc::O_RDONLY;
+use libc::O_RDWR;
+use libc::O_TRUNC;
+use libc::O_WRONLY;
+use normalize_path::NormalizePath;
+
+use crate::constants::*;
+use crate::create_dir_all_at;
+use crate::decode_xattrs;
+use crate::fchmodat;
+use crate::file_type_to_mode;
+use crate::find_symlink_at;
+#[cfg(target_os = "linux")]
+use crate::fsetxattr;
+use crate::fstatat;
+use crate::fstatvfs;
+use crate::lchownat;
+use crate::linkat;
+use crate::mkdirat;
+use crate::mkfifoat;
+use crate::mknodat;
+use crate::openat;
+use crate::path_to_c_string;
+use crate::remove_dir_all_at;
+use crate::renameat;
+use crate::set_file_modified_time;
+use crate::strip_dot_slash;
+use crate::symlinkat;
+use crate::to_patterns;
+use crate::unlinkat;
+use crate::xattrs_target;
+use crate::Entry;
+use crate::FileType;
+use crate::Metadata;
+use crate::MetadataId;
+use crate::NlinkPolicy;
+use crate::Patterns;
+use crate::SkipReason;
+use crate::Xattr;
+
+/// Archive unpacking options.
+///
+/// Covers everything that affects how the entries are written to the file system:
+/// conflicts with the existing files, ownership, permissions, modification times,
+/// hard and symbolic links, special files and paths.
+/// New options are added here as builder methods,
+/// i.e. they don't change the API of [`Archive`](crate::Archive).
+///
+/// Use [`Archive::set_unpack_options`](crate::Archive::set_unpack_options) to apply them.
+#[derive(Default)]
+pub struct UnpackOptions {
+    pub(crate) scanner: Option<Box<Scanner>>,
+    pub(crate) sandbox: bool,
+    pub(crate) dir_over_symlink: ConflictPolicy,
+    pub(crate) symlink_over_dir: ConflictPolicy,
+    pub(crate) dir_over_file: ConflictPolicy,
+    pub(crate) file_over_dir: ConflictPolicy,
+    pub(crate) update_existing_dirs: bool,
+    pub(crate) overwrite: OverwritePolicy,
+    pub(crate) mode_mask: Option<u32>,
+    pub(crate) absolute_paths: AbsolutePathPolicy,
+    pub(crate) strict_paths: bool,
+    pub(crate) hard_links: HardLinkPolicy,
+    pub(crate) on_hard_link: Option<Box<HardLinkReporter>>,
+    pub(crate) symlinks: SymlinkPolicy,
+    pub(crate) absolute_targets: AbsoluteTargetPolicy,
+    pub(crate) on_symlink: Option<Box<SymlinkReporter>>,
+    pub(crate) sparse: bool,
+    pub(crate) groups: GroupPolicy,
+    pub(crate) xattrs: bool,
+    pub(crate) preserve_mtime: bool,
+    pub(crate) min_mtime: u64,
+    pub(crate) preserve_owner: Option<bool>,
+    pub(crate) owner_map: OwnerMap,
+    pub(crate) rootless: bool,
+    pub(crate) placeholder: Placeholder,
+    pub(crate) special_files: SpecialFiles,
+    pub(crate) on_special_file: Option<Box<SpecialFileReporter>>,
+    pub(crate) patterns: Patterns,
+    pub(crate) strip_components: usize,
+    pub(crate) rename: Option<Box<Renamer>>,
+    pub(crate) keep_going: bool,
+    pub(crate) check_free_space: bool,
+}
+
+impl UnpackOptions {
+    /// Create new options with the default values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restrict file system modifications to the target directory before unpacking.
+    ///
+    /// This is a defense-in-depth measure for unpacking untrusted archives
+    /// that uses [Landlock](https://docs.kernel.org/userspace-api/landlock.html).
+    /// The restriction applies to the calling thread and to the threads it spawns afterwards,
+    /// and it can't be lifted, i.e. consider unpacking in a dedicated thread.
+    ///
+    /// Requires `landlock` feature and Linux, unpacking fails otherwise.
+    /// Hard links can only be created on kernels that support Landlock ABI v2 or later.
+    ///
+    /// `false` by default.
+    pub fn sandbox(mut self, value: bool) -> Self {
+        self.sandbox = value;
+        self
+    }
+
+    /// What to do when the archive contains a directory
+    /// and a symbolic link with the same path already exists.
+    ///
+    /// [`ConflictPolicy::Error`] by default.
+    pub fn dir_over_symlink(mut self, policy: ConflictPolicy) -> Self {
+        self.dir_over_symlink = policy;
+        self
+    }
+
+    /// What to do when the archive contains a symbolic link
+    /// and a directory with the same path already exists.
+    ///
+    /// [`ConflictPolicy::Error`] by default.
+    pub fn symlink_over_dir(mut self, policy: ConflictPolicy) -> Self {
+        self.symlink_over_dir = policy;
+        self
+    }
+
+    /// What to do when the archive contains a directory
+    /// and a regular file or a special file with the same path already exists.
+    ///
+    /// [`ConflictPolicy::Error`] by default.
+    pub fn dir_over_file(mut self, policy: ConflictPolicy) -> Self {
+        self.dir_over_file = policy;
+        self
+    }
+
+    /// What to do when the archive contains a regular file, a hard link or a special file
+    /// and a directory with the same path already exists.
+    ///
+    /// [`ConflictPolicy::Replace`] removes the directory with all its contents.
+    ///
+    /// [`ConflictPolicy::Error`] by default.
+    pub fn file_over_dir(mut self, policy: ConflictPolicy) -> Self {
+        self.file_over_dir = policy;
+        self
+    }
+
+    /// Apply permissions, owner and modification time from the archive
+    /// to the directories that already exist in the target directory.
+    ///
+    /// Existing directories are left as is otherwise.
+    /// Only directories are affected: the other existing files are either replaced
+    /// and get their metadata from the archive, or left as is,
+    /// according to [`overwrite`](Self::overwrite).
+    ///
+    /// `false` by default.
+    pub fn update_existing_dirs(mut self, value: bool) -> Self {
+        self.update_existing_dirs = value;
+        self
+    }
+
+    /// What to do when a file, a symbolic link, a hard link or a special file
+    /// with the same path already exists.
+    ///
+    /// The policy applies to the entries of all types except directories.
+    /// The existing file is removed before the entry is unpacked,
+    /// unless it's a directory.
+    ///
+    /// [`OverwritePolicy::Always`] by default.
+    pub fn overwrite(mut self, policy: OverwritePolicy) -> Self {
+        self.overwrite = policy;
+        self
+    }
+
+    /// Clear the specified permission bits of all created files and directories.
+    ///
+    /// The mask is applied instead of the process' umask
+    /// to the modes from the archive and to the default mode (`0o777`)
+    /// of the parent directories that are missing in the archive.
+    /// Use `Some(0o022)` to emulate the typical umask
+    /// and `Some(0o7000)` to strip setuid, setgid and sticky bits only.
+    ///
+    /// When `None`, the modes from the archive are applied as is,
+    /// whereas FIFOs, device files and missing parent directories are subject to the umask.
+    ///
+    /// `None` by default.
+    pub fn mode_mask(mut self, mask: Option<u32>) -> Self {
+        self.mode_mask = mask;
+        self
+    }
+
+    /// What to do with the entries which paths start with `/`.
+    ///
+    /// [`AbsolutePathPolicy::Strip`] by default.
+    pub fn absolute_paths(mut self, policy: AbsolutePathPolicy) -> Self {
+        self.absolute_paths = policy;
+        self
+    }
+
+    /// Fail on the entries which paths are absolute or contain `..` components.
+    ///
+    /// Such entries are otherwise unpacked according to [`AbsolutePathPolicy`]
+    /// or skipped if they point outside of the target directory.
+    /// The strict mode takes precedence over [`AbsolutePathPolicy::Keep`].
+    /// The paths are checked after [`rename`](Self::rename).
+    /// The number of rejected entries is reported in [`UnpackReport::rejected_paths`].
+    ///
+    /// `false` by default.
+    pub fn strict_paths(mut self, value: bool) -> Self {
+        self.strict_paths = value;
+        self
+    }
+
+    /// Inspect the contents of each regular file before it is moved to its final path.
+    ///
+    /// The contents are first written to a temporary file next to the final path,
+    /// then `scanner` is called with the entry's path, metadata and the reader of the temporary file.
+    /// The file is moved to the final path if `scanner` returns `true`
+    /// and is removed otherwise, i.e. the rejected files are never visible under their names.
+    /// Copy the contents from the reader to quarantine the file.
+    /// The hard links to the rejected files are skipped.
+    /// Unpacking is aborted if `scanner` returns an error.
+    pub fn scan_contents<F>(mut self, scanner: F) -> Self
+    where
+        F: FnMut(&Path, &Metadata, &mut dyn Read) -> Result<bool, Error> + Send + 'static,
+    {
+        self.scanner = Some(Box::new(scanner));
+        self
+    }
+
+    /// How to unpack the entries that are hard links to the already unpacked files.
+    ///
+    /// [`HardLinkPolicy::Link`] by default.
+    pub fn hard_links(mut self, policy: HardLinkPolicy) -> Self {
+        self.hard_links = policy;
+        self
+    }
+
+    /// Call `f` with the entry's path and the chosen action for each hard link
+    /// to the already unpacked file.
+    pub fn on_hard_link<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&Path, HardLinkAction) + Send + 'static,
+    {
+        self.on_hard_link = Some(Box::new(f));
+        self
+    }
+
+    /// How to unpack symbolic links.
+    ///
+    /// [`SymlinkPolicy::Create`] by default.
+    pub fn symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlinks = policy;
+        self
+    }
+
+    /// What to do with the absolute targets of symbolic links.
+    ///
+    /// Use [`AbsoluteTargetPolicy::Relative`] or [`AbsoluteTargetPolicy::Prefix`]
+    /// to inspect the unpacked root file system image
+    /// without creating the links that point to the host's files.
+    /// The links with absolute paths kept by [`AbsolutePathPolicy::Keep`] are not changed.
+    ///
+    /// [`AbsoluteTargetPolicy::Keep`] by default.
+    pub fn absolute_symlink_targets(mut self, policy: AbsoluteTargetPolicy) -> Self {
+        self.absolute_targets = policy;
+        self
+    }
+
+    /// Preserve file modification time.
+    ///
+    /// `false` by default.
+    pub fn preserve_mtime(mut self, value: bool) -> Self {
+        self.preserve_mtime = value;
+        self
+    }
+
+    /// Preserve file's user and group IDs, including the ones of symbolic links.
+    ///
+    /// Like in GNU cpio, the owner is preserved by default only when running as root,
+    /// because other users can't give away their files.
+    ///
+    /// `true` for root and `false` for other users by default.
+    pub fn preserve_owner(mut self, value: bool) -> Self {
+        self.preserve_owner = Some(value);
+        self
+    }
+
+    /// Change the user id `from` stored in the archive to `to` when the owner is preserved.
+    ///
+    /// Use the mappings to unpack system images inside user namespaces
+    /// where only a subset of ids is available.
+    /// The ids without mappings are handled according to [`unmapped_ids`](Self::unmapped_ids).
+    pub fn add_uid_mapping(mut self, from: u32, to: u32) -> Self {
+        self.owner_map.uids.insert(from, to);
+        self
+    }
+
+    /// Change the group id `from` stored in the archive to `to` when the group is set from the archive.
+    ///
+    /// [`GroupPolicy::Force`] ignores the mappings.
+    /// The ids without mappings are handled according to [`unmapped_ids`](Self::unmapped_ids).
+    pub fn add_gid_mapping(mut self, from: u32, to: u32) -> Self {
+        self.owner_map.gids.insert(from, to);
+        self
+    }
+
+    /// What to do with the user and group ids that don't have a mapping.
+    ///
+    /// The policy applies to every id that doesn't have a mapping,
+    /// including the case when there are no mappings at all,
+    /// e.g. [`UnmappedIdPolicy::Current`] alone unpacks all the files as the current user.
+    ///
+    /// [`UnmappedIdPolicy::Keep`] by default.
+    pub fn unmapped_ids(mut self, policy: UnmappedIdPolicy) -> Self {
+        self.owner_map.unmapped = policy;
+        self
+    }
+
+    /// Don't restore the modification times older than `mtime` seconds since Unix epoch.
+    ///
+    /// Reproducible archives often store zero modification time;
+    /// restoring it makes the files look older than their build outputs to incremental build tools.
+    /// With this option such files keep the time when they were unpacked
+    /// even if [`preserve_mtime`](Self::preserve_mtime) is enabled,
+    /// e.g. `min_mtime(1)` skips only zero modification times.
+    ///
+    /// `0` by default, i.e. all modification times are restored.
+    pub fn min_mtime(mut self, mtime: u64) -> Self {
+        self.min_mtime = mtime;
+        self
+    }
+
+    /// Create holes in the regular files in place of the blocks of zeros.
+    ///
+    /// This is the counterpart of [`Builder::detect_sparse_files`](crate::Builder::detect_sparse_files):
+    /// the archive always stores the full contents,
+    /// but the unpacked files occupy only the space of their non-zero blocks.
+    ///
+    /// `false` by default.
+    pub fn sparse(mut self, value: bool) -> Self {
+        self.sparse = value;
+        self
+    }
+
+    /// How to choose the group of the unpacked files.
+    ///
+    /// Files created in a directory with setgid bit inherit the directory's group
+    /// unless the group is changed explicitly.
+    ///
+    /// [`GroupPolicy::FollowOwner`] by default.
+    pub fn groups(mut self, policy: GroupPolicy) -> Self {
+        self.groups = policy;
+        self
+    }
+
+    /// Unpack only the entries which paths match at least one of the shell wildcard `patterns`.
+    ///
+    /// `*` matches any sequence of characters including `/`, `?` matches any character,
+    /// `[...]` matches any of the enclosed characters or ranges
+    /// (`[!...]` and `[^...]` negate the set), and `\` escapes the next character.
+    /// The patterns are matched against the whole path of the entry as stored in the archive,
+    /// i.e. like in GNU cpio, `usr/*` doesn't match `./usr/bin/sh`.
+    /// Parent directories of the matching entries are created even if they don't match.
+    ///
+    /// All entries are unpacked by default.
+    pub fn include_patterns<I, P>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<OsStr>,
+    {
+        self.patterns.include.extend(to_patterns(patterns));
+        self
+    }
+
+    /// Don't unpack the entries which paths match any of the shell wildcard `patterns`.
+    ///
+    /// See [`include_patterns`](Self::include_patterns) for the syntax.
+    /// Exclusion takes precedence over inclusion.
+    pub fn exclude_patterns<I, P>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<OsStr>,
+    {
+        self.patterns.exclude.extend(to_patterns(patterns));
+        self
+    }
+
+    /// Remove the first `n` components of the paths of the entries, like `tar --strip-components`.
+    ///
+    /// The components are removed after the path is normalized,
+    /// e.g. `./root/usr/bin/sh` becomes `usr/bin/sh` with `n = 1`,
+    /// and the entries with `n` or fewer components (including `./root` itself) are skipped.
+    /// The patterns are matched against the original paths.
+    ///
+    /// `0` by default.
+    pub fn strip_components(mut self, n: usize) -> Self {
+        self.strip_components = n;
+        self
+    }
+
+    /// Call `f` with the entry's path and metadata to choose the path it is unpacked to.
+    ///
+    /// The entry is unpacked to the returned path or is skipped if `f` returns `None`,
+    /// e.g. return `etc.new/passwd` for `etc/passwd` to keep the existing configuration.
+    /// This is the scriptable equivalent of `--rename` option of GNU cpio.
+    /// The returned path is resolved the same way as the original one,
+    /// i.e. [`strip_components`](Self::strip_components) and [`absolute_paths`](Self::absolute_paths)
+    /// are applied to it, and it can't point outside of the target directory.
+    /// The patterns are matched against the original path.
+    pub fn rename<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Path, &Metadata) -> Option<PathBuf> + Send + 'static,
+    {
+        self.rename = Some(Box::new(f));
+        self
+    }
+
+    /// How to unpack block and character devices.
+    ///
+    /// [`SpecialFilePolicy::Create`] by default.
+    pub fn devices(mut self, policy: SpecialFilePolicy) -> Self {
+        self.special_files.devices = policy;
+        self
+    }
+
+    /// How to unpack FIFOs.
+    ///
+    /// [`SpecialFilePolicy::Create`] by default.
+    pub fn fifos(mut self, policy: SpecialFilePolicy) -> Self {
+        self.special_files.fifos = policy;
+        self
+    }
+
+    /// How to unpack sockets.
+    ///
+    /// [`SpecialFilePolicy::Create`] by default.
+    pub fn sockets(mut self, policy: SpecialFilePolicy) -> Self {
+        self.special_files.sockets = policy;
+        self
+    }
+
+    /// Call `f` with the entry's path and the error for each device, FIFO or socket
+    /// that was skipped according to [`SpecialFilePolicy::Warn`].
+    pub fn on_special_file<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&Path, &Error) + Send + 'static,
+    {
+        self.on_special_file = Some(Box::new(f));
+        self
+    }
+
+    /// Unpack the archives that were meant for root as an unprivileged user.
+    ///
+    /// The owner of the files is never changed, i.e. all files belong to the current user,
+    /// and [`preserve_owner`](Self::preserve_owner), [`groups`](Self::groups)
+    /// and the id mappings are ignored.
+    /// Devices, FIFOs and sockets that are not skipped according to their [`SpecialFilePolicy`]
+    /// are replaced according to [`placeholder`](Self::placeholder).
+    /// Permission errors (`EPERM`) of setting the extended attributes,
+    /// the permissions and the modification times of the directories are ignored.
+    ///
+    /// `false` by default.
+    pub fn rootless(mut self, value: bool) -> Self {
+        self.rootless = value;
+        self
+    }
+
+    /// What to unpack instead of devices, FIFOs and sockets in [`rootless`](Self::rootless) mode.
+    ///
+    /// [`Placeholder::EmptyFile`] by default.
+    pub fn placeholder(mut self, placeholder: Placeholder) -> Self {
+        self.placeholder = placeholder;
+        self
+    }
+
+    /// Restore the extended attributes of regular files and directories (Linux only).
+    ///
+    /// This is the counterpart of [`Builder::preserve_xattrs`](crate::Builder::preserve_xattrs):
+    /// the entries with [`XATTRS_PREFIX`](crate::XATTRS_PREFIX) are not unpacked,
+    /// instead their contents are applied to the files they refer to
+    /// after the owner is changed (changing the owner clears file capabilities).
+    /// When disabled, such entries are unpacked as regular files.
+    /// On other platforms the entries are skipped and the attributes are not restored.
+    ///
+    /// `false` by default.
+    pub fn xattrs(mut self, value: bool) -> Self {
+        self.xattrs = value;
+        self
+    }
+
+    /// Call `f` with the entry's path and the chosen action for each symbolic link.
+    ///
+    /// With [`SymlinkPolicy::Copy`] the links are reported after all the other entries are unpacked.
+    pub fn on_symlink<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&Path, SymlinkAction) + Send + 'static,
+    {
+        self.on_symlink = Some(Box::new(f));
+        self
+    }
+
+    /// Continue unpacking when an entry can't be written.
+    ///
+    /// The errors are collected in [`UnpackReport::errors`] instead of aborting unpacking.
+    /// This includes the errors that occur when the directory permissions are set at the end.
+    /// Errors reading the archive itself are still fatal.
+    ///
+    /// `false` by default.
+    pub fn keep_going(mut self, value: bool) -> Self {
+        self.keep_going = value;
+        self
+    }
+
+    /// Check the free space of the target file system before writing each regular file.
+    ///
+    /// The entry fails with [`ErrorKind::StorageFull`] and a message with the required
+    /// and the available space before its file is created,
+    /// instead of failing with `ENOSPC` midway through writing its contents.
+    /// The check works for the archives that can't be rewound;
+    /// use [`Archive::check_free_space`](crate::Archive::check_free_space)
+    /// to check the space required by the whole archive before unpacking it.
+    ///
+    /// `false` by default.
+    pub fn check_free_space(mut self, value: bool) -> Self {
+        self.check_free_space = value;
+        self
+    }
+}
+
+/// How to choose the group of the unpacked files.
+///
+/// Use [`UnpackOptions::groups`] to choose the policy.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum GroupPolicy {
+    /// Set the group from the archive only if the owner is preserved
+    /// (see [`UnpackOptions::preserve_owner`]).
+    #[default]
+    FollowOwner,
+    /// Always set the group from the archive.
+    Archive,
+    /// Never change the group assigned by the operating system,
+    /// i.e. the group of the parent directory with setgid bit or the effective group of the process.
+    ///
+    /// Setgid bit inherited by the directories is kept as well.
+    /// This is what shared project directories usually need.
+    Inherit,
+    /// Set the specified group id.
+    Force(u32),
+}
+
+/// User and group ids, `None` means unchanged.
+type Owner = (Option<u32>, Option<u32>);
+
+/// How to unpack devices, FIFOs and sockets.
+///
+/// Use [`UnpackOptions::devices`], [`UnpackOptions::fifos`] and [`UnpackOptions::sockets`]
+/// to choose the policy for each file type.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum SpecialFilePolicy {
+    /// Create the file.
+    #[default]
+    Create,
+    /// Don't create the file.
+    Skip,
+    /// Try to create the file and skip it if the process doesn't have the privileges (`EPERM`).
+    ///
+    /// The skipped entries are reported by [`UnpackOptions::on_special_file`].
+    /// This is how unprivileged users unpack initramfs images with `/dev` nodes.
+    Warn,
+    /// Fail with [`ErrorKind::InvalidData`] without creating the file.
+    Error,
+}
+
+type SpecialFileReporter = dyn FnMut(&Path, &Error) + Send;
+
+/// Per-file-type special file policies.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct SpecialFiles {
+    devices: SpecialFilePolicy,
+    fifos: SpecialFilePolicy,
+    sockets: SpecialFilePolicy,
+}
+
+impl SpecialFiles {
+    /// Returns the policy for the special file type or `None` for the other types.
+    pub(crate) fn policy(&self, file_type: FileType) -> Option<SpecialFilePolicy> {
+        match file_type {
+            FileType::BlockDevice | FileType::CharDevice => Some(self.devices),
+            FileType::Fifo => Some(self.fifos),
+            FileType::Socket => Some(self.sockets),
+            _ => None,
+        }
+    }
+
+    /// Fails if the special file type is not allowed.
+    pub(crate) fn check(&self, file_type: FileType) -> Result<(), Error> {
+        if self.policy(file_type) != Some(SpecialFilePolicy::Error) {
+            return Ok(());
+        }
+        let what = match file_type {
+            FileType::BlockDevice | FileType::CharDevice => "devices",
+            FileType::Fifo => "FIFOs",
+            _ => "sockets",
+        };
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} are not allowed", what),
+        ))
+    }
+}
+
+/// What to unpack instead of devices, FIFOs and sockets.
+///
+/// Use [`UnpackOptions::placeholder`] to choose the placeholder.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum Placeholder {
+    /// Create an empty regular file with the same path and permissions.
+    #[default]
+    EmptyFile,
+    /// Don't create anything.
+    Skip,
+}
+
+/// What to do with the ids that don't have a mapping.
+///
+/// Use [`UnpackOptions::unmapped_ids`] to choose the policy.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum UnmappedIdPolicy {
+    /// Use the id from the archive.
+    #[default]
+    Keep,
+    /// Don't change the id assigned by the operating system.
+    Skip,
+    /// Fail with [`ErrorKind::InvalidData`].
+    Error,
+    /// Use the effective user or group id of the process.
+    Current,
+}
+
+/// User and group id mappings.
+#[derive(Clone, Default)]
+pub(crate) struct OwnerMap {
+    uids: HashMap<u32, u32>,
+    gids: HashMap<u32, u32>,
+    unmapped: UnmappedIdPolicy,
+}
+
+impl OwnerMap {
+    /// Map the user id, `None` means unchanged.
+    pub(crate) fn map_uid(&self, uid: u32) -> Result<Option<u32>, Error> {
+        self.map(&self.uids, uid, "uid", || unsafe { libc::geteuid() })
+    }
+
+    /// Map the group id, `None` means unchanged.
+    pub(crate) fn map_gid(&self, gid: u32) -> Result<Option<u32>, Error> {
+        self.map(&self.gids, gid, "gid", || unsafe { libc::getegid() })
+    }
+
+    fn map(
+        &self,
+        mappings: &HashMap<u32, u32>,
+        id: u32,
+        name: &str,
+        current: impl FnOnce() -> u32,
+    ) -> Result<Option<u32>, Error> {
+        if let Some(to) = mappings.get(&id) {
+            return Ok(Some(*to));
+        }
+        match self.unmapped {
+            UnmappedIdPolicy::Keep => Ok(Some(id)),
+            UnmappedIdPolicy::Skip => Ok(None),
+            UnmappedIdPolicy::Error => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unmapped {} {}", name, id),
+            )),
+            UnmappedIdPolicy::Current => Ok(Some(current())),
+        }
+    }
+}
+
+/// How to unpack the hard links.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum HardLinkPolicy {
+    /// Create hard links.
+    #[default]
+    Link,
+    /// Create independent copies of the files.
+    ///
+    /// Useful for the file systems that don't support hard links.
+    Copy,
+    /// Create hard links and fall back to copies
+    /// when the file system doesn't support them or the link count limit is reached.
+    CopyIfUnsupported,
+}
+
+/// How a hard link was unpacked.
+///
+/// Reported by [`UnpackOptions::on_hard_link`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum HardLinkAction {
+    /// The hard link was created.
+    Linked,
+    /// An independent copy of the file was created.
+    Copied,
+}
+
+type HardLinkReporter = dyn FnMut(&Path, HardLinkAction) + Send;
+
+/// How to unpack symbolic links.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum SymlinkPolicy {
+    /// Create symbolic links.
+    #[default]
+    Create,
+    /// Replace symbolic links with the copies of their targets.
+    ///
+    /// Only the links to the regular files from the same archive are copied
+    /// (possibly via other links), the other links are skipped.
+    /// The targets are resolved after all the other entries are unpacked,
+    /// i.e. they may appear in the archive after the links.
+    /// The links with absolute targets are skipped.
+    ///
+    /// Useful for the file systems that don't support symbolic links.
+    Copy,
+    /// Skip symbolic links.
+    Skip,
+}
+
+/// What to do with the absolute targets of symbolic links.
+///
+/// Use [`UnpackOptions::absolute_symlink_targets`] to choose the policy.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum AbsoluteTargetPolicy {
+    /// Keep the targets as is, i.e. the links point to the files outside of the target directory.
+    #[default]
+    Keep,
+    /// Rewrite the targets relative to the link as if the target directory was the root,
+    /// e.g. `/bin/sh` becomes `../bin/sh` for the link `usr/sh`.
+    Relative,
+    /// Prefix the targets with the canonical path of the target directory.
+    ///
+    /// Requires the path of the target directory, i.e. the links with absolute targets
+    /// fail to unpack with [`Archive::unpack_in_dirfd`](crate::Archive::unpack_in_dirfd).
+    Prefix,
+}
+
+/// How a symbolic link was unpacked.
+///
+/// Reported by [`UnpackOptions::on_symlink`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum SymlinkAction {
+    /// The symbolic link was created.
+    Created,
+    /// A copy of the target was created instead of the link.
+    Copied,
+    /// The link was not unpacked.
+    Skipped,
+}
+
+type SymlinkReporter = dyn FnMut(&Path, SymlinkAction) + Send;
+
+/// The symbolic link that is replaced with a copy of its target.
+pub(crate) struct PendingSymlink {
+    // The path of the entry in the archive.
+    entry_path: PathBuf,
+    path: CString,
+    // The resolved path of the target if it is inside the target directory.
+    target: Option<CString>,
+    owner: Option<Owner>,
+    modified: Option<SystemTime>,
+}
+
+impl PendingSymlink {
+    /// Set the modification time and the owner of the copy.
+    fn set_metadata(&self, dirfd: RawFd) -> Result<(), Error> {
+        if let Some(modified) = self.modified {
+            set_file_modified_time(dirfd, &self.path, modified)?;
+        }
+        if let Some((uid, gid)) = self.owner {
+            lchownat(dirfd, &self.path, uid, gid)?;
+        }
+        Ok(())
+    }
+}
+
+/// What to do with the absolute paths when unpacking.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum AbsolutePathPolicy {
+    /// Remove leading `/` and unpack the entry relative to the target directory.
+    #[default]
+    Strip,
+    /// Unpack the entry relative to the file system root, i.e. outside of the target directory.
+    ///
+    /// This is the default behavior of GNU cpio.
+    /// Only use this option for trusted archives:
+    /// the parent directories of such entries are not checked for symbolic links,
+    /// i.e. the files are written through the existing links (e.g. `/lib` on merged-usr systems)
+    /// as well as through the links created by the archive.
+    Keep,
+    /// Fail with an error.
+    Error,
+}
+
+/// Chooses the path of the unpacked entry or returns `None` to skip it.
+///
+/// Used by [`UnpackOptions::rename`].
+pub type Renamer = dyn Fn(&Path, &Metadata) -> Option<PathBuf> + Send;
+
+/// Inspects the contents of a regular file and returns `false` to reject it.
+///
+/// Used by [`UnpackOptions::scan_contents`].
+pub type Scanner = dyn FnMut(&Path, &Metadata, &mut dyn Read) -> Result<bool, Error> + Send;
+
+/// When to overwrite the existing files.
+///
+/// Use [`UnpackOptions::overwrite`] to choose the policy.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum OverwritePolicy {
+    /// Always replace the existing file.
+    #[default]
+    Always,
+    /// Never replace the existing file and skip the entry.
+    Never,
+    /// Replace the existing file only if the entry's modification time is later
+    /// and skip the entry otherwise.
+    ///
+    /// This is what GNU cpio does without `--unconditional`.
+    IfNewer,
+}
+
+/// What to do when an archive entry conflicts with an existing file.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum ConflictPolicy {
+    /// Fail with an error.
+    #[default]
+    Error,
+    /// Remove the existing file and unpack the entry.
+    Replace,
+    /// Keep the existing file and skip the entry.
+    Skip,
+}
+
+/// The summary of unpacking returned by [`Archive::unpack`](crate::Archive::unpack).
+///
+/// Use it to log the results or to remove the unpacked files on failure.
+#[derive(Debug, Default)]
+pub struct UnpackReport {
+    /// The paths of the files, directories, symbolic links and special files that were written,
+    /// in the order of the entries.
+    ///
+    /// The paths are relative to the target directory
+    /// except the absolute paths kept by [`AbsolutePathPolicy::Keep`].
+    /// The hard links are listed separately.
+    pub written: Vec<PathBuf>,
+    /// The total size of the regular files that were written
+    /// including the contents that were written via the hard links.
+    pub bytes: u64,
+    /// The hard links that were created and the paths of the files they point to.
+    pub hard_links: Vec<(PathBuf, PathBuf)>,
+    /// The paths of the skipped entries in the archive and the reasons.
+    pub skipped: Vec<(PathBuf, SkipReason)>,
+    /// The paths of the entries that failed to unpack and the errors.
+    ///
+    /// Only collected with [`UnpackOptions::keep_going`].
+    /// The errors that occur when the directory permissions are set
+    /// are reported with the path of the directory relative to the target directory.
+    pub errors: Vec<(PathBuf, Error)>,
+    /// The number of entries which paths were rejected.
+    ///
+    /// This includes the skipped entries that point outside of the target directory
+    /// and the entries that failed because of [`UnpackOptions::strict_paths`]
+    /// or [`AbsolutePathPolicy::Error`].
+    pub rejected_paths: usize,
+}
+
+/// The error that stopped unpacking and the report on the entries that were unpacked before it.
+///
+/// Use the report to remove the files that were unpacked before the failure.
+#[derive(Debug)]
+pub struct UnpackError {
+    /// The error.
+    pub error: Error,
+    /// The entries that were unpacked before the error.
+    pub report: UnpackReport,
+}
+
+impl Display for UnpackError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for UnpackError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<Error> for UnpackError {
+    fn from(error: Error) -> Self {
+        Self {
+            error,
+            report: Default::default(),
+        }
+    }
+}
+
+impl From<UnpackError> for Error {
+    fn from(e: UnpackError) -> Self {
+        e.error
+    }
+}
+
+/// The result of unpacking an entry.
+enum Outcome {
+    /// The file was written.
+    Written(CString),
+    /// The hard link to the original file was created
+    /// and the contents of the given size were written to it.
+    Linked(CString, CString, u64),
+    /// The entry was skipped.
+    Skipped(SkipReason),
+    /// Nothing is written yet, e.g. for the extended attributes or the copied symbolic links.
+    Deferred,
+}
+
+/// The state of the unpacking process.
+pub(crate) struct Unpacker<'a> {
+    pub(crate) dirfd: RawFd,
+    // Only used to bind sockets on platforms where `mknod` can't create them.
+    #[cfg_attr(target_os = "linux", allow(unused))]
+    pub(crate) directory: Option<&'a Path>,
+    // Directories, their modes and modification times
+    // that are set when all the other files are written.
+    pub(crate) dirs: Vec<(CString, u32, Option<SystemTime>)>,
+    // (dev, inode) -> (path, file size) mapping.
+    pub(crate) hard_links: HashMap<MetadataId, (CString, u64)>,
+    // (dev, inode) -> paths of the copies that were created instead of hard links.
+    pub(crate) copies: HashMap<MetadataId, Vec<CString>>,
+    // (dev, inode) -> paths of the hard links to the original file.
+    pub(crate) links: HashMap<MetadataId, Vec<CString>>,
+    pub(crate) hard_link_policy: HardLinkPolicy,
+    pub(crate) on_hard_link: Option<Box<HardLinkReporter>>,
+    pub(crate) symlink_policy: SymlinkPolicy,
+    pub(crate) absolute_targets: AbsoluteTargetPolicy,
+    // The canonical path of the target directory for [`AbsoluteTargetPolicy::Prefix`].
+    pub(crate) root: Option<PathBuf>,
+    pub(crate) on_symlink: Option<Box<SymlinkReporter>>,
+    // The symbolic links that are copied when all the other files are written.
+    pub(crate) pending_symlinks: Vec<PendingSymlink>,
+    pub(crate) nlink_policy: NlinkPolicy,
+    pub(crate) preserve_mtime: bool,
+    pub(crate) min_mtime: u64,
+    pub(crate) preserve_owner: bool,
+    pub(crate) dir_over_symlink: ConflictPolicy,
+    pub(crate) symlink_over_dir: ConflictPolicy,
+    pub(crate) dir_over_file: ConflictPolicy,
+    pub(crate) file_over_dir: ConflictPolicy,
+    pub(crate) update_existing_dirs: bool,
+    pub(crate) overwrite: OverwritePolicy,
+    pub(crate) mode_mask: Option<u32>,
+    pub(crate) absolute_paths: AbsolutePathPolicy,
+    pub(crate) strict_paths: bool,
+    pub(crate) scanner: Option<Box<Scanner>>,
+    // The inodes of the files rejected by the scanner.
+    pub(crate) rejected: HashSet<MetadataId>,
+    pub(crate) sparse: bool,
+    pub(crate) group_policy: GroupPolicy,
+    pub(crate) owner_map: OwnerMap,
+    pub(crate) rootless: bool,
+    pub(crate) placeholder: Placeholder,
+    pub(crate) special_files: SpecialFiles,
+    pub(crate) on_special_file: Option<Box<SpecialFileReporter>>,
+    pub(crate) patterns: Patterns,
+    pub(crate) strip_components: usize,
+    pub(crate) rename: Option<Box<Renamer>>,
+    pub(crate) xattrs: bool,
+    // Target path -> extended attributes mapping.
+    pub(crate) pending_xattrs: HashMap<PathBuf, Vec<Xattr>>,
+    pub(crate) keep_going: bool,
+    pub(crate) check_free_space: bool,
+    pub(crate) report: UnpackReport,
+}
+
+impl<'a> Unpacker<'a> {
+    pub(crate) fn unpack_entry<R: Read>(&mut self, entry: &mut Entry<'_, R>) -> Result<(), Error> {
+        let outcome = match self.do_unpack_entry(entry) {
+            Ok(outcome) => outcome,
+            Err(e) if self.keep_going => {
+                self.report.errors.push((entry.path.clone(), e));
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let report = &mut self.report;
+        match outcome {
+            Outcome::Written(path) => {
+                if entry.metadata.is_file() {
+                    report.bytes += entry.metadata.file_size;
+                }
+                report.written.push(c_string_to_path_buf(&path));
+            }
+            Outcome::Linked(path, original, bytes) => {
+                report.bytes += bytes;
+                report
+                    .hard_links
+                    .push((c_string_to_path_buf(&path), c_string_to_path_buf(&original)));
+            }
+            Outcome::Skipped(reason) => report.skipped.push((entry.path.clone(), reason)),
+            Outcome::Deferred => {}
+        }
+        Ok(())
+    }
+
+    fn do_unpack_entry<R: Read>(&mut self, entry: &mut Entry<'_, R>) -> Result<Outcome, Error> {
+        use std::collections::hash_map::Entry::*;
+        let dirfd = self.dirfd;
+        let preserve_mtime = self.preserve_mtime && entry.metadata.mtime() >= self.min_mtime;
+        if self.xattrs {
+            if let Some(target) = xattrs_target(&entry.path) {
+                let mut data = Vec::new();
+                entry.reader.read_to_end(&mut data)?;
+                self.pending_xattrs.insert(target, decode_xattrs(&data)?);
+                return Ok(Outcome::Deferred);
+            }
+        }
+        let (path, file_type, owner) = match self.rules().select(&entry.path, &entry.metadata)? {
+            Selection::Unpack {
+                path,
+                file_type,
+                owner,
+            } => (path, file_type, owner),
+            Selection::Skip(_, reason) => {
+                match reason {
+                    SkipReason::Outside => self.report.rejected_paths += 1,
+                    SkipReason::Symlink => self.report_symlink(&entry.path, SymlinkAction::Skipped),
+                    _ => {}
+                }
+                return Ok(Outcome::Skipped(reason));
+            }
+            Selection::Rejected(e) => {
+                self.report.rejected_paths += 1;
+                return Err(e);
+            }
+        };
+        self.check_parents(&path)?;
+        if let Some(dirname) = path.parent() {
+            let mode_mask = self.mode_mask;
+            let dirs = &mut self.dirs;
+            create_dir_all_at(dirfd, dirname, &mut |path| {
+                if let Some(mask) = mode_mask {
+                    // apply the mode later like for the directories from the archive
+                    dirs.push((path.into(), 0o777 & !mask, None));
+                }
+            })?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        let full_path = self.directory.map(|d| d.join(&path));
+        let path = path_to_c_string(path)?;
+        if !self.resolve_conflict(file_type, &path)? {
+            return Ok(Outcome::Skipped(SkipReason::Conflict));
+        }
+        let is_hard_link = self.nlink_policy.is_hard_link(&entry.metadata);
+        if is_hard_link && self.rejected.contains(&entry.metadata.id()) {
+            // hard link to the rejected file
+            return Ok(Outcome::Skipped(SkipReason::Rejected));
+        }
+        let special_file_policy = self.special_files.policy(file_type);
+        // the scanned files replace the existing ones only when they are accepted
+        let replace_later = file_type == FileType::Regular && self.scanner.is_some();
+        if file_type != FileType::Directory {
+            if let Some(reason) = self.overwrite_existing(&entry.metadata, &path, !replace_later)? {
+                return Ok(Outcome::Skipped(reason));
+            }
+        }
+        if file_type == FileType::Regular {
+            self.ensure_free_space(entry.metadata.file_size)?;
+        }
+        let hard_link = if is_hard_link {
+            Some(self.hard_links.entry(entry.metadata.id()))
+        } else {
+            None
+        };
+        // the size of the original file if the entry is unpacked as its copy
+        let mut copy_of = None;
+        // the contents that were accepted by the scanner before linking
+        let mut scanned = None;
+        match hard_link {
+            None => {}
+            Some(Vacant(v)) => {
+                v.insert((path.clone(), entry.metadata.file_size));
+            }
+            Some(Occupied(o)) => {
+                let (original, original_file_size) = o.get().clone();
+                self.check_parents(&c_string_to_path_buf(&original))?;
+                let id = entry.metadata.id();
+                // the earlier links were unpacked without contents
+                let has_contents =
+                    entry.metadata.is_file() && original_file_size < entry.metadata.file_size;
+                if replace_later && self.hard_link_policy != HardLinkPolicy::Copy {
+                    if has_contents {
+                        // scan the contents before they are written to the earlier links
+                        let Some(temp) = self.scan(entry, &path)? else {
+                            self.reject_links(id, &path)?;
+                            return Ok(Outcome::Skipped(SkipReason::Rejected));
+                        };
+                        scanned = Some(temp);
+                    }
+                    // the link can't replace the existing file atomically
+                    match unlinkat(dirfd, &path, 0) {
+                        Ok(()) => {}
+                        Err(e) if e.kind() == ErrorKind::NotFound => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                let linked = match self.hard_link_policy {
+                    HardLinkPolicy::Link => {
+                        linkat(dirfd, &original, &path)?;
+                        true
+                    }
+                    HardLinkPolicy::Copy => false,
+                    HardLinkPolicy::CopyIfUnsupported => match linkat(dirfd, &original, &path) {
+                        Ok(()) => true,
+                        Err(e) if is_link_unsupported(&e) => false,
+                        Err(e) => return Err(e),
+                    },
+                };
+                if let Some(on_hard_link) = self.on_hard_link.as_mut() {
+                    let action = if linked {
+                        HardLinkAction::Linked
+                    } else {
+                        HardLinkAction::Copied
+                    };
+                    on_hard_link(&entry.path, action);
+                }
+                if linked {
+                    self.links.entry(id).or_default().push(path.clone());
+                    if has_contents {
+                        #[allow(clippy::unnecessary_cast)]
+                        let old_mode = fstatat(dirfd, &path)?.st_mode as u32;
+                        if !is_writable(old_mode) {
+                            // make writable
+                            fchmodat(dirfd, &path, 0o644)?;
+                        }
+                        let mut file = openat(dirfd, &path, O_WRONLY | O_TRUNC | O_NOFOLLOW, 0)?;
+                        if let Some((mut temp_file, temp_path)) = scanned {
+                            let result = std::io::copy(&mut temp_file, &mut file);
+                            unlinkat(dirfd, &temp_path, 0)?;
+                            result?;
+                        } else {
+                            entry.reader.copy_to(&mut file)?;
+                        }
+                        if preserve_mtime {
+                            if let Ok(modified) = entry.metadata.modified() {
+                                file.set_modified(modified)?;
+                            }
+                        }
+                        if let Some((uid, gid)) = owner {
+                            fchown(&file, uid, gid)?;
+                        }
+                        drop(file);
+                        fchmodat(dirfd, &path, (old_mode & FILE_MODE_MASK) as mode_t)?;
+                    }
+                    let bytes = if has_contents {
+                        entry.metadata.file_size
+                    } else {
+                        0
+                    };
+                    return Ok(Outcome::Linked(path, original, bytes));
+                }
+                copy_of = Some(original_file_size);
+            }
+        }
+        match file_type {
+            FileType::Regular => {
+                let (mut file, temp_path) = if let Some((file, temp_path)) = scanned {
+                    (file, Some(temp_path))
+                } else if self.scanner.is_some() {
+                    let Some((file, temp_path)) = self.scan(entry, &path)? else {
+                        if is_hard_link {
+                            self.reject_links(entry.metadata.id(), &path)?;
+                        }
+                        return Ok(Outcome::Skipped(SkipReason::Rejected));
+                    };
+                    (file, Some(temp_path))
+                } else {
+                    // copies are read back to update the earlier copies
+                    let access = if copy_of.is_some() { O_RDWR } else { O_WRONLY };
+                    let mut file =
+                        openat(dirfd, &path, access | O_CREAT | O_TRUNC | O_NOFOLLOW, 0o666)?;
+                    // the contents of hard links might come from another entry,
+                    // i.e. the file size doesn't necessarily match
+                    self.write_contents(entry, &mut file)?;
+                    (file, None)
+                };
+                if let Some(original_file_size) = copy_of {
+                    let id = entry.metadata.id();
+                    if original_file_size < entry.metadata.file_size {
+                        // the earlier links were unpacked without contents
+                        let modified = if preserve_mtime {
+                            entry.metadata.modified().ok()
+                        } else {
+                            None
+                        };
+                        self.update_copies(&mut file, id, modified)?;
+                        if let Some((_, file_size)) = self.hard_links.get_mut(&id) {
+                            *file_size = entry.metadata.file_size;
+                        }
+                    }
+                    self.copies.entry(id).or_default().push(path.clone());
+                }
+                if preserve_mtime {
+                    if let Ok(modified) = entry.metadata.modified() {
+                        file.set_modified(modified)?;
+                    }
+                }
+                if let Some((uid, gid)) = owner {
+                    fchown(&file, uid, gid)?;
+                }
+                self.restore_xattrs(&entry.path, &file)?;
+                file.set_permissions(Permissions::from_mode(
+                    self.apply_mode_mask(entry.metadata.file_mode()),
+                ))?;
+                if let Some(temp_path) = temp_path {
+                    renameat(dirfd, &temp_path, &path)?;
+                }
+            }
+            FileType::Directory => {
+                // create directory with default permissions
+                match mkdirat(dirfd, &path, 0o777) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == ErrorKind::AlreadyExists && is_dir_at(dirfd, &path)? => {
+                        if !self.update_existing_dirs {
+                            return Ok(Outcome::Skipped(SkipReason::ExistingDir));
+                        }
+                    }
+                    Err(e) => return Err(e),
+                }
+                if let Some((uid, gid)) = owner {
+                    lchownat(dirfd, &path, uid, gid)?;
+                }
+                if self
+                    .pending_xattrs
+                    .contains_key(strip_dot_slash(&entry.path))
+                {
+                    let dir = openat(dirfd, &path, O_RDONLY | O_DIRECTORY | O_NOFOLLOW, 0)?;
+                    self.restore_xattrs(&entry.path, &dir)?;
+                }
+                let modified = if preserve_mtime {
+                    entry.metadata.modified().ok()
+                } else {
+                    None
+                };
+                // apply proper permissions and modification time later
+                // when we have written all other files
+                let mode = self.apply_mode_mask(entry.metadata.file_mode());
+                self.dirs.push((path.clone(), mode, modified));
+            }
+            FileType::Fifo => {
+                let result = mkfifoat(dirfd, &path, entry.metadata.mode as mode_t);
+                if !self.special_file_created(&entry.path, special_file_policy, result)? {
+                    return Ok(Outcome::Skipped(SkipReason::Special));
+                }
+                self.chmod_masked(&path, entry.metadata.file_mode())?;
+                if preserve_mtime {
+                    if let Ok(modified) = entry.metadata.modified() {
+                        set_file_modified_time(dirfd, &path, modified)?;
+                    }
+                }
+                if let Some((uid, gid)) = owner {
+                    lchownat(dirfd, &path, uid, gid)?;
+                }
+            }
+            FileType::Socket => {
+                #[cfg(target_os = "linux")]
+                let result = mknodat(dirfd, &path, entry.metadata.mode as mode_t, 0);
+                #[cfg(not(target_os = "linux"))]
+                let result = match full_path {
+                    // there is no `bindat` on this platform
+                    Some(full_path) => UnixDatagram::bind(full_path).map(drop),
+                    None => Err(ErrorKind::Unsupported.into()),
+                };
+                if !self.special_file_created(&entry.path, special_file_policy, result)? {
+                    return Ok(Outcome::Skipped(SkipReason::Special));
+                }
+                self.chmod_masked(&path, entry.metadata.file_mode())?;
+                if preserve_mtime {
+                    if let Ok(modified) = entry.metadata.modified() {
+                        set_file_modified_time(dirfd, &path, modified)?;
+                    }
+                }
+                if let Some((uid, gid)) = owner {
+                    lchownat(dirfd, &path, uid, gid)?;
+                }
+            }
+            FileType::BlockDevice | FileType::CharDevice => {
+                let result = mknodat(
+                    dirfd,
+                    &path,
+                    entry.metadata.mode as mode_t,
+                    entry.metadata.rdev() as dev_t,
+                );
+                if !self.special_file_created(&entry.path, special_file_policy, result)? {
+                    return Ok(Outcome::Skipped(SkipReason::Special));
+                }
+                self.chmod_masked(&path, entry.metadata.file_mode())?;
+                if preserve_mtime {
+                    if let Ok(modified) = entry.metadata.modified() {
+                        set_file_modified_time(dirfd, &path, modified)?;
+                    }
+                }
+                if let Some((uid, gid)) = owner {
+                    lchownat(dirfd, &path, uid, gid)?;
+                }
+            }
+            FileType::Symlink => {
+                let mut original = Vec::new();
+                entry.reader.read_to_end(&mut original)?;
+                if let Some(0) = original.last() {
+                    original.pop();
+                }
+                if let Some(target) = self.rewrite_target(&path, &original)? {
+                    original = target.into_os_string().into_vec();
+                }
+                // skipped links are handled above
+                if self.symlink_policy == SymlinkPolicy::Copy {
+                    let target = Path::new(OsStr::from_bytes(&original));
+                    let target = if target.is_absolute() {
+                        None
+                    } else {
+                        let path = Path::new(OsStr::from_bytes(path.to_bytes()));
+                        let dirname = path.parent().unwrap_or(Path::new(""));
+                        dirname.join(target).try_normalize()
+                    };
+                    let target = match target {
+                        Some(target) => Some(path_to_c_string(target)?),
+                        None => None,
+                    };
+                    let modified = if preserve_mtime {
+                        entry.metadata.modified().ok()
+                    } else {
+                        None
+                    };
+                    self.pending_symlinks.push(PendingSymlink {
+                        entry_path: entry.path.clone(),
+                        path,
+                        target,
+                        owner,
+                        modified,
+                    });
+                    return Ok(Outcome::Deferred);
+                }
+                let original = CString::new(original)?;
+                symlinkat(&original, dirfd, &path)?;
+                if preserve_mtime {
+                    if let Ok(modified) = entry.metadata.modified() {
+                        set_file_modified_time(dirfd, &path, modified)?;
+                    }
+                }
+                if let Some((uid, gid)) = owner {
+                    lchownat(dirfd, &path, uid, gid)?;
+                }
+                self.report_symlink(&entry.path, SymlinkAction::Created);
+            }
+        }
+        Ok(Outcome::Written(path))
+    }
+
+    /// Write the contents to a temporary file and pass them to the scanner.
+    ///
+    /// Returns the temporary file and its path if the contents were accepted.
+    /// The file is removed otherwise.
+    fn scan<R: Read>(
+        &mut self,
+        entry: &mut Entry<'_, R>,
+        path: &CStr,
+    ) -> Result<Option<(File, CString)>, Error> {
+        let (mut file, temp_path) = create_temp_file(self.dirfd, path)?;
+        let result = self.do_scan(entry, &mut file);
+        match result {
+            Ok(true) => Ok(Some((file, temp_path))),
+            Ok(false) => {
+                drop(file);
+                unlinkat(self.dirfd, &temp_path, 0)?;
+                Ok(None)
+            }
+            Err(e) => {
+                drop(file);
+                let _ = unlinkat(self.dirfd, &temp_path, 0);
+                Err(e)
+            }
+        }
+    }
+
+    fn do_scan<R: Read>(
+        &mut self,
+        entry: &mut Entry<'_, R>,
+        file: &mut File,
+    ) -> Result<bool, Error> {
+        self.write_contents(entry, file)?;
+        let Some(scanner) = self.scanner.as_mut() else {
+            return Ok(true);
+        };
+        file.seek(SeekFrom::Start(0))?;
+        let accepted = scanner(&entry.path, &entry.metadata, file)?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(accepted)
+    }
+
+    /// Copy the contents of the entry to the newly created `file`.
+    fn write_contents<R: Read>(
+        &self,
+        entry: &mut Entry<'_, R>,
+        file: &mut File,
+    ) -> Result<(), Error> {
+        if !self.sparse {
+            entry.reader.copy_to(file)?;
+            return Ok(());
+        }
+        let mut buf = [0_u8; SPARSE_BLOCK_SIZE];
+        let mut len = 0;
+        let mut ends_with_hole = false;
+        loop {
+            let n = read_block(&mut entry.reader, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            if buf[..n].iter().all(|b| *b == 0) {
+                file.seek(SeekFrom::Current(n as i64))?;
+                ends_with_hole = true;
+            } else {
+                file.write_all(&buf[..n])?;
+                ends_with_hole = false;
+            }
+            len += n as u64;
+        }
+        if ends_with_hole {
+            // extend the file to its full size
+            file.set_len(len)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the earlier links and copies of the file which contents were rejected by the scanner.
+    ///
+    /// The subsequent links to this file are skipped.
+    /// The file at the `current` path is kept because it wasn't unpacked.
+    fn reject_links(&mut self, id: MetadataId, current: &CStr) -> Result<(), Error> {
+        self.rejected.insert(id);
+        let original = self.hard_links.remove(&id).map(|(path, _)| path);
+        let copies = self.copies.remove(&id).into_iter().flatten();
+        let links = self.links.remove(&id).into_iter().flatten();
+        for path in original.into_iter().chain(copies).chain(links) {
+            if path.as_c_str() == current {
+                continue;
+            }
+            match unlinkat(self.dirfd, &path, 0) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            let path = c_string_to_path_buf(&path);
+            self.report.written.retain(|written| *written != path);
+            self.report.hard_links.retain(|(link, _)| *link != path);
+        }
+        Ok(())
+    }
+
+    /// Overwrite the contents of the original file and its copies with the contents of `file`.
+    fn update_copies(
+        &self,
+        file: &mut File,
+        id: MetadataId,
+        modified: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        let original = self.hard_links.get(&id).map(|(path, _)| path);
+        let copies = self.copies.get(&id).into_iter().flatten();
+        for path in original.into_iter().chain(copies) {
+            #[allow(clippy::unnecessary_cast)]
+            let old_mode = fstatat(self.dirfd, path)?.st_mode as u32;
+            if !is_writable(old_mode) {
+                // make writable
+                fchmodat(self.dirfd, path, 0o644)?;
+            }
+            let mut copy = openat(self.dirfd, path, O_WRONLY | O_TRUNC | O_NOFOLLOW, 0)?;
+            file.seek(SeekFrom::Start(0))?;
+            std::io::copy(file, &mut copy)?;
+            if let Some(modified) = modified {
+                copy.set_modified(modified)?;
+            }
+            drop(copy);
+            fchmodat(self.dirfd, path, (old_mode & FILE_MODE_MASK) as mode_t)?;
+        }
+        Ok(())
+    }
+
+    /// Set the extended attributes of the file that were read from the earlier entry.
+    fn restore_xattrs(&mut self, entry_path: &Path, file: &File) -> Result<(), Error> {
+        let Some(xattrs) = self.pending_xattrs.remove(strip_dot_slash(entry_path)) else {
+            return Ok(());
+        };
+        #[cfg(target_os = "linux")]
+        for xattr in xattrs.iter() {
+            ignore_eperm(
+                self.rootless,
+                fsetxattr(file.as_raw_fd(), &xattr.name, &xattr.value),
+            )?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = (xattrs, file);
+        Ok(())
+    }
+
+    fn apply_mode_mask(&self, mode: u32) -> u32 {
+        match self.mode_mask {
+            Some(mask) => mode & !mask,
+            None => mode,
+        }
+    }
+
+    /// Set the masked mode bypassing the umask if the mask is specified.
+    fn chmod_masked(&self, path: &CStr, mode: u32) -> Result<(), Error> {
+        match self.mode_mask {
+            Some(mask) => fchmodat(self.dirfd, path, (mode & !mask) as mode_t),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns `false` if the entry has to be skipped.
+    fn resolve_conflict(&self, file_type: FileType, path: &CStr) -> Result<bool, Error> {
+        let existing = match fstatat(self.dirfd, path) {
+            #[allow(clippy::unnecessary_cast)]
+            Ok(stat) => FileType::new(stat.st_mode as u32)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e),
+        };
+        let Some(policy) = self.rules().conflict(file_type, existing) else {
+            return Ok(true);
+        };
+        match policy {
+            ConflictPolicy::Error => Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!(
+                    "can't unpack {} over the existing {} {:?}",
+                    file_type_name(file_type),
+                    file_type_name(existing),
+                    OsStr::from_bytes(path.to_bytes())
+                ),
+            )),
+            ConflictPolicy::Replace => {
+                if existing == FileType::Directory {
+                    remove_dir_all_at(self.dirfd, path)?;
+                } else {
+                    unlinkat(self.dirfd, path, 0)?;
+                }
+                Ok(true)
+            }
+            ConflictPolicy::Skip => Ok(false),
+        }
+    }
+
+    /// Rewrite the absolute target of the symbolic link according to [`AbsoluteTargetPolicy`].
+    ///
+    /// Returns `None` if the target is not changed.
+    fn rewrite_target(&self, path: &CStr, target: &[u8]) -> Result<Option<PathBuf>, Error> {
+        let path = Path::new(OsStr::from_bytes(path.to_bytes()));
+        let target = Path::new(OsStr::from_bytes(target));
+        if !target.is_absolute() || path.is_absolute() {
+            return Ok(None);
+        }
+        // `..` can't go above the root
+        let mut normalized = PathBuf::new();
+        for component in target.components() {
+            match component {
+                Component::Normal(name) => normalized.push(name),
+                Component::ParentDir => {
+                    normalized.pop();
+                }
+                _ => {}
+            }
+        }
+        match self.absolute_targets {
+            AbsoluteTargetPolicy::Keep => Ok(None),
+            AbsoluteTargetPolicy::Relative => {
+                let depth = path
+                    .parent()
+                    .map_or(0, |parent| parent.components().count());
+                let mut relative: PathBuf = std::iter::repeat_n("..", depth).collect();
+                relative.push(normalized);
+                if relative.as_os_str().is_empty() {
+                    relative.push(".");
+                }
+                Ok(Some(relative))
+            }
+            AbsoluteTargetPolicy::Prefix => match self.root.as_ref() {
+                Some(root) => Ok(Some(root.join(normalized))),
+                None => Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "prefixing symbolic link targets requires the path of the target directory",
+                )),
+            },
+        }
+    }
+
+    /// Fail if any of the parent directories of `path` is a symbolic link.
+    ///
+    /// Otherwise a malicious archive could first create a link to an arbitrary directory
+    /// (directly or as a hard link to another symbolic link)
+    /// and then write files through this link outside of the target directory.
+    /// The parent directories are opened one by one without following symbolic links,
+    /// so the links that existed before unpacking are rejected as well.
+    /// The absolute paths kept by [`AbsolutePathPolicy::Keep`] are not checked.
+    fn check_parents(&self, path: &Path) -> Result<(), Error> {
+        if path.is_absolute() {
+            // not relative to the target directory
+            return Ok(());
+        }
+        let Some(dirname) = path.parent() else {
+            return Ok(());
+        };
+        match find_symlink_at(self.dirfd, dirname)? {
+            Some(link) => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{:?} is inside the symbolic link {:?}", path, link),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Fail if the target file system doesn't have `size` bytes available
+    /// (see [`UnpackOptions::check_free_space`]).
+    fn ensure_free_space(&self, size: u64) -> Result<(), Error> {
+        if !self.check_free_space || size == 0 {
+            return Ok(());
+        }
+        let stat = fstatvfs(self.dirfd)?;
+        #[allow(clippy::unnecessary_cast)]
+        let free_bytes = (stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64);
+        if size > free_bytes {
+            return Err(Error::new(
+                ErrorKind::StorageFull,
+                format!(
+                    "not enough space: {} bytes required, {} bytes available",
+                    size, free_bytes
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Apply [`OverwritePolicy`] to the existing file.
+    ///
+    /// Returns the reason to skip the entry or `None` if the entry should be unpacked,
+    /// in which case the existing file is removed if `unlink` is true and it's not a directory.
+    fn overwrite_existing(
+        &self,
+        metadata: &Metadata,
+        path: &CStr,
+        unlink: bool,
+    ) -> Result<Option<SkipReason>, Error> {
+        let stat = match fstatat(self.dirfd, path) {
+            Ok(stat) => stat,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        #[allow(clippy::unnecessary_cast)]
+        if let Some(reason) = self.rules().overwrite(metadata, stat.st_mtime as i64) {
+            return Ok(Some(reason));
+        }
+        #[allow(clippy::unnecessary_cast)]
+        if unlink && FileType::new(stat.st_mode as u32)? != FileType::Directory {
+            unlinkat(self.dirfd, path, 0)?;
+        }
+        Ok(None)
+    }
+
+    fn rules(&self) -> EntryRules<'_> {
+        EntryRules {
+            patterns: &self.patterns,
+            rename: self.rename.as_deref(),
+            absolute_paths: self.absolute_paths,
+            strict_paths: self.strict_paths,
+            strip_components: self.strip_components,
+            special_files: self.special_files,
+            rootless: self.rootless,
+            placeholder: self.placeholder,
+            symlinks: self.symlink_policy,
+            preserve_owner: self.preserve_owner,
+            groups: self.group_policy,
+            owner_map: &self.owner_map,
+            dir_over_symlink: self.dir_over_symlink,
+            symlink_over_dir: self.symlink_over_dir,
+            dir_over_file: self.dir_over_file,
+            file_over_dir: self.file_over_dir,
+            overwrite: self.overwrite,
+        }
+    }
+
+    /// Returns `false` if the special file was skipped according to [`SpecialFilePolicy::Warn`].
+    fn special_file_created(
+        &mut self,
+        entry_path: &Path,
+        policy: Option<SpecialFilePolicy>,
+        result: Result<(), Error>,
+    ) -> Result<bool, Error> {
+        match result {
+            Ok(()) => Ok(true),
+            Err(e)
+                if policy == Some(SpecialFilePolicy::Warn)
+                    && e.raw_os_error() == Some(libc::EPERM) =>
+            {
+                if let Some(on_special_file) = self.on_special_file.as_mut() {
+                    on_special_file(entry_path, &e);
+                }
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn report_symlink(&mut self, path: &Path, action: SymlinkAction) {
+        if let Some(on_symlink) = self.on_symlink.as_mut() {
+            on_symlink(path, action);
+        }
+    }
+
+    /// Replace the pending symbolic links with the copies of their targets.
+    fn copy_symlinks(&mut self) -> Result<(), Error> {
+        let mut pending = std::mem::take(&mut self.pending_symlinks);
+        // the links to other links are copied after their targets
+        loop {
+            let num_pending = pending.len();
+            let mut i = 0;
+            while i < pending.len() {
+                let symlink = &pending[i];
+                let copied = match symlink.target.as_ref() {
+                    Some(target) => copy_regular_file_at(self.dirfd, target, &symlink.path),
+                    None => Ok(false),
+                };
+                if let Ok(false) = copied {
+                    i += 1;
+                    continue;
+                }
+                let symlink = pending.swap_remove(i);
+                let result = copied.and_then(|_| symlink.set_metadata(self.dirfd));
+                if !self.keep_going(&symlink.entry_path, result)? {
+                    continue;
+                }
+                self.report_symlink(&symlink.entry_path, SymlinkAction::Copied);
+                self.report
+                    .written
+                    .push(c_string_to_path_buf(&symlink.path));
+            }
+            if pending.len() == num_pending {
+                break;
+            }
+        }
+        for symlink in pending.into_iter() {
+            self.report_symlink(&symlink.entry_path, SymlinkAction::Skipped);
+            self.report
+                .skipped
+                .push((symlink.entry_path, SkipReason::Symlink));
+        }
+        Ok(())
+    }
+
+    /// Set the mode and the modification time of the directory.
+    fn finish_dir(
+        &self,
+        path: &CStr,
+        mut mode: u32,
+        modified: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        self.check_parents(&c_string_to_path_buf(path))?;
+        // the directory might have been replaced by a symbolic link
+        if !is_dir_at(self.dirfd, path)? {
+            return Ok(());
+        }
+        if self.group_policy == GroupPolicy::Inherit {
+            // keep setgid bit inherited from the parent directory
+            #[allow(clippy::unnecessary_cast)]
+            let (old_mode, setgid) = (
+                fstatat(self.dirfd, path)?.st_mode as u32,
+                libc::S_ISGID as u32,
+            );
+            mode |= old_mode & setgid;
+        }
+        ignore_eperm(self.rootless, fchmodat(self.dirfd, path, mode as mode_t))?;
+        if let Some(modified) = modified {
+            ignore_eperm(
+                self.rootless,
+                set_file_modified_time(self.dirfd, path, modified),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Collect the error according to [`UnpackOptions::keep_going`].
+    ///
+    /// Returns `false` if the error was collected.
+    fn keep_going(&mut self, path: &Path, result: Result<(), Error>) -> Result<bool, Error> {
+        match result {
+            Ok(()) => Ok(true),
+            Err(e) if self.keep_going => {
+                self.report.errors.push((path.to_path_buf(), e));
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub(crate) fn finish(mut self) -> Result<UnpackReport, UnpackError> {
+        match self.finish_files() {
+            Ok(()) => Ok(self.report),
+            Err(error) => Err(self.into_error(error)),
+        }
+    }
+
+    /// Attach the report on the entries unpacked so far to the `error`.
+    pub(crate) fn into_error(self, error: Error) -> UnpackError {
+        UnpackError {
+            error,
+            report: self.report,
+        }
+    }
+
+    /// Move the hooks back to the `options` they were taken from.
+    pub(crate) fn restore_hooks(&mut self, options: &mut UnpackOptions) {
+        options.on_hard_link = self.on_hard_link.take();
+        options.on_symlink = self.on_symlink.take();
+        options.scanner = self.scanner.take();
+        options.on_special_file = self.on_special_file.take();
+        options.rename = self.rename.take();
+    }
+
+    pub(crate) fn finish_files(&mut self) -> Result<(), Error> {
+        self.copy_symlinks()?;
+        let mut dirs = std::mem::take(&mut self.dirs);
+        dirs.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+        for (path, mode, modified) in dirs.into_iter() {
+            let result = self.finish_dir(&path, mode, modified);
+            self.keep_going(&c_string_to_path_buf(&path), result)?;
+        }
+        Ok(())
+    }
+}
+
+/// The decisions about the entries that don't depend on the existing files.
+///
+/// Shared by the unpacker and the planner so that planning can't diverge from unpacking.
+pub(crate) struct EntryRules<'a> {
+    pub(crate) patterns: &'a Patterns,
+    pub(crate) rename: Option<&'a Renamer>,
+    pub(crate) absolute_paths: AbsolutePathPolicy,
+    pub(crate) strict_paths: bool,
+    pub(crate) strip_components: usize,
+    pub(crate) special_files: SpecialFiles,
+    pub(crate) rootless: bool,
+    pub(crate) placeholder: Placeholder,
+    pub(crate) symlinks: SymlinkPolicy,
+    pub(crate) preserve_owner: bool,
+    pub(crate) groups: GroupPolicy,
+    pub(crate) owner_map: &'a OwnerMap,
+    pub(crate) dir_over_symlink: ConflictPolicy,
+    pub(crate) symlink_over_dir: ConflictPolicy,
+    pub(crate) dir_over_file: ConflictPolicy,
+    pub(crate) file_over_dir: ConflictPolicy,
+    pub(crate) overwrite: OverwritePolicy,
+}
+
+/// What to do with the entry according to [`EntryRules`].
+pub(crate) enum Selection {
+    /// Unpack the entry.
+    Unpack {
+        /// The path relative to the target directory.
+        path: PathBuf,
+        /// The type of the file to create, e.g. the placeholder's type.
+        file_type: FileType,
+        /// The owner to set.
+        owner: Option<Owner>,
+    },
+    /// Skip the entry; the path is empty if it wasn't resolved.
+    Skip(PathBuf, SkipReason),
+    /// The path is rejected, unpacking fails with the error.
+    Rejected(Error),
+}
+
+impl<'a> EntryRules<'a> {
+    pub(crate) fn new(options: &'a UnpackOptions, preserve_owner: bool) -> Self {
+        Self {
+            patterns: &options.patterns,
+            rename: options.rename.as_deref(),
+            absolute_paths: options.absolute_paths,
+            strict_paths: options.strict_paths,
+            strip_components: options.strip_components,
+            special_files: options.special_files,
+            rootless: options.rootless,
+            placeholder: options.placeholder,
+            symlinks: options.symlinks,
+            preserve_owner,
+            groups: options.groups,
+            owner_map: &options.owner_map,
+            dir_over_symlink: options.dir_over_symlink,
+            symlink_over_dir: options.symlink_over_dir,
+            dir_over_file: options.dir_over_file,
+            file_over_dir: options.file_over_dir,
+            overwrite: options.overwrite,
+        }
+    }
+
+    /// Apply the patterns, the rename hook, the path resolution,
+    /// the special file, the placeholder, the symbolic link and the owner policies in this order.
+    pub(crate) fn select(
+        &self,
+        entry_path: &Path,
+        metadata: &Metadata,
+    ) -> Result<Selection, Error> {
+        if !self.patterns.is_selected(entry_path) {
+            return Ok(Selection::Skip(PathBuf::new(), SkipReason::Excluded));
+        }
+        let renamed;
+        let entry_path = match self.rename {
+            Some(rename) => match rename(entry_path, metadata) {
+                Some(path) => {
+                    renamed = path;
+                    &renamed
+                }
+                None => return Ok(Selection::Skip(PathBuf::new(), SkipReason::Rename)),
+            },
+            None => entry_path,
+        };
+        let resolved = resolve_path(
+            entry_path,
+            self.absolute_paths,
+            self.strict_paths,
+            self.strip_components,
+        );
+        let path = match resolved {
+            Ok(ResolvedPath::Path(path)) => path,
+            Ok(ResolvedPath::Outside) => {
+                return Ok(Selection::Skip(PathBuf::new(), SkipReason::Outside))
+            }
+            Ok(ResolvedPath::Target) => {
+                return Ok(Selection::Skip(PathBuf::new(), SkipReason::Target))
+            }
+            Ok(ResolvedPath::Stripped) => {
+                return Ok(Selection::Skip(PathBuf::new(), SkipReason::Stripped))
+            }
+            Err(e) => return Ok(Selection::Rejected(e)),
+        };
+        let mut file_type = metadata.file_type()?;
+        self.special_files.check(file_type)?;
+        if self.special_files.policy(file_type) == Some(SpecialFilePolicy::Skip) {
+            return Ok(Selection::Skip(path, SkipReason::Special));
+        }
+        if self.rootless && file_type.is_special() {
+            match self.placeholder {
+                Placeholder::EmptyFile => file_type = FileType::Regular,
+                Placeholder::Skip => return Ok(Selection::Skip(path, SkipReason::Special)),
+            }
+        }
+        if file_type == FileType::Symlink && self.symlinks == SymlinkPolicy::Skip {
+            return Ok(Selection::Skip(path, SkipReason::Symlink));
+        }
+        let owner = self.owner(metadata)?;
+        Ok(Selection::Unpack {
+            path,
+            file_type,
+            owner,
+        })
+    }
+
+    /// The user and group ids to set, `None` means the ones assigned by the operating system.
+    fn owner(&self, metadata: &Metadata) -> Result<Option<Owner>, Error> {
+        if self.rootless {
+            return Ok(None);
+        }
+        let uid = match self.preserve_owner {
+            true => self.owner_map.map_uid(metadata.uid)?,
+            false => None,
+        };
+        let gid = match self.groups {
+            GroupPolicy::FollowOwner if self.preserve_owner => {
+                self.owner_map.map_gid(metadata.gid)?
+            }
+            GroupPolicy::FollowOwner | GroupPolicy::Inherit => None,
+            GroupPolicy::Archive => self.owner_map.map_gid(metadata.gid)?,
+            GroupPolicy::Force(gid) => Some(gid),
+        };
+        match (uid, gid) {
+            (None, None) => Ok(None),
+            owner => Ok(Some(owner)),
+        }
+    }
+
+    /// The conflict policy for unpacking the file of `file_type` over the `existing` one.
+    ///
+    /// Returns `None` if there is no conflict.
+    pub(crate) fn conflict(
+        &self,
+        file_type: FileType,
+        existing: FileType,
+    ) -> Option<ConflictPolicy> {
+        match (file_type, existing) {
+            (FileType::Directory, FileType::Directory) => None,
+            (FileType::Directory, FileType::Symlink) => Some(self.dir_over_symlink),
+            (FileType::Directory, _) => Some(self.dir_over_file),
+            (FileType::Symlink, FileType::Directory) => Some(self.symlink_over_dir),
+            (_, FileType::Directory) => Some(self.file_over_dir),
+            _ => None,
+        }
+    }
+
+    /// Apply [`OverwritePolicy`] to the existing file with the modification time `existing_mtime`.
+    ///
+    /// Returns the reason to skip the entry or `None` if the entry should be unpacked.
+    pub(crate) fn overwrite(&self, metadata: &Metadata, existing_mtime: i64) -> Option<SkipReason> {
+        match self.overwrite {
+            OverwritePolicy::Always => None,
+            OverwritePolicy::Never => Some(SkipReason::Existing),
+            OverwritePolicy::IfNewer => u64::try_from(existing_mtime)
+                .is_ok_and(|mtime| mtime >= metadata.mtime())
+                .then_some(SkipReason::NotNewer),
+        }
+    }
+}
+
+/// The path of the entry relative to the target directory.
+pub(crate) enum ResolvedPath {
+    /// The normalized path.
+    ///
+    /// Absolute paths are kept only with [`AbsolutePathPolicy::Keep`].
+    Path(PathBuf),
+    /// The path points outside of the target directory.
+    Outside,
+    /// The target directory itself.
+    Target,
+    /// The path has no more components than [`UnpackOptions::strip_components`] removes.
+    Stripped,
+}
+
+pub(crate) fn resolve_path(
+    path: &Path,
+    absolute_paths: AbsolutePathPolicy,
+    strict: bool,
+    strip_components: usize,
+) -> Result<ResolvedPath, Error> {
+    let (path, is_absolute) = match path.strip_prefix("/") {
+        Ok(path) => (path, true),
+        Err(_) => (path, false),
+    };
+    if is_absolute && (strict || absolute_paths == AbsolutePathPolicy::Error) {
+        return Err(Error::new(ErrorKind::InvalidData, "absolute path"));
+    }
+    if strict && path.components().any(|c| c == Component::ParentDir) {
+        return Err(Error::new(ErrorKind::InvalidData, "path contains `..`"));
+    }
+    let Some(path) = path.try_normalize() else {
+        return Ok(ResolvedPath::Outside);
+    };
+    if path == Path::new("") {
+        return Ok(ResolvedPath::Target);
+    }
+    let path = if strip_components != 0 {
+        if path.components().count() <= strip_components {
+            return Ok(ResolvedPath::Stripped);
+        }
+        path.components().skip(strip_components).collect()
+    } else {
+        path
+    };
+    let path = if is_absolute && absolute_paths == AbsolutePathPolicy::Keep {
+        // `dirfd` is ignored for absolute paths
+        Path::new("/").join(path)
+    } else {
+        path
+    };
+    Ok(ResolvedPath::Path(path))
+}
+
+/// The granularity of the holes created when unpacking sparse files.
+const SPARSE_BLOCK_SIZE: usize = 4096;
+
+/// Fill `buf` unless the end of the stream is reached.
+///
+/// Returns the number of bytes read.
+fn read_block<R: Read>(mut reader: R, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut offset = 0;
+    while offset != buf.len() {
+        match reader.read(&mut buf[offset..]) {
+            Ok(0) => break,
+            Ok(n) => offset += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(offset)
+}
+
+/// Resolve [`UnpackOptions::preserve_owner`]:
+/// if it is not set, the owner is preserved only when running as root.
+pub(crate) fn resolve_preserve_owner(options: Option<bool>) -> bool {
+    options.unwrap_or_else(|| unsafe { libc::geteuid() } == 0)
+}
+
+/// Ignore `EPERM` if `ignore` is `true`.
+fn ignore_eperm(ignore: bool, result: Result<(), Error>) -> Result<(), Error> {
+    match result {
+        Err(e) if ignore && e.raw_os_error() == Some(libc::EPERM) => Ok(()),
+        result => result,
+    }
+}
+
+fn file_type_name(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::Regular => "regular file",
+        FileType::Directory => "directory",
+        FileType::Symlink => "symbolic link",
+        FileType::BlockDevice => "block device",
+        FileType::CharDevice => "character device",
+        FileType::Fifo => "FIFO",
+        FileType::Socket => "socket",
+    }
+}
+
+fn c_string_to_path_buf(path: &CStr) -> PathBuf {
+    PathBuf::from(OsStr::from_bytes(path.to_bytes()))
+}
+
+/// Add entry's index and path to the error message.
+pub(crate) fn entry_error(e: Error, index: usize, path: &Path) -> Error {
+    Error::new(e.kind(), format!("entry #{} {:?}: {}", index, path, e))
+}
+
+/// Copy the regular file `from` to the new file `to` with the same permissions.
+///
+/// Returns `false` if `from` doesn't exist or is not a regular file.
+fn copy_regular_file_at(dirfd: RawFd, from: &CStr, to: &CStr) -> Result<bool, Error> {
+    #[allow(clippy::unnecessary_cast)]
+    let mode = match fstatat(dirfd, from) {
+        Ok(stat) => stat.st_mode as u32,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    if (mode & FILE_TYPE_MASK) != file_type_to_mode(FileType::Regular) {
+        return Ok(false);
+    }
+    if !is_readable(mode) {
+        // make readable
+        fchmodat(dirfd, from, 0o644)?;
+    }
+    let result = do_copy_regular_file_at(dirfd, from, to, mode);
+    if !is_readable(mode) {
+        fchmodat(dirfd, from, (mode & FILE_MODE_MASK) as mode_t)?;
+    }
+    result?;
+    Ok(true)
+}
+
+fn do_copy_regular_file_at(dirfd: RawFd, from: &CStr, to: &CStr, mode: u32) -> Result<(), Error> {
+    let mut source = openat(dirfd, from, O_RDONLY | O_NOFOLLOW, 0)?;
+    let mut file = openat(dirfd, to, O_WRONLY | O_CREAT | O_EXCL | O_NOFOLLOW, 0o600)?;
+    std::io::copy(&mut source, &mut file)?;
+    file.set_permissions(Permissions::from_mode(mode & FILE_MODE_MASK))?;
+    Ok(())
+}
+
+/// Create a new file next to `path` that is only accessible by the owner.
+fn create_temp_file(dirfd: RawFd, path: &CStr) -> Result<(File, CString), Error> {
+    for i in 0_u32.. {
+        let mut temp_path = path.to_bytes().to_vec();
+        temp_path.extend_from_slice(format!(".kpea-{}", i).as_bytes());
+        let temp_path = CString::new(temp_path)?;
+        match openat(
+            dirfd,
+            &temp_path,
+            O_RDWR | O_CREAT | O_EXCL | O_NOFOLLOW,
+            0o600,
+        ) {
+            Ok(file) => return Ok((file, temp_path)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(ErrorKind::AlreadyExists.into())
+}
+
+fn is_dir_at(dirfd: RawFd, path: &CStr) -> Result<bool, Error> {
+    #[allow(clippy::unnecessary_cast)]
+    let mode = fstatat(dirfd, path)?.st_mode as u32;
+    Ok((mode & FILE_TYPE_MASK) == file_type_to_mode(FileType::Directory))
+}
+
+/// Returns `true` if the error means that the hard link can't be created in the target directory.
+fn is_link_unsupported(e: &Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EPERM | libc::EOPNOTSUPP | libc::EMLINK | libc::EXDEV)
+    )
+}
+
+fn is_readable(mode: u32) -> bool {
+    (((mode & FILE_MODE_MASK) >> 6) & FILE_READ_BIT) != 0
+}
+
+fn is_writable(mode: u32) -> bool {
+    (((mode & FILE_MODE_MASK) >> 6) & FILE_WRITE_BIT) != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::create_dir_all;
+    use std::fs::read_link;
+    use std::fs::remove_dir_all;
+    use std::os::unix::fs::symlink;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::time::Duration;
+    use std::time::UNIX_EPOCH;
+
+    use random_dir::list_dir_all;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::Archive;
+    use crate::Builder;
+    use crate::Change;
+    use crate::Differences;
+    use crate::Format;
+
+    #[test]
+    fn owner_permissions() {
+        // only the owner bits matter
+        for (mode, readable, writable) in [
+            (0o100644, true, true),
+            (0o100444, true, false),
+            (0o100200, false, true),
+            (0o101077, false, false),
+            (0o40755, true, true),
+        ] {
+            assert_eq!(readable, is_readable(mode), "{:o}", mode);
+            assert_eq!(writable, is_writable(mode), "{:o}", mode);
+        }
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "landlock"))]
+    #[cfg_attr(miri, ignore)]
+    fn unpack_sandboxed() {
+        let workdir = TempDir::new().unwrap();
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_entry(Metadata::regular(0o644, 5), "dir/file", &b"hello"[..])
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let unpack_dir = workdir.path().join("unpacked");
+        let outside = workdir.path().join("outside");
+        // landlock restricts the current thread only
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut archive = Archive::new(&bytes[..]);
+                archive.set_unpack_options(UnpackOptions::new().sandbox(true));
+                match archive.unpack(&unpack_dir) {
+                    Err(e) if e.error.kind() == ErrorKind::Unsupported => return,
+                    other => {
+                        other.unwrap();
+                    }
+                }
+                assert_eq!(
+                    b"hello",
+                    &std::fs::read(unpack_dir.join("dir/file")).unwrap()[..]
+                );
+                assert!(File::create(&outside).is_err());
+            });
+        });
+    }
+
+    #[test]
+    fn unpack_update_existing_dirs() {
+        let mut builder = Builder::new(Vec::new());
+        let mut metadata = Metadata::directory(0o750);
+        metadata.set_mtime(1_000_000);
+        builder.append_dir_with_metadata(metadata, "dir").unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "dir/file", b"hello")
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        for update in [false, true] {
+            let workdir = TempDir::new().unwrap();
+            let dir = workdir.path().join("dir");
+            create_dir_all(&dir).unwrap();
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+            let mut archive = Archive::new(&bytes[..]);
+            archive.set_unpack_options(
+                UnpackOptions::new()
+                    .preserve_mtime(true)
+                    .update_existing_dirs(update),
+            );
+            archive.unpack(workdir.path()).unwrap();
+            assert_eq!(b"hello", &std::fs::read(dir.join("file")).unwrap()[..]);
+            let metadata = dir.metadata().unwrap();
+            if update {
+                assert_eq!(0o750, metadata.permissions().mode() & 0o777);
+                assert_eq!(1_000_000, metadata.mtime());
+            } else {
+                assert_eq!(0o700, metadata.permissions().mode() & 0o777);
+                assert_ne!(1_000_000, metadata.mtime());
+            }
+        }
+    }
+
+    #[test]
+    fn unpack_hard_link_policy() {
+        for format in [Format::Newc, Format::Odc] {
+            let mut builder = Builder::new(Vec::new());
+            builder.set_format(format);
+            let mut metadata = Metadata::regular(0o444, 0);
+            metadata.set_ino(1);
+            metadata.set_nlink(3);
+            for name in ["a", "b", "c"] {
+                builder
+                    .append_data(metadata.clone(), name, b"hello")
+                    .unwrap();
+            }
+            let bytes = builder.finish().unwrap();
+            for (policy, expected_action) in [
+                (HardLinkPolicy::Link, HardLinkAction::Linked),
+                (HardLinkPolicy::Copy, HardLinkAction::Copied),
+                (HardLinkPolicy::CopyIfUnsupported, HardLinkAction::Linked),
+            ] {
+                let workdir = TempDir::new().unwrap();
+                let (sender, receiver) = std::sync::mpsc::channel();
+                let mut archive = Archive::new(&bytes[..]);
+                archive.set_unpack_options(UnpackOptions::new().hard_links(policy).on_hard_link(
+                    move |path, action| {
+                        sender.send((path.to_path_buf(), action)).unwrap();
+                    },
+                ));
+                archive.unpack(workdir.path()).unwrap();
+                let actions: Vec<_> = receiver.into_iter().collect();
+                assert_eq!(
+                    vec![
+                        (PathBuf::from("b"), expected_action),
+                        (PathBuf::from("c"), expected_action)
+                    ],
+                    actions,
+                    "{:?} {:?}",
+                    format,
+                    policy
+                );
+                let mut inodes = HashSet::new();
+                for name in ["a", "b", "c"] {
+                    let path = workdir.path().join(name);
+                    let metadata = path.metadata().unwrap();
+                    inodes.insert(metadata.ino());
+                    assert_eq!(0o444, metadata.mode() & 0o7777);
+                    assert_eq!(
+                        b"hello",
+                        &std::fs::read(&path).unwrap()[..],
+                        "{:?} {:?} {:?}",
+                        format,
+                        policy,
+                        name
+                    );
+                }
+                let expected_inodes = match expected_action {
+                    HardLinkAction::Linked => 1,
+                    HardLinkAction::Copied => 3,
+                };
+                assert_eq!(expected_inodes, inodes.len(), "{:?} {:?}", format, policy);
+            }
+        }
+    }
+
+    #[test]
+    fn unpack_symlink_policy() {
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_data(Metadata::symlink("late"), "early", b"late\0")
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o400, 0), "dir/file", b"hello")
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "late", b"late")
+            .unwrap();
+        for (target, path) in [
+            ("dir/file", "relative"),
+            ("../relative", "dir/chain"),
+            ("/etc/passwd", "absolute"),
+            ("missing", "dangling"),
+            ("dir", "dir-link"),
+            ("../outside", "outside"),
+        ] {
+            let mut contents = target.as_bytes().to_vec();
+            contents.push(0);
+            builder
+                .append_data(Metadata::symlink(target), path, &contents[..])
+                .unwrap();
+        }
+        let bytes = builder.finish().unwrap();
+        let copied = ["dir/chain", "early", "relative"];
+        let skipped = ["absolute", "dangling", "dir-link", "outside"];
+        for policy in [
+            SymlinkPolicy::Create,
+            SymlinkPolicy::Copy,
+            SymlinkPolicy::Skip,
+        ] {
+            let workdir = TempDir::new().unwrap();
+            let (sender, receiver) = std::sync::mpsc::channel();
+            let mut archive = Archive::new(&bytes[..]);
+            archive.set_unpack_options(UnpackOptions::new().symlinks(policy).on_symlink(
+                move |path, action| {
+                    sender.send((path.to_path_buf(), action)).unwrap();
+                },
+            ));
+            archive.unpack(workdir.path()).unwrap();
+            let mut actions: Vec<_> = receiver.into_iter().collect();
+            actions.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            let mut expected_actions: Vec<_> = copied
+                .iter()
+                .chain(skipped.iter())
+                .map(|path| {
+                    let action = match policy {
+                        SymlinkPolicy::Create => SymlinkAction::Created,
+                        SymlinkPolicy::Copy if copied.contains(path) => SymlinkAction::Copied,
+                        SymlinkPolicy::Copy | SymlinkPolicy::Skip => SymlinkAction::Skipped,
+                    };
+                    (PathBuf::from(path), action)
+                })
+                .collect();
+            expected_actions.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            assert_eq!(expected_actions, actions, "{:?}", policy);
+            for path in copied.iter().chain(skipped.iter()) {
+                let metadata = workdir.path().join(path).symlink_metadata();
+                match policy {
+                    SymlinkPolicy::Create => assert!(metadata.unwrap().is_symlink()),
+                    SymlinkPolicy::Copy if copied.contains(path) => {
+                        assert!(metadata.unwrap().is_file(), "{:?}", path)
+                    }
+                    SymlinkPolicy::Copy | SymlinkPolicy::Skip => {
+                        assert!(metadata.is_err(), "{:?} {:?}", policy, path)
+                    }
+                }
+            }
+            if policy == SymlinkPolicy::Copy {
+                let relative = workdir.path().join("relative");
+                assert_eq!(b"hello", &std::fs::read(&relative).unwrap()[..]);
+                assert_eq!(0o400, relative.metadata().unwrap().mode() & 0o7777);
+                assert_eq!(
+                    b"hello",
+                    &std::fs::read(workdir.path().join("dir/chain")).unwrap()[..]
+                );
+                assert_eq!(
+                    b"late",
+                    &std::fs::read(workdir.path().join("early")).unwrap()[..]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn min_mtime() {
+        let mut builder = Builder::new(Vec::new());
+        for (path, mtime) in [("zero", 0), ("old", 1000), ("new", 1_000_000)] {
+            let mut metadata = Metadata::regular(0o644, 0);
+            metadata.set_mtime(mtime);
+            builder.append_data(metadata, path, b"").unwrap();
+        }
+        let bytes = builder.finish().unwrap();
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().preserve_mtime(true).min_mtime(1001));
+        archive.unpack(workdir.path()).unwrap();
+        let mtime = |path| {
+            std::fs::metadata(workdir.path().join(path))
+                .unwrap()
+                .mtime()
+        };
+        assert!(mtime("zero") > 1_000_000);
+        assert!(mtime("old") > 1_000_000);
+        assert_eq!(1_000_000, mtime("new"));
+        let mut archive = Archive::new(&bytes[..]);
+        let changes: Vec<_> = archive
+            .plan_unpack(
+                workdir.path(),
+                &UnpackOptions::new().preserve_mtime(true).min_mtime(1001),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|change| change.change)
+            .collect();
+        assert_eq!(vec![Change::Unchanged; 3], changes);
+    }
+
+    #[test]
+    #[ignore = "requires root"]
+    fn preserve_owner_as_root() {
+        let mut builder = Builder::new(Vec::new());
+        let mut metadata = Metadata::regular(0o644, 0);
+        metadata.set_uid(1234);
+        metadata.set_gid(5678);
+        builder.append_data(metadata, "file", b"").unwrap();
+        let mut metadata = Metadata::symlink("file");
+        metadata.set_uid(4321);
+        metadata.set_gid(8765);
+        builder.append_data(metadata, "link", b"file\0").unwrap();
+        let bytes = builder.finish().unwrap();
+        let owner = |path: &Path| {
+            let metadata = std::fs::symlink_metadata(path).unwrap();
+            (metadata.uid(), metadata.gid())
+        };
+        let workdir = TempDir::new().unwrap();
+        Archive::new(&bytes[..]).unpack(workdir.path()).unwrap();
+        assert_eq!((1234, 5678), owner(&workdir.path().join("file")));
+        assert_eq!((4321, 8765), owner(&workdir.path().join("link")));
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().preserve_owner(false));
+        archive.unpack(workdir.path()).unwrap();
+        assert_eq!((0, 0), owner(&workdir.path().join("file")));
+        assert_eq!((0, 0), owner(&workdir.path().join("link")));
+    }
+
+    #[test]
+    #[ignore = "requires root"]
+    fn unpack_owner_mappings() {
+        let mut builder = Builder::new(Vec::new());
+        for (path, id) in [("mapped", 1000), ("unmapped", 1001)] {
+            let mut metadata = Metadata::regular(0o644, 0);
+            metadata.set_uid(id);
+            metadata.set_gid(id);
+            builder.append_data(metadata, path, b"").unwrap();
+        }
+        let bytes = builder.finish().unwrap();
+        let unpack = |policy| {
+            let workdir = TempDir::new().unwrap();
+            let mut archive = Archive::new(&bytes[..]);
+            archive.set_unpack_options(
+                UnpackOptions::new()
+                    .preserve_owner(true)
+                    .add_uid_mapping(1000, 2000)
+                    .add_gid_mapping(1000, 3000)
+                    .unmapped_ids(policy),
+            );
+            archive.unpack(workdir.path()).map(|_| {
+                ["mapped", "unmapped"].map(|path| {
+                    let metadata = std::fs::metadata(workdir.path().join(path)).unwrap();
+                    (metadata.uid(), metadata.gid())
+                })
+            })
+        };
+        assert_eq!(
+            [(2000, 3000), (1001, 1001)],
+            unpack(UnmappedIdPolicy::Keep).unwrap()
+        );
+        assert_eq!(
+            [(2000, 3000), (0, 0)],
+            unpack(UnmappedIdPolicy::Skip).unwrap()
+        );
+        assert_eq!(
+            [(2000, 3000), (0, 0)],
+            unpack(UnmappedIdPolicy::Current).unwrap()
+        );
+        assert_eq!(
+            ErrorKind::InvalidData,
+            unpack(UnmappedIdPolicy::Error).unwrap_err().error.kind()
+        );
+    }
+
+    #[test]
+    fn rootless() {
+        let mut builder = Builder::new(Vec::new());
+        let mut metadata = Metadata::regular(0o644, 0);
+        metadata.set_uid(1234);
+        metadata.set_gid(1234);
+        builder.append_data(metadata, "file", b"").unwrap();
+        let mut metadata = Metadata::new(FileType::CharDevice, 0o600);
+        metadata.set_rdev(libc::makedev(1, 3) as u64);
+        builder.append_data(metadata, "null", b"").unwrap();
+        builder
+            .append_data(Metadata::new(FileType::Fifo, 0o640), "fifo", b"")
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().preserve_owner(true).rootless(true));
+        archive.unpack(workdir.path()).unwrap();
+        let metadata = std::fs::metadata(workdir.path().join("file")).unwrap();
+        assert_eq!((uid, gid), (metadata.uid(), metadata.gid()));
+        for (path, mode) in [("null", 0o600), ("fifo", 0o640)] {
+            let metadata = std::fs::symlink_metadata(workdir.path().join(path)).unwrap();
+            assert!(metadata.is_file());
+            assert_eq!(0, metadata.len());
+            assert_eq!(mode, metadata.mode() & 0o777);
+        }
+        let workdir = TempDir::new().unwrap();
+        let options = UnpackOptions::new()
+            .rootless(true)
+            .placeholder(Placeholder::Skip);
+        let mut archive = Archive::new(&bytes[..]);
+        let changes: Vec<_> = archive
+            .plan_unpack(workdir.path(), &options)
+            .unwrap()
+            .into_iter()
+            .map(|change| change.change)
+            .collect();
+        assert_eq!(
+            vec![
+                Change::Create,
+                Change::Skip(SkipReason::Special),
+                Change::Skip(SkipReason::Special)
+            ],
+            changes
+        );
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(options);
+        archive.unpack(workdir.path()).unwrap();
+        assert!(workdir.path().join("file").exists());
+        assert!(!workdir.path().join("null").exists());
+        assert!(!workdir.path().join("fifo").exists());
+    }
+
+    #[test]
+    fn special_file_policies() {
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_data(Metadata::new(FileType::Fifo, 0o644), "fifo", b"")
+            .unwrap();
+        builder
+            .append_data(Metadata::new(FileType::Socket, 0o644), "socket", b"")
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let workdir = TempDir::new().unwrap();
+        let options = UnpackOptions::new()
+            .fifos(SpecialFilePolicy::Skip)
+            .sockets(SpecialFilePolicy::Warn);
+        let mut archive = Archive::new(&bytes[..]);
+        let changes: Vec<_> = archive
+            .plan_unpack(workdir.path(), &options)
+            .unwrap()
+            .into_iter()
+            .map(|change| change.change)
+            .collect();
+        assert_eq!(
+            vec![Change::Skip(SkipReason::Special), Change::Create],
+            changes
+        );
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(options);
+        archive.unpack(workdir.path()).unwrap();
+        assert!(!workdir.path().join("fifo").exists());
+        let metadata = std::fs::symlink_metadata(workdir.path().join("socket")).unwrap();
+        assert_eq!(libc::S_IFSOCK, metadata.mode() & libc::S_IFMT);
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().sockets(SpecialFilePolicy::Error));
+        let error = archive.unpack(workdir.path()).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, error.error.kind());
+        assert!(!workdir.path().join("socket").exists());
+    }
+
+    #[test]
+    fn unpack_patterns() {
+        let mut builder = Builder::new(Vec::new());
+        for path in ["etc/passwd", "etc/passwd.bak", "usr/bin/sh"] {
+            builder
+                .append_data(Metadata::regular(0o644, 0), path, b"")
+                .unwrap();
+        }
+        let bytes = builder.finish().unwrap();
+        let workdir = TempDir::new().unwrap();
+        let options = UnpackOptions::new()
+            .include_patterns(["etc/*"])
+            .exclude_patterns(["*.bak"]);
+        let mut archive = Archive::new(&bytes[..]);
+        let changes: Vec<_> = archive
+            .plan_unpack(workdir.path(), &options)
+            .unwrap()
+            .into_iter()
+            .map(|change| change.change)
+            .collect();
+        assert_eq!(
+            vec![
+                Change::Create,
+                Change::Skip(SkipReason::Excluded),
+                Change::Skip(SkipReason::Excluded)
+            ],
+            changes
+        );
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(options);
+        archive.unpack(workdir.path()).unwrap();
+        assert!(workdir.path().join("etc/passwd").exists());
+        assert!(!workdir.path().join("etc/passwd.bak").exists());
+        assert!(!workdir.path().join("usr").exists());
+    }
+
+    #[test]
+    fn strip_components() {
+        let mut builder = Builder::new(Vec::new());
+        builder.append_dir("./root", 0o755).unwrap();
+        builder.append_dir("./root/usr", 0o755).unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "./root/usr/file", b"")
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "top", b"")
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let workdir = TempDir::new().unwrap();
+        let options = UnpackOptions::new().strip_components(1);
+        let mut archive = Archive::new(&bytes[..]);
+        let changes: Vec<_> = archive
+            .plan_unpack(workdir.path(), &options)
+            .unwrap()
+            .into_iter()
+            .map(|change| (change.path, change.change))
+            .collect();
+        assert_eq!(
+            vec![
+                (PathBuf::new(), Change::Skip(SkipReason::Stripped)),
+                (PathBuf::from("usr"), Change::Create),
+                (PathBuf::from("usr/file"), Change::Create),
+                (PathBuf::new(), Change::Skip(SkipReason::Stripped)),
+            ],
+            changes
+        );
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(options);
+        archive.unpack(workdir.path()).unwrap();
+        assert!(workdir.path().join("usr/file").exists());
+        assert!(!workdir.path().join("root").exists());
+        assert!(!workdir.path().join("top").exists());
+    }
+
+    #[test]
+    fn rename_entries() {
+        let mut builder = Builder::new(Vec::new());
+        for path in ["etc/passwd", "usr/bin/sh", "tmp/file"] {
+            builder
+                .append_data(Metadata::regular(0o644, 0), path, b"")
+                .unwrap();
+        }
+        let bytes = builder.finish().unwrap();
+        let options = || {
+            UnpackOptions::new().rename(|path, metadata| {
+                assert!(metadata.is_file());
+                if path.starts_with("tmp") {
+                    None
+                } else if let Ok(rest) = path.strip_prefix("etc") {
+                    Some(Path::new("etc.new").join(rest))
+                } else {
+                    Some(path.to_path_buf())
+                }
+            })
+        };
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        let changes: Vec<_> = archive
+            .plan_unpack(workdir.path(), &options())
+            .unwrap()
+            .into_iter()
+            .map(|change| (change.path, change.change))
+            .collect();
+        assert_eq!(
+            vec![
+                (PathBuf::from("etc.new/passwd"), Change::Create),
+                (PathBuf::from("usr/bin/sh"), Change::Create),
+                (PathBuf::new(), Change::Skip(SkipReason::Rename)),
+            ],
+            changes
+        );
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(options());
+        archive.unpack(workdir.path()).unwrap();
+        assert!(workdir.path().join("etc.new/passwd").exists());
+        assert!(workdir.path().join("usr/bin/sh").exists());
+        assert!(!workdir.path().join("etc").exists());
+        assert!(!workdir.path().join("tmp").exists());
+    }
+
+    #[test]
+    fn unpack_report() {
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_dir_with_metadata(Metadata::directory(0o755), "dir")
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "dir/file", b"hello")
+            .unwrap();
+        let mut metadata = Metadata::regular(0o644, 0);
+        metadata.set_ino(1);
+        metadata.set_nlink(2);
+        builder
+            .append_data(metadata.clone(), "file", b"hello")
+            .unwrap();
+        builder.append_data(metadata, "hard", b"hello").unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "file.bak", b"hello")
+            .unwrap();
+        builder
+            .append_data(Metadata::symlink("file"), "link", b"file\0")
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().exclude_patterns(["*.bak"]));
+        let report = archive.unpack(workdir.path()).unwrap();
+        assert_eq!(
+            ["dir", "dir/file", "file", "link"]
+                .into_iter()
+                .map(PathBuf::from)
+                .collect::<Vec<_>>(),
+            report.written
+        );
+        assert_eq!(10, report.bytes);
+        assert_eq!(
+            vec![(PathBuf::from("hard"), PathBuf::from("file"))],
+            report.hard_links
+        );
+        assert_eq!(
+            vec![(PathBuf::from("file.bak"), SkipReason::Excluded)],
+            report.skipped
+        );
+        assert!(report.errors.is_empty());
+        // the contents stored in the last link are counted
+        let input = TempDir::new().unwrap();
+        std::fs::write(input.path().join("a"), b"hello").unwrap();
+        std::fs::hard_link(input.path().join("a"), input.path().join("b")).unwrap();
+        let mut builder = Builder::new(Vec::new());
+        builder.gnu_compat(true);
+        for name in ["a", "b"] {
+            builder.append_path(input.path().join(name), name).unwrap();
+        }
+        let bytes = builder.finish().unwrap();
+        let workdir = TempDir::new().unwrap();
+        let report = Archive::new(&bytes[..]).unpack(workdir.path()).unwrap();
+        assert_eq!(vec![PathBuf::from("a")], report.written);
+        assert_eq!(5, report.bytes);
+        // the error carries the report on the entries unpacked before it
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_data(Metadata::regular(0o644, 0), "file", b"hello")
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "../outside", b"hello")
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().strict_paths(true));
+        let e = archive.unpack(workdir.path()).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, e.error.kind());
+        assert_eq!(vec![PathBuf::from("file")], e.report.written);
+        assert_eq!(1, e.report.rejected_paths);
+    }
+
+    #[test]
+    fn keep_going() {
+        let mut builder = Builder::new(Vec::new());
+        for path in ["dir/file", "file"] {
+            builder
+                .append_data(Metadata::regular(0o644, 0), path, b"hello")
+                .unwrap();
+        }
+        let bytes = builder.finish().unwrap();
+        let workdir = TempDir::new().unwrap();
+        // the parent directory can't be created
+        std::fs::write(workdir.path().join("dir"), b"").unwrap();
+        let archive = Archive::new(&bytes[..]);
+        assert!(archive.unpack(workdir.path()).is_err());
+        assert!(!workdir.path().join("file").exists());
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().keep_going(true));
+        let report = archive.unpack(workdir.path()).unwrap();
+        assert_eq!(vec![PathBuf::from("file")], report.written);
+        assert_eq!(1, report.errors.len());
+        assert_eq!(Path::new("dir/file"), report.errors[0].0);
+        assert_eq!(
+            b"hello",
+            &std::fs::read(workdir.path().join("file")).unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn overwrite_policies() {
+        let mut builder = Builder::new(Vec::new());
+        let mut file = Metadata::regular(0o644, 0);
+        file.set_mtime(100);
+        builder.append_data(file, "file", b"newer").unwrap();
+        let mut symlink = Metadata::symlink("file");
+        symlink.set_mtime(100);
+        builder.append_data(symlink, "link", b"file\0").unwrap();
+        let mut fifo = Metadata::new(FileType::Fifo, 0o644);
+        fifo.set_mtime(100);
+        builder.append_data(fifo, "fifo", b"").unwrap();
+        let bytes = builder.finish().unwrap();
+        for policy in [
+            OverwritePolicy::Always,
+            OverwritePolicy::Never,
+            OverwritePolicy::IfNewer,
+        ] {
+            let workdir = TempDir::new().unwrap();
+            // the existing file is older than the entry, the others are newer
+            for (path, mtime) in [("file", 50), ("link", 200), ("fifo", 200)] {
+                let path = workdir.path().join(path);
+                std::fs::write(&path, b"old").unwrap();
+                let file = File::options().write(true).open(&path).unwrap();
+                file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))
+                    .unwrap();
+            }
+            let options = || UnpackOptions::new().overwrite(policy);
+            let changes: Vec<_> = Archive::new(&bytes[..])
+                .plan_unpack(workdir.path(), &options())
+                .unwrap()
+                .into_iter()
+                .map(|change| change.change)
+                .collect();
+            let mut archive = Archive::new(&bytes[..]);
+            archive.set_unpack_options(options());
+            let report = archive.unpack(workdir.path()).unwrap();
+            let skipped: Vec<_> = report.skipped.into_iter().map(|(_, x)| x).collect();
+            let file = std::fs::read(workdir.path().join("file")).unwrap();
+            let link = workdir.path().join("link").symlink_metadata().unwrap();
+            let fifo = workdir.path().join("fifo").symlink_metadata().unwrap();
+            match policy {
+                OverwritePolicy::Always => {
+                    assert_eq!(
+                        vec![
+                            Change::Overwrite(Differences {
+                                size: true,
+                                ..Default::default()
+                            }),
+                            Change::Replace,
+                            Change::Replace
+                        ],
+                        changes
+                    );
+                    assert!(skipped.is_empty());
+                    assert_eq!(b"newer", &file[..]);
+                    assert!(link.is_symlink());
+                    assert!(fifo.file_type().is_fifo());
+                }
+                OverwritePolicy::Never => {
+                    let expected = vec![SkipReason::Existing; 3];
+                    let expected_changes: Vec<_> =
+                        expected.iter().copied().map(Change::Skip).collect();
+                    assert_eq!(expected_changes, changes);
+                    assert_eq!(expected, skipped);
+                    assert_eq!(b"old", &file[..]);
+                    assert!(link.is_file());
+                    assert!(fifo.is_file());
+                }
+                OverwritePolicy::IfNewer => {
+                    assert_eq!(
+                        vec![
+                            Change::Overwrite(Differences {
+                                size: true,
+                                ..Default::default()
+                            }),
+                            Change::Skip(SkipReason::NotNewer),
+                            Change::Skip(SkipReason::NotNewer)
+                        ],
+                        changes
+                    );
+                    assert_eq!(vec![SkipReason::NotNewer; 2], skipped);
+                    assert_eq!(b"newer", &file[..]);
+                    assert!(link.is_file());
+                    assert!(fifo.is_file());
+                }
+            }
+        }
+        // the existing file is kept when its replacement is rejected
+        let workdir = TempDir::new().unwrap();
+        std::fs::write(workdir.path().join("file"), b"old").unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(
+            UnpackOptions::new().scan_contents(|_path, _metadata, _reader| Ok(false)),
+        );
+        let report = archive.unpack(workdir.path()).unwrap();
+        assert_eq!(
+            vec![(PathBuf::from("file"), SkipReason::Rejected)],
+            report.skipped
+        );
+        assert_eq!(
+            b"old",
+            &std::fs::read(workdir.path().join("file")).unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn unpack_type_conflicts() {
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_data(Metadata::regular(0o644, 0), "usr/bin", b"hello")
+            .unwrap();
+        builder.append_dir("etc", 0o755).unwrap();
+        let bytes = builder.finish().unwrap();
+        for policy in [
+            ConflictPolicy::Error,
+            ConflictPolicy::Replace,
+            ConflictPolicy::Skip,
+        ] {
+            let workdir = TempDir::new().unwrap();
+            create_dir_all(workdir.path().join("usr/bin")).unwrap();
+            std::fs::write(workdir.path().join("usr/bin/sh"), b"").unwrap();
+            std::fs::write(workdir.path().join("etc"), b"").unwrap();
+            let options = || {
+                UnpackOptions::new()
+                    .file_over_dir(policy)
+                    .dir_over_file(policy)
+            };
+            let changes: Vec<_> = Archive::new(&bytes[..])
+                .plan_unpack(workdir.path(), &options())
+                .unwrap()
+                .into_iter()
+                .map(|change| change.change)
+                .collect();
+            let mut archive = Archive::new(&bytes[..]);
+            archive.set_unpack_options(options());
+            let result = archive.unpack(workdir.path());
+            let bin = workdir.path().join("usr/bin").symlink_metadata().unwrap();
+            let etc = workdir.path().join("etc").symlink_metadata().unwrap();
+            match policy {
+                ConflictPolicy::Error => {
+                    assert_eq!(vec![Change::AlreadyExists; 2], changes);
+                    let e = result.unwrap_err();
+                    assert_eq!(ErrorKind::AlreadyExists, e.error.kind());
+                    assert_eq!(
+                        "entry #0 \"usr/bin\": can't unpack regular file \
+                         over the existing directory \"usr/bin\"",
+                        e.to_string()
+                    );
+                    assert!(bin.is_dir());
+                    assert!(etc.is_file());
+                }
+                ConflictPolicy::Replace => {
+                    assert_eq!(vec![Change::Replace; 2], changes);
+                    result.unwrap();
+                    assert!(bin.is_file());
+                    assert!(etc.is_dir());
+                }
+                ConflictPolicy::Skip => {
+                    assert_eq!(vec![Change::Skip(SkipReason::Conflict); 2], changes);
+                    assert_eq!(2, result.unwrap().skipped.len());
+                    assert!(bin.is_dir());
+                    assert!(etc.is_file());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unpack_through_created_symlinks() {
+        let workdir = TempDir::new().unwrap();
+        let outside = workdir.path().join("outside");
+        let target = workdir.path().join("target");
+        create_dir_all(&outside).unwrap();
+        create_dir_all(target.join("real")).unwrap();
+        // the links that existed before unpacking are rejected as well
+        std::os::unix::fs::symlink("real", target.join("existing")).unwrap();
+        let mut builder = Builder::new(Vec::new());
+        let mut outside_bytes = outside.as_os_str().as_bytes().to_vec();
+        outside_bytes.push(0);
+        let mut symlink = Metadata::symlink(outside.as_os_str());
+        symlink.nlink = 2;
+        symlink.ino = 100;
+        builder
+            .append_data(symlink.clone(), "link", &outside_bytes[..])
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "link/file", b"hello")
+            .unwrap();
+        // the hard link to the symbolic link is a symbolic link too
+        builder
+            .append_data(symlink, "hard-link", &outside_bytes[..])
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "hard-link/evil", b"hello")
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "existing/file", b"hello")
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "real/file", b"hello")
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let e = Archive::new(&bytes[..]).unpack(&target).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, e.error.kind());
+        assert!(!outside.join("file").exists());
+        remove_dir_all(&target).unwrap();
+        create_dir_all(target.join("real")).unwrap();
+        std::os::unix::fs::symlink("real", target.join("existing")).unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().keep_going(true));
+        let report = archive.unpack(&target).unwrap();
+        assert_eq!(
+            vec![
+                Path::new("link/file"),
+                Path::new("hard-link/evil"),
+                Path::new("existing/file")
+            ],
+            report
+                .errors
+                .iter()
+                .map(|(path, _)| path.as_path())
+                .collect::<Vec<_>>()
+        );
+        assert!(!outside.join("file").exists());
+        assert!(!outside.join("evil").exists());
+        assert_eq!(
+            b"hello",
+            &std::fs::read(target.join("real/file")).unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn strict_paths() {
+        let mut builder = Builder::new(Vec::new());
+        for path in ["ok", "../outside", "/absolute", "a/../b"] {
+            builder
+                .append_data(Metadata::regular(0o644, 0), path, b"hello")
+                .unwrap();
+        }
+        let bytes = builder.finish().unwrap();
+        let workdir = TempDir::new().unwrap();
+        let report = Archive::new(&bytes[..])
+            .unpack(workdir.path().join("lax"))
+            .unwrap();
+        assert_eq!(1, report.rejected_paths);
+        assert_eq!(
+            vec![(PathBuf::from("../outside"), SkipReason::Outside)],
+            report.skipped
+        );
+        let options = || UnpackOptions::new().strict_paths(true);
+        let e = Archive::new(&bytes[..])
+            .plan_unpack(workdir.path(), &options())
+            .unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, e.kind());
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(options());
+        let e = archive.unpack(workdir.path().join("strict")).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, e.error.kind());
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(options().keep_going(true));
+        let report = archive.unpack(workdir.path().join("strict")).unwrap();
+        assert_eq!(3, report.rejected_paths);
+        assert_eq!(vec![PathBuf::from("ok")], report.written);
+        let failed: Vec<_> = report.errors.into_iter().map(|(path, _)| path).collect();
+        assert_eq!(
+            ["../outside", "/absolute", "a/../b"]
+                .into_iter()
+                .map(PathBuf::from)
+                .collect::<Vec<_>>(),
+            failed
+        );
+    }
+
+    #[test]
+    fn absolute_symlink_targets() {
+        let links = [
+            ("usr/sh", "/bin/busybox"),
+            ("root", "/"),
+            ("up", "/../etc/passwd"),
+            ("relative", "bin/sh"),
+        ];
+        let mut builder = Builder::new(Vec::new());
+        for (path, target) in links {
+            builder
+                .append_data(
+                    Metadata::symlink(target),
+                    path,
+                    format!("{}\0", target).as_bytes(),
+                )
+                .unwrap();
+        }
+        let bytes = builder.finish().unwrap();
+        for policy in [
+            AbsoluteTargetPolicy::Keep,
+            AbsoluteTargetPolicy::Relative,
+            AbsoluteTargetPolicy::Prefix,
+        ] {
+            let workdir = TempDir::new().unwrap();
+            let root = workdir.path().canonicalize().unwrap();
+            let mut archive = Archive::new(&bytes[..]);
+            archive.set_unpack_options(UnpackOptions::new().absolute_symlink_targets(policy));
+            archive.unpack(workdir.path()).unwrap();
+            let expected: Vec<PathBuf> = match policy {
+                AbsoluteTargetPolicy::Keep => links.iter().map(|(_, x)| x.into()).collect(),
+                AbsoluteTargetPolicy::Relative => {
+                    vec![
+                        "../bin/busybox".into(),
+                        ".".into(),
+                        "etc/passwd".into(),
+                        "bin/sh".into(),
+                    ]
+                }
+                AbsoluteTargetPolicy::Prefix => vec![
+                    root.join("bin/busybox"),
+                    root.clone(),
+                    root.join("etc/passwd"),
+                    "bin/sh".into(),
+                ],
+            };
+            let actual: Vec<_> = links
+                .iter()
+                .map(|(path, _)| read_link(workdir.path().join(path)).unwrap())
+                .collect();
+            assert_eq!(expected, actual, "policy = {:?}", policy);
+        }
+        let workdir = TempDir::new().unwrap();
+        let dir = File::open(workdir.path()).unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(
+            UnpackOptions::new().absolute_symlink_targets(AbsoluteTargetPolicy::Prefix),
+        );
+        let e = archive.unpack_in_dirfd(&dir).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, e.error.kind());
+    }
+
+    #[test]
+    fn preserve_mtime_in_unpack_options() {
+        let mut builder = Builder::new(Vec::new());
+        let mut metadata = Metadata::regular(0o644, 0);
+        metadata.set_mtime(1000);
+        builder.append_data(metadata, "file", b"").unwrap();
+        let bytes = builder.finish().unwrap();
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().preserve_mtime(true));
+        archive.unpack(workdir.path()).unwrap();
+        let metadata = std::fs::metadata(workdir.path().join("file")).unwrap();
+        assert_eq!(1000, metadata.mtime());
+        let mut archive = Archive::new(&bytes[..]);
+        let changes: Vec<_> = archive
+            .plan_unpack(workdir.path(), &UnpackOptions::new().preserve_mtime(true))
+            .unwrap()
+            .into_iter()
+            .map(|change| change.change)
+            .collect();
+        assert_eq!(vec![Change::Unchanged], changes);
+        // the deprecated setter forwards to the unpack options
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        #[allow(deprecated)]
+        archive.preserve_mtime(true);
+        archive.unpack(workdir.path()).unwrap();
+        let metadata = std::fs::metadata(workdir.path().join("file")).unwrap();
+        assert_eq!(1000, metadata.mtime());
+        // and the unpack options can turn the flag off
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        #[allow(deprecated)]
+        archive.preserve_mtime(true);
+        archive.set_unpack_options(UnpackOptions::new().preserve_mtime(false));
+        archive.unpack(workdir.path()).unwrap();
+        let metadata = std::fs::metadata(workdir.path().join("file")).unwrap();
+        assert_ne!(1000, metadata.mtime());
+    }
+
+    #[test]
+    fn group_policy() {
+        let gid = unsafe { libc::getegid() };
+        let mut builder = Builder::new(Vec::new());
+        let mut dir = Metadata::directory(0o755);
+        dir.set_gid(gid + 1);
+        builder.append_dir_with_metadata(dir, "sub").unwrap();
+        let mut file = Metadata::regular(0o644, 0);
+        file.set_gid(gid + 1);
+        builder.append_data(file, "sub/file", b"hello").unwrap();
+        let bytes = builder.finish().unwrap();
+        // setgid bit is inherited by the subdirectories
+        let workdir = TempDir::new().unwrap();
+        std::fs::set_permissions(workdir.path(), Permissions::from_mode(0o2755)).unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().groups(GroupPolicy::Inherit));
+        archive.unpack(workdir.path()).unwrap();
+        let metadata = std::fs::metadata(workdir.path().join("sub")).unwrap();
+        assert_eq!(0o2755, metadata.mode() & 0o7777);
+        // the group is changed explicitly
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().groups(GroupPolicy::Force(gid)));
+        archive.unpack(workdir.path()).unwrap();
+        for path in ["sub", "sub/file"] {
+            let metadata = std::fs::metadata(workdir.path().join(path)).unwrap();
+            assert_eq!(gid, metadata.gid());
+            assert_eq!(0, metadata.mode() & 0o2000);
+        }
+    }
+
+    #[test]
+    fn unpack_absolute_paths() {
+        let outside = TempDir::new().unwrap();
+        let absolute_path = outside.path().join("file");
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_data(Metadata::regular(0o644, 0), &absolute_path, b"hello")
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let relative_path = absolute_path.strip_prefix("/").unwrap();
+        // strip
+        let workdir = TempDir::new().unwrap();
+        Archive::new(&bytes[..]).unpack(workdir.path()).unwrap();
+        assert!(workdir.path().join(relative_path).exists());
+        assert!(!absolute_path.exists());
+        // error
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().absolute_paths(AbsolutePathPolicy::Error));
+        assert_eq!(
+            ErrorKind::InvalidData,
+            archive.unpack(workdir.path()).unwrap_err().error.kind()
+        );
+        assert!(list_dir_all(workdir.path()).unwrap().is_empty());
+        // keep
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().absolute_paths(AbsolutePathPolicy::Keep));
+        archive.unpack(workdir.path()).unwrap();
+        assert!(list_dir_all(workdir.path()).unwrap().is_empty());
+        assert_eq!(b"hello", &std::fs::read(&absolute_path).unwrap()[..]);
+        // keep, the parents are not checked for symbolic links
+        std::fs::create_dir(outside.path().join("real")).unwrap();
+        symlink("real", outside.path().join("link")).unwrap();
+        let linked_path = outside.path().join("link/file");
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_data(Metadata::regular(0o644, 0), &linked_path, b"hello")
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let workdir = TempDir::new().unwrap();
+        // the same path inside the target directory is irrelevant
+        let inner_parent = workdir
+            .path()
+            .join(linked_path.parent().unwrap().strip_prefix("/").unwrap());
+        create_dir_all(inner_parent.parent().unwrap()).unwrap();
+        symlink("/nonexistent", &inner_parent).unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().absolute_paths(AbsolutePathPolicy::Keep));
+        archive.unpack(workdir.path()).unwrap();
+        assert_eq!(
+            b"hello",
+            &std::fs::read(outside.path().join("real/file")).unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn unpack_scan_contents() {
+        let mut builder = Builder::new(Vec::new());
+        builder
+            .append_data(Metadata::regular(0o640, 0), "good", b"hello")
+            .unwrap();
+        let mut infected = Metadata::regular(0o644, 0);
+        infected.set_ino(1);
+        infected.set_nlink(2);
+        builder
+            .append_data(infected.clone(), "dir/infected", b"virus")
+            .unwrap();
+        builder.append_data(infected, "link", b"virus").unwrap();
+        let bytes = builder.finish().unwrap();
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(
+            UnpackOptions::new().scan_contents(|path, metadata, reader| {
+                let mut contents = Vec::new();
+                reader.read_to_end(&mut contents)?;
+                assert_eq!(metadata.size(), contents.len() as u64, "{:?}", path);
+                Ok(contents != b"virus")
+            }),
+        );
+        archive.unpack(workdir.path()).unwrap();
+        assert_eq!(
+            b"hello",
+            &std::fs::read(workdir.path().join("good")).unwrap()[..]
+        );
+        assert_eq!(
+            0o640,
+            workdir
+                .path()
+                .join("good")
+                .metadata()
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        );
+        let files: Vec<_> = list_dir_all(workdir.path())
+            .unwrap()
+            .into_iter()
+            .map(|file| file.path)
+            .collect();
+        assert_eq!(vec![PathBuf::from("dir"), PathBuf::from("good")], files);
+        // the contents are stored in the last link, the earlier links are removed on rejection
+        let input = TempDir::new().unwrap();
+        std::fs::write(input.path().join("a"), b"virus").unwrap();
+        for name in ["b", "c"] {
+            std::fs::hard_link(input.path().join("a"), input.path().join(name)).unwrap();
+        }
+        let mut builder = Builder::new(Vec::new());
+        builder.gnu_compat(true);
+        for name in ["a", "b", "c"] {
+            builder.append_path(input.path().join(name), name).unwrap();
+        }
+        let gnu_bytes = builder.finish().unwrap();
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&gnu_bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().scan_contents(
+            |_path, _metadata, reader| {
+                let mut contents = Vec::new();
+                reader.read_to_end(&mut contents)?;
+                Ok(contents != b"virus")
+            },
+        ));
+        let report = archive.unpack(workdir.path()).unwrap();
+        assert!(report.written.is_empty(), "{:?}", report.written);
+        assert!(report.hard_links.is_empty(), "{:?}", report.hard_links);
+        assert_eq!(
+            vec![(PathBuf::from("c"), SkipReason::Rejected)],
+            report.skipped
+        );
+        assert!(list_dir_all(workdir.path()).unwrap().is_empty());
+        // scanner errors abort unpacking
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(
+            UnpackOptions::new()
+                .scan_contents(|_path, _metadata, _reader| Err(ErrorKind::Other.into())),
+        );
+        assert!(archive.unpack(workdir.path()).is_err());
+        assert!(list_dir_all(workdir.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unpack_mode_mask() {
+        let mut builder = Builder::new(Vec::new());
+        builder.append_dir("dir", 0o777).unwrap();
+        builder
+            .append_data(Metadata::regular(0o4777, 0), "a/b/file", b"hello")
+            .unwrap();
+        builder
+            .append_special("a/fifo", FileType::Fifo, 0o666, 0)
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        let workdir = TempDir::new().unwrap();
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().mode_mask(Some(0o7027)));
+        archive.unpack(workdir.path()).unwrap();
+        for (path, mode) in [
+            ("dir", 0o750),
+            ("a", 0o750),
+            ("a/b", 0o750),
+            ("a/b/file", 0o750),
+            ("a/fifo", 0o640),
+        ] {
+            let metadata = workdir.path().join(path).symlink_metadata().unwrap();
+            assert_eq!(mode, metadata.permissions().mode() & 0o7777, "{}", path);
+        }
+    }
+
+    #[test]
+    fn unpack_dir_symlink_conflicts() {
+        let mut builder = Builder::new(Vec::new());
+        builder.append_dir("dir", 0o755).unwrap();
+        builder
+            .append_data(Metadata::symlink("target"), "link", b"target\0")
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        for policy in [
+            ConflictPolicy::Error,
+            ConflictPolicy::Replace,
+            ConflictPolicy::Skip,
+        ] {
+            let workdir = TempDir::new().unwrap();
+            std::os::unix::fs::symlink("target", workdir.path().join("dir")).unwrap();
+            create_dir_all(workdir.path().join("link/subdir")).unwrap();
+            let mut archive = Archive::new(&bytes[..]);
+            archive.set_unpack_options(
+                UnpackOptions::new()
+                    .dir_over_symlink(policy)
+                    .symlink_over_dir(policy),
+            );
+            let result = archive.unpack(workdir.path());
+            let dir = workdir.path().join("dir").symlink_metadata().unwrap();
+            let link = workdir.path().join("link").symlink_metadata().unwrap();
+            match policy {
+                ConflictPolicy::Error => {
+                    assert_eq!(ErrorKind::AlreadyExists, result.unwrap_err().error.kind());
+                }
+                ConflictPolicy::Replace => {
+                    result.unwrap();
+                    assert!(dir.is_dir());
+                    assert!(link.is_symlink());
+                }
+                ConflictPolicy::Skip => {
+                    result.unwrap();
+                    assert!(dir.is_symlink());
+                    assert!(link.is_dir());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::io::Error;
+use std::io::Sink;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::limits::HeaderField;
+use crate::limits::LimitViolation;
+use crate::Builder;
+use crate::DoNotEditMetadata;
+use crate::FileType;
+use crate::Format;
+use crate::WalkerOptions;
+
+/// The problem that prevents the file from being archived.
+///
+/// Returned by [`validate_tree`].
+#[derive(Debug)]
+pub struct Issue {
+    /// The path of the file relative to the root directory.
+    pub path: PathBuf,
+    /// The problem.
+    pub kind: IssueKind,
+}
+
+/// The kind of the [`Issue`].
+#[derive(Debug)]
+pub enum IssueKind {
+    /// The file type can't be stored in the archive.
+    UnsupportedFileType,
+    /// The value doesn't fit into the header.
+    Limit(LimitViolation),
+    /// The file can't be accessed or its metadata can't be stored in the archive
+    /// (e.g. modification time is before Unix epoch).
+    Io(Error),
+}
+
+/// Find all the files in the `directory` that can't be archived in the specified `format`.
+///
+/// The directory is traversed the same way as in
+/// [`Builder::append_dir_all`](crate::Builder::append_dir_all)
+/// and each file is checked by [`Builder::check_path`] with the default settings
+/// and the paths relative to the `directory`.
+/// Inode numbers and the ids of the devices that contain the files are not checked
+/// because they are replaced by `Builder`.
+/// Returns an empty vector if the directory can be packed.
+pub fn validate_tree<P: AsRef<Path>>(directory: P, format: Format) -> Vec<Issue> {
+    let directory = directory.as_ref();
+    let relative_path = |path: &Path| match path.strip_prefix(directory) {
+        Ok(path) => path.to_path_buf(),
+        Err(_) => path.to_path_buf(),
+    };
+    let mut issues = Vec::new();
+    let mut walker = match WalkerOptions::default().walk(directory) {
+        Ok(walker) => walker,
+        Err(e) => {
+            issues.push(Issue {
+                path: directory.to_path_buf(),
+                kind: IssueKind::Io(e),
+            });
+            return issues;
+        }
+    };
+    let mut builder = Builder::new(std::io::sink());
+    builder.set_format(format);
+    while let Some(entry) = walker.next() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                issues.push(Issue {
+                    path: relative_path(walker.error_path().unwrap_or(directory)),
+                    kind: IssueKind::Io(e),
+                });
+                continue;
+            }
+        };
+        let outer_path = entry.path();
+        let path = relative_path(&outer_path);
+        for kind in validate_file(&mut builder, &outer_path, &path) {
+            issues.push(Issue {
+                path: path.clone(),
+                kind,
+            });
+        }
+    }
+    issues
+}
+
+fn validate_file(
+    builder: &mut Builder<Sink, DoNotEditMetadata>,
+    outer_path: &Path,
+    inner_path: &Path,
+) -> Vec<IssueKind> {
+    let fs_metadata = match outer_path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(e) => return vec![IssueKind::Io(e)],
+    };
+    if FileType::new(fs_metadata.mode()).is_err() {
+        return vec![IssueKind::UnsupportedFileType];
+    }
+    match builder.check_path(outer_path, inner_path) {
+        Ok(violations) => violations
+            .into_iter()
+            .filter(|violation| !matches!(violation.field, HeaderField::Ino | HeaderField::Dev))
+            .map(IssueKind::Limit)
+            .collect(),
+        Err(e) => vec![IssueKind::Io(e)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::limits::*;
+
+    #[test]
+    fn validate() {
+        let workdir = TempDir::new().unwrap();
+        std::fs::create_dir(workdir.path().join("dir")).unwrap();
+        let large = File::create(workdir.path().join("dir/large")).unwrap();
+        // sparse file
+        large.set_len(NEWC_MAX_FILE_SIZE + 1).unwrap();
+        let old = File::create(workdir.path().join("old")).unwrap();
+        old.set_modified(SystemTime::UNIX_EPOCH - Duration::from_secs(1))
+            .unwrap();
+        std::fs::write(workdir.path().join("ok"), b"hello").unwrap();
+        let mut issues = validate_tree(workdir.path(), Format::Newc);
+        issues.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(2, issues.len(), "{:?}", issues);
+        assert_eq!(Path::new("dir/large"), issues[0].path);
+        assert!(matches!(
+            issues[0].kind,
+            IssueKind::Limit(LimitViolation {
+                field: HeaderField::FileSize,
+                ..
+            })
+        ));
+        assert_eq!(Path::new("old"), issues[1].path);
+        assert!(matches!(issues[1].kind, IssueKind::Io(..)));
+        let issues = validate_tree(workdir.path(), Format::Odc);
+        assert_eq!(1, issues.len(), "{:?}", issues);
+        assert_eq!(Path::new("old"), issues[0].path);
+        let issues = validate_tree(workdir.path().join("missing"), Format::Odc);
+        assert_eq!(1, issues.len(), "{:?}", issues);
+        assert_eq!(workdir.path().join("missing"), issues[0].path);
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::collections::VecDeque;
 use std::fs::DirEntry;
 use std::io::Error;
@@ -10,15 +11,21 @@ use std::path::PathBuf;
 pub struct WalkerOptions {
     follow_symlinks: bool,
     cross_device: bool,
+    sort: bool,
 }
 
 impl WalkerOptions {
-    #[allow(unused)]
     pub fn follow_symlinks(mut self, value: bool) -> Self {
         self.follow_symlinks = value;
         self
     }
 
+    /// Visit directory entries in the order of their file names.
+    pub fn sort(mut self, value: bool) -> Self {
+        self.sort = value;
+        self
+    }
+
     #[allow(unused)]
     pub fn cross_device(mut self, value: bool) -> Self {
         self.cross_device = value;
@@ -26,13 +33,21 @@ impl WalkerOptions {
     }
 
     pub fn walk<P: AsRef<Path>>(self, root: P) -> Result<Walker, Error> {
-        let root_dev = root.as_ref().metadata()?.dev();
+        let root_metadata = root.as_ref().metadata()?;
+        let root_dev = root_metadata.dev();
         let mut walker = Walker {
             entries: Default::default(),
+            pending_dir: None,
+            error_path: None,
+            visited_dirs: Default::default(),
             root_dev,
             follow_symlinks: self.follow_symlinks,
             cross_device: self.cross_device,
+            sort: self.sort,
         };
+        if walker.follow_symlinks {
+            walker.visited_dirs.insert((root_dev, root_metadata.ino()));
+        }
         walker.visit_dir(root)?;
         Ok(walker)
     }
@@ -40,19 +55,53 @@ impl WalkerOptions {
 
 /// Traverse file tree recursively, breadth-first.
 pub struct Walker {
-    entries: VecDeque<Result<DirEntry, Error>>,
+    // The errors are paired with the directory which entries failed to be read.
+    entries: VecDeque<Result<DirEntry, (PathBuf, Error)>>,
+    // The directory that was returned last and which contents are not yet visited.
+    pending_dir: Option<PathBuf>,
+    // The path of the file or directory that caused the last error.
+    error_path: Option<PathBuf>,
+    // (dev, ino) of the root and the directories visited via symlinks, used to detect loops.
+    visited_dirs: HashSet<(u64, u64)>,
     root_dev: u64,
     follow_symlinks: bool,
     cross_device: bool,
+    sort: bool,
 }
 
 impl Walker {
+    #[allow(unused)]
     pub fn new<P: AsRef<Path>>(root: P) -> Result<Self, Error> {
         WalkerOptions::default().walk(root)
     }
 
+    /// Do not descend into the directory that was returned last.
+    pub fn skip_current_dir(&mut self) {
+        self.pending_dir = None;
+    }
+
+    /// Get the path of the file or directory that caused the error that was returned last.
+    pub fn error_path(&self) -> Option<&Path> {
+        self.error_path.as_deref()
+    }
+
+    fn fail(&mut self, path: PathBuf, e: Error) -> Option<Result<DirEntry, Error>> {
+        self.error_path = Some(path);
+        Some(Err(e))
+    }
+
     fn visit_dir<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
-        self.entries.extend(path.as_ref().read_dir()?);
+        let path = path.as_ref();
+        let entries = path
+            .read_dir()?
+            .map(|entry| entry.map_err(|e| (path.to_path_buf(), e)));
+        if self.sort {
+            let mut entries: Vec<_> = entries.collect();
+            entries.sort_by_key(|entry| entry.as_ref().ok().map(|entry| entry.file_name()));
+            self.entries.extend(entries);
+        } else {
+            self.entries.extend(entries);
+        }
         Ok(())
     }
 }
@@ -61,6 +110,11 @@ impl Iterator for Walker {
     type Item = Result<DirEntry, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if let Some(dir) = self.pending_dir.take() {
+            if let Err(e) = self.visit_dir(&dir) {
+                return self.fail(dir, e);
+            }
+        }
         loop {
             let entry = self.entries.pop_front()?;
             match entry {
@@ -71,12 +125,12 @@ impl Iterator for Walker {
                         // most of the time)
                         match entry.file_type() {
                             Ok(kind) => kind,
-                            Err(e) => return Some(Err(e)),
+                            Err(e) => return self.fail(entry.path(), e),
                         }
                     } else {
                         let metadata = match entry.metadata() {
                             Ok(metadata) => metadata,
-                            Err(e) => return Some(Err(e)),
+                            Err(e) => return self.fail(entry.path(), e),
                         };
                         if !self.cross_device && metadata.dev() != self.root_dev {
                             continue;
@@ -86,19 +140,23 @@ impl Iterator for Walker {
                     let is_dir = if kind.is_dir() {
                         true
                     } else if self.follow_symlinks && kind.is_symlink() {
-                        // resolve symlink
-                        entry.path().is_dir()
+                        // resolve symlink and do not visit the same directory twice via symlinks
+                        match entry.path().metadata() {
+                            Ok(metadata) => {
+                                metadata.is_dir()
+                                    && self.visited_dirs.insert((metadata.dev(), metadata.ino()))
+                            }
+                            Err(_) => false,
+                        }
                     } else {
                         false
                     };
                     if is_dir {
-                        if let Err(e) = self.visit_dir(entry.path()) {
-                            return Some(Err(e));
-                        }
+                        self.pending_dir = Some(entry.path());
                     }
                     return Some(Ok(entry));
                 }
-                other => return Some(other),
+                Err((path, e)) => return self.fail(path, e),
             }
         }
     }
@@ -106,6 +164,7 @@ impl Iterator for Walker {
 
 impl FusedIterator for Walker {}
 
+#[allow(unused)]
 pub trait Walk {
     fn walk(&self) -> Result<Walker, Error>;
 }
@@ -121,3 +180,25 @@ impl Walk for PathBuf {
         Walker::new(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn error_path() {
+        let workdir = TempDir::new().unwrap();
+        std::fs::create_dir(workdir.path().join("dir")).unwrap();
+        let mut walker = Walker::new(workdir.path()).unwrap();
+        let entry = walker.next().unwrap().unwrap();
+        assert_eq!(workdir.path().join("dir"), entry.path());
+        assert_eq!(None, walker.error_path());
+        // the directory disappears before its entries are read
+        std::fs::remove_dir(entry.path()).unwrap();
+        assert!(walker.next().unwrap().is_err());
+        assert_eq!(Some(entry.path().as_path()), walker.error_path());
+        assert!(walker.next().is_none());
+    }
+}
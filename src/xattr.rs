@@ -0,0 +1,215 @@
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::ffi::OsStr;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// The path prefix of the entries that store extended attributes.
+///
+/// CPIO headers have no room for extended attributes,
+/// hence they are stored in the contents of a separate regular file entry
+/// which path consists of this prefix, the path of the file and [`XATTRS_SUFFIX`], e.g.
+/// the attributes of `usr/bin/ping` are stored in `.kpea-xattrs/usr/bin/ping.xattrs`.
+/// The suffix prevents the entries of the directories from conflicting with
+/// the entries of their files when unpacked by other tools as regular files.
+///
+/// See [`Builder::preserve_xattrs`](crate::Builder::preserve_xattrs)
+/// and [`UnpackOptions::xattrs`](crate::UnpackOptions::xattrs).
+pub const XATTRS_PREFIX: &str = ".kpea-xattrs/";
+
+/// The path suffix of the entries that store extended attributes.
+///
+/// See [`XATTRS_PREFIX`].
+pub const XATTRS_SUFFIX: &str = ".xattrs";
+
+/// Extended attribute.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Xattr {
+    /// The name including the namespace, e.g. `user.comment`.
+    pub name: CString,
+    /// The value.
+    pub value: Vec<u8>,
+}
+
+/// Encode the attributes as the contents of the [`XATTRS_PREFIX`] entry.
+///
+/// Each attribute is stored as the NUL-terminated name followed by
+/// the length of the value as little-endian 32-bit integer and the value itself.
+pub fn encode_xattrs(xattrs: &[Xattr]) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    for xattr in xattrs.iter() {
+        let len: u32 =
+            xattr.value.len().try_into().map_err(|_| {
+                Error::new(ErrorKind::InvalidData, "extended attribute is too large")
+            })?;
+        data.extend_from_slice(xattr.name.as_bytes_with_nul());
+        data.extend_from_slice(&len.to_le_bytes());
+        data.extend_from_slice(&xattr.value);
+    }
+    Ok(data)
+}
+
+/// Decode the contents of the [`XATTRS_PREFIX`] entry.
+pub fn decode_xattrs(mut data: &[u8]) -> Result<Vec<Xattr>, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "invalid extended attributes");
+    let mut xattrs = Vec::new();
+    while !data.is_empty() {
+        let name = CStr::from_bytes_until_nul(data).map_err(|_| invalid())?;
+        data = &data[name.to_bytes_with_nul().len()..];
+        let (len, rest) = data.split_first_chunk::<4>().ok_or_else(invalid)?;
+        let len = u32::from_le_bytes(*len) as usize;
+        if rest.len() < len || name.is_empty() {
+            return Err(invalid());
+        }
+        let (value, rest) = rest.split_at(len);
+        xattrs.push(Xattr {
+            name: name.into(),
+            value: value.to_vec(),
+        });
+        data = rest;
+    }
+    Ok(xattrs)
+}
+
+/// Get the path of the entry that stores the extended attributes of the file with the specified path.
+pub fn xattrs_entry_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = strip_dot_slash(path.as_ref());
+    let mut entry_path = XATTRS_PREFIX.as_bytes().to_vec();
+    // absolute paths are kept as is
+    entry_path.extend_from_slice(path.as_os_str().as_bytes());
+    entry_path.extend_from_slice(XATTRS_SUFFIX.as_bytes());
+    OsStr::from_bytes(&entry_path).into()
+}
+
+/// Get the path of the file which extended attributes are stored in the entry with the specified path.
+///
+/// Returns `None` if the entry doesn't store extended attributes.
+pub fn xattrs_target<P: AsRef<Path>>(entry_path: P) -> Option<PathBuf> {
+    let path = strip_dot_slash(entry_path.as_ref());
+    let target = path
+        .as_os_str()
+        .as_bytes()
+        .strip_prefix(XATTRS_PREFIX.as_bytes())?
+        .strip_suffix(XATTRS_SUFFIX.as_bytes())?;
+    if target.is_empty() {
+        return None;
+    }
+    Some(Path::new(OsStr::from_bytes(target)).into())
+}
+
+/// Returns `true` if the attribute is preserved by [`Builder::preserve_xattrs`](crate::Builder::preserve_xattrs).
+pub(crate) fn is_preserved_xattr(name: &CStr) -> bool {
+    let name = name.to_bytes();
+    name.starts_with(b"user.") || name == b"security.capability"
+}
+
+/// Remove the leading `./` from the path.
+pub(crate) fn strip_dot_slash(path: &Path) -> &Path {
+    path.strip_prefix("./").unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use arbtest::arbtest;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::Archive;
+    use crate::Builder;
+    use crate::Metadata;
+    use crate::UnpackOptions;
+
+    #[test]
+    fn xattrs_symmetry() {
+        arbtest(|u| {
+            let mut xattrs = Vec::new();
+            for _ in 0..u.int_in_range(0..=5)? {
+                let mut name: Vec<u8> = u.arbitrary()?;
+                name.retain(|ch| *ch != 0);
+                name.insert(0, b'x');
+                xattrs.push(Xattr {
+                    name: CString::new(name).unwrap(),
+                    value: u.arbitrary()?,
+                });
+            }
+            let data = encode_xattrs(&xattrs).unwrap();
+            assert_eq!(xattrs, decode_xattrs(&data).unwrap());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn paths() {
+        for path in ["usr/bin/ping", "./usr/bin/ping"] {
+            let entry_path = xattrs_entry_path(path);
+            assert_eq!(Path::new(".kpea-xattrs/usr/bin/ping.xattrs"), entry_path);
+            assert_eq!(Some("usr/bin/ping".into()), xattrs_target(&entry_path));
+            assert_eq!(
+                Some("usr/bin/ping".into()),
+                xattrs_target(Path::new("./").join(&entry_path))
+            );
+        }
+        assert_eq!(
+            Some("/usr/bin/ping".into()),
+            xattrs_target(xattrs_entry_path("/usr/bin/ping"))
+        );
+        assert_eq!(None, xattrs_target("usr/bin/ping"));
+        assert_eq!(None, xattrs_target(".kpea-xattrs/usr/bin/ping"));
+        assert_eq!(None, xattrs_target(".kpea-xattrs/.xattrs"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn xattrs() {
+        use std::os::fd::AsRawFd;
+
+        let workdir = TempDir::new().unwrap();
+        let input = workdir.path().join("input");
+        std::fs::create_dir(&input).unwrap();
+        std::fs::write(input.join("file"), b"hello").unwrap();
+        let name = c"user.test";
+        for path in [input.clone(), input.join("file")] {
+            let file = File::open(&path).unwrap();
+            match crate::fsetxattr(file.as_raw_fd(), name, b"value") {
+                Ok(()) => {}
+                Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return,
+                result => result.unwrap(),
+            }
+        }
+        let mut builder = Builder::new(Vec::new());
+        builder.preserve_xattrs(true);
+        builder.append_path(&input, "input").unwrap();
+        builder
+            .append_path(input.join("file"), "input/file")
+            .unwrap();
+        builder
+            .append_data(Metadata::regular(0o644, 0), "plain", b"data")
+            .unwrap();
+        let bytes = builder.finish().unwrap();
+        // the attributes are restored
+        let output = workdir.path().join("output");
+        let mut archive = Archive::new(&bytes[..]);
+        archive.set_unpack_options(UnpackOptions::new().xattrs(true));
+        archive.unpack(&output).unwrap();
+        for path in ["input", "input/file"] {
+            let file = File::open(output.join(path)).unwrap();
+            let xattrs = crate::fgetxattrs(file.as_raw_fd()).unwrap();
+            assert!(
+                xattrs.contains(&(name.into(), b"value".to_vec())),
+                "{:?}",
+                xattrs
+            );
+        }
+        assert!(!output.join(XATTRS_PREFIX).exists());
+        // the entries are unpacked as regular files
+        let output = workdir.path().join("output2");
+        Archive::new(&bytes[..]).unpack(&output).unwrap();
+        assert!(output.join(xattrs_entry_path("input")).is_file());
+        assert!(output.join(xattrs_entry_path("input/file")).is_file());
+    }
+}